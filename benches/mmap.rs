@@ -0,0 +1,104 @@
+//! Compare the buffered and memory-mapped comparison paths.
+//!
+//! Run with `cargo bench --bench mmap`. Files of `RSDIFF_BENCH_MB`
+//! megabytes (default 256) are written to the temporary directory: a pair
+//! of raw files for `diff_bytes` and a pair of float32 NIfTI images for the
+//! voxel comparison. Each path is timed several times after a warm-up run,
+//! so the files are in the page cache and the numbers reflect the cost of
+//! getting bytes from the cache into the comparison.
+
+use std::{
+    env,
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    time::Instant,
+};
+
+use rsdiff::{diff_bytes_with_options, diff_nii_with_options, Diff, DiffOptions,
+             Result};
+
+/// Timed runs per path, after one warm-up run.
+const RUNS: usize = 5;
+
+fn main() {
+    let megabytes: usize = env::var("RSDIFF_BENCH_MB").ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(256);
+    let size = megabytes << 20;
+    let dir = env::temp_dir().join(format!("rsdiff-bench-{}",
+                                           std::process::id()));
+    fs::create_dir_all(&dir).expect("Can't create benchmark directory!");
+
+    let (left, right) = write_pair(&dir, "raw", &[], size);
+    bench("bytes", &left, &right, size, diff_bytes_with_options);
+    let header = nifti_header(megabytes);
+    let (left, right) = write_pair(&dir, "nii", &header, size);
+    bench("voxels", &left, &right, size, diff_nii_with_options);
+
+    fs::remove_dir_all(&dir).expect("Can't remove benchmark directory!");
+}
+
+/// Time the buffered and mapped paths of one comparison, printing the
+/// median throughput of each.
+fn bench(name: &str, left: &Path, right: &Path, size: usize,
+         diff: fn(&str, &str, &DiffOptions) -> Result<Diff>) {
+    let (left, right) = (left.to_str().unwrap(), right.to_str().unwrap());
+    for mmap in [false, true] {
+        let opts = DiffOptions::builder().mmap(mmap).build().unwrap();
+        diff(left, right, &opts).unwrap();
+        let mut seconds: Vec<f64> = (0..RUNS)
+            .map(|_| {
+                let start = Instant::now();
+                diff(left, right, &opts).unwrap();
+                start.elapsed().as_secs_f64()
+            })
+            .collect();
+        seconds.sort_by(|a, b| a.total_cmp(b));
+        let median = seconds[RUNS / 2];
+        println!("{:<7} {:<8} {:>8.1} ms {:>8.0} MB/s", name,
+                 if mmap { "mmap" } else { "buffered" }, median * 1e3,
+                 size as f64 / median / 1e6);
+    }
+}
+
+/// Write two files that start with `header` and hold `size` bytes of data
+/// each, differing in one byte in every 4096.
+fn write_pair(dir: &Path, extension: &str, header: &[u8], size: usize)
+    -> (PathBuf, PathBuf) {
+    let data: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+    let mut changed = data.clone();
+    for byte in changed.iter_mut().step_by(4096) {
+        *byte = byte.wrapping_add(1);
+    }
+    let left = dir.join(format!("left.{}", extension));
+    let right = dir.join(format!("right.{}", extension));
+    for (path, data) in [(&left, &data), (&right, &changed)] {
+        let mut file = fs::File::create(path).unwrap();
+        file.write_all(header).unwrap();
+        file.write_all(data).unwrap();
+    }
+    (left, right)
+}
+
+/// A little-endian NIfTI-1 header for a 256 x 1024 x `slices` float32
+/// volume, a megabyte per slice, with the voxels right after it.
+fn nifti_header(slices: usize) -> Vec<u8> {
+    let mut header = vec![0u8; 352];
+    let mut put = |offset: usize, bytes: &[u8]| {
+        header[offset..offset + bytes.len()].copy_from_slice(bytes);
+    };
+    put(0, &348i32.to_le_bytes());
+    let dim: [i16; 8] = [3, 256, 1024, slices as i16, 1, 1, 1, 1];
+    for (i, d) in dim.iter().enumerate() {
+        put(40 + 2 * i, &d.to_le_bytes());
+    }
+    put(70, &16i16.to_le_bytes());
+    put(72, &32i16.to_le_bytes());
+    for i in 0..8 {
+        put(76 + 4 * i, &1f32.to_le_bytes());
+    }
+    put(108, &352f32.to_le_bytes());
+    put(344, b"n+1\0");
+    header
+}
@@ -0,0 +1,25 @@
+//! Build script for rsdiff
+//!
+//! With the `capi` feature, this generates the C header declaring the C
+//! bindings into `OUT_DIR`, since a build mustn't write to the source tree.
+//! The copy committed as `include/rsdiff.h` is refreshed by running
+//! `cbindgen --output include/rsdiff.h`, and a test fails when it no
+//! longer matches the generated one.
+
+fn main() {
+    #[cfg(feature = "capi")]
+    write_c_header();
+}
+
+#[cfg(feature = "capi")]
+fn write_c_header() {
+    let crate_dir = std::env::var("CARGO_MANIFEST_DIR")
+        .expect("cargo sets CARGO_MANIFEST_DIR");
+    let out_dir = std::env::var("OUT_DIR").expect("cargo sets OUT_DIR");
+    println!("cargo:rerun-if-changed=src/capi.rs");
+    println!("cargo:rerun-if-changed=cbindgen.toml");
+    let header = std::path::Path::new(&out_dir).join("rsdiff.h");
+    cbindgen::generate(&crate_dir)
+        .expect("can't generate the C header")
+        .write_to_file(header);
+}
@@ -0,0 +1,73 @@
+//! Tolerant comparison of floating point numbers
+
+use serde::Serialize;
+
+/// FloatComparison
+/// How floating point voxels are compared. Absolute differences suit data
+/// on a known scale; intensities spanning orders of magnitude are better
+/// compared relative to their size, or by how many representable floats
+/// lie between them.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FloatComparison {
+    /// Match when the values are equal or differ by less than the absolute
+    /// tolerance, so that equal values match at a tolerance of zero.
+    #[default]
+    Absolute,
+    /// Match when `|a - b| <= rtol * max(|a|, |b|)`.
+    Relative(f64),
+    /// Match when at most this many units in the last place apart.
+    Ulps(u64),
+}
+
+impl FloatComparison {
+    /// Whether two single precision values match, given the absolute
+    /// tolerance.
+    pub fn same_f32(&self, a: f32, b: f32, tolerance: f32) -> bool {
+        match *self {
+            FloatComparison::Absolute => a == b || (a - b).abs() < tolerance,
+            FloatComparison::Relative(rtol) => {
+                a == b || (a - b).abs() as f64
+                    <= rtol * a.abs().max(b.abs()) as f64
+            }
+            FloatComparison::Ulps(max) => {
+                !a.is_nan() && !b.is_nan()
+                    && ulps(ordered_bits_f32(a), ordered_bits_f32(b)) <= max
+            }
+        }
+    }
+
+    /// Whether two double precision values match, given the absolute
+    /// tolerance.
+    pub fn same_f64(&self, a: f64, b: f64, tolerance: f64) -> bool {
+        match *self {
+            FloatComparison::Absolute => a == b || (a - b).abs() < tolerance,
+            FloatComparison::Relative(rtol) => {
+                a == b || (a - b).abs() <= rtol * a.abs().max(b.abs())
+            }
+            FloatComparison::Ulps(max) => {
+                !a.is_nan() && !b.is_nan()
+                    && ulps(ordered_bits_f64(a), ordered_bits_f64(b)) <= max
+            }
+        }
+    }
+}
+
+/// The bits of a float as an integer ordered like the floats themselves,
+/// so adjacent floats are adjacent integers, across zero too.
+fn ordered_bits_f32(x: f32) -> i128 {
+    let bits = x.to_bits() as i32;
+    (if bits < 0 { i32::MIN - bits } else { bits }) as i128
+}
+
+/// As `ordered_bits_f32`, for doubles.
+fn ordered_bits_f64(x: f64) -> i128 {
+    let bits = x.to_bits() as i64;
+    (if bits < 0 { i64::MIN - bits } else { bits }) as i128
+}
+
+/// How many units in the last place apart two floats are, given as
+/// ordered bits, saturating at the largest u64.
+fn ulps(a: i128, b: i128) -> u64 {
+    (a - b).unsigned_abs().min(u64::MAX as u128) as u64
+}
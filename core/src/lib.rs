@@ -0,0 +1,187 @@
+//! The comparison engine of rsdiff
+//!
+//! rsdiff reads objects from disk, but how it compares what it has read
+//! doesn't depend on where the bytes came from. This crate holds that
+//! part: counting matching bytes, reading typed voxels, comparing floats
+//! within tolerances, and summarizing how far apart numbers are. It needs
+//! no filesystem, so it builds for WebAssembly as well, where images come
+//! from a browser rather than a disk.
+
+mod float;
+mod metric;
+mod tally;
+mod voxel;
+
+use serde::Serialize;
+
+pub use float::FloatComparison;
+pub use metric::{Metric, MetricTally};
+pub use tally::{DifferenceTally, NumericDifferences};
+pub use voxel::{voxel_reader, VoxelRead};
+
+/// VoxelComparison
+/// How two buffers of voxels compare.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct VoxelComparison {
+    /// How many voxels match.
+    pub matched: usize,
+    /// How many voxels were compared.
+    pub total: usize,
+    /// How far apart the voxels are, unless none could be told apart.
+    pub numeric_differences: Option<NumericDifferences>,
+    /// The value of the requested metric, the fraction of matching voxels
+    /// by default, or None if the images have none.
+    pub similarity: Option<f64>,
+}
+
+/// Calculate how many bytes match between two buffers. The buffers must be
+/// of equal size.
+pub fn diff_buffer(left: &[u8], right: &[u8]) -> usize {
+    // Verify arrays match in size
+    if left.len() != right.len() {
+        panic!("Buffers supplied to rsdiff::diff_buffer must have the \
+               same length! Instead, left is size {} and right is size {}",
+               left.len(), right.len());
+    }
+    // Iterate and compare bytes
+    let mut matches: usize = 0;
+    for (a, b) in left.iter().zip(right.iter()) {
+        matches += (a == b) as usize;
+    }
+    matches
+}
+
+/// Compare two equally long buffers of little-endian voxels of a NIfTI
+/// datatype as rsdiff compares images: floats match under `comparison`,
+/// given the absolute `tolerance`, and other numbers when they're equal.
+/// Fails for datatypes rsdiff doesn't read, and for buffers that differ in
+/// length or don't hold whole voxels.
+pub fn compare_voxels(left: &[u8], right: &[u8], datatype: i16,
+                      comparison: FloatComparison, tolerance: f64,
+                      metric: Metric) -> Result<VoxelComparison, String> {
+    let (read, width) = voxel_reader(datatype)
+        .ok_or_else(|| format!("Unsupported datatype {}", datatype))?;
+    if left.len() != right.len() {
+        return Err(format!("Buffers differ in length: {} vs. {} bytes",
+                           left.len(), right.len()));
+    }
+    if !left.len().is_multiple_of(width) {
+        return Err(format!("Buffers don't hold whole voxels of {} bytes",
+                           width));
+    }
+    // Integers match when their bytes do; doubles can't tell apart 64-bit
+    // integers above 2^53
+    let same = |a: &[u8], b: &[u8], x: f64, y: f64| match datatype {
+        16 => comparison.same_f32(x as f32, y as f32, tolerance as f32),
+        64 => comparison.same_f64(x, y, tolerance),
+        _ => a == b,
+    };
+    let mut matched = 0;
+    let mut differences = DifferenceTally::default();
+    let mut metrics = MetricTally::default();
+    for (a, b) in left.chunks_exact(width).zip(right.chunks_exact(width)) {
+        let (x, y) = (read(a), read(b));
+        matched += same(a, b, x, y) as usize;
+        differences.add(x, y);
+        metrics.add(x, y);
+    }
+    let total = left.len() / width;
+    let similarity = match metric {
+        Metric::Matches if total == 0 => Some(1.0),
+        Metric::Matches => Some(matched as f64 / total as f64),
+        metric => metrics.value(metric),
+    };
+    Ok(VoxelComparison {
+        matched,
+        total,
+        numeric_differences: differences.finish(),
+        similarity,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Compare little-endian voxel buffers exactly.
+    fn compare(left: &[u8], right: &[u8], datatype: i16)
+        -> Result<VoxelComparison, String> {
+        compare_voxels(left, right, datatype, FloatComparison::Absolute, 0.0,
+                       Metric::Matches)
+    }
+
+    /// Two voxels on each side, the first equal and the second not.
+    fn pair<const N: usize>(same: [u8; N], left: [u8; N], right: [u8; N])
+        -> (Vec<u8>, Vec<u8>) {
+        ([same, left].concat(), [same, right].concat())
+    }
+
+    #[test]
+    fn every_datatype_counts_matching_voxels() {
+        let pairs = [
+            (2, pair(7u8.to_le_bytes(), 1u8.to_le_bytes(),
+                     2u8.to_le_bytes())),
+            (256, pair((-7i8).to_le_bytes(), (-1i8).to_le_bytes(),
+                       1i8.to_le_bytes())),
+            (4, pair((-7i16).to_le_bytes(), 300i16.to_le_bytes(),
+                     301i16.to_le_bytes())),
+            (512, pair(7u16.to_le_bytes(), 60000u16.to_le_bytes(),
+                       60001u16.to_le_bytes())),
+            (8, pair((-7i32).to_le_bytes(), i32::MAX.to_le_bytes(),
+                     i32::MIN.to_le_bytes())),
+            (768, pair(7u32.to_le_bytes(), u32::MAX.to_le_bytes(),
+                       0u32.to_le_bytes())),
+            (16, pair(0.5f32.to_le_bytes(), 1.5f32.to_le_bytes(),
+                      2.5f32.to_le_bytes())),
+            (64, pair(0.5f64.to_le_bytes(), 1.5f64.to_le_bytes(),
+                      2.5f64.to_le_bytes())),
+            (1024, pair((-7i64).to_le_bytes(), i64::MIN.to_le_bytes(),
+                        i64::MAX.to_le_bytes())),
+            (1280, pair(7u64.to_le_bytes(), 0u64.to_le_bytes(),
+                        u64::MAX.to_le_bytes())),
+        ];
+        for (datatype, (left, right)) in pairs {
+            let compared = compare(&left, &right, datatype).unwrap();
+            assert_eq!((compared.matched, compared.total), (1, 2),
+                       "datatype {}", datatype);
+            assert_eq!(compare(&left, &left, datatype).unwrap().matched, 2,
+                       "datatype {}", datatype);
+        }
+    }
+
+    #[test]
+    fn wide_integers_differing_above_2_to_the_53_dont_match() {
+        let big = 1i64 << 60;
+        // Both round to the same double
+        assert_eq!(big as f64, (big + 1) as f64);
+        let compared = compare(&big.to_le_bytes(), &(big + 1).to_le_bytes(),
+                               1024).unwrap();
+        assert_eq!(compared.matched, 0);
+        let big = 1u64 << 63;
+        let compared = compare(&big.to_le_bytes(), &(big + 1).to_le_bytes(),
+                               1280).unwrap();
+        assert_eq!(compared.matched, 0);
+    }
+
+    #[test]
+    fn floats_match_within_the_tolerance() {
+        let (left, right) = (1.0f32.to_le_bytes(), 1.01f32.to_le_bytes());
+        let compared = compare_voxels(&left, &right, 16,
+                                      FloatComparison::Absolute, 0.1,
+                                      Metric::Matches).unwrap();
+        assert_eq!(compared.matched, 1);
+        assert_eq!(compare(&left, &right, 16).unwrap().matched, 0);
+    }
+
+    #[test]
+    fn unusable_buffers_are_errors() {
+        assert!(compare(&[0; 2], &[0; 2], 128).is_err());
+        assert!(compare(&[0; 4], &[0; 2], 4).is_err());
+        assert!(compare(&[0; 3], &[0; 3], 4).is_err());
+    }
+
+    #[test]
+    fn matching_bytes_are_counted() {
+        assert_eq!(diff_buffer(b"abcd", b"abed"), 3);
+    }
+}
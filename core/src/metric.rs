@@ -0,0 +1,157 @@
+//! Image similarity metrics, and the running sums they are derived from
+
+use std::{fmt, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+/// Metric
+/// What the similarity of two NIfTI images is measured by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize,
+         Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Metric {
+    /// The fraction of voxels that match.
+    #[default]
+    Matches,
+    /// The Pearson correlation of the voxels, for continuous images.
+    Pearson,
+    /// The Dice coefficient of the nonzero voxels, for masks.
+    Dice,
+    /// The Jaccard index of the nonzero voxels, for masks.
+    Jaccard,
+    /// Dice for images holding only zeros and ones, and Pearson otherwise.
+    Auto,
+}
+
+impl fmt::Display for Metric {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Metric::Matches => "matches",
+            Metric::Pearson => "pearson",
+            Metric::Dice => "dice",
+            Metric::Jaccard => "jaccard",
+            Metric::Auto => "auto",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for Metric {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Metric, String> {
+        match s {
+            "matches" => Ok(Metric::Matches),
+            "pearson" => Ok(Metric::Pearson),
+            "dice" => Ok(Metric::Dice),
+            "jaccard" => Ok(Metric::Jaccard),
+            "auto" => Ok(Metric::Auto),
+            _ => Err(format!("Unknown similarity metric {}", s)),
+        }
+    }
+}
+
+/// MetricTally
+/// Running sums to derive similarity metrics from, voxel pair by voxel
+/// pair. Means and co-moments are updated as in Welford's algorithm, so
+/// that long images don't lose precision to large sums.
+#[derive(Debug, Clone, Copy)]
+pub struct MetricTally {
+    count: u64,
+    left_mean: f64,
+    right_mean: f64,
+    left_moment: f64,
+    right_moment: f64,
+    co_moment: f64,
+    left_on: u64,
+    right_on: u64,
+    both_on: u64,
+    binary: bool,
+}
+
+impl Default for MetricTally {
+    fn default() -> MetricTally {
+        MetricTally {
+            count: 0,
+            left_mean: 0.0,
+            right_mean: 0.0,
+            left_moment: 0.0,
+            right_moment: 0.0,
+            co_moment: 0.0,
+            left_on: 0,
+            right_on: 0,
+            both_on: 0,
+            binary: true,
+        }
+    }
+}
+
+impl MetricTally {
+    /// Count a pair of voxels. Pairs with NaN or infinity are left out,
+    /// as they have no place in a correlation.
+    pub fn add(&mut self, left: f64, right: f64) {
+        if !left.is_finite() || !right.is_finite() {
+            return;
+        }
+        self.count += 1;
+        let n = self.count as f64;
+        let left_delta = left - self.left_mean;
+        self.left_mean += left_delta / n;
+        let right_delta = right - self.right_mean;
+        self.right_mean += right_delta / n;
+        self.left_moment += left_delta * (left - self.left_mean);
+        self.right_moment += right_delta * (right - self.right_mean);
+        self.co_moment += left_delta * (right - self.right_mean);
+        self.left_on += (left != 0.0) as u64;
+        self.right_on += (right != 0.0) as u64;
+        self.both_on += (left != 0.0 && right != 0.0) as u64;
+        self.binary &= (left == 0.0 || left == 1.0)
+            && (right == 0.0 || right == 1.0);
+    }
+
+    /// The metric to use for `metric`, settling `Auto` by whether the
+    /// images counted so far are masks.
+    pub fn resolve(&self, metric: Metric) -> Metric {
+        match metric {
+            Metric::Auto if self.binary => Metric::Dice,
+            Metric::Auto => Metric::Pearson,
+            metric => metric,
+        }
+    }
+
+    /// The value of a metric for the pairs counted, or None if it has none,
+    /// as a correlation with a constant image doesn't. Masks that are both
+    /// empty overlap perfectly.
+    pub fn value(&self, metric: Metric) -> Option<f64> {
+        match self.resolve(metric) {
+            Metric::Pearson => {
+                let spread = (self.left_moment * self.right_moment).sqrt();
+                if spread > 0.0 {
+                    Some(self.co_moment / spread)
+                }
+                else {
+                    None
+                }
+            }
+            Metric::Dice => {
+                let on = self.left_on + self.right_on;
+                if on == 0 {
+                    Some(1.0)
+                }
+                else {
+                    Some(2.0 * self.both_on as f64 / on as f64)
+                }
+            }
+            Metric::Jaccard => {
+                let either = self.left_on + self.right_on - self.both_on;
+                if either == 0 {
+                    Some(1.0)
+                }
+                else {
+                    Some(self.both_on as f64 / either as f64)
+                }
+            }
+            Metric::Matches | Metric::Auto => None,
+        }
+    }
+}
@@ -0,0 +1,74 @@
+//! Summaries of how far apart two sets of numbers are
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+/// NumericDifferences
+/// How far apart the numbers of two objects are, in the numbers' own
+/// units, which a count of matches doesn't say.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct NumericDifferences {
+    /// The root mean square of the differences.
+    pub rmse: f64,
+    /// The largest absolute difference.
+    pub max_abs: f64,
+    /// The mean absolute difference.
+    pub mean_abs: f64,
+}
+
+impl fmt::Display for NumericDifferences {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "RMSE {:.3e}, largest absolute difference {:.3e}, mean \
+                   absolute difference {:.3e}", self.rmse, self.max_abs,
+               self.mean_abs)
+    }
+}
+
+/// DifferenceTally
+/// Running sums to derive NumericDifferences from, pair of numbers by pair
+/// of numbers.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DifferenceTally {
+    count: u64,
+    sum_abs: f64,
+    sum_squares: f64,
+    max_abs: f64,
+}
+
+impl DifferenceTally {
+    /// Count `n` pairs of equal numbers.
+    pub fn add_equal(&mut self, n: usize) {
+        self.count += n as u64;
+    }
+
+    /// Count a pair of numbers. Pairs that differ by NaN or infinity have
+    /// no finite difference and are left out.
+    pub fn add(&mut self, a: f64, b: f64) {
+        if a == b || (a.is_nan() && b.is_nan()) {
+            self.count += 1;
+            return;
+        }
+        let difference = (a - b).abs();
+        if !difference.is_finite() {
+            return;
+        }
+        self.count += 1;
+        self.sum_abs += difference;
+        self.sum_squares += difference * difference;
+        self.max_abs = self.max_abs.max(difference);
+    }
+
+    /// The differences counted, or None if no pair was.
+    pub fn finish(&self) -> Option<NumericDifferences> {
+        if self.count == 0 {
+            return None;
+        }
+        let n = self.count as f64;
+        Some(NumericDifferences {
+            rmse: (self.sum_squares / n).sqrt(),
+            max_abs: self.max_abs,
+            mean_abs: self.sum_abs / n,
+        })
+    }
+}
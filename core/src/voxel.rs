@@ -0,0 +1,32 @@
+//! Reading typed voxels out of raw buffers
+
+use byteorder::{ByteOrder, LittleEndian};
+
+/// Reads one little-endian voxel as a double.
+pub type VoxelRead = fn(&[u8]) -> f64;
+
+/// How to read little-endian voxels of a NIfTI datatype as doubles, and
+/// how many bytes each takes, or None for datatypes that aren't numbers
+/// rsdiff reads.
+pub fn voxel_reader(datatype: i16) -> Option<(VoxelRead, usize)> {
+    let read: VoxelRead = match datatype {
+        2 => |b| b[0] as f64,
+        4 => |b| LittleEndian::read_i16(b) as f64,
+        8 => |b| LittleEndian::read_i32(b) as f64,
+        16 => |b| LittleEndian::read_f32(b) as f64,
+        64 => LittleEndian::read_f64,
+        512 => |b| LittleEndian::read_u16(b) as f64,
+        768 => |b| LittleEndian::read_u32(b) as f64,
+        1024 => |b| LittleEndian::read_i64(b) as f64,
+        1280 => |b| LittleEndian::read_u64(b) as f64,
+        256 => |b| b[0] as i8 as f64,
+        _ => return None,
+    };
+    let width = match datatype {
+        2 | 256 => 1,
+        4 | 512 => 2,
+        8 | 16 | 768 => 4,
+        _ => 8,
+    };
+    Some((read, width))
+}
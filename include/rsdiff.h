@@ -0,0 +1,146 @@
+#ifndef RSDIFF_H
+#define RSDIFF_H
+
+/* Generated by cbindgen from src/capi.rs; don't edit by hand. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * RsdiffStatus
+ * What became of a call into rsdiff.
+ */
+typedef enum rsdiff_status {
+  /**
+   * The call succeeded.
+   */
+  RSDIFF_STATUS_OK = 0,
+  /**
+   * A pointer was null, a string wasn't UTF-8, or an option was unknown
+   * or given a value it can't take.
+   */
+  RSDIFF_STATUS_INVALID_ARGUMENT = 1,
+  /**
+   * An object couldn't be read.
+   */
+  RSDIFF_STATUS_IO = 2,
+  /**
+   * The comparison couldn't be carried out otherwise.
+   */
+  RSDIFF_STATUS_ERROR = 3,
+} rsdiff_status;
+
+/**
+ * RsdiffOptions
+ * Options for a comparison, built up one at a time.
+ */
+typedef struct rsdiff_options rsdiff_options;
+
+/**
+ * RsdiffResult
+ * A comparison's result.
+ */
+typedef struct rsdiff_result rsdiff_result;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * Create options with every setting at its default. Free them with
+ * `rsdiff_options_free`.
+ */
+struct rsdiff_options *rsdiff_options_new(void);
+
+/**
+ * Set the option `name` to `value`, parsed as on the command line, e.g.
+ * "true" for flags. Options that can take several values, like
+ * "exclude" and "sentinels", gain one with each call.
+ *
+ * # Safety
+ * `opts` must come from `rsdiff_options_new`, and `name` and `value` must
+ * be null-terminated strings.
+ */
+enum rsdiff_status rsdiff_options_set(struct rsdiff_options *opts,
+                                      const char *name,
+                                      const char *value);
+
+/**
+ * Free options.
+ *
+ * # Safety
+ * `opts` must come from `rsdiff_options_new` and not have been freed, or
+ * be null.
+ */
+void rsdiff_options_free(struct rsdiff_options *opts);
+
+/**
+ * Compare two objects, with default options if `opts` is null. On success,
+ * `*result` is set to the result, to free with `rsdiff_result_free`.
+ *
+ * # Safety
+ * `left` and `right` must be null-terminated strings, `opts` must come
+ * from `rsdiff_options_new` or be null, and `result` must point to where
+ * a result can be stored.
+ */
+enum rsdiff_status rsdiff_diff(const char *left,
+                               const char *right,
+                               const struct rsdiff_options *opts,
+                               struct rsdiff_result **result);
+
+/**
+ * Whether the objects compared match.
+ *
+ * # Safety
+ * `result` must come from `rsdiff_diff` and not have been freed.
+ */
+bool rsdiff_result_matches(const struct rsdiff_result *result);
+
+/**
+ * How similar the objects compared are, from 0 to 1.
+ *
+ * # Safety
+ * `result` must come from `rsdiff_diff` and not have been freed.
+ */
+float rsdiff_result_similarity(const struct rsdiff_result *result);
+
+/**
+ * The comparison's report, as rsdiff prints it, which lives as long as
+ * the result does.
+ *
+ * # Safety
+ * `result` must come from `rsdiff_diff` and not have been freed.
+ */
+const char *rsdiff_result_report(const struct rsdiff_result *result);
+
+/**
+ * The comparison's JSON report, which lives as long as the result does.
+ *
+ * # Safety
+ * `result` must come from `rsdiff_diff` and not have been freed.
+ */
+const char *rsdiff_result_json(const struct rsdiff_result *result);
+
+/**
+ * Free a result.
+ *
+ * # Safety
+ * `result` must come from `rsdiff_diff` and not have been freed, or be
+ * null.
+ */
+void rsdiff_result_free(struct rsdiff_result *result);
+
+/**
+ * Why the last call on this thread that failed did, which lives until the
+ * next call that fails.
+ */
+const char *rsdiff_last_error(void);
+
+#ifdef __cplusplus
+} // extern "C"
+#endif // __cplusplus
+
+#endif /* RSDIFF_H */
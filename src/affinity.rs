@@ -0,0 +1,70 @@
+//! CPU affinity for rsdiff
+//!
+//! On multi-socket nodes, a thread that wanders away from the socket its
+//! buffers were allocated on pays for every byte it compares with
+//! cross-socket memory traffic. Pinning the threads rsdiff starts to a set
+//! of CPUs keeps them, and the buffers they allocate (Linux places pages on
+//! the node of the thread that first touches them), on those CPUs' nodes.
+//! Pinning is only supported on Linux; elsewhere it is a no-op.
+
+use std::{
+    io,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// Position in the CPU list of the next thread to pin.
+static NEXT: AtomicUsize = AtomicUsize::new(0);
+
+/// Parse a CPU list in the format of `taskset` and `/proc`, e.g.
+/// `0-3,8,10-11`.
+pub fn parse_cpu_list(s: &str) -> Result<Vec<usize>, String> {
+    let invalid = || format!("{} is not a valid CPU list", s);
+    let mut cpus = vec!();
+    for part in s.split(',') {
+        let part = part.trim();
+        match part.split_once('-') {
+            Some((first, last)) => {
+                let first: usize = first.parse().map_err(|_| invalid())?;
+                let last: usize = last.parse().map_err(|_| invalid())?;
+                if last < first {
+                    return Err(invalid());
+                }
+                cpus.extend(first..=last);
+            }
+            None => cpus.push(part.parse().map_err(|_| invalid())?),
+        }
+    }
+    Ok(cpus)
+}
+
+/// Pick the CPU for the next thread, spreading threads round-robin over
+/// the list. Returns None if no CPUs were given.
+pub fn next_cpu(cpus: &[usize]) -> Option<usize> {
+    if cpus.is_empty() {
+        return None;
+    }
+    Some(cpus[NEXT.fetch_add(1, Ordering::Relaxed) % cpus.len()])
+}
+
+/// Pin the calling thread to a CPU.
+#[cfg(target_os = "linux")]
+pub fn pin_current_thread(cpu: usize) -> io::Result<()> {
+    // SAFETY: cpu_set_t is plain data, initialized by CPU_ZERO before use,
+    // and sched_setaffinity only reads it
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        libc::CPU_ZERO(&mut set);
+        libc::CPU_SET(cpu, &mut set);
+        if libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(),
+                                   &set) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Pin the calling thread to a CPU.
+#[cfg(not(target_os = "linux"))]
+pub fn pin_current_thread(_cpu: usize) -> io::Result<()> {
+    Ok(())
+}
@@ -0,0 +1,477 @@
+//! Archive comparison for rsdiff
+//!
+//! Results are often shipped as zip or tar archives, and checking two of
+//! them used to mean extracting both first. Instead, the members of each
+//! archive are read into memory and compared the way directories are:
+//! members present on only one side are listed, and common members are
+//! compared as sub-diffs, level by level. Files are compared byte-wise and
+//! symbolic links by their targets.
+//!
+//! Zip archives, plain tar, and gzipped tar (`.tar.gz` or `.tgz`) are
+//! understood. Directories that an archive only implies, by holding files
+//! under them, count the same as ones it lists.
+
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{BufReader, Read},
+    path::{Path, PathBuf},
+    time::Instant,
+};
+
+use tar::Archive;
+
+use crate::{
+    diff_buffer, gz, hash::hash_bytes_with, interrupt, summarize_entries,
+    tarstream::normalize, Diff, DiffOptions, Result, RsdiffError, Unit,
+};
+
+/// Suffixes of the archives that can be compared.
+const SUFFIXES: [&str; 4] = [".zip", ".tar", ".tar.gz", ".tgz"];
+
+/// Member
+/// One entry of an archive.
+#[derive(Debug, Clone, PartialEq)]
+enum Member {
+    Directory,
+    File(Vec<u8>),
+    Link(String),
+}
+
+impl Member {
+    /// What kind of entry this is, for reports.
+    fn kind(&self) -> &'static str {
+        match self {
+            Member::Directory => "directory",
+            Member::File(_) => "file",
+            Member::Link(_) => "link",
+        }
+    }
+}
+
+/// An archive's members by their normalized paths.
+type Members = BTreeMap<PathBuf, Member>;
+
+/// Whether a path names an archive that can be compared.
+pub fn is_archive(path: &str) -> bool {
+    SUFFIXES.iter().any(|s| path.ends_with(s))
+}
+
+/// Compare the members of two archives.
+pub fn diff_archives(left: &str, right: &str) -> Result<Diff> {
+    diff_archives_with_options(left, right, &DiffOptions::default())
+}
+
+/// Compare the members of two archives with custom options. The archives
+/// need not be of the same kind; a zip can be compared against a tarball
+/// of the same files.
+pub fn diff_archives_with_options(left: &str, right: &str,
+                                  opts: &DiffOptions) -> Result<Diff> {
+    let left_members = read(left, opts)?;
+    let right_members = read(right, opts)?;
+    let level = Level {
+        left,
+        right,
+        left_members: &left_members,
+        right_members: &right_members,
+        opts,
+    };
+    Ok(level.diff(Path::new("")))
+}
+
+/// Level
+/// The two archives being compared, for comparing them level by level.
+struct Level<'a> {
+    left: &'a str,
+    right: &'a str,
+    left_members: &'a Members,
+    right_members: &'a Members,
+    opts: &'a DiffOptions,
+}
+
+impl Level<'_> {
+    /// Compare the members directly under `dir` on both sides, descending
+    /// into common directories.
+    fn diff(&self, dir: &Path) -> Diff {
+        let mut d = Diff::new(&self.label(self.left, dir),
+                              &self.label(self.right, dir));
+        let left_names = children(self.left_members, dir);
+        let right_names = children(self.right_members, dir);
+        for name in left_names.iter() {
+            if right_names.contains(name) {
+                d.common.push(name.to_string_lossy().into_owned());
+            }
+            else {
+                d.left_only.push(name.to_string_lossy().into_owned());
+            }
+        }
+        d.right_only = right_names.iter()
+            .filter(|name| !left_names.contains(name))
+            .map(|name| name.to_string_lossy().into_owned())
+            .collect();
+        for name in left_names.iter().filter(|n| right_names.contains(n)) {
+            if interrupt::requested() {
+                d.interrupted = true;
+                break;
+            }
+            let path = dir.join(name);
+            let started = Instant::now();
+            let mut subdiff = self.diff_member(&path);
+            subdiff.seconds = started.elapsed().as_secs_f64();
+            let stop = self.opts.fail_fast && !subdiff.matches;
+            d.interrupted |= subdiff.interrupted;
+            d.sub_diffs.push(Box::new(subdiff));
+            if stop || d.interrupted {
+                break;
+            }
+        }
+        summarize_entries(&mut d, self.opts);
+        d
+    }
+
+    /// Compare a member both archives have.
+    fn diff_member(&self, path: &Path) -> Diff {
+        match (&self.left_members[path], &self.right_members[path]) {
+            (Member::Directory, Member::Directory) => self.diff(path),
+            (Member::File(l), Member::File(r)) => {
+                let mut d = Diff::new(&self.label(self.left, path),
+                                      &self.label(self.right, path));
+                diff_contents(&mut d, l, r, self.opts);
+                d
+            }
+            (Member::Link(l), Member::Link(r)) => {
+                let mut d = Diff::new(&self.label(self.left, path),
+                                      &self.label(self.right, path));
+                d.set_counts((l == r) as usize, 1, Unit::Entries);
+                d.matches = l == r;
+                if !d.matches {
+                    d.additional_info = format!("link to {} vs. link to {}",
+                                                l, r);
+                    d.report = format!("{} vs {}: {}", d.left, d.right,
+                                       d.additional_info);
+                }
+                d
+            }
+            (l, r) => {
+                let mut d = Diff::new(&self.label(self.left, path),
+                                      &self.label(self.right, path));
+                d.set_counts(0, 1, Unit::Entries);
+                d.additional_info = format!("{} vs. {}", l.kind(), r.kind());
+                d.report = format!("{} vs {}: {}", d.left, d.right,
+                                   d.additional_info);
+                d
+            }
+        }
+    }
+
+    /// Name a member of an archive in reports.
+    fn label(&self, archive: &str, path: &Path) -> String {
+        if path.as_os_str().is_empty() {
+            String::from(archive)
+        }
+        else {
+            format!("{}:{}", archive, path.to_string_lossy())
+        }
+    }
+}
+
+/// Compare the contents of two files from the archives.
+fn diff_contents(d: &mut Diff, left: &[u8], right: &[u8],
+                 opts: &DiffOptions) {
+    d.left_hash = opts.hasher().map(|h| hash_bytes_with(left, h));
+    d.right_hash = opts.hasher().map(|h| hash_bytes_with(right, h));
+    if left.len() == right.len() {
+        let total_matches = diff_buffer(left, right);
+        d.set_counts(total_matches, left.len(), Unit::Bytes);
+        d.matches = total_matches == left.len();
+        if !d.matches {
+            d.additional_info = format!(
+                "{} of {} bytes match ({:.1}%)",
+                total_matches, left.len(), d.similarity * 100.0
+            );
+        }
+    }
+    else {
+        d.additional_info = format!("file sizes differ: {} vs. {}",
+                                    left.len(), right.len());
+    }
+    if !d.matches {
+        d.report = format!("{} vs {}: {}", d.left, d.right, d.additional_info);
+    }
+}
+
+/// The names of the members directly under `dir`, in order.
+fn children(members: &Members, dir: &Path) -> Vec<PathBuf> {
+    members.keys()
+        .filter(|path| path.parent() == Some(dir))
+        .filter_map(|path| path.file_name().map(PathBuf::from))
+        .collect()
+}
+
+/// Read the members of an archive, leaving out excluded ones.
+fn read(path: &str, opts: &DiffOptions) -> Result<Members> {
+    let mut members = if path.ends_with(".zip") {
+        read_zip(path)?
+    }
+    else {
+        read_tar(path)?
+    };
+    members.retain(|p, _| !opts.excludes_path(p));
+    // Add the directories members are in, where the archive doesn't list
+    // them
+    let parents: Vec<PathBuf> = members.keys()
+        .flat_map(|p| p.ancestors().skip(1))
+        .filter(|p| !p.as_os_str().is_empty())
+        .map(Path::to_path_buf)
+        .collect();
+    for parent in parents {
+        members.entry(parent).or_insert(Member::Directory);
+    }
+    Ok(members)
+}
+
+/// Read the members of a zip archive.
+fn read_zip(path: &str) -> Result<Members> {
+    let corrupt = |e: zip::result::ZipError| RsdiffError::Corrupt(format!(
+        "can't read {} as a zip archive: {}", path, e
+    ));
+    let file = File::open(path).map_err(|e| RsdiffError::io(path, e))?;
+    let mut archive = zip::ZipArchive::new(BufReader::new(file))
+        .map_err(corrupt)?;
+    let mut members = Members::new();
+    for i in 0..archive.len() {
+        let mut entry = archive.by_index(i).map_err(corrupt)?;
+        let name = match entry.enclosed_name() {
+            Some(name) => normalize(&name),
+            None => continue,
+        };
+        if name.as_os_str().is_empty() {
+            continue;
+        }
+        let member = if entry.is_dir() {
+            Member::Directory
+        }
+        else {
+            let mut contents = vec!();
+            entry.read_to_end(&mut contents)
+                .map_err(|e| RsdiffError::io(path, e))?;
+            if entry.is_symlink() {
+                Member::Link(String::from_utf8_lossy(&contents).into_owned())
+            }
+            else {
+                Member::File(contents)
+            }
+        };
+        members.insert(name, member);
+    }
+    Ok(members)
+}
+
+/// Read the members of a tar archive, plain or gzipped. Entries other than
+/// files, directories, and symbolic links are skipped.
+fn read_tar(path: &str) -> Result<Members> {
+    let error = |e| RsdiffError::io(path, e);
+    let file = BufReader::new(File::open(path).map_err(error)?);
+    let reader: Box<dyn Read> = if path.ends_with(".tar") {
+        Box::new(file)
+    }
+    else {
+        Box::new(gz::decoder(file))
+    };
+    let mut archive = Archive::new(reader);
+    let mut members = Members::new();
+    for entry in archive.entries().map_err(error)? {
+        let mut entry = entry.map_err(error)?;
+        let name = normalize(&entry.path().map_err(error)?);
+        if name.as_os_str().is_empty() {
+            continue;
+        }
+        let kind = entry.header().entry_type();
+        let member = if kind.is_dir() {
+            Member::Directory
+        }
+        else if kind.is_symlink() {
+            let target = entry.link_name().map_err(error)?
+                .map(|t| t.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            Member::Link(target)
+        }
+        else if kind.is_file() {
+            let mut contents = vec!();
+            entry.read_to_end(&mut contents).map_err(error)?;
+            Member::File(contents)
+        }
+        else {
+            continue;
+        };
+        members.insert(name, member);
+    }
+    Ok(members)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{fs, io::Write};
+
+    use crate::workspace::{test_scratch, Scratch};
+
+    use flate2::{write::GzEncoder, Compression};
+    use tar::{Builder, EntryType, Header};
+    use zip::{write::SimpleFileOptions, ZipWriter};
+
+    /// A member of a test archive: its path, and for files their contents
+    /// or for links their target.
+    enum Entry {
+        Dir(&'static str),
+        File(&'static str, &'static str),
+        Link(&'static str, &'static str),
+    }
+
+    /// The path of an archive named `name` in a test's scratch directory.
+    fn scratch_path(scratch: &Scratch, name: &str) -> String {
+        scratch.path().join(name).to_string_lossy().into_owned()
+    }
+
+    fn write_zip(path: &str, entries: &[Entry]) {
+        let mut zip = ZipWriter::new(File::create(path).unwrap());
+        let options = SimpleFileOptions::default();
+        for entry in entries {
+            match *entry {
+                Entry::Dir(name) => zip.add_directory(name, options).unwrap(),
+                Entry::File(name, contents) => {
+                    zip.start_file(name, options).unwrap();
+                    zip.write_all(contents.as_bytes()).unwrap();
+                }
+                Entry::Link(name, target) => {
+                    zip.add_symlink(name, target, options).unwrap();
+                }
+            }
+        }
+        zip.finish().unwrap();
+    }
+
+    /// Write a tar archive, gzipped if its name says so.
+    fn write_tar(path: &str, entries: &[Entry]) {
+        let mut builder = Builder::new(vec!());
+        for entry in entries {
+            let mut header = Header::new_gnu();
+            header.set_mode(0o644);
+            let (name, data) = match *entry {
+                Entry::Dir(name) => {
+                    header.set_entry_type(EntryType::Directory);
+                    (name, "")
+                }
+                Entry::File(name, contents) => (name, contents),
+                Entry::Link(name, target) => {
+                    header.set_entry_type(EntryType::Symlink);
+                    header.set_link_name(target).unwrap();
+                    (name, "")
+                }
+            };
+            header.set_size(data.len() as u64);
+            builder.append_data(&mut header, name, data.as_bytes()).unwrap();
+        }
+        let tar = builder.into_inner().unwrap();
+        if path.ends_with(".tar") {
+            fs::write(path, tar).unwrap();
+        }
+        else {
+            let mut gz = GzEncoder::new(File::create(path).unwrap(),
+                                        Compression::default());
+            gz.write_all(&tar).unwrap();
+            gz.finish().unwrap();
+        }
+    }
+
+    /// The sub-diff of `d` for the member at `path`, searching every level.
+    fn member<'a>(d: &'a Diff, path: &str) -> &'a Diff {
+        d.flatten().into_iter()
+            .find(|sub| sub.left.ends_with(&format!(":{}", path)))
+            .unwrap_or_else(|| panic!("no sub-diff for {}", path))
+    }
+
+    #[test]
+    fn a_zip_pairs_with_a_tarball_of_the_same_files() {
+        let scratch = test_scratch("archive-same");
+        let zip = scratch_path(&scratch, "results.zip");
+        let tgz = scratch_path(&scratch, "results.tgz");
+        // The zip lists its directory; the tarball only implies it
+        write_zip(&zip, &[
+            Entry::Dir("sub/"),
+            Entry::File("sub/a.txt", "a\n"),
+            Entry::File("b.txt", "b\n"),
+        ]);
+        write_tar(&tgz, &[
+            Entry::File("./b.txt", "b\n"),
+            Entry::File("./sub/a.txt", "a\n"),
+        ]);
+        let d = diff_archives(&zip, &tgz).unwrap();
+        assert!(d.matches, "{}", d.report);
+        assert_eq!(d.common, vec!("b.txt", "sub"));
+        assert!(member(&d, "sub/a.txt").matches);
+    }
+
+    #[test]
+    fn members_pair_by_path_level_by_level() {
+        let scratch = test_scratch("archive-pairs");
+        let left = scratch_path(&scratch, "left.zip");
+        let right = scratch_path(&scratch, "right.tar");
+        write_zip(&left, &[
+            Entry::File("same.txt", "same\n"),
+            Entry::File("changed.txt", "left\n"),
+            Entry::File("sub/left-only.txt", "x\n"),
+            Entry::Link("sub/link", "same.txt"),
+            Entry::File("became-link", "x\n"),
+        ]);
+        write_tar(&right, &[
+            Entry::File("same.txt", "same\n"),
+            Entry::File("changed.txt", "right\n"),
+            Entry::File("sub/right-only.txt", "x\n"),
+            Entry::Link("sub/link", "changed.txt"),
+            Entry::Link("became-link", "same.txt"),
+        ]);
+        let d = diff_archives(&left, &right).unwrap();
+        assert!(!d.matches);
+        assert_eq!(d.common, vec!("became-link", "changed.txt", "same.txt",
+                                  "sub"));
+        assert!(member(&d, "same.txt").matches);
+        assert_eq!(member(&d, "changed.txt").additional_info,
+                   "file sizes differ: 5 vs. 6");
+        assert_eq!(member(&d, "became-link").additional_info,
+                   "file vs. link");
+        assert_eq!(member(&d, "sub/link").additional_info,
+                   "link to same.txt vs. link to changed.txt");
+        let sub = d.sub_diffs.iter().find(|s| s.left.ends_with(":sub"))
+            .unwrap();
+        assert_eq!(sub.left_only, vec!("left-only.txt"));
+        assert_eq!(sub.right_only, vec!("right-only.txt"));
+    }
+
+    #[test]
+    fn excluded_members_are_left_out() {
+        let scratch = test_scratch("archive-exclude");
+        let left = scratch_path(&scratch, "left.tar.gz");
+        let right = scratch_path(&scratch, "right.tar.gz");
+        write_tar(&left, &[Entry::File("data.txt", "x\n"),
+                           Entry::File("run.log", "left\n")]);
+        write_tar(&right, &[Entry::File("data.txt", "x\n"),
+                            Entry::File("run.log", "right\n")]);
+        let opts = DiffOptions {
+            exclude: vec!(String::from("*.log")), ..DiffOptions::default()
+        };
+        let d = diff_archives_with_options(&left, &right, &opts).unwrap();
+        assert!(d.matches, "{}", d.report);
+        assert_eq!(d.common, vec!("data.txt"));
+    }
+
+    #[test]
+    fn unreadable_archives_are_errors() {
+        let scratch = test_scratch("archive-corrupt");
+        let zip = scratch_path(&scratch, "corrupt.zip");
+        fs::write(&zip, b"not a zip").unwrap();
+        assert!(matches!(diff_archives(&zip, &zip),
+                         Err(RsdiffError::Corrupt(_))));
+    }
+}
@@ -0,0 +1,115 @@
+//! Status badges for rsdiff
+//!
+//! Long-running reproducibility efforts show how their outputs hold up in
+//! their READMEs and on their dashboards. rsdiff can render the result of
+//! a comparison as a small SVG badge in the style of shields.io, e.g.
+//! "dataset match | 99.98%", colored by how close the objects are, to be
+//! committed or served alongside the project.
+
+use crate::Diff;
+
+/// Height of a badge, in pixels.
+const HEIGHT: u32 = 20;
+/// Space on either side of each half's text, in pixels.
+const PADDING: f64 = 6.0;
+/// Background of the label half.
+const LABEL_COLOR: &str = "#555";
+/// Background of the value half, from the best result to the worst.
+const MATCH_COLOR: &str = "#4c1";
+const CLOSE_COLOR: &str = "#dfb317";
+const FAR_COLOR: &str = "#fe7d37";
+const DIFFERENT_COLOR: &str = "#e05d44";
+const INCOMPLETE_COLOR: &str = "#9f9f9f";
+
+/// Render the badge for a comparison. The value is the share of matching
+/// units, rounded down so that only a match reads 100%.
+pub fn render(label: &str, d: &Diff) -> String {
+    let (value, color) = if d.interrupted {
+        (String::from("incomplete"), INCOMPLETE_COLOR)
+    }
+    else if d.matches {
+        (String::from("100%"), MATCH_COLOR)
+    }
+    else if d.similarity < 0.0 {
+        (String::from("differs"), DIFFERENT_COLOR)
+    }
+    else {
+        let percent = (d.similarity as f64 * 10000.0).floor() / 100.0;
+        let color = if percent >= 99.0 {
+            CLOSE_COLOR
+        }
+        else if percent >= 90.0 {
+            FAR_COLOR
+        }
+        else {
+            DIFFERENT_COLOR
+        };
+        (format!("{:.2}%", percent.min(99.99)), color)
+    };
+    svg(label, &value, color)
+}
+
+/// Render the badge for a comparison that couldn't be carried out.
+pub fn render_failure(label: &str) -> String {
+    svg(label, "error", DIFFERENT_COLOR)
+}
+
+/// Lay out a badge in the flat style of shields.io.
+fn svg(label: &str, value: &str, color: &str) -> String {
+    let label_width = (text_width(label) + 2.0 * PADDING).round();
+    let value_width = (text_width(value) + 2.0 * PADDING).round();
+    let width = label_width + value_width;
+    let label_x = label_width / 2.0;
+    let value_x = label_width + value_width / 2.0;
+    let label = escape(label);
+    let value = escape(value);
+    format!(
+        concat!(
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{w}\" ",
+            "height=\"{h}\" role=\"img\" aria-label=\"{l}: {v}\">",
+            "<title>{l}: {v}</title>",
+            "<linearGradient id=\"s\" x2=\"0\" y2=\"100%\">",
+            "<stop offset=\"0\" stop-color=\"#bbb\" stop-opacity=\".1\"/>",
+            "<stop offset=\"1\" stop-opacity=\".1\"/></linearGradient>",
+            "<clipPath id=\"r\"><rect width=\"{w}\" height=\"{h}\" rx=\"3\" ",
+            "fill=\"#fff\"/></clipPath>",
+            "<g clip-path=\"url(#r)\">",
+            "<rect width=\"{lw}\" height=\"{h}\" fill=\"{lc}\"/>",
+            "<rect x=\"{lw}\" width=\"{vw}\" height=\"{h}\" fill=\"{vc}\"/>",
+            "<rect width=\"{w}\" height=\"{h}\" fill=\"url(#s)\"/></g>",
+            "<g fill=\"#fff\" text-anchor=\"middle\" ",
+            "font-family=\"Verdana,Geneva,DejaVu Sans,sans-serif\" ",
+            "font-size=\"11\">",
+            "<text x=\"{lx}\" y=\"15\" fill=\"#010101\" fill-opacity=\".3\">",
+            "{l}</text><text x=\"{lx}\" y=\"14\">{l}</text>",
+            "<text x=\"{vx}\" y=\"15\" fill=\"#010101\" fill-opacity=\".3\">",
+            "{v}</text><text x=\"{vx}\" y=\"14\">{v}</text></g></svg>\n"
+        ),
+        w = width, h = HEIGHT, lw = label_width, vw = value_width,
+        lx = label_x, vx = value_x, lc = LABEL_COLOR, vc = color,
+        l = label, v = value
+    )
+}
+
+/// Roughly how wide text is in 11px Verdana, in pixels. Badges are laid
+/// out without the font at hand, so characters are sized by kind.
+fn text_width(text: &str) -> f64 {
+    text.chars()
+        .map(|c| match c {
+            'i' | 'j' | 'l' | '.' | ',' | ':' | ';' | '\'' | '|' => 3.5,
+            ' ' | 'f' | 'r' | 't' | '(' | ')' | '[' | ']' | '-' => 4.5,
+            'm' | 'w' => 10.0,
+            '%' | 'M' | 'W' => 12.0,
+            c if c.is_ascii_uppercase() => 7.5,
+            _ => 7.0,
+        })
+        .sum()
+}
+
+/// Escape text for SVG.
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
@@ -0,0 +1,430 @@
+//! C bindings for rsdiff
+//!
+//! Pipeline tools written in C and C++ would otherwise run rsdiff as a
+//! process and parse its output. With the `capi` feature, the library
+//! exports a C interface, declared in `include/rsdiff.h`, which cbindgen
+//! generates:
+//!
+//! ```c
+//! rsdiff_options *opts = rsdiff_options_new();
+//! rsdiff_options_set(opts, "tolerance", "1e-6");
+//! rsdiff_result *result;
+//! if (rsdiff_diff("left.nii.gz", "right.nii.gz", opts, &result)
+//!         == RSDIFF_STATUS_OK) {
+//!     if (!rsdiff_result_matches(result))
+//!         puts(rsdiff_result_report(result));
+//!     rsdiff_result_free(result);
+//! }
+//! else {
+//!     fprintf(stderr, "%s\n", rsdiff_last_error());
+//! }
+//! rsdiff_options_free(opts);
+//! ```
+//!
+//! Options are set by name and value as strings, with the names the Python
+//! bindings take. Functions that fail return a status other than
+//! `RSDIFF_STATUS_OK`, and leave a message for `rsdiff_last_error`.
+
+use std::{
+    cell::RefCell,
+    collections::BTreeMap,
+    ffi::{CStr, CString},
+    fmt::Display,
+    os::raw::c_char,
+    panic::{self, AssertUnwindSafe},
+    ptr,
+    str::FromStr,
+};
+
+use crate::{
+    checksums::Checksums, differ_with_options, options::DiffOptionsBuilder,
+    preset::Preset, Metric, MixedCompression, RsdiffError, SymlinkPolicy, Unit,
+};
+
+thread_local! {
+    static LAST_ERROR: RefCell<CString> = RefCell::new(CString::default());
+}
+
+/// RsdiffStatus
+/// What became of a call into rsdiff.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RsdiffStatus {
+    /// The call succeeded.
+    Ok = 0,
+    /// A pointer was null, a string wasn't UTF-8, or an option was unknown
+    /// or given a value it can't take.
+    InvalidArgument = 1,
+    /// An object couldn't be read.
+    Io = 2,
+    /// The comparison couldn't be carried out otherwise.
+    Error = 3,
+}
+
+/// RsdiffOptions
+/// Options for a comparison, built up one at a time.
+#[derive(Debug, Default)]
+pub struct RsdiffOptions {
+    builder: DiffOptionsBuilder,
+    sentinels: Vec<f64>,
+}
+
+/// RsdiffResult
+/// A comparison's result.
+#[derive(Debug)]
+pub struct RsdiffResult {
+    matches: bool,
+    similarity: f32,
+    report: CString,
+    json: CString,
+}
+
+/// Create options with every setting at its default. Free them with
+/// `rsdiff_options_free`.
+#[no_mangle]
+pub extern "C" fn rsdiff_options_new() -> *mut RsdiffOptions {
+    Box::into_raw(Box::default())
+}
+
+/// Set the option `name` to `value`, parsed as on the command line, e.g.
+/// "true" for flags. Options that can take several values, like
+/// "exclude" and "sentinels", gain one with each call.
+///
+/// # Safety
+/// `opts` must come from `rsdiff_options_new`, and `name` and `value` must
+/// be null-terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn rsdiff_options_set(opts: *mut RsdiffOptions,
+                                            name: *const c_char,
+                                            value: *const c_char)
+    -> RsdiffStatus {
+    let opts = match opts.as_mut() {
+        Some(opts) => opts,
+        None => return fail(RsdiffStatus::InvalidArgument, "options are null"),
+    };
+    let set = string(name).and_then(|name| {
+        let value = string(value)?;
+        let builder = std::mem::take(&mut opts.builder);
+        opts.builder = set_option(builder, &mut opts.sentinels, name, value)?;
+        Ok(())
+    });
+    match set {
+        Ok(()) => RsdiffStatus::Ok,
+        Err(message) => fail(RsdiffStatus::InvalidArgument, &message),
+    }
+}
+
+/// Free options.
+///
+/// # Safety
+/// `opts` must come from `rsdiff_options_new` and not have been freed, or
+/// be null.
+#[no_mangle]
+pub unsafe extern "C" fn rsdiff_options_free(opts: *mut RsdiffOptions) {
+    if !opts.is_null() {
+        drop(Box::from_raw(opts));
+    }
+}
+
+/// Compare two objects, with default options if `opts` is null. On success,
+/// `*result` is set to the result, to free with `rsdiff_result_free`.
+///
+/// # Safety
+/// `left` and `right` must be null-terminated strings, `opts` must come
+/// from `rsdiff_options_new` or be null, and `result` must point to where
+/// a result can be stored.
+#[no_mangle]
+pub unsafe extern "C" fn rsdiff_diff(left: *const c_char, right: *const c_char,
+                                     opts: *const RsdiffOptions,
+                                     result: *mut *mut RsdiffResult)
+    -> RsdiffStatus {
+    if result.is_null() {
+        return fail(RsdiffStatus::InvalidArgument, "result is null");
+    }
+    *result = ptr::null_mut();
+    let sides = string(left).and_then(|l| Ok((l, string(right)?)));
+    let (left, right) = match sides {
+        Ok(sides) => sides,
+        Err(message) => return fail(RsdiffStatus::InvalidArgument, &message),
+    };
+    let built = match opts.as_ref() {
+        Some(opts) => opts.builder.clone()
+            .sentinels(opts.sentinels.clone())
+            .build(),
+        None => Ok(Default::default()),
+    };
+    let opts = match built {
+        Ok(opts) => opts,
+        Err(message) => return fail(RsdiffStatus::InvalidArgument, &message),
+    };
+    // Unwinding into C is undefined, so panics are reported as errors
+    let compared = panic::catch_unwind(AssertUnwindSafe(|| {
+        differ_with_options(left, right, &opts)
+    }));
+    let d = match compared {
+        Ok(Ok(d)) => d,
+        Ok(Err(e @ RsdiffError::Io { .. })) => {
+            return fail(RsdiffStatus::Io, &e.to_string());
+        }
+        Ok(Err(e)) => return fail(RsdiffStatus::Error, &e.to_string()),
+        Err(_) => return fail(RsdiffStatus::Error, "rsdiff panicked"),
+    };
+    *result = Box::into_raw(Box::new(RsdiffResult {
+        matches: d.matches,
+        similarity: d.similarity,
+        report: c_string(d.report.clone()),
+        json: c_string(d.to_json().to_string()),
+    }));
+    RsdiffStatus::Ok
+}
+
+/// Whether the objects compared match.
+///
+/// # Safety
+/// `result` must come from `rsdiff_diff` and not have been freed.
+#[no_mangle]
+pub unsafe extern "C" fn rsdiff_result_matches(result: *const RsdiffResult)
+    -> bool {
+    result.as_ref().is_some_and(|r| r.matches)
+}
+
+/// How similar the objects compared are, from 0 to 1.
+///
+/// # Safety
+/// `result` must come from `rsdiff_diff` and not have been freed.
+#[no_mangle]
+pub unsafe extern "C" fn rsdiff_result_similarity(result: *const RsdiffResult)
+    -> f32 {
+    result.as_ref().map_or(0.0, |r| r.similarity)
+}
+
+/// The comparison's report, as rsdiff prints it, which lives as long as
+/// the result does.
+///
+/// # Safety
+/// `result` must come from `rsdiff_diff` and not have been freed.
+#[no_mangle]
+pub unsafe extern "C" fn rsdiff_result_report(result: *const RsdiffResult)
+    -> *const c_char {
+    result.as_ref().map_or(ptr::null(), |r| r.report.as_ptr())
+}
+
+/// The comparison's JSON report, which lives as long as the result does.
+///
+/// # Safety
+/// `result` must come from `rsdiff_diff` and not have been freed.
+#[no_mangle]
+pub unsafe extern "C" fn rsdiff_result_json(result: *const RsdiffResult)
+    -> *const c_char {
+    result.as_ref().map_or(ptr::null(), |r| r.json.as_ptr())
+}
+
+/// Free a result.
+///
+/// # Safety
+/// `result` must come from `rsdiff_diff` and not have been freed, or be
+/// null.
+#[no_mangle]
+pub unsafe extern "C" fn rsdiff_result_free(result: *mut RsdiffResult) {
+    if !result.is_null() {
+        drop(Box::from_raw(result));
+    }
+}
+
+/// Why the last call on this thread that failed did, which lives until the
+/// next call that fails.
+#[no_mangle]
+pub extern "C" fn rsdiff_last_error() -> *const c_char {
+    LAST_ERROR.with(|e| e.borrow().as_ptr())
+}
+
+/// Leave a message for `rsdiff_last_error`, passing on the status.
+fn fail(status: RsdiffStatus, message: &str) -> RsdiffStatus {
+    LAST_ERROR.with(|e| *e.borrow_mut() = c_string(String::from(message)));
+    status
+}
+
+/// Convert a string for C, dropping any interior nulls.
+fn c_string(s: String) -> CString {
+    CString::new(s.replace('\0', "")).unwrap_or_default()
+}
+
+/// Borrow a C string as UTF-8.
+unsafe fn string<'a>(s: *const c_char) -> Result<&'a str, String> {
+    if s.is_null() {
+        return Err(String::from("a string is null"));
+    }
+    CStr::from_ptr(s).to_str()
+        .map_err(|e| format!("a string is not UTF-8: {}", e))
+}
+
+/// Set one option from its name and value.
+fn set_option(builder: DiffOptionsBuilder, sentinels: &mut Vec<f64>,
+              name: &str, value: &str) -> Result<DiffOptionsBuilder, String> {
+    Ok(match name {
+        "tolerance" => builder.tolerance(parsed(name, value)?),
+        "rtol" => builder.relative_tolerance(parsed(name, value)?),
+        "max_ulps" => builder.max_ulps(parsed(name, value)?),
+        "hash" => builder.hash(parsed(name, value)?),
+        "precheck" => builder.precheck(parsed(name, value)?),
+        "jobs" => builder.jobs(parsed(name, value)?),
+        "max_depth" => builder.max_depth(parsed(name, value)?),
+        "chunk_size" => builder.chunk_size(parsed(name, value)?),
+        "split_above" => builder.split_above(parsed(name, value)?),
+        "mmap" => builder.mmap(parsed(name, value)?),
+        "fail_fast" => builder.fail_fast(parsed(name, value)?),
+        "fail_on_inaccessible" => {
+            builder.fail_on_inaccessible(parsed(name, value)?)
+        }
+        "drift" => builder.drift(parsed(name, value)?),
+        "datalad" => builder.datalad(parsed(name, value)?),
+        "new_file" => builder.new_file(parsed(name, value)?),
+        "schema_only" => builder.schema_only(parsed(name, value)?),
+        "force_text" => builder.force_text(parsed(name, value)?),
+        "scaled_voxels" => builder.scaled_voxels(parsed(name, value)?),
+        "normalize_units" => builder.normalize_units(parsed(name, value)?),
+        "canonical_json" => builder.canonical_json(parsed(name, value)?),
+        "unordered_rows" => builder.unordered_rows(parsed(name, value)?),
+        "ignore_comments" => builder.ignore_comments(parsed(name, value)?),
+        "ignore_whitespace" => builder.ignore_whitespace(parsed(name, value)?),
+        "ignore_outputs" => builder.ignore_outputs(parsed(name, value)?),
+        "ignore_metadata" => builder.ignore_metadata(parsed(name, value)?),
+        "voxel_unit" => builder.voxel_unit(parsed::<Unit>(name, value)?),
+        "metric" => builder.metric(parsed::<Metric>(name, value)?),
+        "symlinks" => builder.symlinks(parsed::<SymlinkPolicy>(name, value)?),
+        "mixed_compression" => {
+            builder.mixed_compression(parsed::<MixedCompression>(name, value)?)
+        }
+        "sentinels" => {
+            sentinels.push(parsed(name, value)?);
+            builder
+        }
+        "hash_algorithm" => builder.hash_algorithm(value),
+        "checksums" => builder.checksums(Checksums::load(value)
+            .map_err(|e| e.to_string())?),
+        "exclude" => builder.exclude(value),
+        "ignore_keys" => builder.ignore_key(value),
+        "preset" => builder.preset(&Preset::named(value, &BTreeMap::new())
+            .map_err(|e| e.to_string())?),
+        "table_key" => builder.table_key(value),
+        "ignore_columns" => builder.ignore_column(value),
+        _ => return Err(format!("unknown option '{}'", name)),
+    })
+}
+
+/// Parse an option's value.
+fn parsed<T: FromStr>(name: &str, value: &str) -> Result<T, String>
+where T::Err: Display {
+    value.parse()
+        .map_err(|e| format!("invalid value '{}' for {}: {}", value, name, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+
+    use crate::workspace::test_scratch;
+
+    /// Compare two paths through the C interface, with default options.
+    fn diff(left: *const c_char, right: *const c_char)
+        -> (RsdiffStatus, *mut RsdiffResult) {
+        let mut result = ptr::null_mut();
+        let status = unsafe {
+            rsdiff_diff(left, right, ptr::null(), &mut result)
+        };
+        (status, result)
+    }
+
+    fn last_error() -> String {
+        unsafe { CStr::from_ptr(rsdiff_last_error()) }
+            .to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn the_committed_header_is_up_to_date() {
+        assert!(include_str!(concat!(env!("OUT_DIR"), "/rsdiff.h"))
+                    == include_str!("../include/rsdiff.h"),
+                "include/rsdiff.h is stale; regenerate it with \
+                 `cbindgen --output include/rsdiff.h`");
+    }
+
+    #[test]
+    fn results_are_read_through_the_accessors() {
+        let scratch = test_scratch("capi-diff");
+        let path = |name: &str, contents: &str| {
+            let path = scratch.path().join(name);
+            fs::write(&path, contents).unwrap();
+            CString::new(path.to_string_lossy().into_owned()).unwrap()
+        };
+        let (left, right) = (path("left.txt", "a\nb\n"),
+                             path("right.txt", "a\nc\n"));
+        let (status, result) = diff(left.as_ptr(), left.as_ptr());
+        assert_eq!(status, RsdiffStatus::Ok);
+        unsafe {
+            assert!(rsdiff_result_matches(result));
+            assert_eq!(rsdiff_result_similarity(result), 1.0);
+            rsdiff_result_free(result);
+        }
+        let (status, result) = diff(left.as_ptr(), right.as_ptr());
+        assert_eq!(status, RsdiffStatus::Ok);
+        unsafe {
+            assert!(!rsdiff_result_matches(result));
+            assert!(rsdiff_result_similarity(result) < 1.0);
+            let report = CStr::from_ptr(rsdiff_result_report(result));
+            assert!(report.to_str().unwrap().contains("left.txt"));
+            let json = CStr::from_ptr(rsdiff_result_json(result));
+            let json: serde_json::Value =
+                serde_json::from_str(json.to_str().unwrap()).unwrap();
+            assert_eq!(json["matches"], false);
+            rsdiff_result_free(result);
+        }
+        let missing = CString::new("/nonexistent/left.txt").unwrap();
+        let (status, result) = diff(missing.as_ptr(), right.as_ptr());
+        assert_eq!(status, RsdiffStatus::Io);
+        assert!(result.is_null());
+    }
+
+    #[test]
+    fn bad_arguments_are_reported_not_crashed_on() {
+        let path = CString::new("left.txt").unwrap();
+        let (status, result) = diff(ptr::null(), path.as_ptr());
+        assert_eq!(status, RsdiffStatus::InvalidArgument);
+        assert!(result.is_null());
+        assert_eq!(last_error(), "a string is null");
+        let not_utf8 = CString::new(vec!(b'l', 0xff, 0xfe)).unwrap();
+        let (status, _) = diff(path.as_ptr(), not_utf8.as_ptr());
+        assert_eq!(status, RsdiffStatus::InvalidArgument);
+        assert!(last_error().starts_with("a string is not UTF-8"));
+        let status = unsafe {
+            rsdiff_diff(path.as_ptr(), path.as_ptr(), ptr::null(),
+                        ptr::null_mut())
+        };
+        assert_eq!(status, RsdiffStatus::InvalidArgument);
+        assert_eq!(last_error(), "result is null");
+        // Accessors and the free function take null results too
+        unsafe {
+            assert!(!rsdiff_result_matches(ptr::null()));
+            assert!(rsdiff_result_report(ptr::null()).is_null());
+            assert!(rsdiff_result_json(ptr::null()).is_null());
+            rsdiff_result_free(ptr::null_mut());
+        }
+    }
+
+    #[test]
+    fn options_are_set_by_name() {
+        let opts = rsdiff_options_new();
+        let set = |name: &str, value: &str| {
+            let (name, value) = (CString::new(name).unwrap(),
+                                 CString::new(value).unwrap());
+            unsafe { rsdiff_options_set(opts, name.as_ptr(), value.as_ptr()) }
+        };
+        assert_eq!(set("tolerance", "1e-6"), RsdiffStatus::Ok);
+        assert_eq!(set("exclude", "*.log"), RsdiffStatus::Ok);
+        assert_eq!(set("tolerance", "small"), RsdiffStatus::InvalidArgument);
+        assert_eq!(set("no_such_option", "1"), RsdiffStatus::InvalidArgument);
+        assert_eq!(last_error(), "unknown option 'no_such_option'");
+        unsafe { rsdiff_options_free(opts) };
+    }
+}
@@ -0,0 +1,304 @@
+//! Checksum databases for rsdiff
+//!
+//! Archives and data releases often come with the hashes their files had
+//! when they were published. Comparing two copies shows whether they agree
+//! with each other, not whether either still holds what was published: both
+//! could carry the same damage, or the one that differs could be the good
+//! one. Given a checksum database, each file's hash is computed as it is
+//! read for the comparison and checked against the recorded one, so a
+//! single pass both compares the copies and verifies each of them.
+//!
+//! A database is either a CSV file with the columns `path`, `algorithm`,
+//! and `hash`, or an SQLite database with a `checksums` table of the same
+//! columns. Paths are relative to the directories compared, and every file
+//! must be hashed with the same algorithm.
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::Read,
+    path::Path,
+};
+
+use rusqlite::{Connection, OpenFlags};
+
+use crate::{
+    hash::{self, hash_file, NewHasher},
+    table::Table,
+    Diff, DiffOptions, Result, RsdiffError,
+};
+
+/// How every SQLite database starts.
+const SQLITE_MAGIC: &[u8; 16] = b"SQLite format 3\0";
+
+/// Checksums
+/// The hashes files are recorded to have, by path.
+#[derive(Debug, Clone, Default)]
+pub struct Checksums {
+    /// Where the checksums were loaded from.
+    pub path: String,
+    /// The name of the algorithm the files were hashed with.
+    pub algorithm: String,
+    hashes: HashMap<String, String>,
+}
+
+impl Checksums {
+    /// Load checksums from a CSV file or an SQLite database.
+    pub fn load(path: &str) -> Result<Checksums> {
+        let mut magic = [0u8; 16];
+        let is_sqlite = File::open(path)
+            .and_then(|mut f| f.read_exact(&mut magic))
+            .is_ok_and(|()| &magic == SQLITE_MAGIC);
+        let rows = if is_sqlite {
+            sqlite_rows(path)?
+        }
+        else {
+            csv_rows(path)?
+        };
+        let mut checksums = Checksums {
+            path: String::from(path), ..Checksums::default()
+        };
+        for (name, algorithm, hash) in rows {
+            if checksums.algorithm.is_empty() {
+                checksums.algorithm = algorithm;
+            }
+            else if algorithm != checksums.algorithm {
+                return Err(RsdiffError::Corrupt(format!(
+                    "{} records both {} and {} hashes; files can only be \
+                     checked with one algorithm", path, checksums.algorithm,
+                    algorithm
+                )));
+            }
+            let name = name.strip_prefix("./").unwrap_or(&name);
+            checksums.hashes.insert(String::from(name), hash.to_lowercase());
+        }
+        if checksums.is_empty() {
+            return Err(RsdiffError::Corrupt(format!(
+                "{} records no checksums", path
+            )));
+        }
+        if hash::hasher(&checksums.algorithm).is_none() {
+            return Err(RsdiffError::Corrupt(format!(
+                "{} records {} hashes, which isn't a known algorithm; known \
+                 algorithms are {}", path, checksums.algorithm,
+                hash::algorithms().join(", ")
+            )));
+        }
+        Ok(checksums)
+    }
+
+    /// How many files have a recorded hash.
+    pub fn len(&self) -> usize {
+        self.hashes.len()
+    }
+
+    /// Whether no file has a recorded hash.
+    pub fn is_empty(&self) -> bool {
+        self.hashes.is_empty()
+    }
+
+    /// The hash recorded for the file at `name`, relative to the
+    /// directories compared.
+    pub fn recorded(&self, name: &str) -> Option<&str> {
+        self.hashes.get(name).map(String::as_str)
+    }
+
+    /// Check each side of a comparison of two files against its recorded
+    /// hash, if it has one, noting a finding for a side that doesn't match.
+    /// Hashes the comparison computed are used; a side it didn't hash is
+    /// hashed now.
+    pub(crate) fn verify(&self, d: &mut Diff, opts: &DiffOptions)
+        -> Result<()> {
+        let new_hasher = hash::new_hasher(&self.algorithm)
+            .ok_or_else(|| RsdiffError::Corrupt(format!(
+                "{} records {} hashes, which is no longer a known algorithm",
+                self.path, self.algorithm
+            )))?;
+        let left = self.verify_side(
+            &d.left, d.left_hash.as_deref(), opts,
+            new_hasher
+        )?;
+        let right = self.verify_side(
+            &d.right, d.right_hash.as_deref(), opts,
+            new_hasher
+        )?;
+        for (side, verified) in [("left", left), ("right", right)] {
+            if verified == Some(false) {
+                d.findings.push(format!(
+                    "{} file doesn't match the {} hash recorded in {}",
+                    side, self.algorithm, self.path
+                ));
+            }
+        }
+        d.left_verified = left;
+        d.right_verified = right;
+        Ok(())
+    }
+
+    /// Whether one file matches its recorded hash, or `None` if it has none.
+    /// The file is hashed with `new_hasher` unless its hash was `computed`.
+    fn verify_side(&self, path: &str, computed: Option<&str>,
+                   opts: &DiffOptions, new_hasher: NewHasher)
+        -> Result<Option<bool>> {
+        // Files compared on their own are looked up by their names
+        let name = if opts.relative_dir.as_os_str().is_empty() {
+            Path::new(path).file_name().unwrap_or_default()
+        }
+        else {
+            opts.relative_dir.as_os_str()
+        };
+        let Some(recorded) = self.recorded(&name.to_string_lossy()) else {
+            return Ok(None);
+        };
+        let hash = match computed {
+            Some(hash) => String::from(hash),
+            None => hash_file(path, new_hasher())
+                .map_err(|e| RsdiffError::io(path, e))?,
+        };
+        Ok(Some(hash == recorded))
+    }
+}
+
+/// The rows of a CSV file of checksums.
+fn csv_rows(path: &str) -> Result<Vec<(String, String, String)>> {
+    let table = Table::read_csv(path)?;
+    let column = |name| table.column(name).ok_or_else(|| {
+        RsdiffError::Corrupt(format!("{} has no {} column", path, name))
+    });
+    let (name, algorithm, hash) =
+        (column("path")?, column("algorithm")?, column("hash")?);
+    Ok(table.rows.iter()
+        .map(|row| (row[name].clone(), row[algorithm].clone(),
+                    row[hash].clone()))
+        .collect())
+}
+
+/// The rows of the checksums table of an SQLite database.
+fn sqlite_rows(path: &str) -> Result<Vec<(String, String, String)>> {
+    let unreadable = |e: rusqlite::Error| RsdiffError::Corrupt(
+        format!("can't read checksums from {}: {}", path, e)
+    );
+    let db = Connection::open_with_flags(path,
+                                         OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .map_err(unreadable)?;
+    let mut query = db.prepare("SELECT path, algorithm, hash FROM checksums")
+        .map_err(unreadable)?;
+    let rows = query.query_map([], |row| {
+        Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+    }).map_err(unreadable)?;
+    rows.collect::<rusqlite::Result<_>>().map_err(unreadable)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{fs, io::Write, path::PathBuf};
+
+    use flate2::{write::GzEncoder, Compression};
+
+    use crate::{differ_with_options, hash::hash_bytes,
+                workspace::test_scratch};
+
+    fn gzip(payload: &[u8]) -> Vec<u8> {
+        let mut gz = GzEncoder::new(vec!(), Compression::default());
+        gz.write_all(payload).unwrap();
+        gz.finish().unwrap()
+    }
+
+    /// Gzipped `good.txt.gz` and `bad.txt.gz` in a left and a right
+    /// directory under `dir`, with checksums recording the right hash of `good.txt.gz`
+    /// and a wrong one for `bad.txt.gz`.
+    fn gzipped_pairs(dir: &Path) -> (PathBuf, PathBuf, Checksums) {
+        let (left, right) = (dir.join("left"), dir.join("right"));
+        let mut csv = String::from("path,algorithm,hash\n");
+        for name in ["good.txt.gz", "bad.txt.gz"] {
+            let gz = gzip(b"same contents\n");
+            for side in [&left, &right] {
+                fs::create_dir_all(side).unwrap();
+                fs::write(side.join(name), &gz).unwrap();
+            }
+            let recorded = if name == "good.txt.gz" {
+                hash_bytes(&gz)
+            }
+            else {
+                hash_bytes(b"something else")
+            };
+            csv.push_str(&format!("./{},sha256,{}\n", name, recorded));
+        }
+        let db = dir.join("checksums.csv");
+        fs::write(&db, csv).unwrap();
+        let checksums = Checksums::load(&db.to_string_lossy()).unwrap();
+        (left, right, checksums)
+    }
+
+    fn verified(d: &Diff) -> (Option<bool>, Option<bool>, bool) {
+        let noted = d.findings.iter().any(|f| f.contains("doesn't match"));
+        (d.left_verified, d.right_verified, noted)
+    }
+
+    #[test]
+    fn checksums_load_from_csv() {
+        let scratch = test_scratch("checksums");
+        let (_, _, checksums) = gzipped_pairs(scratch.path());
+        assert_eq!(checksums.algorithm, "sha256");
+        assert_eq!(checksums.len(), 2);
+        assert!(checksums.recorded("good.txt.gz").is_some());
+        assert!(checksums.recorded("./good.txt.gz").is_none());
+    }
+
+    #[test]
+    fn mixed_or_unknown_algorithms_are_rejected() {
+        let scratch = test_scratch("checksums-algorithms");
+        let dir = scratch.path();
+        let db = dir.join("mixed.csv");
+        fs::write(&db, "path,algorithm,hash\na,sha256,00\nb,md5,00\n")
+            .unwrap();
+        assert!(Checksums::load(&db.to_string_lossy()).is_err());
+        let db = dir.join("unknown.csv");
+        fs::write(&db, "path,algorithm,hash\na,crc7,00\n").unwrap();
+        assert!(Checksums::load(&db.to_string_lossy()).is_err());
+        let db = dir.join("empty.csv");
+        fs::write(&db, "path,algorithm,hash\n").unwrap();
+        assert!(Checksums::load(&db.to_string_lossy()).is_err());
+    }
+
+    #[test]
+    fn gzipped_files_are_verified_as_they_are_stored() {
+        let scratch = test_scratch("checksums");
+        let (left, right, checksums) = gzipped_pairs(scratch.path());
+        let mut opts = DiffOptions::default();
+        opts.check_against(checksums);
+        let path = |dir: &PathBuf, name| {
+            dir.join(name).to_string_lossy().into_owned()
+        };
+        let d = differ_with_options(&path(&left, "good.txt.gz"),
+                                    &path(&right, "good.txt.gz"), &opts)
+            .unwrap();
+        assert!(d.matches);
+        assert_eq!(verified(&d), (Some(true), Some(true), false));
+        let d = differ_with_options(&path(&left, "bad.txt.gz"),
+                                    &path(&right, "bad.txt.gz"), &opts)
+            .unwrap();
+        assert!(d.matches);
+        assert_eq!(verified(&d), (Some(false), Some(false), true));
+    }
+
+    #[test]
+    fn gzipped_files_in_directories_are_verified_as_they_are_stored() {
+        let scratch = test_scratch("checksums");
+        let (left, right, checksums) = gzipped_pairs(scratch.path());
+        let mut opts = DiffOptions::default();
+        opts.check_against(checksums);
+        let d = differ_with_options(&left.to_string_lossy(),
+                                    &right.to_string_lossy(), &opts)
+            .unwrap();
+        let file = |name| *d.flatten().iter()
+            .find(|sub| sub.left.ends_with(name))
+            .unwrap();
+        assert_eq!(verified(file("good.txt.gz")), (Some(true), Some(true),
+                                                   false));
+        assert_eq!(verified(file("bad.txt.gz")), (Some(false), Some(false),
+                                                  true));
+    }
+}
@@ -0,0 +1,116 @@
+//! Source code comparison for rsdiff
+//!
+//! Pipeline scripts drift: a flag changes, a comment is reworded, a block
+//! is reindented. A byte-wise similarity says little about how much, since
+//! one inserted line shifts every byte after it. Scripts are instead split
+//! into tokens, which are aligned, and the similarity is the share of
+//! tokens in the longest common subsequence. Comments and whitespace can be
+//! left out, so that only changes to what the script does are counted.
+
+use std::fs;
+
+use crate::{sequence::lcs_len, Diff, DiffOptions, Result, RsdiffError, Unit};
+
+/// Extensions of scripts compared token by token. All of these languages
+/// use `#` comments.
+const EXTENSIONS: [&str; 5] = [".py", ".sh", ".bash", ".R", ".r"];
+/// Most insertions and deletions to consider when aligning tokens.
+const MAX_EDITS: usize = 100_000;
+
+/// Whether a file is a script to compare token by token.
+pub fn is_code(path: &str) -> bool {
+    EXTENSIONS.iter().any(|e| path.ends_with(e))
+}
+
+/// Compare two scripts token by token.
+pub fn diff_code(left: &str, right: &str) -> Result<Diff> {
+    diff_code_with_options(left, right, &DiffOptions::default())
+}
+
+/// Compare two scripts token by token with custom options.
+pub fn diff_code_with_options(left: &str, right: &str, opts: &DiffOptions)
+    -> Result<Diff> {
+    let left_text = fs::read(left).map_err(|e| RsdiffError::io(left, e))?;
+    let right_text = fs::read(right).map_err(|e| RsdiffError::io(right, e))?;
+    let left_text = String::from_utf8_lossy(&left_text);
+    let right_text = String::from_utf8_lossy(&right_text);
+    let left_tokens = tokenize(&left_text, opts);
+    let right_tokens = tokenize(&right_text, opts);
+
+    let mut d = Diff::new(left, right);
+    let lines = format!("{} vs. {} lines", left_text.lines().count(),
+                        right_text.lines().count());
+    let total = left_tokens.len().max(right_tokens.len());
+    match lcs_len(&left_tokens, &right_tokens, MAX_EDITS) {
+        Some(matched) => {
+            d.set_counts(matched, total, Unit::Tokens);
+            d.matches = left_tokens == right_tokens;
+            if !d.matches {
+                d.additional_info = format!(
+                    "{}; {} of {} tokens match ({:.1}%)", lines, matched,
+                    total, d.similarity * 100.0
+                );
+            }
+        }
+        None => {
+            d.additional_info = format!("{}; too different to align", lines);
+        }
+    }
+    if !d.matches {
+        d.report = format!("{} vs {}: {}", left, right, d.additional_info);
+    }
+    Ok(d)
+}
+
+/// Split a script into tokens: words, numbers, string literals, single
+/// punctuation characters, and, unless ignored, comments and runs of
+/// whitespace. With nothing ignored, the tokens reassemble the script.
+fn tokenize<'a>(text: &'a str, opts: &DiffOptions) -> Vec<&'a str> {
+    let mut tokens = vec!();
+    let mut rest = text;
+    while let Some(c) = rest.chars().next() {
+        let len = if c.is_whitespace() {
+            rest.find(|c: char| !c.is_whitespace()).unwrap_or(rest.len())
+        }
+        else if c == '#' {
+            rest.find('\n').unwrap_or(rest.len())
+        }
+        else if c == '"' || c == '\'' {
+            string_len(rest, c)
+        }
+        else if c.is_alphanumeric() || c == '_' || c == '.' {
+            rest.find(|c: char| !(c.is_alphanumeric() || c == '_' || c == '.'))
+                .unwrap_or(rest.len())
+        }
+        else {
+            c.len_utf8()
+        };
+        let (token, remainder) = rest.split_at(len);
+        rest = remainder;
+        let ignored = (c.is_whitespace() && opts.ignore_whitespace)
+            || (c == '#' && opts.ignore_comments);
+        if !ignored {
+            tokens.push(token);
+        }
+    }
+    tokens
+}
+
+/// Length of the string literal at the start of `text`, opened with
+/// `quote`, including its quotes. Backslash escapes are skipped over; an
+/// unterminated literal runs to the end of the text.
+fn string_len(text: &str, quote: char) -> usize {
+    let mut escaped = false;
+    for (i, c) in text.char_indices().skip(1) {
+        if escaped {
+            escaped = false;
+        }
+        else if c == '\\' {
+            escaped = true;
+        }
+        else if c == quote {
+            return i + c.len_utf8();
+        }
+    }
+    text.len()
+}
@@ -0,0 +1,136 @@
+//! Configuration files for rsdiff
+//!
+//! Settings that belong to a user or site rather than to a single run live
+//! in a TOML file. It is read from `--config`, then `$RSDIFF_CONFIG`, then
+//! `$XDG_CONFIG_HOME/rsdiff/config.toml` (or `~/.config/rsdiff/config.toml`).
+
+use std::{
+    collections::BTreeMap,
+    env,
+    fs,
+    path::PathBuf,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{hooks::Hook, preset::Preset, Result, RsdiffError};
+
+/// Config
+/// Settings loaded from an rsdiff configuration file.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Where the configuration was loaded from, if anywhere.
+    #[serde(skip_deserializing)]
+    pub path: Option<String>,
+    /// Path to an unencrypted minisign secret key used to sign reports.
+    pub signing_key: Option<String>,
+    /// Conversions to apply to files before comparing them.
+    pub hooks: Vec<Hook>,
+    /// Where to cache files converted by hooks.
+    pub cache_dir: Option<String>,
+    /// Presets to use by name with `--preset`, replacing any built-in ones
+    /// of the same name.
+    pub presets: BTreeMap<String, Preset>,
+}
+
+impl Config {
+    /// Load the configuration from an explicit path, or from the first
+    /// default location that exists. Without any file, the default
+    /// configuration is returned.
+    pub fn load(path: Option<&str>) -> Result<Config> {
+        let path = match path {
+            Some(p) => PathBuf::from(p),
+            None => match default_path() {
+                Some(p) => p,
+                None => return Ok(Config::default()),
+            },
+        };
+        let name = path.to_string_lossy().into_owned();
+        let text = fs::read_to_string(&path)
+            .map_err(|e| RsdiffError::io(&name, e))?;
+        let mut config: Config = toml::from_str(&text)
+            .map_err(|e| RsdiffError::Corrupt(format!(
+                "can't parse config file {}: {}", name, e
+            )))?;
+        config.path = Some(name);
+        Ok(config)
+    }
+}
+
+/// The first default config location that exists, if any.
+fn default_path() -> Option<PathBuf> {
+    if let Some(p) = env::var_os("RSDIFF_CONFIG") {
+        return Some(PathBuf::from(p));
+    }
+    let base = match env::var_os("XDG_CONFIG_HOME") {
+        Some(p) => PathBuf::from(p),
+        None => PathBuf::from(env::var_os("HOME")?).join(".config"),
+    };
+    let path = base.join("rsdiff").join("config.toml");
+    if path.is_file() { Some(path) } else { None }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::workspace::{test_scratch, Scratch};
+
+    /// Write a config file to a test's scratch directory and return its
+    /// path.
+    fn write(scratch: &Scratch, text: &str) -> String {
+        let path = scratch.path().join("config.toml");
+        fs::write(&path, text).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn every_setting_loads() {
+        let scratch = test_scratch("config-settings");
+        let path = write(&scratch, r#"
+            signing_key = "/keys/rsdiff.key"
+            cache_dir = "/tmp/rsdiff-cache"
+
+            [[hooks]]
+            pattern = "*.mgz"
+            command = "mri_convert {input} {output}"
+            extension = ".nii.gz"
+
+            [presets.site]
+            exclude = ["*.log"]
+            tolerance = 1e-5
+        "#);
+        let config = Config::load(Some(&path)).unwrap();
+        assert_eq!(config.path.as_deref(), Some(path.as_str()));
+        assert_eq!(config.signing_key.as_deref(), Some("/keys/rsdiff.key"));
+        assert_eq!(config.cache_dir.as_deref(), Some("/tmp/rsdiff-cache"));
+        assert_eq!(config.hooks.len(), 1);
+        assert_eq!(config.hooks[0].pattern, "*.mgz");
+        let site = &config.presets["site"];
+        assert_eq!(site.exclude, vec!("*.log"));
+        assert_eq!(site.tolerance, Some(1e-5));
+    }
+
+    #[test]
+    fn an_empty_file_is_the_default_config() {
+        let scratch = test_scratch("config-empty");
+        let config = Config::load(Some(&write(&scratch, ""))).unwrap();
+        assert!(config.signing_key.is_none());
+        assert!(config.hooks.is_empty());
+        assert!(config.presets.is_empty());
+    }
+
+    #[test]
+    fn bad_config_files_are_errors() {
+        let scratch = test_scratch("config-bad");
+        for (test, text) in [("syntax", "signing_key = "),
+                             ("unknown", "signing-key = \"x\""),
+                             ("type", "hooks = 3")] {
+            assert!(matches!(Config::load(Some(&write(&scratch, text))),
+                             Err(RsdiffError::Corrupt(_))), "{}", test);
+        }
+        assert!(matches!(Config::load(Some("/nonexistent/config.toml")),
+                         Err(RsdiffError::Io { .. })));
+    }
+}
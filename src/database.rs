@@ -0,0 +1,138 @@
+//! SQLite results for rsdiff
+//!
+//! Labs that re-validate a pipeline after every upgrade want to ask how
+//! its outputs have changed over time, which is a query, not a report.
+//! Each run is written to an SQLite database: the run itself, the latest
+//! result for every compared pair, keyed by the diff's stable node ID so a
+//! re-run updates rows rather than duplicating them, and each run's status
+//! for every pair, so history can be queried with plain SQL.
+
+use rusqlite::{params, Connection, Transaction};
+
+use crate::{provenance::Provenance, Diff};
+
+/// Tables and indexes, created if the database doesn't have them yet.
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS runs (
+        id INTEGER PRIMARY KEY,
+        started TEXT NOT NULL,
+        hostname TEXT NOT NULL,
+        command_line TEXT NOT NULL,
+        rsdiff_version TEXT NOT NULL,
+        left TEXT NOT NULL,
+        right TEXT NOT NULL,
+        matches INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS results (
+        id TEXT PRIMARY KEY,
+        parent TEXT,
+        left TEXT NOT NULL,
+        right TEXT NOT NULL,
+        matches INTEGER NOT NULL,
+        similarity REAL,
+        unit TEXT,
+        matched INTEGER NOT NULL,
+        total INTEGER NOT NULL,
+        additional_info TEXT NOT NULL,
+        left_hash TEXT,
+        right_hash TEXT,
+        seconds REAL NOT NULL,
+        max_relative_difference REAL,
+        interrupted INTEGER NOT NULL,
+        first_run INTEGER NOT NULL REFERENCES runs(id),
+        last_run INTEGER NOT NULL REFERENCES runs(id)
+    );
+    CREATE INDEX IF NOT EXISTS results_parent ON results(parent);
+    CREATE TABLE IF NOT EXISTS findings (
+        result TEXT NOT NULL REFERENCES results(id),
+        finding TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS findings_result ON findings(result);
+    CREATE TABLE IF NOT EXISTS one_sided (
+        result TEXT NOT NULL REFERENCES results(id),
+        side TEXT NOT NULL CHECK (side IN ('left', 'right')),
+        name TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS one_sided_result ON one_sided(result);
+    CREATE TABLE IF NOT EXISTS history (
+        run INTEGER NOT NULL REFERENCES runs(id),
+        result TEXT NOT NULL REFERENCES results(id),
+        matches INTEGER NOT NULL,
+        similarity REAL,
+        PRIMARY KEY (run, result)
+    );
+";
+
+/// Write a comparison to the database at `path`, creating it if need be.
+/// Everything is written in one transaction, so an interrupted write
+/// leaves the database as it was.
+pub fn save(path: &str, d: &Diff, prov: &Provenance) -> rusqlite::Result<()> {
+    let mut db = Connection::open(path)?;
+    db.execute_batch(SCHEMA)?;
+    let tx = db.transaction()?;
+    tx.execute(
+        "INSERT INTO runs (started, hostname, command_line, rsdiff_version,
+                           left, right, matches)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        params![prov.started, prov.hostname, prov.command_line.join(" "),
+                env!("CARGO_PKG_VERSION"), d.left, d.right, d.matches],
+    )?;
+    let run = tx.last_insert_rowid();
+    for (parent, node) in d.flatten_with_parents() {
+        save_node(&tx, run, parent.map(|p| p.id()), node)?;
+    }
+    tx.commit()
+}
+
+/// Upsert one node's result, replace its findings and one-sided entries,
+/// and record its status in this run.
+fn save_node(tx: &Transaction, run: i64, parent: Option<String>, node: &Diff)
+    -> rusqlite::Result<()> {
+    let id = node.id();
+    let similarity = node.unit.map(|_| node.similarity as f64);
+    tx.execute(
+        "INSERT INTO results (id, parent, left, right, matches, similarity,
+                              unit, matched, total, additional_info,
+                              left_hash, right_hash, seconds,
+                              max_relative_difference, interrupted,
+                              first_run, last_run)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14,
+                 ?15, ?16, ?16)
+         ON CONFLICT (id) DO UPDATE SET
+             parent = excluded.parent,
+             matches = excluded.matches,
+             similarity = excluded.similarity,
+             unit = excluded.unit,
+             matched = excluded.matched,
+             total = excluded.total,
+             additional_info = excluded.additional_info,
+             left_hash = excluded.left_hash,
+             right_hash = excluded.right_hash,
+             seconds = excluded.seconds,
+             max_relative_difference = excluded.max_relative_difference,
+             interrupted = excluded.interrupted,
+             last_run = excluded.last_run",
+        params![id, parent, node.left, node.right, node.matches, similarity,
+                node.unit.map(|u| u.to_string()), node.matched as i64,
+                node.total as i64, node.additional_info, node.left_hash,
+                node.right_hash, node.seconds, node.max_relative_difference,
+                node.interrupted, run],
+    )?;
+    tx.execute("DELETE FROM findings WHERE result = ?1", params![id])?;
+    for finding in node.findings.iter() {
+        tx.execute("INSERT INTO findings (result, finding) VALUES (?1, ?2)",
+                   params![id, finding])?;
+    }
+    tx.execute("DELETE FROM one_sided WHERE result = ?1", params![id])?;
+    for (side, names) in [("left", &node.left_only),
+                          ("right", &node.right_only)] {
+        for name in names.iter() {
+            tx.execute("INSERT INTO one_sided (result, side, name)
+                        VALUES (?1, ?2, ?3)", params![id, side, name])?;
+        }
+    }
+    tx.execute("INSERT INTO history (run, result, matches, similarity)
+                VALUES (?1, ?2, ?3, ?4)",
+               params![run, id, node.matches, similarity])?;
+    Ok(())
+}
@@ -0,0 +1,57 @@
+//! DataLad dataset awareness for rsdiff
+//!
+//! A DataLad dataset carries bookkeeping alongside its content: the git
+//! repository, and a `.datalad` directory holding the dataset's config
+//! (with its unique ID) and the records of `datalad run` commands. Two
+//! datasets with identical content still differ in all of these, so when
+//! comparing datasets they are left out of the comparison, and the dataset
+//! IDs are reported instead.
+
+use std::{fs, path::Path};
+
+/// Entries at the root of a dataset that hold bookkeeping, not content.
+pub const METADATA_ENTRIES: [&str; 2] = [".datalad", ".git"];
+
+/// Whether a directory is the root of a DataLad dataset.
+pub fn is_dataset(path: &str) -> bool {
+    Path::new(path).join(".datalad").join("config").is_file()
+}
+
+/// Read a dataset's ID from `.datalad/config`, if it has one.
+pub fn dataset_id(path: &str) -> Option<String> {
+    let config = fs::read_to_string(
+        Path::new(path).join(".datalad").join("config")
+    ).ok()?;
+    // The config is in git-config format; the ID is datalad.dataset.id
+    let mut in_section = false;
+    for line in config.lines() {
+        let line = line.trim();
+        if line.starts_with('[') {
+            in_section = line == "[datalad \"dataset\"]";
+        }
+        else if in_section {
+            if let Some((key, value)) = line.split_once('=') {
+                if key.trim() == "id" {
+                    return Some(String::from(value.trim()));
+                }
+            }
+        }
+    }
+    None
+}
+
+/// Describe how two datasets' IDs relate, for reporting as a finding.
+pub fn describe_ids(left: &str, right: &str) -> String {
+    let left_id = dataset_id(left);
+    let right_id = dataset_id(right);
+    let show = |id: &Option<String>| {
+        id.clone().unwrap_or_else(|| String::from("unknown"))
+    };
+    if left_id.is_some() && left_id == right_id {
+        format!("both are DataLad dataset {}", show(&left_id))
+    }
+    else {
+        format!("DataLad datasets {} and {}", show(&left_id),
+                show(&right_id))
+    }
+}
@@ -0,0 +1,88 @@
+//! Voxel difference images for rsdiff
+//!
+//! A voxel count says how much two images disagree, not where. The
+//! difference between two NIfTI images can be written as an image of its
+//! own, `left - right` per voxel, or as a mask marking the voxels that
+//! differ, to be overlaid on the inputs in a viewer. Both take their
+//! geometry from the left image's header, so they line up with it.
+//!
+//! Both images are read whole into memory, with their scaling applied, so
+//! differences are in the units a viewer would show.
+
+use ndarray::{Array, IxDyn};
+use nifti::{
+    writer::WriterOptions, IntoNdArray, NiftiHeader, NiftiObject,
+    ReaderOptions,
+};
+
+use crate::{DiffOptions, Result, RsdiffError};
+
+/// VoxelDifference
+/// Two NIfTI images' voxels, read for writing their difference.
+pub struct VoxelDifference {
+    /// The left image's header, which the outputs are written with.
+    header: NiftiHeader,
+    left: Array<f64, IxDyn>,
+    right: Array<f64, IxDyn>,
+}
+
+impl VoxelDifference {
+    /// Read two images' voxels. Returns None if their shapes diverge, since
+    /// then voxels can't be paired up.
+    pub fn read(left: &str, right: &str) -> Result<Option<VoxelDifference>> {
+        let read = |path: &str| {
+            ReaderOptions::new().read_file(path)
+                .and_then(|obj| {
+                    let header = obj.header().clone();
+                    obj.into_volume().into_ndarray::<f64>()
+                        .map(|voxels| (header, voxels))
+                })
+                .map_err(|e| RsdiffError::Nifti {
+                    path: String::from(path), source: e
+                })
+        };
+        let (mut header, left) = read(left)?;
+        let (_, right) = read(right)?;
+        if left.shape() != right.shape() {
+            return Ok(None);
+        }
+        // The left image's display range would clip differences in viewers
+        header.cal_min = 0.0;
+        header.cal_max = 0.0;
+        Ok(Some(VoxelDifference { header, left, right }))
+    }
+
+    /// Write `left - right` per voxel. Differences are stored as 64-bit
+    /// floats if the images' voxels have more precision than 32-bit floats
+    /// do, and as 32-bit floats otherwise.
+    pub fn write_difference(&self, path: &str) -> nifti::Result<()> {
+        let difference = &self.left - &self.right;
+        let writer = WriterOptions::new(path).reference_header(&self.header);
+        match self.header.datatype {
+            // float64, uint32, int64, uint64
+            64 | 768 | 1024 | 1280 => writer.write_nifti(&difference),
+            _ => writer.write_nifti(&difference.mapv(|v| v as f32)),
+        }
+    }
+
+    /// Write a mask of the voxels that differ, 1 where they do and 0 where
+    /// they don't. Floating-point voxels are compared the way the
+    /// comparison compared them.
+    pub fn write_mask(&self, path: &str, opts: &DiffOptions)
+        -> nifti::Result<()> {
+        let floats = matches!(self.header.datatype, 16 | 64);
+        let mut mask = self.left.mapv(|_| 0u8);
+        let pairs = self.left.iter().zip(self.right.iter());
+        for (m, (&a, &b)) in mask.iter_mut().zip(pairs) {
+            let same = if floats {
+                opts.float_comparison.same_f64(a, b, opts.tolerance)
+            }
+            else {
+                a == b
+            };
+            *m = !same as u8;
+        }
+        WriterOptions::new(path).reference_header(&self.header)
+            .write_nifti(&mask)
+    }
+}
@@ -0,0 +1,451 @@
+//! Combining diffs for rsdiff
+//!
+//! Large datasets are compared as array jobs, one subject or session per
+//! job, each writing its own JSON report. A DiffSet gathers those diffs,
+//! from memory or from the reports, and combines them into a single diff
+//! with one sub-diff per run, so the dataset gets one summary as if it had
+//! been compared in one go.
+//!
+//! Reports of shards of one directory comparison, from `--shard`, are
+//! merged back into the tree a single run would have produced.
+
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::{
+    drift::NumericDifferences, report, summarize_entries, Diff, DiffOptions,
+    Result, RsdiffError, Shard, Unit,
+};
+
+/// DiffSet
+/// Diffs from separate comparisons, to be combined into one.
+#[derive(Debug, Default)]
+pub struct DiffSet {
+    diffs: Vec<Diff>,
+}
+
+impl DiffSet {
+    /// Create an empty set.
+    pub fn new() -> DiffSet {
+        DiffSet::default()
+    }
+
+    /// Add a diff to the set.
+    pub fn add(&mut self, d: Diff) {
+        self.diffs.push(d);
+    }
+
+    /// Add the diff in a report written by `--format json`, `msgpack`, or
+    /// `cbor`, compressed or not. The encoding is told apart by the first
+    /// byte, since a report is a map and each encoding marks maps
+    /// differently.
+    pub fn load(&mut self, path: &str) -> Result<()> {
+        let bytes = report::read(path)?;
+        let corrupt = |format: &str, e: String| RsdiffError::Corrupt(
+            format!("{} is not an rsdiff {} report: {}", path, format, e)
+        );
+        let record: DiffRecord = match bytes.first() {
+            Some(0x80..=0x8f) | Some(0xde) | Some(0xdf) => {
+                rmp_serde::from_slice(&bytes)
+                    .map_err(|e| corrupt("MessagePack", e.to_string()))?
+            }
+            Some(0xa0..=0xbf) => ciborium::de::from_reader(&bytes[..])
+                .map_err(|e| corrupt("CBOR", match e {
+                    ciborium::de::Error::Io(e) => e.to_string(),
+                    e => e.to_string(),
+                }))?,
+            _ => serde_json::from_slice(&bytes)
+                .map_err(|e| corrupt("JSON", e.to_string()))?,
+        };
+        self.add(record.into_diff());
+        Ok(())
+    }
+
+    /// How many diffs are in the set.
+    pub fn len(&self) -> usize {
+        self.diffs.len()
+    }
+
+    /// Whether the set has no diffs.
+    pub fn is_empty(&self) -> bool {
+        self.diffs.is_empty()
+    }
+
+    /// The diffs in the set, in the order they were added.
+    pub fn diffs(&self) -> &[Diff] {
+        &self.diffs
+    }
+
+    /// Combine the set into one diff, with each diff as a sub-diff. The
+    /// combined diff compares the deepest directories holding every left
+    /// and every right path, matches if every diff did, and counts
+    /// similarity like a directory would. Shards of the same comparison
+    /// are first merged into one diff; if that leaves a single diff, it is
+    /// the result.
+    pub fn combine(self, opts: &DiffOptions) -> Diff {
+        let sharded = self.diffs.iter().all(|d| d.shard.is_some());
+        let mut diffs = merge_shards(self.diffs, opts);
+        if sharded && diffs.len() == 1 {
+            return diffs.remove(0);
+        }
+        let left = common_parent(diffs.iter().map(|d| d.left.as_str()));
+        let right = common_parent(diffs.iter().map(|d| d.right.as_str()));
+        let mut d = Diff::new(&left, &right);
+        d.common = diffs.iter().map(|s| s.left.clone()).collect();
+        d.interrupted = diffs.iter().any(|s| s.interrupted);
+        d.seconds = diffs.iter().map(|s| s.seconds).sum();
+        d.sub_diffs = diffs.into_iter().map(Box::new).collect();
+        summarize_entries(&mut d, opts);
+        d
+    }
+}
+
+/// Merge the shards of each comparison in a list of diffs, leaving other
+/// diffs as they are. Merged comparisons take the place of their first
+/// shard.
+fn merge_shards(diffs: Vec<Diff>, opts: &DiffOptions) -> Vec<Diff> {
+    let mut groups: Vec<Vec<Diff>> = vec!();
+    for d in diffs {
+        let group = groups.iter_mut().find(|g| {
+            d.shard.is_some() && g[0].shard.is_some()
+                && g[0].left == d.left && g[0].right == d.right
+        });
+        match group {
+            Some(g) => g.push(d),
+            None => groups.push(vec!(d)),
+        }
+    }
+    groups.into_iter()
+        .map(|group| {
+            if group[0].shard.is_none() {
+                return group.into_iter().next().unwrap();
+            }
+            let shards: Vec<Shard> = group.iter()
+                .filter_map(|d| d.shard)
+                .collect();
+            let mut d = merge_entries(group, opts);
+            check_shards(&mut d, &shards);
+            d
+        })
+        .collect()
+}
+
+/// Merge shards of a directory comparison, entry by entry, descending into
+/// the directories every shard compared.
+fn merge_entries(shards: Vec<Diff>, opts: &DiffOptions) -> Diff {
+    let mut d = Diff::new(&shards[0].left, &shards[0].right);
+    let mut entries: Vec<Vec<Diff>> = vec!();
+    for shard in shards {
+        for (merged, names) in [(&mut d.common, shard.common),
+                                (&mut d.left_only, shard.left_only),
+                                (&mut d.right_only, shard.right_only),
+                                (&mut d.skipped, shard.skipped),
+                                (&mut d.inaccessible, shard.inaccessible),
+                                (&mut d.findings, shard.findings)] {
+            for name in names {
+                if !merged.contains(&name) {
+                    merged.push(name);
+                }
+            }
+        }
+        d.seconds += shard.seconds;
+        d.interrupted |= shard.interrupted;
+        for subdiff in shard.sub_diffs {
+            match entries.iter_mut().find(|e| e[0].left == subdiff.left) {
+                Some(e) => e.push(*subdiff),
+                None => entries.push(vec!(*subdiff)),
+            }
+        }
+    }
+    // Files were compared by exactly one shard; directories by all of them
+    d.sub_diffs = entries.into_iter()
+        .map(|entry| {
+            if entry[0].shard.is_some() {
+                Box::new(merge_entries(entry, opts))
+            }
+            else {
+                Box::new(entry.into_iter().next().unwrap())
+            }
+        })
+        .collect();
+    summarize_entries(&mut d, opts);
+    d
+}
+
+/// Check that the merged shards are every shard of the comparison, and
+/// mark it as not matching if any are missing, since their files went
+/// uncompared.
+fn check_shards(d: &mut Diff, shards: &[Shard]) {
+    let count = shards.iter().map(|s| s.count).max().unwrap_or(0);
+    let missing: Vec<String> = (0..count)
+        .filter(|&i| !shards.contains(&Shard { index: i, count }))
+        .map(|i| i.to_string())
+        .collect();
+    let mismatched = shards.iter().any(|s| s.count != count);
+    if missing.is_empty() && !mismatched {
+        return;
+    }
+    let problem = if mismatched {
+        String::from("shards split the comparison different ways")
+    }
+    else {
+        format!("missing shard(s) {} of {}", missing.join(", "), count)
+    };
+    if d.matches {
+        d.report = format!("{} vs. {}\n", d.left, d.right);
+    }
+    d.matches = false;
+    d.findings.push(problem);
+}
+
+/// The deepest directory holding every path, or `.` if they share none.
+fn common_parent<'a>(paths: impl Iterator<Item = &'a str>) -> String {
+    let mut common: Option<PathBuf> = None;
+    for path in paths {
+        let path = Path::new(path);
+        let parent = path.parent().unwrap_or(path);
+        common = Some(match common {
+            None => parent.to_path_buf(),
+            Some(c) => c.components()
+                .zip(path.components())
+                .take_while(|(a, b)| a == b)
+                .map(|(a, _)| a)
+                .collect(),
+        });
+    }
+    match common {
+        Some(c) if !c.as_os_str().is_empty() => c.display().to_string(),
+        _ => String::from("."),
+    }
+}
+
+/// DiffRecord
+/// A diff as written to a JSON report by `Diff::to_json`.
+#[derive(Debug, Deserialize)]
+pub(crate) struct DiffRecord {
+    left: String,
+    right: String,
+    matches: bool,
+    similarity: Option<f32>,
+    unit: Option<Unit>,
+    #[serde(default)]
+    matched: usize,
+    #[serde(default)]
+    total: usize,
+    #[serde(default)]
+    additional_info: String,
+    #[serde(default)]
+    report: Option<String>,
+    #[serde(default)]
+    findings: Vec<String>,
+    #[serde(default)]
+    left_only: Vec<String>,
+    #[serde(default)]
+    right_only: Vec<String>,
+    #[serde(default)]
+    common: Vec<String>,
+    #[serde(default)]
+    skipped: Vec<String>,
+    #[serde(default)]
+    inaccessible: Vec<String>,
+    left_hash: Option<String>,
+    right_hash: Option<String>,
+    left_verified: Option<bool>,
+    right_verified: Option<bool>,
+    #[serde(default)]
+    seconds: f64,
+    #[serde(default)]
+    interrupted: bool,
+    max_relative_difference: Option<f64>,
+    shard: Option<Shard>,
+    numeric_differences: Option<NumericDifferences>,
+    mismatch_density: Option<Vec<f64>>,
+    hint: Option<String>,
+    #[serde(default)]
+    sub_diffs: Vec<DiffRecord>,
+}
+
+impl DiffRecord {
+    pub(crate) fn into_diff(self) -> Diff {
+        let mut d = Diff::new(&self.left, &self.right);
+        d.matches = self.matches;
+        d.similarity = self.similarity.unwrap_or(-1.0);
+        d.unit = self.unit;
+        d.matched = self.matched;
+        d.total = self.total;
+        // Reports written before the report was recorded are rebuilt the
+        // way leaf comparisons write them
+        d.report = match self.report {
+            Some(report) => report,
+            None if self.matches => String::new(),
+            None => format!("{} vs {}: {}", self.left, self.right,
+                            self.additional_info),
+        };
+        d.additional_info = self.additional_info;
+        d.findings = self.findings;
+        d.left_only = self.left_only;
+        d.right_only = self.right_only;
+        d.common = self.common;
+        d.skipped = self.skipped;
+        d.inaccessible = self.inaccessible;
+        d.left_hash = self.left_hash;
+        d.right_hash = self.right_hash;
+        d.left_verified = self.left_verified;
+        d.right_verified = self.right_verified;
+        d.seconds = self.seconds;
+        d.interrupted = self.interrupted;
+        d.max_relative_difference = self.max_relative_difference;
+        d.shard = self.shard;
+        d.numeric_differences = self.numeric_differences;
+        d.mismatch_density = self.mismatch_density;
+        d.hint = self.hint;
+        d.sub_diffs = self.sub_diffs.into_iter()
+            .map(|s| Box::new(s.into_diff()))
+            .collect();
+        d
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+
+    use crate::{differ_with_options, workspace::test_scratch};
+
+    /// Left and right trees under `dir` of files `0.txt` to `11.txt`, one
+    /// subdirectory, and one file that differs.
+    fn trees(dir: &Path) -> (String, String) {
+        let (left, right) = (dir.join("left"), dir.join("right"));
+        for side in [&left, &right] {
+            fs::create_dir_all(side.join("sub")).unwrap();
+            for i in 0..12 {
+                fs::write(side.join(format!("{}.txt", i)), format!("{}\n", i))
+                    .unwrap();
+                fs::write(side.join("sub").join(format!("{}.txt", i)),
+                          format!("sub {}\n", i))
+                    .unwrap();
+            }
+        }
+        fs::write(right.join("sub/7.txt"), "changed\n").unwrap();
+        (left.to_string_lossy().into_owned(),
+         right.to_string_lossy().into_owned())
+    }
+
+    /// Write a diff as a JSON report under `dir` and return its path.
+    fn save(dir: &Path, name: &str, d: &Diff) -> String {
+        let path = dir.join(name);
+        fs::write(&path, d.to_json().to_string()).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    /// The left paths of every comparison under a diff, sorted.
+    fn compared(d: &Diff) -> Vec<String> {
+        let mut lefts: Vec<String> = d.flatten().iter()
+            .map(|sub| sub.left.clone())
+            .collect();
+        lefts.sort();
+        lefts
+    }
+
+    #[test]
+    fn separate_comparisons_combine_under_their_common_parent() {
+        let scratch = test_scratch("diffset-combine");
+        let dir = scratch.path();
+        let (first, second) = (dir.join("first"), dir.join("second"));
+        let (left_a, right_a) = trees(&first);
+        let (left_b, right_b) = trees(&second);
+        fs::write(second.join("right/sub/7.txt"), "sub 7\n").unwrap();
+        let opts = DiffOptions::default();
+        let mut set = DiffSet::new();
+        set.add(differ_with_options(&left_a, &right_a, &opts).unwrap());
+        set.add(differ_with_options(&left_b, &right_b, &opts).unwrap());
+        assert_eq!(set.len(), 2);
+        let d = set.combine(&opts);
+        assert_eq!(d.left, dir.to_string_lossy());
+        assert_eq!(d.sub_diffs.len(), 2);
+        assert_eq!(d.common, vec!(left_a, left_b));
+        // One run differs, so the whole does
+        assert!(!d.sub_diffs[0].matches);
+        assert!(d.sub_diffs[1].matches);
+        assert!(!d.matches);
+    }
+
+    #[test]
+    fn shards_merge_back_into_the_whole_comparison() {
+        let scratch = test_scratch("diffset-shards");
+        let dir = scratch.path();
+        let (left, right) = trees(dir);
+        let whole = differ_with_options(&left, &right,
+                                        &DiffOptions::default())
+            .unwrap();
+        let mut set = DiffSet::new();
+        for index in 0..3 {
+            let opts = DiffOptions {
+                shard: Some(Shard { index, count: 3 }),
+                ..DiffOptions::default()
+            };
+            let d = differ_with_options(&left, &right, &opts).unwrap();
+            set.load(&save(dir, &format!("shard-{}.json", index), &d))
+                .unwrap();
+        }
+        let merged = set.combine(&DiffOptions::default());
+        assert_eq!(merged.left, whole.left);
+        assert_eq!(merged.matches, whole.matches);
+        assert_eq!((merged.matched, merged.total),
+                   (whole.matched, whole.total));
+        assert_eq!(compared(&merged), compared(&whole));
+        assert!(merged.findings.is_empty(), "{:?}", merged.findings);
+    }
+
+    #[test]
+    fn a_missing_shard_fails_the_merge() {
+        let scratch = test_scratch("diffset-missing-shard");
+        let dir = scratch.path();
+        let (left, right) = trees(dir);
+        fs::write(Path::new(&right).join("sub/7.txt"), "sub 7\n").unwrap();
+        let mut set = DiffSet::new();
+        for index in [0, 2] {
+            let opts = DiffOptions {
+                shard: Some(Shard { index, count: 3 }),
+                ..DiffOptions::default()
+            };
+            set.add(differ_with_options(&left, &right, &opts).unwrap());
+        }
+        let merged = set.combine(&DiffOptions::default());
+        assert!(!merged.matches);
+        assert_eq!(merged.findings, vec!("missing shard(s) 1 of 3"));
+    }
+
+    /// A diff's JSON without the timings, whose last bits JSON doesn't keep.
+    fn untimed(mut json: serde_json::Value) -> serde_json::Value {
+        if let Some(object) = json.as_object_mut() {
+            object.remove("seconds");
+            if let Some(subs) = object.get_mut("sub_diffs")
+                .and_then(|subs| subs.as_array_mut()) {
+                for sub in subs {
+                    *sub = untimed(sub.take());
+                }
+            }
+        }
+        json
+    }
+
+    #[test]
+    fn reports_load_as_they_were_written() {
+        let scratch = test_scratch("diffset-record");
+        let dir = scratch.path();
+        let (left, right) = trees(dir);
+        let d = differ_with_options(&left, &right, &DiffOptions::default())
+            .unwrap();
+        let mut set = DiffSet::new();
+        set.load(&save(dir, "report.json", &d)).unwrap();
+        let loaded = &set.diffs()[0];
+        assert_eq!(untimed(loaded.to_json()), untimed(d.to_json()));
+        let garbage = dir.join("garbage.json");
+        fs::write(&garbage, "not a report").unwrap();
+        assert!(set.load(&garbage.to_string_lossy()).is_err());
+    }
+}
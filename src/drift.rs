@@ -0,0 +1,113 @@
+//! Numeric drift for rsdiff
+//!
+//! Rerunning a pipeline on another machine or with another library build
+//! rarely reproduces floating point outputs bit for bit, and a methods
+//! section needs to say by how much they drift. Each comparison of numeric
+//! data can measure the largest relative difference between its numbers;
+//! across many files those measurements summarize as a distribution, so
+//! one run can establish that, say, all outputs agree to within 1e-5.
+
+use std::fmt;
+
+use crate::Diff;
+
+pub use rsdiff_core::NumericDifferences;
+pub(crate) use rsdiff_core::{voxel_reader, DifferenceTally, VoxelRead};
+
+/// Upper bounds of the buckets drift is counted in, with their labels.
+/// Buckets are finer around the tolerances methods sections tend to quote.
+const BOUNDS: [(f64, &str); 10] = [
+    (1e-15, "1e-15"), (1e-12, "1e-12"), (1e-9, "1e-9"), (1e-6, "1e-6"),
+    (1e-5, "1e-5"), (1e-4, "1e-4"), (1e-3, "1e-3"), (1e-2, "1e-2"),
+    (1e-1, "1e-1"), (1.0, "1"),
+];
+
+/// The relative difference between two numbers, `|a - b| / max(|a|, |b|)`.
+/// Equal numbers, including two NaNs, differ by zero; a NaN or infinity
+/// against anything else differs infinitely.
+pub fn relative_difference(a: f64, b: f64) -> f64 {
+    if a == b || (a.is_nan() && b.is_nan()) {
+        0.0
+    }
+    else if !a.is_finite() || !b.is_finite() {
+        f64::INFINITY
+    }
+    else {
+        (a - b).abs() / a.abs().max(b.abs())
+    }
+}
+
+/// The largest relative difference between two equally long buffers of
+/// little-endian voxels of a NIfTI datatype, or None for datatypes that
+/// aren't numbers rsdiff reads.
+pub fn max_voxel_difference(left: &[u8], right: &[u8], datatype: i16)
+    -> Option<f64> {
+    let (read, width) = voxel_reader(datatype)?;
+    let max = left.chunks_exact(width)
+        .zip(right.chunks_exact(width))
+        .map(|(a, b)| relative_difference(read(a), read(b)))
+        .fold(0.0, f64::max);
+    Some(max)
+}
+
+/// Fold a new measurement into a running maximum.
+pub fn record(max: &mut Option<f64>, difference: f64) {
+    *max = Some(max.map_or(difference, |m| m.max(difference)));
+}
+
+/// DriftSummary
+/// How the largest relative differences of many files are distributed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DriftSummary {
+    /// Each file that had numbers compared, with its largest relative
+    /// difference.
+    pub files: Vec<(String, f64)>,
+}
+
+impl DriftSummary {
+    /// Collect the measurements of a diff and all of its sub-diffs.
+    pub fn from_diff(d: &Diff) -> DriftSummary {
+        let files = d.flatten().into_iter()
+            .filter_map(|node| Some((node.left.clone(),
+                                     node.max_relative_difference?)))
+            .collect();
+        DriftSummary { files }
+    }
+
+    /// The file with the largest relative difference, if any file had
+    /// numbers compared.
+    pub fn worst(&self) -> Option<&(String, f64)> {
+        self.files.iter().max_by(|a, b| a.1.total_cmp(&b.1))
+    }
+}
+
+impl fmt::Display for DriftSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (worst_file, worst) = match self.worst() {
+            Some(w) => w,
+            None => return writeln!(f, "Numeric drift: no numbers compared"),
+        };
+        writeln!(f, "Numeric drift across {} file(s), by largest relative \
+                     difference:", self.files.len())?;
+        let exact = self.files.iter().filter(|(_, d)| *d == 0.0).count();
+        writeln!(f, "  {:<8}  {}", "exact", exact)?;
+        let mut lower = 0.0;
+        for &(bound, label) in BOUNDS.iter() {
+            let count = self.files.iter()
+                .filter(|&&(_, d)| d > lower && d <= bound)
+                .count();
+            writeln!(f, "  <= {:<5}  {}", label, count)?;
+            lower = bound;
+        }
+        let beyond = self.files.iter().filter(|(_, d)| *d > lower).count();
+        writeln!(f, "  {:<8}  {}", "> 1", beyond)?;
+        writeln!(f, "Largest: {:.2e} in {}", worst, worst_file)?;
+        match BOUNDS.iter().find(|&&(bound, _)| *worst <= bound) {
+            Some(_) if *worst == 0.0 => writeln!(f, "All outputs identical"),
+            Some((_, label)) => {
+                writeln!(f, "All outputs within {} relative", label)
+            }
+            None => writeln!(f, "Some outputs differ by more than 100%"),
+        }
+    }
+}
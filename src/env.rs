@@ -0,0 +1,126 @@
+//! Software environment comparison for rsdiff
+//!
+//! "Were these results produced with the same software?" comes up as often
+//! as "are these results the same?". Conda environments and virtualenvs
+//! are directories, but comparing them naively drowns real differences in
+//! bytecode caches and install-time bookkeeping. Environments are compared
+//! with those excluded, and the packages installed in each are compared by
+//! name and version, from conda's `conda-meta` records and the
+//! `.dist-info` directories of Python packages.
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::Path,
+};
+
+use serde_json::Value;
+
+use crate::{diff_directory_with_options, Diff, DiffOptions, Result};
+
+/// Paths left out of environment comparisons. `conda-meta` records when and
+/// from where packages were installed; its package list is compared
+/// separately.
+pub const DEFAULT_EXCLUDES: [&str; 6] = [
+    "__pycache__",
+    "*.pyc",
+    "*.pyo",
+    "conda-meta",
+    "*.dist-info/INSTALLER",
+    "*.dist-info/direct_url.json",
+];
+
+/// Compare two environment prefixes.
+pub fn diff_envs(left: &str, right: &str) -> Result<Diff> {
+    diff_envs_with_options(left, right, &DiffOptions::default())
+}
+
+/// Compare two environment prefixes with custom options. The default
+/// excludes are added to any given in the options.
+pub fn diff_envs_with_options(left: &str, right: &str, opts: &DiffOptions)
+    -> Result<Diff> {
+    let mut env_opts = opts.clone();
+    env_opts.exclude.extend(DEFAULT_EXCLUDES.iter().map(|p| String::from(*p)));
+    let mut d = diff_directory_with_options(left, right, &env_opts)?;
+
+    let left_packages = packages(Path::new(left));
+    let right_packages = packages(Path::new(right));
+    let mut changes = vec!();
+    for (name, version) in left_packages.iter() {
+        match right_packages.get(name) {
+            None => changes.push(format!("{} {} only in {}", name, version,
+                                         left)),
+            Some(other) if other != version => {
+                changes.push(format!("{} {} vs. {}", name, version, other));
+            }
+            _ => {}
+        }
+    }
+    for (name, version) in right_packages.iter() {
+        if !left_packages.contains_key(name) {
+            changes.push(format!("{} {} only in {}", name, version, right));
+        }
+    }
+    if !changes.is_empty() {
+        d.matches = false;
+        d.report = format!("{} vs. {}: packages differ\n  {}\n{}", left,
+                           right, changes.join("\n  "), d.report);
+    }
+    d.findings.push(format!("{} vs. {} packages compared",
+                            left_packages.len(), right_packages.len()));
+    Ok(d)
+}
+
+/// Find the packages installed in an environment, by name, with their
+/// versions. Python packages are keyed by `pip:` plus their name, as they
+/// may shadow conda packages of the same name.
+pub fn packages(prefix: &Path) -> BTreeMap<String, String> {
+    let mut found = BTreeMap::new();
+    if let Ok(entries) = fs::read_dir(prefix.join("conda-meta")) {
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.extension().is_none_or(|e| e != "json") {
+                continue;
+            }
+            let record: Option<Value> = fs::read_to_string(&path).ok()
+                .and_then(|text| serde_json::from_str(&text).ok());
+            if let Some(record) = record {
+                if let (Some(name), Some(version)) =
+                    (record["name"].as_str(), record["version"].as_str()) {
+                    found.insert(String::from(name), String::from(version));
+                }
+            }
+        }
+    }
+    for site_packages in site_packages_dirs(prefix) {
+        let entries = match fs::read_dir(&site_packages) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let package = name.strip_suffix(".dist-info")
+                .and_then(|p| p.rsplit_once('-'));
+            if let Some((package, version)) = package {
+                found.insert(format!("pip:{}", package.to_lowercase()),
+                             String::from(version));
+            }
+        }
+    }
+    found
+}
+
+/// Find the site-packages directories of an environment, in either the
+/// Unix (`lib/pythonX.Y/site-packages`) or Windows (`Lib/site-packages`)
+/// layout.
+fn site_packages_dirs(prefix: &Path) -> Vec<std::path::PathBuf> {
+    let mut dirs = vec!(prefix.join("Lib").join("site-packages"));
+    if let Ok(entries) = fs::read_dir(prefix.join("lib")) {
+        for entry in entries.flatten() {
+            if entry.file_name().to_string_lossy().starts_with("python") {
+                dirs.push(entry.path().join("site-packages"));
+            }
+        }
+    }
+    dirs.into_iter().filter(|d| d.is_dir()).collect()
+}
@@ -0,0 +1,95 @@
+//! Errors for rsdiff
+//!
+//! Differences between objects are results, not errors. An RsdiffError
+//! means a comparison couldn't be carried out at all: an object is missing
+//! or unreadable, or is of a kind rsdiff can't compare.
+
+use std::{error, fmt, io};
+
+/// RsdiffError
+/// Why a comparison couldn't be carried out.
+#[derive(Debug)]
+pub enum RsdiffError {
+    /// An object couldn't be opened or read.
+    Io { path: String, source: io::Error },
+    /// A directory was compared against something that isn't one.
+    NotADirectory(String),
+    /// A file was compared against something that isn't one.
+    NotAFile(String),
+    /// A NIfTI file's header couldn't be parsed.
+    Nifti { path: String, source: nifti::NiftiError },
+    /// A NIfTI data type that can't be compared voxel-wise.
+    UnsupportedDatatype(i16),
+    /// An object's data is damaged, so its contents can't be compared.
+    Corrupt(String),
+    /// A container image's layout couldn't be understood.
+    Image { path: String, reason: String },
+    /// Worker threads for a parallel comparison couldn't be started.
+    ThreadPool(String),
+    /// A preprocessing hook failed to convert a file.
+    Conversion { path: String, source: io::Error },
+}
+
+/// Result of an rsdiff operation.
+pub type Result<T> = std::result::Result<T, RsdiffError>;
+
+impl RsdiffError {
+    /// Wrap an I/O error with the path of the object it happened on.
+    pub fn io(path: &str, source: io::Error) -> RsdiffError {
+        RsdiffError::Io { path: String::from(path), source }
+    }
+
+    /// Whether an object couldn't be read because the user may not read
+    /// it, as with other people's files in a shared project space.
+    pub fn is_permission_denied(&self) -> bool {
+        let source = match self {
+            RsdiffError::Io { source, .. } => source,
+            RsdiffError::Nifti {
+                source: nifti::NiftiError::Io(source), ..
+            } => source,
+            _ => return false,
+        };
+        source.kind() == io::ErrorKind::PermissionDenied
+    }
+}
+
+impl fmt::Display for RsdiffError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RsdiffError::Io { path, source } => {
+                write!(f, "can't read {}: {}", path, source)
+            }
+            RsdiffError::NotADirectory(path) => {
+                write!(f, "{} is not a directory", path)
+            }
+            RsdiffError::NotAFile(path) => write!(f, "{} is not a file", path),
+            RsdiffError::Nifti { path, source } => {
+                write!(f, "can't read {} as NIfTI: {}", path, source)
+            }
+            RsdiffError::UnsupportedDatatype(dtype) => {
+                write!(f, "unsupported NIfTI data type {}", dtype)
+            }
+            RsdiffError::Corrupt(reason) => write!(f, "{}", reason),
+            RsdiffError::Image { path, reason } => {
+                write!(f, "can't read image {}: {}", path, reason)
+            }
+            RsdiffError::ThreadPool(reason) => {
+                write!(f, "can't start worker threads: {}", reason)
+            }
+            RsdiffError::Conversion { path, source } => {
+                write!(f, "can't convert {}: {}", path, source)
+            }
+        }
+    }
+}
+
+impl error::Error for RsdiffError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            RsdiffError::Io { source, .. } => Some(source),
+            RsdiffError::Nifti { source, .. } => Some(source),
+            RsdiffError::Conversion { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
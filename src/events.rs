@@ -0,0 +1,309 @@
+//! BIDS events file comparison for rsdiff
+//!
+//! Task events files log when each trial happened. Stimulus software
+//! records onsets with some jitter, so two runs of the same task, or a
+//! re-export of the same log, rarely agree to the microsecond. Trials are
+//! aligned by onset instead of by row, so a dropped trial doesn't shift
+//! every row after it, and onsets and durations are compared with a time
+//! tolerance. Other columns are compared exactly, unless ignored as for
+//! other tables.
+
+use crate::{
+    table::{self, Table, MISSING},
+    Diff, DiffOptions, Result, RsdiffError, Unit,
+};
+
+/// Columns holding times in seconds, compared with the onset tolerance.
+const TIME_COLUMNS: [&str; 2] = ["onset", "duration"];
+/// Most trial differences to describe in a report.
+const MAX_REPORTED: usize = 5;
+
+/// Whether a file is a BIDS events file.
+pub fn is_events(path: &str) -> bool {
+    path.ends_with("_events.tsv")
+}
+
+/// Compare two BIDS events files trial by trial.
+pub fn diff_events(left: &str, right: &str) -> Result<Diff> {
+    diff_events_with_options(left, right, &DiffOptions::default())
+}
+
+/// Compare two BIDS events files trial by trial with custom options.
+pub fn diff_events_with_options(left: &str, right: &str, opts: &DiffOptions)
+    -> Result<Diff> {
+    let left_table = Table::read_tsv(left)?;
+    let right_table = Table::read_tsv(right)?;
+    if opts.schema_only {
+        return Ok(table::diff_table_schemas(left, right, &left_table,
+                                            &right_table, opts));
+    }
+    let left_onsets = onsets(&left_table, left)?;
+    let right_onsets = onsets(&right_table, right)?;
+    let tolerance = opts.onset_tolerance;
+
+    let mut d = Diff::new(left, right);
+    let mut problems = vec!();
+    let left_only: Vec<&String> = left_table.columns.iter()
+        .filter(|c| !opts.ignore_columns.contains(c))
+        .filter(|c| right_table.column(c).is_none())
+        .collect();
+    let right_only: Vec<&String> = right_table.columns.iter()
+        .filter(|c| !opts.ignore_columns.contains(c))
+        .filter(|c| left_table.column(c).is_none())
+        .collect();
+    if !left_only.is_empty() {
+        problems.push(format!("columns only in left: {}",
+                              join(&left_only)));
+    }
+    if !right_only.is_empty() {
+        problems.push(format!("columns only in right: {}",
+                              join(&right_only)));
+    }
+    // Columns both sides have, as (left index, right index, name)
+    let shared: Vec<(usize, usize, &str)> = left_table.columns.iter()
+        .enumerate()
+        .filter(|(_, c)| !opts.ignore_columns.contains(c))
+        .filter_map(|(i, c)| Some((i, right_table.column(c)?, c.as_str())))
+        .collect();
+
+    // Walk both sides in onset order, pairing trials with close onsets
+    let left_order = by_onset(&left_onsets);
+    let right_order = by_onset(&right_onsets);
+    let (mut i, mut j) = (0, 0);
+    let (mut matched, mut total) = (0, 0);
+    let (mut left_unpaired, mut right_unpaired) = (vec!(), vec!());
+    let mut differing = vec!();
+    while i < left_order.len() || j < right_order.len() {
+        total += 1;
+        let (l, r) = (left_order.get(i), right_order.get(j));
+        let (l, r) = match (l, r) {
+            (Some(&l), Some(&r)) => {
+                let (a, b) = (left_onsets[l], right_onsets[r]);
+                if same_time(a, b, tolerance) {
+                    (l, r)
+                }
+                else if a < b || b.is_nan() {
+                    left_unpaired.push(a);
+                    i += 1;
+                    continue;
+                }
+                else {
+                    right_unpaired.push(b);
+                    j += 1;
+                    continue;
+                }
+            }
+            (Some(&l), None) => {
+                left_unpaired.push(left_onsets[l]);
+                i += 1;
+                continue;
+            }
+            (None, Some(&r)) => {
+                right_unpaired.push(right_onsets[r]);
+                j += 1;
+                continue;
+            }
+            (None, None) => unreachable!(),
+        };
+        i += 1;
+        j += 1;
+        let cells: Vec<String> = shared.iter()
+            .filter_map(|&(lc, rc, name)| {
+                let (a, b) = (&left_table.rows[l][lc],
+                              &right_table.rows[r][rc]);
+                let same = if TIME_COLUMNS.contains(&name) {
+                    same_cell_time(a, b, tolerance)
+                }
+                else {
+                    a == b
+                };
+                if same {
+                    None
+                }
+                else {
+                    Some(format!("{} {} vs. {}", name, a, b))
+                }
+            })
+            .collect();
+        if cells.is_empty() {
+            matched += 1;
+        }
+        else {
+            differing.push(format!("trial at {}s: {}", left_onsets[l],
+                                   cells.join(", ")));
+        }
+    }
+
+    d.set_counts(matched, total, Unit::Rows);
+    if !left_unpaired.is_empty() {
+        problems.push(format!("{} trial(s) only in left, at {}s",
+                              left_unpaired.len(), times(&left_unpaired)));
+    }
+    if !right_unpaired.is_empty() {
+        problems.push(format!("{} trial(s) only in right, at {}s",
+                              right_unpaired.len(), times(&right_unpaired)));
+    }
+    if differing.len() > MAX_REPORTED {
+        let more = differing.len() - MAX_REPORTED;
+        differing.truncate(MAX_REPORTED);
+        differing.push(format!("{} more differing trial(s)", more));
+    }
+    problems.extend(differing);
+    d.matches = problems.is_empty();
+    if !d.matches {
+        d.additional_info = format!(
+            "{} of {} trials match ({:.1}%); {}", matched, total,
+            d.similarity * 100.0, problems.join("; ")
+        );
+        d.report = format!("{} vs {}: {}", left, right, d.additional_info);
+    }
+    Ok(d)
+}
+
+/// The onset of every row, NaN where it is missing.
+fn onsets(table: &Table, path: &str) -> Result<Vec<f64>> {
+    let column = table.column("onset").ok_or_else(|| RsdiffError::Corrupt(
+        format!("{} has no onset column", path)
+    ))?;
+    Ok(table.rows.iter()
+        .map(|row| row[column].trim().parse().unwrap_or(f64::NAN))
+        .collect())
+}
+
+/// Row indices sorted by onset, with missing onsets last and ties kept in
+/// row order.
+fn by_onset(onsets: &[f64]) -> Vec<usize> {
+    let mut order: Vec<usize> = (0..onsets.len()).collect();
+    order.sort_by(|&a, &b| onsets[a].total_cmp(&onsets[b]));
+    order
+}
+
+/// Whether two times in seconds are within the tolerance.
+fn same_time(a: f64, b: f64, tolerance: f64) -> bool {
+    a == b || (a - b).abs() < tolerance || (a.is_nan() && b.is_nan())
+}
+
+/// Whether two cells hold the same time, or are both missing.
+fn same_cell_time(a: &str, b: &str, tolerance: f64) -> bool {
+    if a == b {
+        return true;
+    }
+    if a == MISSING || b == MISSING {
+        return false;
+    }
+    match (a.trim().parse::<f64>(), b.trim().parse::<f64>()) {
+        (Ok(x), Ok(y)) => same_time(x, y, tolerance),
+        _ => false,
+    }
+}
+
+/// List names for a report.
+fn join(names: &[&String]) -> String {
+    names.iter().map(|s| s.as_str()).collect::<Vec<_>>().join(", ")
+}
+
+/// List a few times for a report.
+fn times(times: &[f64]) -> String {
+    let mut listed: Vec<String> = times.iter()
+        .take(MAX_REPORTED)
+        .map(|t| t.to_string())
+        .collect();
+    if times.len() > MAX_REPORTED {
+        listed.push(String::from("..."));
+    }
+    listed.join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+
+    use crate::workspace::{test_scratch, Scratch};
+
+    /// Write an events file to a test's scratch directory and return its
+    /// path.
+    fn write(scratch: &Scratch, name: &str, text: &str) -> String {
+        let path = scratch.path().join(name);
+        fs::write(&path, text).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    fn compare(left: &str, right: &str, tolerance: f64) -> Diff {
+        let scratch = test_scratch("events");
+        let left = write(&scratch, "left_events.tsv", left);
+        let right = write(&scratch, "right_events.tsv", right);
+        let opts = DiffOptions {
+            onset_tolerance: tolerance,
+            ..DiffOptions::default()
+        };
+        diff_events_with_options(&left, &right, &opts).unwrap()
+    }
+
+    #[test]
+    fn trials_match_within_the_onset_tolerance() {
+        let d = compare("onset\tduration\ttrial_type\n1.0\t0.5\tgo\n\
+                         2.0\t0.5\tstop\n",
+                        "onset\tduration\ttrial_type\n1.001\t0.5005\tgo\n\
+                         2.0\t0.5\tstop\n", 0.01);
+        assert!(d.matches, "{}", d.report);
+        assert_eq!((d.matched, d.total), (2, 2));
+    }
+
+    #[test]
+    fn a_dropped_trial_does_not_shift_the_others() {
+        let d = compare("onset\ttrial_type\n1\tgo\n2\tstop\n3\tgo\n",
+                        "onset\ttrial_type\n1\tgo\n3\tgo\n", 0.01);
+        assert!(!d.matches);
+        assert_eq!((d.matched, d.total), (2, 3));
+        assert!(d.additional_info.contains("1 trial(s) only in left, at 2s"),
+                "{}", d.additional_info);
+    }
+
+    #[test]
+    fn rows_are_paired_by_onset_not_order() {
+        let d = compare("onset\ttrial_type\n2\tstop\n1\tgo\n",
+                        "onset\ttrial_type\n1\tgo\n2\tstop\n", 0.01);
+        assert!(d.matches, "{}", d.report);
+    }
+
+    #[test]
+    fn other_columns_are_compared_exactly() {
+        let d = compare("onset\tresponse_time\n1\t0.5\n",
+                        "onset\tresponse_time\n1\t0.50\n", 0.01);
+        assert!(!d.matches);
+        assert!(d.additional_info.contains("response_time 0.5 vs. 0.50"),
+                "{}", d.additional_info);
+    }
+
+    #[test]
+    fn missing_onsets_and_durations_match_each_other_only() {
+        let d = compare("onset\tduration\nn/a\tn/a\n1\tn/a\n",
+                        "onset\tduration\nn/a\tn/a\n1\t0\n", 0.01);
+        assert!(!d.matches);
+        assert_eq!((d.matched, d.total), (1, 2));
+        assert!(same_cell_time(MISSING, MISSING, 0.0));
+        assert!(!same_cell_time(MISSING, "0", 1.0));
+        assert!(same_time(f64::NAN, f64::NAN, 0.0));
+        assert!(same_time(1.5, 1.5, 0.0));
+    }
+
+    #[test]
+    fn empty_events_files_match() {
+        let d = compare("onset\tduration\n", "onset\tduration\n", 0.01);
+        assert!(d.matches, "{}", d.report);
+        assert_eq!((d.matched, d.total), (0, 0));
+        let d = compare("onset\tduration\n", "onset\tduration\n1\t1\n", 0.01);
+        assert!(!d.matches);
+        assert_eq!((d.matched, d.total), (0, 1));
+    }
+
+    #[test]
+    fn an_events_file_needs_an_onset_column() {
+        let scratch = test_scratch("events");
+        let left = write(&scratch, "no-onset_events.tsv", "duration\n1\n");
+        assert!(matches!(diff_events(&left, &left),
+                         Err(RsdiffError::Corrupt(_))));
+    }
+}
@@ -0,0 +1,134 @@
+//! Expected contents for rsdiff
+//!
+//! Some files should hold nothing but a constant: masked-out regions
+//! written as zeros, scratch files wiped before release, or padding filled
+//! with a marker byte. Checking them needs no second file to compare with.
+//! The file is instead compared byte by byte with its expected contents, a
+//! pattern repeated for as long as the file is, in the same chunks any
+//! other comparison of bytes is streamed in.
+
+use std::{
+    fs::{self, File},
+    io::{self, Read},
+};
+
+use crate::{
+    diff_streams, heatmap::{self, MismatchBuckets}, progress::FileProgress,
+    Diff, DiffOptions, Result, RsdiffError, Unit,
+};
+
+/// Expected
+/// Contents a file is expected to consist of, as a pattern of bytes
+/// repeated throughout it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expected {
+    /// The bytes repeated.
+    pub pattern: Vec<u8>,
+    /// What the contents are called in reports, such as "zeros".
+    pub description: String,
+}
+
+impl Expected {
+    /// Contents of nothing but zeros.
+    pub fn zeros() -> Expected {
+        Expected { pattern: vec!(0), description: String::from("zeros") }
+    }
+
+    /// Contents of nothing but `byte`.
+    pub fn fill(byte: u8) -> Expected {
+        if byte == 0 {
+            return Expected::zeros();
+        }
+        Expected {
+            pattern: vec!(byte),
+            description: format!("fill value {:#04x}", byte),
+        }
+    }
+
+    /// Contents repeating those of the file at `path`.
+    pub fn from_file(path: &str) -> Result<Expected> {
+        let pattern = fs::read(path).map_err(|e| RsdiffError::io(path, e))?;
+        if pattern.is_empty() {
+            return Err(RsdiffError::Corrupt(format!(
+                "{} is empty, so there is no pattern to repeat", path
+            )));
+        }
+        Ok(Expected { pattern, description: format!("pattern in {}", path) })
+    }
+}
+
+/// Repeat
+/// A pattern repeated until a given number of bytes have been read. The
+/// last repetition is cut short if the pattern doesn't fit evenly.
+struct Repeat<'a> {
+    pattern: &'a [u8],
+    /// Where in the pattern the next read starts.
+    at: usize,
+    remaining: u64,
+}
+
+impl Read for Repeat<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = buf.len().min(self.remaining as usize);
+        for byte in buf[..n].iter_mut() {
+            *byte = self.pattern[self.at];
+            self.at = (self.at + 1) % self.pattern.len();
+        }
+        self.remaining -= n as u64;
+        Ok(n)
+    }
+}
+
+/// Check that a file consists of its expected contents.
+pub fn diff_expected(path: &str, expected: &Expected) -> Result<Diff> {
+    diff_expected_with_options(path, expected, &DiffOptions::default())
+}
+
+/// Check that a file consists of its expected contents with custom
+/// options. The file is the left side of the comparison and its expected
+/// contents the right, which is named by their description.
+pub fn diff_expected_with_options(path: &str, expected: &Expected,
+                                  opts: &DiffOptions) -> Result<Diff> {
+    let file = File::open(path).map_err(|e| RsdiffError::io(path, e))?;
+    let meta = file.metadata().map_err(|e| RsdiffError::io(path, e))?;
+    if !meta.is_file() {
+        return Err(RsdiffError::NotAFile(String::from(path)));
+    }
+    if expected.pattern.is_empty() {
+        return Err(RsdiffError::Corrupt(format!(
+            "there is no pattern to check {} against", path
+        )));
+    }
+    let size = meta.len();
+    let contents = Repeat {
+        pattern: &expected.pattern, at: 0, remaining: size
+    };
+    let mut buckets = opts.heatmap_buckets
+        .map(|n| MismatchBuckets::new(size, n));
+    let progress = FileProgress::new(path, size);
+    let streamed = diff_streams(file, contents,
+                                (path, &expected.description), opts,
+                                progress.as_ref(), buckets.as_mut())?;
+
+    let mut d = Diff::new(path, &expected.description);
+    // Only the file's hash says anything about it
+    d.left_hash = streamed.left_hash;
+    d.set_counts(streamed.matches, streamed.left_len as usize, Unit::Bytes);
+    d.matches = streamed.matches as u64 == streamed.left_len;
+    if !d.matches {
+        d.additional_info = format!(
+            "{} of {} bytes match ({:.1}%)", streamed.matches,
+            streamed.left_len, d.similarity * 100.0
+        );
+        d.report = format!("{} vs {}: {}", d.left, d.right,
+                           d.additional_info);
+        if let Some(buckets) = buckets {
+            let density = buckets.density();
+            d.report.push_str(&format!(
+                "\n  mismatches by position: {}", heatmap::ascii(&density)
+            ));
+            d.mismatch_density = Some(density);
+        }
+    }
+    Ok(d)
+}
@@ -0,0 +1,173 @@
+//! Explanations of comparisons for rsdiff
+//!
+//! "Why does rsdiff say these differ?" is usually answered by how the
+//! files were compared rather than by what is in them: which comparator
+//! picked them up, which tolerances applied, and what was left out. An
+//! explanation spells that out next to the verdict, for one comparison.
+
+use serde_json::Value;
+
+use crate::{
+    code, json, notebook, route, table, Diff, DiffOptions, FloatComparison,
+    Result, Route, SymlinkPolicy,
+};
+
+/// Options that change how fast a comparison runs or how it is shown, but
+/// not its result, so they are left out of explanations.
+const PRESENTATION_OPTIONS: [&str; 9] = [
+    "hash", "chunk_size", "mmap", "max_memory", "cpus", "jobs", "color",
+    "cache_dir", "workspace",
+];
+
+/// Explain a comparison of two objects that gave `d`.
+pub fn explain(left: &str, right: &str, d: &Diff, opts: &DiffOptions)
+    -> Result<String> {
+    let route = route(left, right, opts)?;
+    // Links and empty files are compared without looking into them
+    let contents = !matches!(route, Route::Links | Route::EmptyFile);
+    let mut out = format!("{} vs. {}\n", left, right);
+    out.push_str(&format!("  compared {}\n", route.describe()));
+    out.push_str(&format!("  verdict: {}\n", verdict(d)));
+    let changed = changed_options(opts);
+    if changed.is_empty() {
+        out.push_str("  options: all at their defaults\n");
+    }
+    else {
+        out.push_str("  options changed from their defaults:\n");
+        for option in changed.iter() {
+            out.push_str(&format!("    {}\n", option));
+        }
+    }
+    let ignored = ignored(d, opts, contents);
+    if !ignored.is_empty() {
+        out.push_str("  ignored:\n");
+        for what in ignored.iter() {
+            out.push_str(&format!("    {}\n", what));
+        }
+    }
+    for node in d.flatten() {
+        for finding in node.findings.iter() {
+            out.push_str(&format!("  noted for {}: {}\n", node.left, finding));
+        }
+    }
+    Ok(out)
+}
+
+/// Say whether the objects match and what that rests on.
+fn verdict(d: &Diff) -> String {
+    if d.interrupted {
+        return String::from("incomplete, since the comparison was \
+                             interrupted");
+    }
+    let entries = d.common.len() + d.left_only.len() + d.right_only.len();
+    if d.matches {
+        match d.unit {
+            Some(_) if d.total == 0 => {
+                String::from("match, since there was nothing to compare")
+            }
+            Some(unit) => format!("match, since all {} {} compared match",
+                                  d.total, unit),
+            None => String::from("match"),
+        }
+    }
+    else if entries > 0 {
+        let differing = d.sub_diffs.iter().filter(|s| !s.matches).count();
+        format!("differ: {} of {} compared entries differ, {} only on the \
+                 left, {} only on the right", differing, d.sub_diffs.len(),
+                d.left_only.len(), d.right_only.len())
+    }
+    else if d.additional_info.is_empty() {
+        String::from("differ")
+    }
+    else {
+        format!("differ: {}", d.additional_info)
+    }
+}
+
+/// List the options that bear on the result and aren't at their defaults,
+/// as `name = value`.
+fn changed_options(opts: &DiffOptions) -> Vec<String> {
+    let to_map = |o: &DiffOptions| match serde_json::to_value(o) {
+        Ok(Value::Object(map)) => map,
+        _ => unreachable!("Options serialize to an object"),
+    };
+    let defaults = to_map(&DiffOptions::default());
+    to_map(opts).into_iter()
+        .filter(|(name, _)| !PRESENTATION_OPTIONS.contains(&name.as_str()))
+        .filter(|(name, value)| defaults.get(name) != Some(value))
+        .map(|(name, value)| format!("{} = {}", name, value))
+        .collect()
+}
+
+/// List what the comparison left out, as far as it concerns the objects
+/// compared. What is left out of files' contents only matters if their
+/// `contents` were compared.
+fn ignored(d: &Diff, opts: &DiffOptions, contents: bool) -> Vec<String> {
+    let involves = |is_kind: fn(&str) -> bool| {
+        contents && d.flatten().iter().any(|node| is_kind(&node.left))
+    };
+    let mut ignored = vec!();
+    if !opts.exclude.is_empty() {
+        ignored.push(format!("entries matching {}", opts.exclude.join(", ")));
+    }
+    if opts.symlinks == SymlinkPolicy::Skip {
+        ignored.push(String::from("symbolic links inside directories"));
+    }
+    for range in opts.ignore_ranges.iter() {
+        ignored.push(format!("bytes {} to {}", range.start, range.end));
+    }
+    if opts.scaled_voxels && !opts.sentinels.is_empty() && contents {
+        let values: Vec<String> = opts.sentinels.iter()
+            .map(|v| v.to_string())
+            .collect();
+        ignored.push(format!("voxels stored as {}, as missing data",
+                             values.join(" or ")));
+    }
+    match opts.float_comparison {
+        // The default tolerance only absorbs rounding error
+        FloatComparison::Absolute
+            if opts.tolerance != DiffOptions::default().tolerance => {
+            ignored.push(format!("numeric differences smaller than {}",
+                                 opts.tolerance));
+        }
+        FloatComparison::Absolute => (),
+        FloatComparison::Relative(rtol) => {
+            ignored.push(format!("floating point differences up to {} of \
+                                  the values' size", rtol));
+        }
+        FloatComparison::Ulps(ulps) => {
+            ignored.push(format!("floating point differences up to {} \
+                                  units in the last place", ulps));
+        }
+    }
+    if opts.schema_only {
+        ignored.push(String::from("values, since only structure was \
+                                   compared"));
+    }
+    if opts.canonical_json && involves(json::is_json) {
+        ignored.push(String::from("JSON key order and how numbers are \
+                                   written"));
+    }
+    if !opts.ignore_columns.is_empty() && involves(table::is_table) {
+        ignored.push(format!("table columns {}",
+                             opts.ignore_columns.join(", ")));
+    }
+    if involves(code::is_code) {
+        if opts.ignore_comments {
+            ignored.push(String::from("comments in source code"));
+        }
+        if opts.ignore_whitespace {
+            ignored.push(String::from("whitespace in source code"));
+        }
+    }
+    if involves(notebook::is_notebook) {
+        if opts.ignore_outputs {
+            ignored.push(String::from("notebook outputs and execution \
+                                       counts"));
+        }
+        if opts.ignore_metadata {
+            ignored.push(String::from("notebook metadata"));
+        }
+    }
+    ignored
+}
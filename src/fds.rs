@@ -0,0 +1,97 @@
+//! File descriptor budgets for rsdiff
+//!
+//! Every comparison of two files holds a few files open, and a run with
+//! many jobs on a cluster node with a low limit on open files could run out
+//! of them partway, failing comparisons with "Too many open files".
+//! Instead, comparisons of files wait for a share of a budget sized from
+//! the process's RLIMIT_NOFILE before they start.
+
+use std::{
+    cell::Cell,
+    marker::PhantomData,
+    sync::{Condvar, Mutex, OnceLock},
+};
+
+/// Most descriptors a comparison of two files holds open at once: both
+/// files, and the pipes of a preprocessing hook.
+pub(crate) const PER_COMPARISON: usize = 8;
+/// Descriptors kept out of the budget for everything else: the standard
+/// streams, output files, sockets, and directories being listed.
+const RESERVED: usize = 64;
+
+thread_local! {
+    /// Descriptors held by comparisons running on this thread.
+    static HELD: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Budget
+/// How many descriptors are free, which can go below zero when
+/// comparisons nest.
+struct Budget {
+    size: usize,
+    free: Mutex<isize>,
+    freed: Condvar,
+}
+
+/// The budget, sized on first use.
+fn budget() -> &'static Budget {
+    static BUDGET: OnceLock<Budget> = OnceLock::new();
+    BUDGET.get_or_init(|| {
+        let size = open_file_limit().saturating_sub(RESERVED)
+            .clamp(PER_COMPARISON, isize::MAX as usize);
+        Budget { size, free: Mutex::new(size as isize), freed: Condvar::new() }
+    })
+}
+
+/// Permit
+/// A share of the budget, given back when dropped, on the thread that
+/// took it.
+pub(crate) struct Permit {
+    fds: usize,
+    _thread: PhantomData<*const ()>,
+}
+
+/// Take `fds` descriptors from the budget, or the whole budget if that is
+/// less, waiting until they are free. A comparison nested in another on
+/// the same thread, such as of a container image's files, takes them
+/// without waiting, since what it waits for may be its own parent.
+pub(crate) fn acquire(fds: usize) -> Permit {
+    let budget = budget();
+    let fds = fds.min(budget.size);
+    let mut free = budget.free.lock().unwrap();
+    if HELD.get() == 0 {
+        while *free < fds as isize {
+            free = budget.freed.wait(free).unwrap();
+        }
+    }
+    *free -= fds as isize;
+    HELD.set(HELD.get() + fds);
+    Permit { fds, _thread: PhantomData }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let budget = budget();
+        HELD.set(HELD.get() - self.fds);
+        *budget.free.lock().unwrap() += self.fds as isize;
+        budget.freed.notify_all();
+    }
+}
+
+/// The soft limit on open files.
+#[cfg(unix)]
+fn open_file_limit() -> usize {
+    let mut limit = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+    // SAFETY: getrlimit only writes to the struct it is given
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0
+        || limit.rlim_cur == libc::RLIM_INFINITY {
+        return usize::MAX;
+    }
+    limit.rlim_cur as usize
+}
+
+/// Open files are only limited as Unix limits them.
+#[cfg(not(unix))]
+fn open_file_limit() -> usize {
+    usize::MAX
+}
@@ -0,0 +1,219 @@
+//! Dataset fingerprints for rsdiff
+//!
+//! Comparing mirrors of a multi-terabyte dataset byte by byte takes hours,
+//! while most mirrors that differ are missing files or hold truncated ones.
+//! A fingerprint summarizes a directory tree from its metadata alone: how
+//! many files it holds, how their sizes are distributed, how they break
+//! down by extension, and a hash of every file's path and size. Two
+//! fingerprints can be compared in an instant, including one saved at the
+//! source against one taken at the mirror, as a first pass before a full
+//! comparison.
+//!
+//! Since contents aren't read, files that differ only in their contents
+//! have the same fingerprint.
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    io,
+    path::Path,
+};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{Diff, DiffOptions, Result, RsdiffError, Unit};
+
+/// Tally
+/// How many files there are of some kind, and how large they are together.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct Tally {
+    pub files: u64,
+    pub bytes: u64,
+}
+
+impl Tally {
+    /// Count a file of `size` bytes.
+    fn add(&mut self, size: u64) {
+        self.files += 1;
+        self.bytes += size;
+    }
+}
+
+/// Fingerprint
+/// A statistical summary of a directory tree's files.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Fingerprint {
+    /// The directory the fingerprint was taken of.
+    pub path: String,
+    /// All files in the tree.
+    pub total: Tally,
+    /// Files by how many bits their sizes take, so that bucket `n` holds
+    /// sizes from 2^(n-1) up to 2^n bytes, and bucket 0 empty files.
+    pub sizes: BTreeMap<u32, u64>,
+    /// Files by extension, everything after the first dot in their names,
+    /// so that `.nii.gz` is told apart from `.gz`. Files without one are
+    /// under the empty extension.
+    pub extensions: BTreeMap<String, Tally>,
+    /// SHA-256 of every file's relative path and size, in path order.
+    pub hash: String,
+}
+
+impl Fingerprint {
+    /// Take the fingerprint of a directory tree, leaving out excluded
+    /// entries. Only regular files are counted; symbolic links aren't
+    /// followed.
+    pub fn of(dir: &str, opts: &DiffOptions) -> Result<Fingerprint> {
+        if !fs::metadata(dir).map_err(|e| RsdiffError::io(dir, e))?.is_dir() {
+            return Err(RsdiffError::NotADirectory(String::from(dir)));
+        }
+        let mut files = BTreeMap::new();
+        list_files(Path::new(dir), Path::new(""), opts, &mut files)
+            .map_err(|e| RsdiffError::io(dir, e))?;
+        let mut fingerprint = Fingerprint {
+            path: String::from(dir),
+            ..Fingerprint::default()
+        };
+        let mut hasher = Sha256::new();
+        for (relative, size) in files.iter() {
+            fingerprint.total.add(*size);
+            let bits = u64::BITS - size.leading_zeros();
+            *fingerprint.sizes.entry(bits).or_insert(0) += 1;
+            fingerprint.extensions.entry(extension(Path::new(relative)))
+                .or_default()
+                .add(*size);
+            hasher.update(format!("{}\0{}\n", relative, size).as_bytes());
+        }
+        fingerprint.hash = hasher.finalize().iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        Ok(fingerprint)
+    }
+
+    /// Read a fingerprint saved as JSON.
+    pub fn load(path: &str) -> Result<Fingerprint> {
+        let bytes = fs::read(path).map_err(|e| RsdiffError::io(path, e))?;
+        serde_json::from_slice(&bytes).map_err(|e| RsdiffError::Corrupt(
+            format!("{} is not an rsdiff fingerprint: {}", path, e)
+        ))
+    }
+
+    /// The fingerprint's items, named as they are reported, in the order
+    /// they are reported in.
+    fn items(&self) -> Vec<(String, String)> {
+        let mut items = vec!(
+            (String::from("files"), self.total.files.to_string()),
+            (String::from("bytes"), self.total.bytes.to_string()),
+        );
+        for (bits, files) in self.sizes.iter() {
+            items.push((format!("files of {}", size_range(*bits)),
+                        files.to_string()));
+        }
+        for (extension, tally) in self.extensions.iter() {
+            let name = if extension.is_empty() {
+                String::from("without an extension")
+            }
+            else {
+                format!(".{}", extension)
+            };
+            items.push((format!("files {}", name), tally.files.to_string()));
+            items.push((format!("bytes in files {}", name),
+                        tally.bytes.to_string()));
+        }
+        items.push((String::from("hash of paths and sizes"),
+                    self.hash.clone()));
+        items
+    }
+}
+
+/// Compare two fingerprints item by item. Items only one fingerprint has,
+/// such as an extension only one tree holds, count as differing.
+pub fn diff_fingerprints(left: &Fingerprint, right: &Fingerprint) -> Diff {
+    let mut d = Diff::new(&left.path, &right.path);
+    let left_items = left.items();
+    let right_items = right.items();
+    let value = |items: &[(String, String)], name: &str| {
+        items.iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.clone())
+            .unwrap_or_else(|| String::from("none"))
+    };
+    // The left fingerprint's items, then any only the right one has
+    let mut names: Vec<&String> = left_items.iter().map(|(n, _)| n).collect();
+    for (name, _) in right_items.iter() {
+        if !names.contains(&name) {
+            names.push(name);
+        }
+    }
+    let mut matched = 0;
+    for name in names.iter() {
+        let l = value(&left_items, name);
+        let r = value(&right_items, name);
+        if l == r {
+            matched += 1;
+            continue;
+        }
+        let mut subdiff = Diff::new(&format!("{}:{}", d.left, name),
+                                    &format!("{}:{}", d.right, name));
+        subdiff.additional_info = format!("{} vs. {}", l, r);
+        subdiff.report = format!("  {}: {}", name, subdiff.additional_info);
+        d.sub_diffs.push(Box::new(subdiff));
+    }
+    d.set_counts(matched, names.len(), Unit::Values);
+    d.matches = matched == names.len();
+    if !d.matches {
+        d.additional_info = format!("fingerprints differ in {} of {} items",
+                                    names.len() - matched, names.len());
+        d.report = format!("{} vs. {}: {}", d.left, d.right,
+                           d.additional_info);
+        for subdiff in d.sub_diffs.iter() {
+            d.report.push('\n');
+            d.report.push_str(&subdiff.report);
+        }
+    }
+    d
+}
+
+/// Gather the sizes of the regular files under `base`, by their paths
+/// relative to it, descending from `relative`.
+pub(crate) fn list_files(base: &Path, relative: &Path, opts: &DiffOptions,
+                         files: &mut BTreeMap<String, u64>)
+    -> io::Result<()> {
+    for entry in fs::read_dir(base.join(relative))? {
+        let entry = entry?;
+        let path = relative.join(entry.file_name());
+        if opts.excludes_path(&path) {
+            continue;
+        }
+        let kind = entry.file_type()?;
+        if kind.is_dir() {
+            list_files(base, &path, opts, files)?;
+        }
+        else if kind.is_file() {
+            files.insert(path.to_string_lossy().into_owned(),
+                         entry.metadata()?.len());
+        }
+    }
+    Ok(())
+}
+
+/// The extension of a file, everything after the first dot that doesn't
+/// start its name, in lowercase.
+fn extension(path: &Path) -> String {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    name.char_indices()
+        .skip(1)
+        .find(|(_, c)| *c == '.')
+        .map(|(i, _)| name[i + 1..].to_lowercase())
+        .unwrap_or_default()
+}
+
+/// Describe the sizes a bucket of the histogram holds.
+fn size_range(bits: u32) -> String {
+    match bits {
+        0 => String::from("0 bytes"),
+        1 => String::from("1 byte"),
+        _ => format!("{} to {} bytes", 1u64 << (bits - 1),
+                     (1u128 << bits) - 1),
+    }
+}
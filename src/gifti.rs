@@ -0,0 +1,635 @@
+//! GIFTI surface comparison for rsdiff
+//!
+//! GIFTI files hold surfaces and the data mapped onto them as XML, with
+//! each data array encoded as text, as base64, or as zlib-compressed
+//! base64, in either byte order. Two files holding the same vertices can
+//! differ in every byte just for having been written with another encoding
+//! or compression level. Data arrays are decoded and compared element by
+//! element instead, with floats under the tolerance, and the metadata of
+//! the file and of each array is compared on its own and reported apart
+//! from the data.
+
+use std::{
+    collections::BTreeMap,
+    convert::TryFrom,
+    fs::File,
+    io::Read,
+};
+
+use base64::{Engine, engine::general_purpose::STANDARD};
+use flate2::read::{MultiGzDecoder, ZlibDecoder};
+use rsdiff_core::{compare_voxels, voxel_reader};
+
+use crate::{Diff, DiffOptions, Metric, Result, RsdiffError, Unit};
+
+/// Most diverging arrays, and most metadata differences, to list under a
+/// report.
+const MAX_REPORTED: usize = 20;
+
+/// The outcome of decoding, failing with why.
+type Parsed<T> = std::result::Result<T, String>;
+
+/// Whether a file is a GIFTI file, gzipped or not.
+pub fn is_gifti(path: &str) -> bool {
+    path.ends_with(".gii") || path.ends_with(".gii.gz")
+}
+
+/// Compare two GIFTI files data array by data array.
+pub fn diff_gifti(left: &str, right: &str) -> Result<Diff> {
+    diff_gifti_with_options(left, right, &DiffOptions::default())
+}
+
+/// Compare two GIFTI files data array by data array with custom options.
+pub fn diff_gifti_with_options(left: &str, right: &str, opts: &DiffOptions)
+    -> Result<Diff> {
+    let left_gifti = load(left)?;
+    let right_gifti = load(right)?;
+    let mut d = Diff::new(left, right);
+    let (mut matched, mut total) = (0, 0);
+    let count = left_gifti.arrays.len().max(right_gifti.arrays.len());
+    for i in 0..count {
+        let subdiff = diff_arrays(&d, i, left_gifti.arrays.get(i),
+                                  right_gifti.arrays.get(i), opts)?;
+        matched += subdiff.matched;
+        total += subdiff.total;
+        d.sub_diffs.push(Box::new(subdiff));
+    }
+    d.set_counts(matched, total, Unit::Elements);
+    let arrays_match = d.sub_diffs.iter().all(|s| s.matches);
+
+    let metadata_differences = metadata_differences(&left_gifti,
+                                                    &right_gifti);
+    d.matches = arrays_match && metadata_differences.is_empty();
+    if d.matches {
+        return Ok(d);
+    }
+    d.additional_info = if arrays_match {
+        format!("Data arrays match, metadata diverges in {} field(s)",
+                metadata_differences.len())
+    }
+    else {
+        let diverging = d.sub_diffs.iter().filter(|s| !s.matches).count();
+        let mut info = format!(
+            "{} of {} data arrays diverge; {} of {} elements match \
+             ({:04.2}%)", diverging, count, matched, total,
+            d.similarity.max(0.0) * 100.0
+        );
+        if !metadata_differences.is_empty() {
+            info.push_str(&format!("; metadata diverges in {} field(s)",
+                                   metadata_differences.len()));
+        }
+        info
+    };
+    d.report = format!("{} vs {}: {}", left, right, d.additional_info);
+    let diverging: Vec<String> = d.sub_diffs.iter()
+        .filter(|s| !s.matches)
+        .map(|s| s.report.clone())
+        .collect();
+    for line in diverging.iter().take(MAX_REPORTED) {
+        d.report.push('\n');
+        d.report.push_str(line);
+    }
+    if diverging.len() > MAX_REPORTED {
+        d.report.push_str(&format!("\n  and {} more diverging arrays",
+                                   diverging.len() - MAX_REPORTED));
+    }
+    add_metadata_differences(&mut d, metadata_differences);
+    Ok(d)
+}
+
+/// Gifti
+/// What a GIFTI file holds, decoded.
+struct Gifti {
+    /// The file's metadata, by name.
+    metadata: BTreeMap<String, String>,
+    arrays: Vec<DataArray>,
+}
+
+/// DataArray
+/// One data array of a GIFTI file, with its elements in little-endian
+/// order whatever order they were stored in.
+struct DataArray {
+    /// The NIfTI datatype code of the elements.
+    datatype: i16,
+    dims: Vec<usize>,
+    /// The array's attributes and metadata, such as its intent, by name.
+    metadata: BTreeMap<String, String>,
+    data: Vec<u8>,
+}
+
+/// Compare the `i`th data arrays of two files, either of which may be
+/// missing, as a sub-diff of `d`.
+fn diff_arrays(d: &Diff, i: usize, left: Option<&DataArray>,
+               right: Option<&DataArray>, opts: &DiffOptions)
+    -> Result<Diff> {
+    let mut subdiff = Diff::new(&format!("{}:array-{}", d.left, i),
+                                &format!("{}:array-{}", d.right, i));
+    let elements = |a: Option<&DataArray>| a.map_or(0, |a| {
+        a.data.len() / voxel_reader(a.datatype).map_or(1, |(_, w)| w)
+    });
+    let total = elements(left).max(elements(right));
+    match (left, right) {
+        (Some(l), Some(r)) if l.datatype == r.datatype && l.dims == r.dims => {
+            let compared = compare_voxels(&l.data, &r.data, l.datatype,
+                                          opts.float_comparison,
+                                          opts.tolerance, Metric::Matches)
+                .map_err(|e| RsdiffError::Corrupt(
+                    format!("{}: {}", subdiff.left, e)
+                ))?;
+            subdiff.set_counts(compared.matched, compared.total,
+                               Unit::Elements);
+            subdiff.numeric_differences = compared.numeric_differences;
+            subdiff.matches = compared.matched == compared.total;
+            subdiff.additional_info = format!(
+                "{} of {} elements match ({:04.2}%)", compared.matched,
+                compared.total, subdiff.similarity * 100.0
+            );
+        }
+        (Some(l), Some(r)) => {
+            subdiff.set_counts(0, total, Unit::Elements);
+            subdiff.additional_info = format!(
+                "{} {} vs. {} {}", describe_datatype(l.datatype),
+                describe_dims(&l.dims), describe_datatype(r.datatype),
+                describe_dims(&r.dims)
+            );
+        }
+        (Some(_), None) => {
+            subdiff.set_counts(0, total, Unit::Elements);
+            subdiff.additional_info = String::from("only in the left file");
+        }
+        (None, _) => {
+            subdiff.set_counts(0, total, Unit::Elements);
+            subdiff.additional_info = String::from("only in the right file");
+        }
+    }
+    if !subdiff.matches {
+        subdiff.report = format!("  array {}: {}", i, subdiff.additional_info);
+    }
+    Ok(subdiff)
+}
+
+/// The metadata two files differ in, as the names of the fields with the
+/// value on each side. Arrays' fields are named `array-<i>/<name>`.
+fn metadata_differences(left: &Gifti, right: &Gifti)
+    -> Vec<(String, Option<String>, Option<String>)> {
+    let mut differences = vec!();
+    let mut compare = |prefix: &str, l: &BTreeMap<String, String>,
+                       r: &BTreeMap<String, String>| {
+        let mut names: Vec<&String> = l.keys().chain(r.keys()).collect();
+        names.sort();
+        names.dedup();
+        for name in names {
+            if l.get(name) != r.get(name) {
+                differences.push((format!("{}{}", prefix, name),
+                                  l.get(name).cloned(), r.get(name).cloned()));
+            }
+        }
+    };
+    compare("", &left.metadata, &right.metadata);
+    // Arrays only one file has are reported as data
+    for (i, (l, r)) in left.arrays.iter().zip(&right.arrays).enumerate() {
+        compare(&format!("array-{}/", i), &l.metadata, &r.metadata);
+    }
+    differences
+}
+
+/// Record the metadata fields two files differ in as sub-diffs, one per
+/// field, and list the first few under the report.
+fn add_metadata_differences(
+    d: &mut Diff, differences: Vec<(String, Option<String>, Option<String>)>
+) {
+    let count = differences.len();
+    for (i, (field, left, right)) in differences.into_iter().enumerate() {
+        let mut subdiff = Diff::new(&format!("{}:metadata/{}", d.left, field),
+                                    &format!("{}:metadata/{}", d.right,
+                                             field));
+        let show = |v: Option<String>| v.map_or_else(
+            || String::from("(none)"), |v| format!("{:?}", v)
+        );
+        subdiff.additional_info = format!("{} vs. {}", show(left),
+                                          show(right));
+        subdiff.report = format!("  {}: {}", field, subdiff.additional_info);
+        if i < MAX_REPORTED {
+            d.report.push('\n');
+            d.report.push_str(&subdiff.report);
+        }
+        d.sub_diffs.push(Box::new(subdiff));
+    }
+    if count > MAX_REPORTED {
+        d.report.push_str(&format!("\n  and {} more metadata differences",
+                                   count - MAX_REPORTED));
+    }
+}
+
+/// Read and decode a GIFTI file, gunzipping it first if it is gzipped.
+fn load(path: &str) -> Result<Gifti> {
+    let mut file = File::open(path).map_err(|e| RsdiffError::io(path, e))?;
+    let mut text = String::new();
+    let read = if path.ends_with(".gz") {
+        MultiGzDecoder::new(file).read_to_string(&mut text)
+    }
+    else {
+        file.read_to_string(&mut text)
+    };
+    read.map_err(|e| RsdiffError::io(path, e))?;
+    parse(&text).map_err(|e| RsdiffError::Corrupt(
+        format!("{} is not a GIFTI file rsdiff can read: {}", path, e)
+    ))
+}
+
+/// Decode the XML of a GIFTI file.
+fn parse(text: &str) -> Parsed<Gifti> {
+    let doc = roxmltree::Document::parse(text).map_err(|e| e.to_string())?;
+    let root = doc.root_element();
+    if root.tag_name().name() != "GIFTI" {
+        return Err(format!("the root element is {}",
+                           root.tag_name().name()));
+    }
+    let mut gifti = Gifti { metadata: BTreeMap::new(), arrays: vec!() };
+    for node in root.children().filter(|n| n.is_element()) {
+        match node.tag_name().name() {
+            "MetaData" => gifti.metadata = metadata(node),
+            "DataArray" => gifti.arrays.push(data_array(node)?),
+            // Label tables say what label values mean, which is metadata
+            "LabelTable" => {
+                for label in node.children().filter(|n| n.is_element()) {
+                    let key = label.attribute("Key").unwrap_or_default();
+                    gifti.metadata.insert(format!("LabelTable/{}", key),
+                                          label.text().unwrap_or_default()
+                                              .trim().to_string());
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(gifti)
+}
+
+/// The name-value pairs of a MetaData element.
+fn metadata(node: roxmltree::Node) -> BTreeMap<String, String> {
+    node.children()
+        .filter(|md| md.has_tag_name("MD"))
+        .filter_map(|md| {
+            let text = |tag: &str| md.children()
+                .find(|n| n.has_tag_name(tag))
+                .map(|n| n.text().unwrap_or_default().trim().to_string());
+            Some((text("Name")?, text("Value").unwrap_or_default()))
+        })
+        .collect()
+}
+
+/// Decode a DataArray element. How the data were encoded and in which
+/// byte order is left out of the array's metadata, since it doesn't
+/// change the data.
+fn data_array(node: roxmltree::Node) -> Parsed<DataArray> {
+    let attribute = |name: &str| node.attribute(name)
+        .ok_or_else(|| format!("a data array has no {}", name));
+    let datatype = datatype_code(attribute("DataType")?)?;
+    let rank: usize = attribute("Dimensionality")?.parse()
+        .map_err(|_| String::from("a data array's Dimensionality is not a \
+                                   number"))?;
+    let dims = (0..rank)
+        .map(|i| attribute(&format!("Dim{}", i))?.parse::<usize>()
+             .map_err(|_| format!("a data array's Dim{} is not a number", i)))
+        .collect::<Parsed<Vec<usize>>>()?;
+    let mut metadata = BTreeMap::new();
+    for name in ["Intent", "ArrayIndexingOrder", "ExternalFileName"] {
+        if let Some(value) = node.attribute(name) {
+            metadata.insert(String::from(name), String::from(value));
+        }
+    }
+    let mut text = "";
+    for child in node.children().filter(|n| n.is_element()) {
+        match child.tag_name().name() {
+            "MetaData" => {
+                for (name, value) in self::metadata(child) {
+                    metadata.insert(format!("MetaData/{}", name), value);
+                }
+            }
+            "CoordinateSystemTransformMatrix" => {
+                for part in child.children().filter(|n| n.is_element()) {
+                    let value = part.text().unwrap_or_default()
+                        .split_whitespace().collect::<Vec<_>>().join(" ");
+                    metadata.insert(format!("{}/{}", child.tag_name().name(),
+                                            part.tag_name().name()), value);
+                }
+            }
+            "Data" => text = child.text().unwrap_or_default(),
+            _ => {}
+        }
+    }
+    let (_, width) = voxel_reader(datatype)
+        .ok_or_else(|| format!("datatype {} is not supported", datatype))?;
+    let mut data = match attribute("Encoding")? {
+        "ASCII" => ascii_elements(text, datatype)?,
+        "Base64Binary" => base64(text)?,
+        "GZipBase64Binary" => {
+            let mut inflated = vec!();
+            ZlibDecoder::new(&base64(text)?[..]).read_to_end(&mut inflated)
+                .map_err(|e| format!("a data array won't inflate: {}", e))?;
+            inflated
+        }
+        encoding => {
+            return Err(format!("data arrays encoded as {} are not \
+                                supported", encoding));
+        }
+    };
+    if node.attribute("Endian") == Some("BigEndian") {
+        for element in data.chunks_exact_mut(width) {
+            element.reverse();
+        }
+    }
+    let expected = dims.iter()
+        .try_fold(width, |size, &dim| size.checked_mul(dim))
+        .ok_or_else(|| String::from("a data array's dimensions are too \
+                                     large"))?;
+    if data.len() != expected {
+        return Err(format!("a data array holds {} bytes where its \
+                            dimensions call for {}", data.len(), expected));
+    }
+    Ok(DataArray { datatype, dims, metadata, data })
+}
+
+/// Decode base64, which GIFTI writers often wrap across lines.
+fn base64(text: &str) -> Parsed<Vec<u8>> {
+    let packed: String = text.split_whitespace().collect();
+    STANDARD.decode(packed)
+        .map_err(|e| format!("a data array is not valid base64: {}", e))
+}
+
+/// Encode the numbers of an ASCII data array as little-endian elements of
+/// a NIfTI datatype.
+fn ascii_elements(text: &str, datatype: i16)
+    -> Parsed<Vec<u8>> {
+    let mut data = vec!();
+    for token in text.split_whitespace() {
+        let bad = || format!("a data array holds {:?}, which is not a \
+                              {}", token, describe_datatype(datatype));
+        let int = || token.parse::<i64>().map_err(|_| bad());
+        match datatype {
+            2 => data.push(token.parse::<u8>().map_err(|_| bad())?),
+            256 => data.push(token.parse::<i8>().map_err(|_| bad())? as u8),
+            4 => data.extend(i16::try_from(int()?).map_err(|_| bad())?
+                             .to_le_bytes()),
+            512 => data.extend(u16::try_from(int()?).map_err(|_| bad())?
+                               .to_le_bytes()),
+            8 => data.extend(i32::try_from(int()?).map_err(|_| bad())?
+                             .to_le_bytes()),
+            768 => data.extend(u32::try_from(int()?).map_err(|_| bad())?
+                               .to_le_bytes()),
+            1024 => data.extend(int()?.to_le_bytes()),
+            1280 => data.extend(token.parse::<u64>().map_err(|_| bad())?
+                                .to_le_bytes()),
+            16 => data.extend(token.parse::<f32>().map_err(|_| bad())?
+                              .to_le_bytes()),
+            _ => data.extend(token.parse::<f64>().map_err(|_| bad())?
+                             .to_le_bytes()),
+        }
+    }
+    Ok(data)
+}
+
+/// The NIfTI datatype code of a GIFTI DataType.
+fn datatype_code(name: &str) -> Parsed<i16> {
+    Ok(match name {
+        "NIFTI_TYPE_UINT8" => 2,
+        "NIFTI_TYPE_INT16" => 4,
+        "NIFTI_TYPE_INT32" => 8,
+        "NIFTI_TYPE_FLOAT32" => 16,
+        "NIFTI_TYPE_FLOAT64" => 64,
+        "NIFTI_TYPE_INT8" => 256,
+        "NIFTI_TYPE_UINT16" => 512,
+        "NIFTI_TYPE_UINT32" => 768,
+        "NIFTI_TYPE_INT64" => 1024,
+        "NIFTI_TYPE_UINT64" => 1280,
+        _ => return Err(format!("DataType {} is not supported", name)),
+    })
+}
+
+/// Name a datatype code for a report.
+fn describe_datatype(datatype: i16) -> &'static str {
+    match datatype {
+        2 => "uint8",
+        4 => "int16",
+        8 => "int32",
+        16 => "float32",
+        64 => "float64",
+        256 => "int8",
+        512 => "uint16",
+        768 => "uint32",
+        1024 => "int64",
+        _ => "uint64",
+    }
+}
+
+/// Write an array's dimensions as e.g. `32492x3`.
+fn describe_dims(dims: &[usize]) -> String {
+    dims.iter().map(|d| d.to_string()).collect::<Vec<_>>().join("x")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{fs, io::Write};
+
+    use flate2::{write::ZlibEncoder, Compression};
+
+    use crate::workspace::{test_scratch, Scratch};
+
+    const VERTICES: [f32; 6] = [0.0, 1.5, -2.25, 3.0, 1e-20, 4.0e5];
+
+    /// Write a file to a test's scratch directory and return its path.
+    fn write(scratch: &Scratch, name: &str, text: &str) -> String {
+        let path = scratch.path().join(name);
+        fs::write(&path, text).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    /// A GIFTI document holding `arrays`, with `metadata` for the file.
+    fn gifti(metadata: &str, arrays: &[String]) -> String {
+        format!("<?xml version=\"1.0\"?>\n<GIFTI Version=\"1.0\" \
+                 NumberOfDataArrays=\"{}\">\n<MetaData>{}</MetaData>\n{}\
+                 </GIFTI>\n", arrays.len(), metadata, arrays.concat())
+    }
+
+    /// A data array element with the given attributes and data.
+    fn array(attributes: &str, data: &str) -> String {
+        format!("<DataArray Intent=\"NIFTI_INTENT_POINTSET\" {}>\n\
+                 <Data>{}</Data>\n</DataArray>\n", attributes, data)
+    }
+
+    /// The vertices as a 2x3 float32 array in the given encoding.
+    fn vertices(encoding: &str, endian: &str, data: &str) -> String {
+        array(&format!("DataType=\"NIFTI_TYPE_FLOAT32\" \
+                        Dimensionality=\"2\" Dim0=\"2\" Dim1=\"3\" \
+                        Encoding=\"{}\" Endian=\"{}\"", encoding, endian),
+              data)
+    }
+
+    fn little_endian() -> Vec<u8> {
+        VERTICES.iter().flat_map(|v| v.to_le_bytes()).collect()
+    }
+
+    /// The vertices in every encoding GIFTI allows.
+    fn encodings() -> Vec<String> {
+        let ascii: Vec<String> = VERTICES.iter()
+            .map(|v| v.to_string())
+            .collect();
+        let big: Vec<u8> = VERTICES.iter()
+            .flat_map(|v| v.to_be_bytes())
+            .collect();
+        let mut zlib = ZlibEncoder::new(vec!(), Compression::best());
+        zlib.write_all(&little_endian()).unwrap();
+        let wrapped = STANDARD.encode(little_endian())
+            .as_bytes()
+            .chunks(8)
+            .map(|line| String::from_utf8_lossy(line).into_owned())
+            .collect::<Vec<_>>()
+            .join("\n  ");
+        vec!(
+            vertices("ASCII", "LittleEndian", &ascii.join(" ")),
+            vertices("Base64Binary", "LittleEndian",
+                     &STANDARD.encode(little_endian())),
+            vertices("Base64Binary", "LittleEndian", &wrapped),
+            vertices("Base64Binary", "BigEndian", &STANDARD.encode(big)),
+            vertices("GZipBase64Binary", "LittleEndian",
+                     &STANDARD.encode(zlib.finish().unwrap())),
+        )
+    }
+
+    fn parse_error(array: String) -> String {
+        match parse(&gifti("", &[array])) {
+            Ok(_) => panic!("a malformed array parsed"),
+            Err(e) => e,
+        }
+    }
+
+    #[test]
+    fn every_encoding_decodes_to_the_same_elements() {
+        for encoded in encodings().chunks(1) {
+            let parsed = parse(&gifti("", encoded)).unwrap();
+            let array = &parsed.arrays[0];
+            assert_eq!(array.datatype, 16);
+            assert_eq!(array.dims, vec!(2, 3));
+            assert_eq!(array.data, little_endian(), "{}", encoded[0]);
+            assert_eq!(array.metadata.get("Intent").unwrap(),
+                       "NIFTI_INTENT_POINTSET");
+            assert!(!array.metadata.contains_key("Encoding"));
+        }
+    }
+
+    #[test]
+    fn differently_encoded_files_match() {
+        let scratch = test_scratch("gifti");
+        let encodings = encodings();
+        let left = write(&scratch, "ascii.gii", &gifti("", &encodings[..1]));
+        for (i, encoded) in encodings.chunks(1).enumerate() {
+            let right = write(&scratch, &format!("encoding-{}.gii", i),
+                              &gifti("", encoded));
+            let d = diff_gifti(&left, &right).unwrap();
+            assert!(d.matches, "{}", d.report);
+            assert_eq!((d.matched, d.total), (6, 6));
+        }
+    }
+
+    #[test]
+    fn metadata_is_compared_apart_from_the_data() {
+        let scratch = test_scratch("gifti");
+        let md = |name: &str, value: &str| format!(
+            "<MD><Name>{}</Name><Value>{}</Value></MD>", name, value
+        );
+        let arrays = &encodings()[..1];
+        let left = write(&scratch, "md-left.gii",
+                         &gifti(&md("AnatomicalStructurePrimary",
+                                    "CortexLeft"), arrays));
+        let right = write(&scratch, "md-right.gii",
+                          &gifti(&format!("{}{}",
+                                          md("AnatomicalStructurePrimary",
+                                             "CortexRight"),
+                                          md("Date", "today")), arrays));
+        let d = diff_gifti(&left, &right).unwrap();
+        assert!(!d.matches);
+        assert_eq!((d.matched, d.total), (6, 6));
+        assert_eq!(d.additional_info,
+                   "Data arrays match, metadata diverges in 2 field(s)");
+        assert!(d.report.contains(
+            "AnatomicalStructurePrimary: \"CortexLeft\" vs. \"CortexRight\""
+        ), "{}", d.report);
+        assert!(d.report.contains("Date: (none) vs. \"today\""),
+                "{}", d.report);
+    }
+
+    #[test]
+    fn arrays_differ_by_element_shape_and_presence() {
+        let scratch = test_scratch("gifti");
+        let left = write(&scratch, "shape-left.gii",
+                         &gifti("", &encodings()[..1]));
+        let transposed = array("DataType=\"NIFTI_TYPE_FLOAT32\" \
+                                Dimensionality=\"2\" Dim0=\"3\" Dim1=\"2\" \
+                                Encoding=\"ASCII\"", "0 1.5 -2.25 3 0 4e5");
+        let right = write(&scratch, "shape-right.gii",
+                          &gifti("", &[transposed, encodings()[0].clone()]));
+        let d = diff_gifti(&left, &right).unwrap();
+        assert!(!d.matches);
+        assert_eq!((d.matched, d.total), (0, 12));
+        assert!(d.report.contains("array 0: float32 2x3 vs. float32 3x2"),
+                "{}", d.report);
+        assert!(d.report.contains("array 1: only in the right file"),
+                "{}", d.report);
+    }
+
+    #[test]
+    fn malformed_documents_are_rejected() {
+        let scratch = test_scratch("gifti");
+        assert!(parse("<GIFTI><DataArray").is_err());
+        assert_eq!(parse("<NIFTI/>").err().unwrap(),
+                   "the root element is NIFTI");
+        let path = write(&scratch, "truncated.gii", "<GIFTI><DataArray");
+        match diff_gifti(&path, &path) {
+            Err(RsdiffError::Corrupt(why)) => assert!(
+                why.contains("is not a GIFTI file rsdiff can read"), "{}", why
+            ),
+            _ => panic!("a truncated file was read"),
+        }
+    }
+
+    #[test]
+    fn malformed_arrays_are_rejected() {
+        let ints = |data: &str| array(
+            "DataType=\"NIFTI_TYPE_INT16\" Dimensionality=\"1\" Dim0=\"2\" \
+             Encoding=\"ASCII\"", data
+        );
+        assert_eq!(parse_error(ints("1")),
+                   "a data array holds 2 bytes where its dimensions call \
+                    for 4");
+        assert_eq!(parse_error(ints("1 70000")),
+                   "a data array holds \"70000\", which is not a int16");
+        assert_eq!(parse_error(ints("1 two")),
+                   "a data array holds \"two\", which is not a int16");
+        assert_eq!(parse_error(vertices("Base64Binary", "LittleEndian",
+                                        "not base64!"))
+                   .split(':').next().unwrap(),
+                   "a data array is not valid base64");
+        assert!(parse_error(vertices("GZipBase64Binary", "LittleEndian",
+                                     &STANDARD.encode(little_endian())))
+                .starts_with("a data array won't inflate"));
+        assert_eq!(parse_error(vertices("ExternalFileBinary", "LittleEndian",
+                                        "")),
+                   "data arrays encoded as ExternalFileBinary are not \
+                    supported");
+        assert_eq!(parse_error(array("DataType=\"NIFTI_TYPE_COMPLEX64\" \
+                                      Dimensionality=\"1\" Dim0=\"1\" \
+                                      Encoding=\"ASCII\"", "1")),
+                   "DataType NIFTI_TYPE_COMPLEX64 is not supported");
+        assert_eq!(parse_error(array("DataType=\"NIFTI_TYPE_UINT8\" \
+                                      Dimensionality=\"2\" Dim0=\"1\" \
+                                      Encoding=\"ASCII\"", "1")),
+                   "a data array has no Dim1");
+        assert_eq!(parse_error(array("DataType=\"NIFTI_TYPE_UINT8\" \
+                                      Dimensionality=\"2\" \
+                                      Dim0=\"18446744073709551615\" \
+                                      Dim1=\"2\" Encoding=\"ASCII\"", "1")),
+                   "a data array's dimensions are too large");
+    }
+}
@@ -0,0 +1,426 @@
+//! Gzip decompression for rsdiff
+//!
+//! Parallel compressors such as pigz and bgzip write multi-member gzip
+//! streams: several complete gzip files back to back. A plain gzip decoder
+//! stops at the end of the first member and would silently compare only part
+//! of the data, so every gzip stream in rsdiff is read through here.
+//!
+//! BGZF, the blocked gzip flavor written by bgzip, is a multi-member stream
+//! whose members carry their compressed size in an extra header field. Its
+//! block layout can be inspected without decompressing anything.
+
+use std::{
+    fs::File,
+    io::{self, Read, Seek, SeekFrom},
+    sync::mpsc::{sync_channel, Receiver},
+    thread::{self, JoinHandle},
+};
+
+use flate2::read::MultiGzDecoder;
+
+use crate::{affinity, hash::HashingReader, read_chunk, DiffOptions};
+
+/// The bytes every gzip member starts with.
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Decompress a gzip stream, including every member of a multi-member
+/// stream.
+pub fn decoder<R: Read>(inner: R) -> MultiGzDecoder<R> {
+    MultiGzDecoder::new(inner)
+}
+
+/// Whether a file is gzipped, judging by its magic bytes rather than its
+/// name.
+pub fn is_gzip(path: &str) -> io::Result<bool> {
+    let mut magic = [0u8; 2];
+    let mut file = File::open(path)?;
+    match file.read_exact(&mut magic) {
+        Ok(()) => Ok(magic == GZIP_MAGIC),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Describe a gzip stream that failed to decompress. The decoder checks
+/// each member's CRC32 and length trailers as it goes, so a stream that
+/// reads to the end without error is intact.
+pub fn describe_corruption(side: &str, e: &io::Error) -> String {
+    format!("{} file's gzip stream is corrupt: {}", side, e)
+}
+
+/// BackgroundDecoder
+/// Decompresses a gzip file on its own thread and hands the payload over in
+/// chunks. Decompression is single-threaded and dominates the time spent
+/// comparing gzipped files, so decompressing both sides of a comparison on
+/// their own threads roughly doubles throughput.
+pub struct BackgroundDecoder {
+    chunks: Option<Receiver<io::Result<Vec<u8>>>>,
+    current: Vec<u8>,
+    position: usize,
+    worker: JoinHandle<io::Result<Option<String>>>,
+}
+
+impl BackgroundDecoder {
+    /// How many chunks the worker may get ahead of the reader.
+    pub const QUEUE_DEPTH: usize = 4;
+
+    /// Start decompressing a file in the background, handing it over in
+    /// chunks of the size set in the options, and hashing the compressed
+    /// bytes as they are read if hashing was requested. The worker thread
+    /// is pinned to the next of the options' CPUs, if any were given.
+    pub fn spawn(file: File, opts: &DiffOptions) -> BackgroundDecoder {
+        let (sender, chunks) = sync_channel(Self::QUEUE_DEPTH);
+        let hasher = opts.hasher();
+        let hash = hasher.is_some();
+        let chunk_size = opts.chunk_size;
+        let cpu = affinity::next_cpu(&opts.cpus);
+        let worker = thread::spawn(move || {
+            if let Some(cpu) = cpu {
+                // Pinning is an optimization; carry on unpinned if it fails
+                let _ = affinity::pin_current_thread(cpu);
+            }
+            let mut gz = decoder(HashingReader::new(file, hasher));
+            loop {
+                let mut chunk = vec![0u8; chunk_size];
+                match read_chunk(&mut gz, &mut chunk) {
+                    Ok(0) => break,
+                    Ok(n) => {
+                        chunk.truncate(n);
+                        // Once the reader hangs up, only keep going if the
+                        // hash still needs the rest of the file
+                        if sender.send(Ok(chunk)).is_err() && !hash {
+                            return Ok(None);
+                        }
+                    }
+                    Err(e) => {
+                        let _ = sender.send(
+                            Err(io::Error::new(e.kind(), e.to_string()))
+                        );
+                        return Err(e);
+                    }
+                }
+            }
+            gz.into_inner().finish()
+        });
+        BackgroundDecoder {
+            chunks: Some(chunks),
+            current: vec!(),
+            position: 0,
+            worker,
+        }
+    }
+
+    /// Stop reading and wait for the worker, returning the hash of the
+    /// compressed file if one was requested.
+    pub fn finish(mut self) -> io::Result<Option<String>> {
+        self.chunks = None;
+        self.worker.join().unwrap_or_else(|_| {
+            Err(io::Error::other("decompression thread panicked"))
+        })
+    }
+}
+
+impl Read for BackgroundDecoder {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position == self.current.len() {
+            let next = match &self.chunks {
+                Some(chunks) => chunks.recv(),
+                None => return Ok(0),
+            };
+            match next {
+                Ok(chunk) => {
+                    self.current = chunk?;
+                    self.position = 0;
+                }
+                // The worker hung up, so the stream is done
+                Err(_) => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.current.len() - self.position);
+        buf[..n].copy_from_slice(&self.current[self.position..self.position + n]);
+        self.position += n;
+        Ok(n)
+    }
+}
+
+/// BgzfBlock
+/// The layout of one block of a BGZF (blocked gzip) file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BgzfBlock {
+    /// Offset of the block in the compressed file.
+    pub offset: u64,
+    /// Size of the compressed block, including its header and trailer.
+    pub compressed_size: u64,
+    /// Size of the block's payload once decompressed.
+    pub size: u32,
+}
+
+/// Size of a BGZF block header: the gzip header plus the BC extra field.
+const BGZF_HEADER_LEN: usize = 18;
+
+/// Read the block size from a BGZF block header, or None if the header is
+/// not a BGZF header.
+fn bgzf_block_size(header: &[u8; BGZF_HEADER_LEN]) -> Option<u64> {
+    // gzip magic, deflate, FEXTRA set, XLEN 6, then the BC subfield
+    let is_bgzf = header[..4] == [0x1f, 0x8b, 8, 4]
+        && header[10..16] == [6, 0, b'B', b'C', 2, 0];
+    if is_bgzf {
+        Some(u16::from_le_bytes([header[16], header[17]]) as u64 + 1)
+    }
+    else {
+        None
+    }
+}
+
+/// Whether a file is BGZF, judging by its first block header.
+pub fn is_bgzf(path: &str) -> io::Result<bool> {
+    let mut header = [0u8; BGZF_HEADER_LEN];
+    let mut file = File::open(path)?;
+    match file.read_exact(&mut header) {
+        Ok(()) => Ok(bgzf_block_size(&header).is_some()),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// List the blocks of a BGZF file by walking its block headers. Only the
+/// headers and trailers are read; nothing is decompressed.
+pub fn bgzf_blocks(path: &str) -> io::Result<Vec<BgzfBlock>> {
+    let mut file = File::open(path)?;
+    let len = file.metadata()?.len();
+    let mut blocks = vec!();
+    let mut offset = 0;
+    let mut header = [0u8; BGZF_HEADER_LEN];
+    let mut isize = [0u8; 4];
+    while offset < len {
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut header)?;
+        let compressed_size = bgzf_block_size(&header).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData,
+                           format!("{} has a non-BGZF block at {}",
+                                   path, offset))
+        })?;
+        // A block holds at least its header and the CRC32 and size trailer
+        if compressed_size < (BGZF_HEADER_LEN + 8) as u64 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} has a block at {} of {} bytes, too small to be \
+                         a BGZF block", path, offset, compressed_size)
+            ));
+        }
+        // The trailer ends with the uncompressed size of the block
+        file.seek(SeekFrom::Start(offset + compressed_size - 4))?;
+        file.read_exact(&mut isize)?;
+        blocks.push(BgzfBlock {
+            offset,
+            compressed_size,
+            size: u32::from_le_bytes(isize),
+        });
+        offset += compressed_size;
+    }
+    Ok(blocks)
+}
+
+/// Describe how two BGZF block layouts differ, if they do. Only the
+/// boundaries between payload blocks are considered, not the compressed
+/// bytes.
+pub fn describe_block_differences(left: &[BgzfBlock], right: &[BgzfBlock])
+    -> Option<String> {
+    let first_divergence = left.iter().zip(right.iter())
+        .position(|(l, r)| l.size != r.size);
+    match first_divergence {
+        Some(i) => Some(format!(
+            "BGZF block boundaries diverge at block {} ({} vs. {} blocks)",
+            i, left.len(), right.len()
+        )),
+        None if left.len() != right.len() => Some(format!(
+            "BGZF block counts differ: {} vs. {}", left.len(), right.len()
+        )),
+        None => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{fs, io::Write};
+
+    use flate2::{write::{DeflateEncoder, GzEncoder}, Compression, Crc};
+
+    use crate::workspace::{test_scratch, Scratch};
+
+    /// Write a file to a test's scratch directory and return its path.
+    fn write(scratch: &Scratch, name: &str, bytes: &[u8]) -> String {
+        let path = scratch.path().join(name);
+        fs::write(&path, bytes).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    /// A complete single-member gzip stream of `payload`.
+    fn member(payload: &[u8]) -> Vec<u8> {
+        let mut gz = GzEncoder::new(vec!(), Compression::default());
+        gz.write_all(payload).unwrap();
+        gz.finish().unwrap()
+    }
+
+    /// A BGZF block of `payload`, as bgzip writes them.
+    fn bgzf_block(payload: &[u8]) -> Vec<u8> {
+        let mut deflate = DeflateEncoder::new(vec!(), Compression::default());
+        deflate.write_all(payload).unwrap();
+        let data = deflate.finish().unwrap();
+        let bsize = (BGZF_HEADER_LEN + data.len() + 8 - 1) as u16;
+        let mut block = vec!(0x1f, 0x8b, 8, 4, 0, 0, 0, 0, 0, 0xff,
+                             6, 0, b'B', b'C', 2, 0);
+        block.extend_from_slice(&bsize.to_le_bytes());
+        block.extend_from_slice(&data);
+        let mut crc = Crc::new();
+        crc.update(payload);
+        block.extend_from_slice(&crc.sum().to_le_bytes());
+        block.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        block
+    }
+
+    fn decompress(bytes: &[u8]) -> io::Result<Vec<u8>> {
+        let mut out = vec!();
+        decoder(bytes).read_to_end(&mut out)?;
+        Ok(out)
+    }
+
+    fn background(path: &str) -> (io::Result<Vec<u8>>,
+                                   io::Result<Option<String>>) {
+        let file = File::open(path).unwrap();
+        let mut gz = BackgroundDecoder::spawn(file, &DiffOptions::default());
+        let mut out = vec!();
+        let read = gz.read_to_end(&mut out).map(|_| out);
+        (read, gz.finish())
+    }
+
+    #[test]
+    fn every_member_of_a_concatenated_stream_is_read() {
+        let scratch = test_scratch("gz");
+        let mut stream = member(b"first member\n");
+        stream.extend(member(b""));
+        stream.extend(member(b"second member\n"));
+        assert_eq!(decompress(&stream).unwrap(),
+                   b"first member\nsecond member\n");
+        let path = write(&scratch, "members.gz", &stream);
+        let (read, finished) = background(&path);
+        assert_eq!(read.unwrap(), b"first member\nsecond member\n");
+        assert!(finished.unwrap().is_none());
+    }
+
+    #[test]
+    fn a_truncated_member_is_an_error() {
+        let scratch = test_scratch("gz");
+        let payload: Vec<u8> = (0..10_000u32)
+            .flat_map(|i| i.to_le_bytes())
+            .collect();
+        let mut stream = member(b"intact\n");
+        let second = member(&payload);
+        // Cut into the deflate data, and then just the trailer
+        for cut in [second.len() / 2, second.len() - 3] {
+            let mut truncated = stream.clone();
+            truncated.extend_from_slice(&second[..cut]);
+            assert!(decompress(&truncated).is_err(), "cut at {}", cut);
+            let path = write(&scratch, &format!("truncated-{}.gz", cut),
+                             &truncated);
+            let (read, finished) = background(&path);
+            assert!(read.is_err(), "cut at {}", cut);
+            assert!(finished.is_err(), "cut at {}", cut);
+        }
+        stream.extend_from_slice(&second);
+        assert_eq!(decompress(&stream).unwrap().len(), 7 + payload.len());
+    }
+
+    #[test]
+    fn a_corrupt_trailer_is_an_error() {
+        let mut stream = member(b"checked\n");
+        let crc = stream.len() - 8;
+        stream[crc] ^= 0xff;
+        assert!(decompress(&stream).is_err());
+    }
+
+    #[test]
+    fn gzip_is_recognized_by_its_magic() {
+        let scratch = test_scratch("gz");
+        let path = |name, bytes: &[u8]| write(&scratch, name, bytes);
+        assert!(is_gzip(&path("magic.txt.gz", &member(b"x"))).unwrap());
+        assert!(!is_gzip(&path("plain.gz", b"plain text")).unwrap());
+        assert!(!is_gzip(&path("short.gz", b"\x1f")).unwrap());
+        assert!(!is_bgzf(&path("plain-member.gz", &member(b"x"))).unwrap());
+    }
+
+    #[test]
+    fn bgzf_blocks_are_listed_without_decompressing() {
+        let scratch = test_scratch("gz");
+        let first = bgzf_block(b"first block\n");
+        let second = bgzf_block(b"second\n");
+        let eof = bgzf_block(b"");
+        let mut stream = first.clone();
+        stream.extend(&second);
+        stream.extend(&eof);
+        let path = write(&scratch, "blocks.bgz", &stream);
+        assert!(is_bgzf(&path).unwrap());
+        assert_eq!(decompress(&stream).unwrap(), b"first block\nsecond\n");
+        let blocks = bgzf_blocks(&path).unwrap();
+        assert_eq!(blocks, vec!(
+            BgzfBlock {
+                offset: 0, compressed_size: first.len() as u64, size: 12
+            },
+            BgzfBlock {
+                offset: first.len() as u64,
+                compressed_size: second.len() as u64,
+                size: 7,
+            },
+            BgzfBlock {
+                offset: (first.len() + second.len()) as u64,
+                compressed_size: eof.len() as u64,
+                size: 0,
+            },
+        ));
+    }
+
+    #[test]
+    fn truncated_or_mixed_bgzf_is_an_error() {
+        let scratch = test_scratch("gz");
+        let mut stream = bgzf_block(b"whole\n");
+        let last = bgzf_block(b"cut short\n");
+        stream.extend_from_slice(&last[..last.len() - 2]);
+        assert!(bgzf_blocks(&write(&scratch, "truncated.bgz", &stream))
+                    .is_err());
+        let mut stream = bgzf_block(b"bgzf\n");
+        stream.extend(member(b"plain gzip\n"));
+        let e = bgzf_blocks(&write(&scratch, "mixed.bgz", &stream))
+            .unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn a_bgzf_block_smaller_than_its_header_is_an_error() {
+        let scratch = test_scratch("gz");
+        let mut stream = bgzf_block(b"bgzf\n");
+        stream[16..18].copy_from_slice(&2u16.to_le_bytes());
+        let e = bgzf_blocks(&write(&scratch, "bsize.bgz", &stream))
+            .unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn block_layouts_differ_by_their_payload_sizes() {
+        let block = |offset, compressed_size, size| BgzfBlock {
+            offset, compressed_size, size
+        };
+        let left = vec!(block(0, 40, 100), block(40, 40, 100));
+        // Recompressing changes nothing but the compressed sizes
+        let right = vec!(block(0, 30, 100), block(30, 50, 100));
+        assert_eq!(describe_block_differences(&left, &right), None);
+        let right = vec!(block(0, 40, 100), block(40, 40, 50),
+                         block(80, 40, 50));
+        assert_eq!(describe_block_differences(&left, &right).unwrap(),
+                   "BGZF block boundaries diverge at block 1 (2 vs. 3 blocks)");
+        let right = vec!(block(0, 40, 100));
+        assert_eq!(describe_block_differences(&left, &right).unwrap(),
+                   "BGZF block counts differ: 2 vs. 1");
+    }
+}
@@ -0,0 +1,255 @@
+//! Content hashing for rsdiff
+//!
+//! Hashes are computed on the fly as files are streamed for comparison, so
+//! that a checksum manifest comes for free with a full comparison.
+//!
+//! Manifests are only useful if they can be checked against the ones that
+//! already exist, which archives and existing tooling write with all sorts
+//! of algorithms. Hashing goes through the `Hasher` trait, and algorithms
+//! are chosen by name at runtime: SHA-256, the default, BLAKE3, xxHash3,
+//! MD5, and CRC-32 are built in, and downstream crates can register others
+//! under names of their own.
+
+use std::{
+    fs::File,
+    io::{self, prelude::*},
+    sync::{OnceLock, RwLock},
+};
+
+use flate2::Crc;
+use md5::Md5;
+use sha2::{Digest, Sha256};
+use xxhash_rust::xxh3::Xxh3;
+
+/// The algorithm content is hashed with unless another is chosen.
+pub const DEFAULT_ALGORITHM: &str = "sha256";
+
+/// Makes a hasher, fresh for each object hashed.
+pub type NewHasher = fn() -> Box<dyn Hasher>;
+
+/// Hasher
+/// A hash algorithm part way through hashing an object.
+pub trait Hasher: Send {
+    /// Hash more of the object's bytes.
+    fn update(&mut self, bytes: &[u8]);
+
+    /// The object's digest, as lowercase hex.
+    fn finish(self: Box<Self>) -> String;
+}
+
+impl Hasher for Sha256 {
+    fn update(&mut self, bytes: &[u8]) {
+        Digest::update(self, bytes);
+    }
+
+    fn finish(self: Box<Self>) -> String {
+        to_hex(&self.finalize())
+    }
+}
+
+// MD5 is only here to match manifests written by `md5sum` and archives
+// that record MD5 checksums, not for anything needing collision resistance
+impl Hasher for Md5 {
+    fn update(&mut self, bytes: &[u8]) {
+        Digest::update(self, bytes);
+    }
+
+    fn finish(self: Box<Self>) -> String {
+        to_hex(&self.finalize())
+    }
+}
+
+impl Hasher for blake3::Hasher {
+    fn update(&mut self, bytes: &[u8]) {
+        blake3::Hasher::update(self, bytes);
+    }
+
+    fn finish(self: Box<Self>) -> String {
+        blake3::Hasher::finalize(&self).to_hex().to_string()
+    }
+}
+
+impl Hasher for Xxh3 {
+    fn update(&mut self, bytes: &[u8]) {
+        Xxh3::update(self, bytes);
+    }
+
+    fn finish(self: Box<Self>) -> String {
+        format!("{:016x}", self.digest())
+    }
+}
+
+impl Hasher for Crc {
+    fn update(&mut self, bytes: &[u8]) {
+        Crc::update(self, bytes);
+    }
+
+    fn finish(self: Box<Self>) -> String {
+        format!("{:08x}", self.sum())
+    }
+}
+
+/// The registered algorithms, by name.
+fn registry() -> &'static RwLock<Vec<(String, NewHasher)>> {
+    static REGISTRY: OnceLock<RwLock<Vec<(String, NewHasher)>>> =
+        OnceLock::new();
+    REGISTRY.get_or_init(|| RwLock::new(vec!(
+        (String::from("sha256"), || Box::new(Sha256::new())),
+        (String::from("blake3"), || Box::new(blake3::Hasher::new())),
+        (String::from("xxh3"), || Box::new(Xxh3::new())),
+        (String::from("md5"), || Box::new(Md5::new())),
+        (String::from("crc32"), || Box::new(Crc::new())),
+    )))
+}
+
+/// Register a hash algorithm under `name`, replacing any registered under
+/// that name before, including the built-in ones.
+pub fn register_hasher(name: &str, new: NewHasher) {
+    let mut registry = registry().write().unwrap();
+    registry.retain(|(n, _)| n != name);
+    registry.push((String::from(name), new));
+}
+
+/// Start hashing with the algorithm registered as `name`, if any is.
+pub fn hasher(name: &str) -> Option<Box<dyn Hasher>> {
+    new_hasher(name).map(|new| new())
+}
+
+/// What makes hashers for the algorithm registered as `name`, if any is.
+/// Work hashing many objects should look it up once, as registering
+/// algorithms may replace it in the meantime.
+pub fn new_hasher(name: &str) -> Option<NewHasher> {
+    registry().read().unwrap().iter()
+        .find(|(n, _)| n == name)
+        .map(|(_, new)| *new)
+}
+
+/// The names of the registered algorithms.
+pub fn algorithms() -> Vec<String> {
+    registry().read().unwrap().iter()
+        .map(|(name, _)| name.clone())
+        .collect()
+}
+
+/// HashingReader
+/// Wraps a reader and hashes every byte that passes through it. Without a
+/// hasher, it is a transparent pass-through.
+pub struct HashingReader<R: Read> {
+    inner: R,
+    hasher: Option<Box<dyn Hasher>>,
+}
+
+impl<R: Read> HashingReader<R> {
+    /// Wrap a reader, hashing with `hasher` if there is one.
+    pub fn new(inner: R, hasher: Option<Box<dyn Hasher>>) -> HashingReader<R> {
+        HashingReader { inner, hasher }
+    }
+
+    /// Drain whatever has not yet been read and return the hex digest, or
+    /// None if there was no hasher.
+    pub fn finish(mut self) -> io::Result<Option<String>> {
+        if self.hasher.is_none() {
+            return Ok(None);
+        }
+        io::copy(&mut self, &mut io::sink())?;
+        Ok(self.hasher.map(|h| h.finish()))
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if let Some(h) = self.hasher.as_mut() {
+            h.update(&buf[..n]);
+        }
+        Ok(n)
+    }
+}
+
+/// Hash a whole file, for cases where no comparison pass reads it.
+pub fn hash_file(path: &str, hasher: Box<dyn Hasher>) -> io::Result<String> {
+    let reader = HashingReader::new(File::open(path)?, Some(hasher));
+    Ok(reader.finish()?.unwrap_or_default())
+}
+
+/// Hash bytes already in memory with SHA-256, as for IDs.
+pub fn hash_bytes(bytes: &[u8]) -> String {
+    to_hex(&Sha256::digest(bytes))
+}
+
+/// Hash bytes already in memory with a hasher.
+pub fn hash_bytes_with(bytes: &[u8], mut hasher: Box<dyn Hasher>) -> String {
+    hasher.update(bytes);
+    hasher.finish()
+}
+
+/// Render a digest as lowercase hex.
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Digests of known messages: RFC 1321's for MD5, and the published
+    /// ones of the other algorithms.
+    const VECTORS: &[(&str, &[u8], &str)] = &[
+        ("md5", b"", "d41d8cd98f00b204e9800998ecf8427e"),
+        ("md5", b"a", "0cc175b9c0f1b6a831c399e269772661"),
+        ("md5", b"abc", "900150983cd24fb0d6963f7d28e17f72"),
+        ("md5", b"message digest", "f96b697d7cb7938d525a2f31aaf161d0"),
+        ("md5", b"abcdefghijklmnopqrstuvwxyz",
+         "c3fcd3d76192e4007dfb496cca67e13b"),
+        ("md5",
+         b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789",
+         "d174ab98d277d9f5a5611c2c9f419d9f"),
+        ("md5",
+         b"1234567890123456789012345678901234567890\
+           1234567890123456789012345678901234567890",
+         "57edf4a22be3c955ac49da2e2107b67a"),
+        ("sha256", b"",
+         "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"),
+        ("sha256", b"abc",
+         "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"),
+        ("blake3", b"",
+         "af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262"),
+        ("blake3", b"abc",
+         "6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85"),
+        ("xxh3", b"", "2d06800538d394c2"),
+        ("xxh3", b"abc", "78af5f94892f3950"),
+        ("crc32", b"", "00000000"),
+        ("crc32", b"123456789", "cbf43926"),
+    ];
+
+    #[test]
+    fn known_messages_hash_to_known_digests() {
+        for (name, message, digest) in VECTORS {
+            let hashed = hash_bytes_with(message, hasher(name).unwrap());
+            assert_eq!(&hashed, digest, "{} of {:?}", name,
+                       String::from_utf8_lossy(message));
+        }
+    }
+
+    #[test]
+    fn every_built_in_algorithm_has_vectors() {
+        for name in algorithms() {
+            assert!(VECTORS.iter().any(|(n, _, _)| *n == name), "{}", name);
+        }
+    }
+
+    #[test]
+    fn digests_do_not_depend_on_how_updates_are_split() {
+        let message: Vec<u8> = (0..=255u8).cycle().take(1000).collect();
+        for name in algorithms() {
+            let whole = hash_bytes_with(&message, hasher(&name).unwrap());
+            for split in [1, 7, 55, 56, 63, 64, 65, 128, 999] {
+                let mut h = hasher(&name).unwrap();
+                for part in message.chunks(split) {
+                    h.update(part);
+                }
+                assert_eq!(h.finish(), whole, "{} in {}s", name, split);
+            }
+        }
+    }
+}
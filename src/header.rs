@@ -0,0 +1,159 @@
+//! NIfTI header comparison for rsdiff
+//!
+//! Two images can hold the same voxels and still not be the same image:
+//! a different sform puts them in a different space, and a different
+//! scl_slope changes what the voxels mean. Headers are compared field by
+//! field so a report can say exactly what changed.
+//!
+//! The same geometry can also be written in different units: a voxel 2 mm
+//! wide is 0.002 m wide. Headers can be brought to common units first, so
+//! that only differences in what they describe are reported.
+
+use std::fmt::Debug;
+
+use nifti::NiftiHeader;
+
+/// FieldDifference
+/// A header field whose value differs between two images.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FieldDifference {
+    /// Name of the field, as in the NIfTI-1 standard.
+    pub field: &'static str,
+    /// The left image's value, formatted for reporting.
+    pub left: String,
+    /// The right image's value, formatted for reporting.
+    pub right: String,
+}
+
+/// Compare two headers field by field, in the order the fields appear in
+/// the header. Fields that only matter to ANALYZE 7.5 readers are skipped.
+pub fn differences(left: &NiftiHeader, right: &NiftiHeader)
+    -> Vec<FieldDifference> {
+    let mut out = vec!();
+    let mut check = |field: &'static str, l: String, r: String| {
+        if l != r {
+            out.push(FieldDifference { field, left: l, right: r });
+        }
+    };
+    check("dim_info", show(&left.dim_info), show(&right.dim_info));
+    check("dim", show(&left.dim), show(&right.dim));
+    check("intent_p1", float(left.intent_p1), float(right.intent_p1));
+    check("intent_p2", float(left.intent_p2), float(right.intent_p2));
+    check("intent_p3", float(left.intent_p3), float(right.intent_p3));
+    check("intent_code", show(&left.intent_code), show(&right.intent_code));
+    check("datatype", show(&left.datatype), show(&right.datatype));
+    check("bitpix", show(&left.bitpix), show(&right.bitpix));
+    check("slice_start", show(&left.slice_start), show(&right.slice_start));
+    check("pixdim", floats(&left.pixdim), floats(&right.pixdim));
+    check("vox_offset", float(left.vox_offset), float(right.vox_offset));
+    check("scl_slope", float(left.scl_slope), float(right.scl_slope));
+    check("scl_inter", float(left.scl_inter), float(right.scl_inter));
+    check("slice_end", show(&left.slice_end), show(&right.slice_end));
+    check("slice_code", show(&left.slice_code), show(&right.slice_code));
+    check("xyzt_units", show(&left.xyzt_units), show(&right.xyzt_units));
+    check("cal_max", float(left.cal_max), float(right.cal_max));
+    check("cal_min", float(left.cal_min), float(right.cal_min));
+    check("slice_duration", float(left.slice_duration),
+          float(right.slice_duration));
+    check("toffset", float(left.toffset), float(right.toffset));
+    check("descrip", text(&left.descrip), text(&right.descrip));
+    check("aux_file", text(&left.aux_file), text(&right.aux_file));
+    check("qform_code", show(&left.qform_code), show(&right.qform_code));
+    check("sform_code", show(&left.sform_code), show(&right.sform_code));
+    check("quatern_b", float(left.quatern_b), float(right.quatern_b));
+    check("quatern_c", float(left.quatern_c), float(right.quatern_c));
+    check("quatern_d", float(left.quatern_d), float(right.quatern_d));
+    check("qoffset_x", float(left.quatern_x), float(right.quatern_x));
+    check("qoffset_y", float(left.quatern_y), float(right.quatern_y));
+    check("qoffset_z", float(left.quatern_z), float(right.quatern_z));
+    check("srow_x", floats(&left.srow_x), floats(&right.srow_x));
+    check("srow_y", floats(&left.srow_y), floats(&right.srow_y));
+    check("srow_z", floats(&left.srow_z), floats(&right.srow_z));
+    check("intent_name", text(&left.intent_name), text(&right.intent_name));
+    check("magic", text(&left.magic), text(&right.magic));
+    out
+}
+
+/// Spatial units of `xyzt_units`, by code, with their names and how many
+/// millimeters each is.
+const SPACE_UNITS: [(u8, &str, f64); 3] = [
+    (1, "m", 1000.0), (2, "mm", 1.0), (3, "um", 0.001),
+];
+/// Temporal units of `xyzt_units`, by code, with their names and how many
+/// seconds each is. Frequencies can't be converted to times, and aren't
+/// listed.
+const TIME_UNITS: [(u8, &str, f64); 3] = [
+    (8, "s", 1.0), (16, "ms", 0.001), (24, "us", 0.000001),
+];
+/// The bits of `xyzt_units` that hold the spatial unit.
+const SPACE_MASK: u8 = 0x07;
+/// The bits of `xyzt_units` that hold the temporal unit.
+const TIME_MASK: u8 = 0x38;
+
+/// A copy of a header with its spatial fields in millimeters and its
+/// temporal fields in seconds, as far as its units say what they are in.
+/// Fields in unknown units are left as they are. Converted values are as
+/// precise as the header's floats.
+pub fn normalize_units(hdr: &NiftiHeader) -> NiftiHeader {
+    let mut out = hdr.clone();
+    let convert = |value: &mut f32, factor: f64| {
+        *value = (*value as f64 * factor) as f32;
+    };
+    let space = hdr.xyzt_units & SPACE_MASK;
+    if let Some(&(_, _, factor)) = SPACE_UNITS.iter().find(|u| u.0 == space) {
+        for value in out.pixdim[1..4].iter_mut()
+            .chain(out.srow_x.iter_mut())
+            .chain(out.srow_y.iter_mut())
+            .chain(out.srow_z.iter_mut()) {
+            convert(value, factor);
+        }
+        convert(&mut out.quatern_x, factor);
+        convert(&mut out.quatern_y, factor);
+        convert(&mut out.quatern_z, factor);
+        out.xyzt_units = (out.xyzt_units & !SPACE_MASK) | 2;
+    }
+    let time = hdr.xyzt_units & TIME_MASK;
+    if let Some(&(_, _, factor)) = TIME_UNITS.iter().find(|u| u.0 == time) {
+        convert(&mut out.pixdim[4], factor);
+        convert(&mut out.toffset, factor);
+        convert(&mut out.slice_duration, factor);
+        out.xyzt_units = (out.xyzt_units & !TIME_MASK) | 8;
+    }
+    out
+}
+
+/// Name the units of a header's `xyzt_units`, such as "mm and s".
+pub fn describe_units(xyzt_units: u8) -> String {
+    let name = |units: &[(u8, &str, f64)], code: u8| {
+        match units.iter().find(|u| u.0 == code) {
+            Some(unit) => String::from(unit.1),
+            None if code == 0 => String::from("unknown units"),
+            None => format!("unit code {}", code),
+        }
+    };
+    format!("{} and {}", name(&SPACE_UNITS, xyzt_units & SPACE_MASK),
+            name(&TIME_UNITS, xyzt_units & TIME_MASK))
+}
+
+/// Format an integer field, or an array of them.
+fn show(value: &impl Debug) -> String {
+    format!("{:?}", value)
+}
+
+/// Format a float field. Formatting compares NaNs, which many writers use
+/// for unset fields, as equal.
+fn float(value: f32) -> String {
+    format!("{}", value)
+}
+
+/// Format an array of float fields.
+fn floats(values: &[f32]) -> String {
+    let values: Vec<String> = values.iter().map(|&v| float(v)).collect();
+    format!("[{}]", values.join(", "))
+}
+
+/// Format a fixed-size string field, which is padded with NULs.
+fn text(bytes: &[u8]) -> String {
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    format!("{:?}", String::from_utf8_lossy(&bytes[..end]))
+}
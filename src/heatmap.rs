@@ -0,0 +1,156 @@
+//! Mismatch heatmaps for rsdiff
+//!
+//! That two large binaries differ in 0.1% of their bytes says little about
+//! why. Differences confined to the first few kilobytes are usually a
+//! header, a timestamp, or a build ID; differences at the end, an appended
+//! record; differences spread throughout, different data. Dividing the
+//! files into equal stretches and charting how many bytes mismatch in each
+//! shows which at a glance, as a strip of characters in the report or as a
+//! PNG.
+
+use std::{fs::File, io::{self, BufWriter, Write}};
+
+use flate2::{write::ZlibEncoder, Compression, Crc};
+
+use crate::diff_buffer;
+
+/// Characters for increasing mismatch density, from none to all.
+const RAMP: &[u8] = b" .:-=+*#%@";
+/// Height of PNG heatmaps, in pixels.
+const PNG_HEIGHT: u32 = 32;
+/// Narrowest PNG heatmaps are drawn, in pixels, so few stretches still
+/// make a visible image.
+const PNG_MIN_WIDTH: u32 = 512;
+/// Signature starting every PNG file.
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a,
+                                b'\n'];
+
+/// MismatchBuckets
+/// Counts of mismatching bytes in equal stretches of two files, filled in
+/// as the files are compared.
+#[derive(Debug, Clone)]
+pub(crate) struct MismatchBuckets {
+    len: u64,
+    bucket_len: u64,
+    mismatches: Vec<u64>,
+}
+
+impl MismatchBuckets {
+    /// Divide `len` bytes into `buckets` stretches, or fewer for files
+    /// shorter than that in bytes.
+    pub(crate) fn new(len: u64, buckets: usize) -> MismatchBuckets {
+        let buckets = (buckets as u64).min(len).max(1);
+        let bucket_len = len.div_ceil(buckets).max(1);
+        MismatchBuckets {
+            len,
+            bucket_len,
+            mismatches: vec![0; len.div_ceil(bucket_len).max(1) as usize],
+        }
+    }
+
+    /// Count the mismatches of two equally long buffers holding the files'
+    /// bytes from `offset` on.
+    pub(crate) fn add(&mut self, offset: u64, left: &[u8], right: &[u8]) {
+        let mut start = 0;
+        while start < left.len() {
+            let position = offset + start as u64;
+            let bucket = (position / self.bucket_len) as usize;
+            let room = (bucket as u64 + 1) * self.bucket_len - position;
+            let end = left.len().min(start + room as usize);
+            let matches = diff_buffer(&left[start..end], &right[start..end]);
+            if let Some(count) = self.mismatches.get_mut(bucket) {
+                *count += (end - start - matches) as u64;
+            }
+            start = end;
+        }
+    }
+
+    /// Add in the mismatches counted over other ranges of the same files.
+    pub(crate) fn merge(&mut self, other: &MismatchBuckets) {
+        for (count, more) in self.mismatches.iter_mut()
+            .zip(other.mismatches.iter()) {
+            *count += more;
+        }
+    }
+
+    /// The fraction of mismatching bytes in each stretch.
+    pub(crate) fn density(&self) -> Vec<f64> {
+        self.mismatches.iter()
+            .enumerate()
+            .map(|(i, count)| {
+                let start = i as u64 * self.bucket_len;
+                let len = self.bucket_len.min(self.len - start.min(self.len));
+                if len == 0 { 0.0 } else { *count as f64 / len as f64 }
+            })
+            .collect()
+    }
+}
+
+/// Draw mismatch densities as a strip of characters, from ' ' where every
+/// byte matches through '.' for any mismatch up to '@' where none does.
+pub fn ascii(density: &[f64]) -> String {
+    let strip: String = density.iter()
+        .map(|d| {
+            let level = if *d <= 0.0 {
+                0
+            }
+            else {
+                1 + (d.min(1.0) * (RAMP.len() - 2) as f64).floor() as usize
+            };
+            RAMP[level.min(RAMP.len() - 1)] as char
+        })
+        .collect();
+    format!("|{}|", strip)
+}
+
+/// Write mismatch densities as a PNG strip, left to right, light grey
+/// where every byte matches and yellow through red as mismatches grow.
+pub fn write_png(path: &str, density: &[f64]) -> io::Result<()> {
+    let buckets = density.len().max(1) as u32;
+    let scale = PNG_MIN_WIDTH.div_ceil(buckets);
+    let width = buckets * scale;
+    let mut row = Vec::with_capacity(1 + 3 * width as usize);
+    // Each row starts with its filter type, none
+    row.push(0);
+    for d in density.iter() {
+        let pixel = if *d <= 0.0 {
+            [240, 240, 240]
+        }
+        else {
+            let d = d.min(1.0);
+            [(255.0 - 55.0 * d) as u8, (230.0 * (1.0 - d)) as u8, 0]
+        };
+        for _ in 0..scale {
+            row.extend_from_slice(&pixel);
+        }
+    }
+    let mut pixels = ZlibEncoder::new(vec!(), Compression::default());
+    for _ in 0..PNG_HEIGHT {
+        pixels.write_all(&row)?;
+    }
+
+    let mut header = vec!();
+    header.extend_from_slice(&width.to_be_bytes());
+    header.extend_from_slice(&PNG_HEIGHT.to_be_bytes());
+    // 8 bits per channel, RGB, deflate, adaptive filtering, no interlacing
+    header.extend_from_slice(&[8, 2, 0, 0, 0]);
+    let mut out = BufWriter::new(File::create(path)?);
+    out.write_all(&PNG_SIGNATURE)?;
+    write_chunk(&mut out, b"IHDR", &header)?;
+    write_chunk(&mut out, b"IDAT", &pixels.finish()?)?;
+    write_chunk(&mut out, b"IEND", &[])?;
+    out.flush()
+}
+
+/// Write one PNG chunk: its length, type, data, and the CRC of the latter
+/// two.
+fn write_chunk(out: &mut impl Write, kind: &[u8; 4], data: &[u8])
+    -> io::Result<()> {
+    out.write_all(&(data.len() as u32).to_be_bytes())?;
+    out.write_all(kind)?;
+    out.write_all(data)?;
+    let mut crc = Crc::new();
+    crc.update(kind);
+    crc.update(data);
+    out.write_all(&crc.sum().to_be_bytes())
+}
@@ -0,0 +1,74 @@
+//! Hints for triaging differences in rsdiff
+//!
+//! A regression run over thousands of files turns up failures faster than
+//! anyone can look into them one by one. Many come down to a tolerance set
+//! a little too tight, a header field nobody cares about, or stray files
+//! that shouldn't be compared at all. While comparing, rsdiff notes when a
+//! failure is of such a kind, and what would make it pass, e.g. "would
+//! pass with --tolerance 2e-6", so that such failures can be sorted out of
+//! a JSON report before anyone reads its reports.
+
+use std::path::Path;
+
+/// Most exclude patterns a hint suggests before it isn't worth following.
+const MAX_EXCLUDES: usize = 5;
+
+/// Hint at the absolute tolerance that would let numbers at most
+/// `max_abs` apart match: the next value of one significant digit above
+/// it, since numbers match only when closer than the tolerance.
+pub(crate) fn tolerance(max_abs: f64) -> Option<String> {
+    if !max_abs.is_finite() || max_abs <= 0.0 {
+        return None;
+    }
+    let exponent = max_abs.log10().floor() as i32;
+    let scale = 10f64.powi(exponent);
+    let mut digit = (max_abs / scale).ceil();
+    if digit * scale <= max_abs {
+        digit += 1.0;
+    }
+    let tolerance = if digit >= 10.0 {
+        format!("1e{}", exponent + 1)
+    }
+    else {
+        format!("{}e{}", digit, exponent)
+    };
+    Some(format!("would pass with --tolerance {}", tolerance))
+}
+
+/// Hint that only the named header fields keep two images apart.
+pub(crate) fn header_fields(fields: &[&str]) -> Option<String> {
+    match fields {
+        [] => None,
+        [field] => Some(format!("only header field {} differs", field)),
+        _ => Some(format!("only header fields {} differ", fields.join(", "))),
+    }
+}
+
+/// Hint at the exclude patterns that would leave out every entry only one
+/// side of a tree holds: one for each extension among them, and the names
+/// of those without one. Too many patterns to be useful give no hint.
+pub(crate) fn exclusions<'a>(names: impl Iterator<Item = &'a String>)
+    -> Option<String> {
+    let mut count = 0;
+    let mut patterns: Vec<String> = vec!();
+    for name in names {
+        count += 1;
+        let pattern = match Path::new(name).extension() {
+            Some(ext) => format!("*.{}", ext.to_string_lossy()),
+            None => name.clone(),
+        };
+        if !patterns.contains(&pattern) {
+            patterns.push(pattern);
+        }
+    }
+    if patterns.is_empty() || patterns.len() > MAX_EXCLUDES {
+        return None;
+    }
+    patterns.sort();
+    let flags: Vec<String> = patterns.iter()
+        .map(|p| format!("--exclude '{}'", p))
+        .collect();
+    Some(format!("{} {} only on one side; would pass with {}", count,
+                 if count == 1 { "entry" } else { "entries" },
+                 flags.join(" ")))
+}
@@ -0,0 +1,175 @@
+//! Preprocessing hooks for rsdiff
+//!
+//! Heterogeneous exports of the same data (say, `.mgz` from one pipeline
+//! and `.nii.gz` from another) only compare meaningfully once converted to
+//! a common format. A hook transforms each side of a comparison before it
+//! is diffed, either with an external command or a built-in converter.
+//! Converted files are cached, keyed on the hook and the input's path,
+//! size, and modification time, so repeated runs don't convert again.
+//!
+//! Hooks are configured in the config file:
+//!
+//! ```toml
+//! [[hooks]]
+//! pattern = "*.mgz"
+//! command = "mri_convert {input} {output}"
+//! extension = ".nii.gz"
+//!
+//! [[hooks]]
+//! pattern = "*.img.gz"
+//! builtin = "gunzip"
+//! ```
+
+use std::{
+    env,
+    fs::{self, File},
+    io::{self, BufReader},
+    path::{Path, PathBuf},
+    process::Command,
+    time::UNIX_EPOCH,
+};
+
+use globset::Glob;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::gz;
+
+/// Hook
+/// A conversion applied to files matching a pattern before they are
+/// compared.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Hook {
+    /// Glob matched against file names, e.g. `*.mgz`.
+    pub pattern: String,
+    /// External command to run, with `{input}` and `{output}` standing in
+    /// for the file to convert and where to write the result. The command
+    /// is split on whitespace and run directly, without a shell.
+    pub command: Option<String>,
+    /// Name of a built-in converter to use instead of a command. The only
+    /// built-in is `gunzip`.
+    pub builtin: Option<String>,
+    /// Extension to give converted files, so that they are compared with
+    /// the comparator for their new format.
+    pub extension: String,
+}
+
+impl Hook {
+    /// Whether this hook applies to a path, judging by its file name.
+    pub fn matches(&self, path: &str) -> bool {
+        let name = match Path::new(path).file_name() {
+            Some(n) => n,
+            None => return false,
+        };
+        Glob::new(&self.pattern)
+            .map(|g| g.compile_matcher().is_match(name))
+            .unwrap_or(false)
+    }
+
+    /// Convert a file, or reuse a cached conversion of it. Returns the
+    /// path of the converted file.
+    pub fn convert(&self, input: &str, cache_dir: &Path) -> io::Result<PathBuf> {
+        let meta = fs::metadata(input)?;
+        let mtime = meta.modified()?
+            .duration_since(UNIX_EPOCH)
+            .map(|t| t.as_nanos())
+            .unwrap_or(0);
+        let absolute = fs::canonicalize(input)?;
+        // Key the cache on everything that could change the output
+        let mut hasher = Sha256::new();
+        hasher.update(format!("{:?}\0{}\0{}\0{}", self, absolute.display(),
+                              meta.len(), mtime));
+        let key: String = hasher.finalize()[..16].iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        let name = Path::new(input).file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let name = match self.builtin.as_deref() {
+            Some("gunzip") => String::from(
+                name.strip_suffix(".gz").unwrap_or(&name)
+            ),
+            _ => name,
+        };
+        let output = cache_dir.join(
+            format!("{}-{}{}", key, name, self.extension)
+        );
+        if output.exists() {
+            return Ok(output);
+        }
+
+        // Convert to a temporary name so a failed conversion never leaves
+        // a bad cache entry behind
+        fs::create_dir_all(cache_dir)?;
+        let partial = cache_dir.join(
+            format!("{}.partial{}", key, self.extension)
+        );
+        match (self.builtin.as_deref(), &self.command) {
+            (Some("gunzip"), _) => {
+                let mut decoder = gz::decoder(BufReader::new(File::open(input)?));
+                io::copy(&mut decoder, &mut File::create(&partial)?)?;
+            }
+            (Some(other), _) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Unknown built-in converter {}", other)
+                ));
+            }
+            (None, Some(command)) => run_command(command, input, &partial)?,
+            (None, None) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Hook for {} has no command or builtin",
+                            self.pattern)
+                ));
+            }
+        }
+        fs::rename(&partial, &output)?;
+        Ok(output)
+    }
+}
+
+/// Run a hook's command template on an input, writing to output.
+fn run_command(template: &str, input: &str, output: &Path) -> io::Result<()> {
+    let output = output.to_string_lossy();
+    let args: Vec<String> = template.split_whitespace()
+        .map(|a| a.replace("{input}", input).replace("{output}", &output))
+        .collect();
+    let (program, args) = args.split_first().ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, "Hook command is empty")
+    })?;
+    let status = Command::new(program).args(args).status()?;
+    if !status.success() {
+        return Err(io::Error::other(
+            format!("{} failed on {} ({})", program, input, status)
+        ));
+    }
+    Ok(())
+}
+
+/// The hook decompressing gzipped files, for comparing their contents.
+pub(crate) fn gunzip_hook() -> Hook {
+    Hook {
+        pattern: String::from("*.gz"),
+        builtin: Some(String::from("gunzip")),
+        ..Hook::default()
+    }
+}
+
+/// Find the first hook that applies to a path.
+pub fn find_hook<'a>(hooks: &'a [Hook], path: &str) -> Option<&'a Hook> {
+    hooks.iter().find(|h| h.matches(path))
+}
+
+/// Where converted files are cached by default:
+/// `$XDG_CACHE_HOME/rsdiff/converted`, or `~/.cache/rsdiff/converted`.
+pub fn default_cache_dir() -> PathBuf {
+    let base = match env::var_os("XDG_CACHE_HOME") {
+        Some(p) => PathBuf::from(p),
+        None => env::var_os("HOME")
+            .map(|h| PathBuf::from(h).join(".cache"))
+            .unwrap_or_else(env::temp_dir),
+    };
+    base.join("rsdiff").join("converted")
+}
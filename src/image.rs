@@ -0,0 +1,463 @@
+//! Container image comparison for rsdiff
+//!
+//! Two builds of a pipeline's container should carry the same tools and
+//! atlases, but their tarballs never match byte for byte: layer digests,
+//! timestamps, and layer boundaries all vary. Instead, each image's layers
+//! are applied in order, honoring whiteouts, to rebuild the filesystem a
+//! container would see, and the two filesystems are compared as
+//! directories.
+//!
+//! Both `docker save` tarballs (with a `manifest.json`) and OCI image
+//! layouts (with an `index.json`) are understood; layers may be plain or
+//! gzipped tar. Symbolic links are compared by their targets rather than
+//! followed, since they may point anywhere on the host. Device files and
+//! other special entries are skipped.
+
+use std::{
+    fs::{self, File},
+    io::{self, BufReader, Read},
+    path::{Path, PathBuf},
+};
+
+use serde_json::Value;
+use tar::{Archive, EntryType};
+
+use crate::{
+    diff_directory_with_options, gz, tarstream::normalize,
+    workspace::Scratch, Diff, DiffOptions, Result, RsdiffError,
+};
+
+/// Prefix of files marking a path deleted from lower layers.
+const WHITEOUT_PREFIX: &str = ".wh.";
+/// Marker hiding all of a directory's contents from lower layers.
+const OPAQUE_WHITEOUT: &str = ".wh..wh..opq";
+
+/// Compare the merged filesystems of two container image tarballs.
+pub fn diff_images(left: &str, right: &str) -> Result<Diff> {
+    diff_images_with_options(left, right, &DiffOptions::default())
+}
+
+/// Compare the merged filesystems of two container image tarballs with
+/// custom options. The images are unpacked to scratch directories of the
+/// options' workspace, which are removed afterwards.
+pub fn diff_images_with_options(left: &str, right: &str, opts: &DiffOptions)
+    -> Result<Diff> {
+    let left_image = Image::unpack(left, opts)?;
+    let right_image = Image::unpack(right, opts)?;
+    let left_root = left_image.rootfs().to_string_lossy().into_owned();
+    let right_root = right_image.rootfs().to_string_lossy().into_owned();
+    let mut d = diff_directory_with_options(&left_root, &right_root, opts)?;
+    relabel(&mut d, &left_root, left, &right_root, right);
+    if left_image.tags != right_image.tags {
+        d.findings.push(format!(
+            "images are tagged {} and {}",
+            describe_tags(&left_image.tags), describe_tags(&right_image.tags)
+        ));
+    }
+    Ok(d)
+}
+
+/// Image
+/// A container image unpacked to a scratch directory, which goes with it.
+struct Image {
+    scratch: Scratch,
+    tags: Vec<String>,
+}
+
+impl Image {
+    /// Unpack an image tarball and apply its layers.
+    fn unpack(path: &str, opts: &DiffOptions) -> Result<Image> {
+        let io_error = |e| RsdiffError::io(path, e);
+        let scratch = opts.workspace.scratch("image").map_err(io_error)?;
+        let mut image = Image { scratch, tags: vec!() };
+        // The tarball's contents take about as much room as the tarball
+        let size = fs::metadata(path).map_err(io_error)?.len();
+        image.scratch.charge(size).map_err(io_error)?;
+        let blobs = image.scratch.path().join("image");
+        fs::create_dir_all(&blobs).map_err(io_error)?;
+        Archive::new(BufReader::new(File::open(path).map_err(io_error)?))
+            .unpack(&blobs)
+            .map_err(io_error)?;
+        let layers = if blobs.join("manifest.json").is_file() {
+            let (layers, tags) = docker_layers(&blobs)
+                .map_err(|reason| image_error(path, reason))?;
+            image.tags = tags;
+            layers
+        }
+        else {
+            oci_layers(&blobs).map_err(|reason| image_error(path, reason))?
+        };
+        let rootfs = image.rootfs();
+        fs::create_dir_all(&rootfs).map_err(io_error)?;
+        for layer in layers.iter() {
+            apply_layer(&blobs.join(normalize(Path::new(layer))), &rootfs,
+                        &image.scratch)
+                .map_err(|e| RsdiffError::io(&format!("{}:{}", path, layer), e))?;
+        }
+        Ok(image)
+    }
+
+    /// Where the merged filesystem lives.
+    fn rootfs(&self) -> PathBuf {
+        self.scratch.path().join("rootfs")
+    }
+}
+
+/// Describe why an image couldn't be understood.
+fn image_error(path: &str, reason: String) -> RsdiffError {
+    RsdiffError::Image { path: String::from(path), reason }
+}
+
+/// Read a JSON file from an unpacked image.
+fn read_json(path: &Path) -> std::result::Result<Value, String> {
+    let text = fs::read_to_string(path)
+        .map_err(|e| format!("can't read {}: {}", path.display(), e))?;
+    serde_json::from_str(&text)
+        .map_err(|e| format!("can't parse {}: {}", path.display(), e))
+}
+
+/// Find the layers of a `docker save` tarball, bottom first, and the tags
+/// of its first image.
+fn docker_layers(blobs: &Path)
+    -> std::result::Result<(Vec<String>, Vec<String>), String> {
+    let manifest = read_json(&blobs.join("manifest.json"))?;
+    let image = manifest.get(0)
+        .ok_or_else(|| String::from("manifest.json lists no images"))?;
+    let strings = |key: &str| -> Vec<String> {
+        image[key].as_array()
+            .map(|a| a.iter().filter_map(|v| v.as_str())
+                 .map(String::from).collect())
+            .unwrap_or_default()
+    };
+    Ok((strings("Layers"), strings("RepoTags")))
+}
+
+/// Find the layers of an OCI image layout, bottom first, following the
+/// index to the first image manifest.
+fn oci_layers(blobs: &Path) -> std::result::Result<Vec<String>, String> {
+    let blob_path = |digest: &str| {
+        digest.split_once(':')
+            .map(|(algorithm, hex)| format!("blobs/{}/{}", algorithm, hex))
+            .ok_or_else(|| format!("bad digest {}", digest))
+    };
+    let mut document = read_json(&blobs.join("index.json"))
+        .map_err(|e| format!("not a docker or OCI image: {}", e))?;
+    // Indexes may point to further indexes before reaching a manifest
+    while document.get("layers").is_none() {
+        let digest = document["manifests"][0]["digest"].as_str()
+            .ok_or_else(|| String::from("index lists no manifests"))?;
+        document = read_json(&blobs.join(blob_path(digest)?))?;
+    }
+    document["layers"].as_array()
+        .ok_or_else(|| String::from("manifest lists no layers"))?
+        .iter()
+        .map(|layer| {
+            layer["digest"].as_str()
+                .ok_or_else(|| String::from("layer has no digest"))
+                .and_then(blob_path)
+        })
+        .collect()
+}
+
+/// Apply one layer tarball, plain or gzipped, on top of a filesystem,
+/// charging what it writes to the scratch directory holding it.
+fn apply_layer(layer: &Path, rootfs: &Path, scratch: &Scratch)
+    -> io::Result<()> {
+    // Whiteouts only hide what lower layers put there, so apply them all
+    // before unpacking anything from this layer
+    for_each_entry(layer, &mut |_, path| {
+        let name = path.file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let parent = rootfs.join(path.parent().unwrap_or(Path::new("")));
+        if name == OPAQUE_WHITEOUT {
+            if parent.is_dir() {
+                for child in fs::read_dir(&parent)? {
+                    remove(&child?.path())?;
+                }
+            }
+        }
+        else if let Some(hidden) = name.strip_prefix(WHITEOUT_PREFIX) {
+            remove(&parent.join(hidden))?;
+        }
+        Ok(())
+    })?;
+    for_each_entry(layer, &mut |entry, path| {
+        let name = path.file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        if name.starts_with(WHITEOUT_PREFIX) {
+            return Ok(());
+        }
+        let target = rootfs.join(&path);
+        let kind = entry.header().entry_type();
+        if kind.is_dir() {
+            if target.exists() && !target.is_dir() {
+                remove(&target)?;
+            }
+            return fs::create_dir_all(&target);
+        }
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        match kind {
+            EntryType::Regular | EntryType::Continuous => {
+                remove(&target)?;
+                scratch.charge(entry.size())?;
+                io::copy(entry, &mut File::create(&target)?)?;
+            }
+            EntryType::Symlink => {
+                // Stand in for the link with a file naming its target
+                let link = entry.link_name()?.unwrap_or_default().into_owned();
+                remove(&target)?;
+                fs::write(&target,
+                          format!("symlink to {}\n", link.display()))?;
+            }
+            EntryType::Link => {
+                let link = entry.link_name()?.unwrap_or_default().into_owned();
+                let source = rootfs.join(normalize(&link));
+                if source.is_file() && source != target {
+                    remove(&target)?;
+                    scratch.charge(fs::metadata(&source)?.len())?;
+                    fs::copy(&source, &target)?;
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    })
+}
+
+/// Something done to each entry of a layer, given its normalized path.
+type EntryVisitor<'a> =
+    dyn FnMut(&mut tar::Entry<'_, Box<dyn Read>>, PathBuf) -> io::Result<()>
+        + 'a;
+
+/// Run a function over every entry of a layer, with its normalized path.
+fn for_each_entry(layer: &Path, f: &mut EntryVisitor) -> io::Result<()> {
+    let mut file = BufReader::new(File::open(layer)?);
+    let mut magic = [0u8; 2];
+    let compressed = file.read_exact(&mut magic).is_ok()
+        && magic == [0x1f, 0x8b];
+    let file = BufReader::new(File::open(layer)?);
+    let reader: Box<dyn Read> = if compressed {
+        Box::new(gz::decoder(file))
+    }
+    else {
+        Box::new(file)
+    };
+    let mut archive = Archive::new(reader);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = normalize(&entry.path()?);
+        if path.as_os_str().is_empty() {
+            continue;
+        }
+        f(&mut entry, path)?;
+    }
+    Ok(())
+}
+
+/// Remove a file or directory if it exists.
+fn remove(path: &Path) -> io::Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(meta) if meta.is_dir() => fs::remove_dir_all(path),
+        Ok(_) => fs::remove_file(path),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Point a diff of unpacked filesystems back at the image tarballs.
+fn relabel(d: &mut Diff, from_left: &str, to_left: &str, from_right: &str,
+           to_right: &str) {
+    d.left = d.left.replacen(from_left, to_left, 1);
+    d.right = d.right.replacen(from_right, to_right, 1);
+    d.report = d.report.replace(from_left, to_left)
+        .replace(from_right, to_right);
+    for subdiff in d.sub_diffs.iter_mut() {
+        relabel(subdiff, from_left, to_left, from_right, to_right);
+    }
+}
+
+/// List an image's tags for a report.
+fn describe_tags(tags: &[String]) -> String {
+    if tags.is_empty() {
+        String::from("(untagged)")
+    }
+    else {
+        tags.join(", ")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tar::{Builder, Header};
+
+    use crate::workspace::test_scratch;
+
+    /// A tar archive of files, by path and contents. Paths ending in `/`
+    /// are directories.
+    fn tar(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut builder = Builder::new(vec!());
+        for (path, contents) in files {
+            let mut header = Header::new_gnu();
+            header.set_mode(0o644);
+            if path.ends_with('/') {
+                header.set_entry_type(EntryType::Directory);
+            }
+            header.set_size(contents.len() as u64);
+            builder.append_data(&mut header, path, *contents).unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    /// The base layer the tests' images start from.
+    fn base_layer() -> Vec<u8> {
+        tar(&[
+            ("etc/", b""),
+            ("etc/removed.conf", b"removed\n"),
+            ("etc/kept.conf", b"kept\n"),
+            ("opt/atlas/", b""),
+            ("opt/atlas/old.dat", b"old\n"),
+            ("opt/atlas/sub/", b""),
+            ("opt/atlas/sub/old.txt", b"old\n"),
+        ])
+    }
+
+    /// A layer deleting one file and replacing the atlas directory's
+    /// contents.
+    fn whiteout_layer() -> Vec<u8> {
+        tar(&[
+            ("etc/.wh.removed.conf", b""),
+            ("opt/atlas/.wh..wh..opq", b""),
+            ("opt/atlas/new.dat", b"new\n"),
+        ])
+    }
+
+    /// Every file under `root`, relative to it, sorted.
+    fn files(root: &Path) -> Vec<String> {
+        let mut found = vec!();
+        let mut dirs = vec!(root.to_path_buf());
+        while let Some(dir) = dirs.pop() {
+            for entry in fs::read_dir(&dir).unwrap() {
+                let path = entry.unwrap().path();
+                if path.is_dir() {
+                    dirs.push(path);
+                }
+                else {
+                    found.push(path.strip_prefix(root).unwrap()
+                               .to_string_lossy().into_owned());
+                }
+            }
+        }
+        found.sort();
+        found
+    }
+
+    #[test]
+    fn whiteouts_hide_what_lower_layers_put_there() {
+        let scratch = test_scratch("image-whiteouts");
+        let dir = scratch.path();
+        let (base, upper) = (dir.join("base.tar"), dir.join("upper.tar"));
+        fs::write(&base, base_layer()).unwrap();
+        fs::write(&upper, whiteout_layer()).unwrap();
+        let rootfs = dir.join("rootfs");
+        fs::create_dir_all(&rootfs).unwrap();
+        apply_layer(&base, &rootfs, &scratch).unwrap();
+        apply_layer(&upper, &rootfs, &scratch).unwrap();
+        assert_eq!(files(&rootfs), vec!("etc/kept.conf",
+                                        "opt/atlas/new.dat"));
+        assert!(rootfs.join("opt/atlas").is_dir());
+    }
+
+    /// Write a `docker save` tarball of `layers`, bottom first, tagged
+    /// `tag`, and return its path.
+    fn docker_image(dir: &Path, name: &str, tag: &str, layers: &[Vec<u8>])
+        -> String {
+        let names: Vec<String> = (0..layers.len())
+            .map(|i| format!("layer{}/layer.tar", i))
+            .collect();
+        let manifest = serde_json::json!([{
+            "Config": "config.json", "RepoTags": [tag], "Layers": names,
+        }]).to_string();
+        let mut files: Vec<(&str, &[u8])> = vec!(
+            ("manifest.json", manifest.as_bytes()),
+        );
+        for (name, layer) in names.iter().zip(layers) {
+            files.push((name, layer));
+        }
+        let path = dir.join(name);
+        fs::write(&path, tar(&files)).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    /// Write an OCI image layout of `layers`, bottom first, behind an
+    /// index, and return its path.
+    fn oci_image(dir: &Path, name: &str, layers: &[Vec<u8>]) -> String {
+        let digests: Vec<String> = layers.iter()
+            .map(|layer| crate::hash::hash_bytes(layer))
+            .collect();
+        let manifest = serde_json::json!({
+            "layers": digests.iter()
+                .map(|d| serde_json::json!({"digest": format!("sha256:{}", d)}))
+                .collect::<Vec<_>>(),
+        }).to_string();
+        let manifest_digest = crate::hash::hash_bytes(manifest.as_bytes());
+        let index = serde_json::json!({
+            "manifests": [{"digest": format!("sha256:{}", manifest_digest)}],
+        }).to_string();
+        let blob = |digest: &str| format!("blobs/sha256/{}", digest);
+        let mut entries: Vec<(String, &[u8])> = vec!(
+            (String::from("index.json"), index.as_bytes()),
+            (blob(&manifest_digest), manifest.as_bytes()),
+        );
+        for (digest, layer) in digests.iter().zip(layers) {
+            entries.push((blob(digest), layer));
+        }
+        let files: Vec<(&str, &[u8])> = entries.iter()
+            .map(|(path, contents)| (path.as_str(), *contents))
+            .collect();
+        let path = dir.join(name);
+        fs::write(&path, tar(&files)).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn images_compare_by_their_merged_filesystems() {
+        let scratch = test_scratch("image-merged");
+        let dir = scratch.path();
+        let layered = docker_image(dir, "layered.tar", "pipeline:1",
+                                   &[base_layer(), whiteout_layer()]);
+        // The same filesystem in one layer, in an OCI layout
+        let flat = oci_image(dir, "flat.tar", &[tar(&[
+            ("etc/kept.conf", b"kept\n"),
+            ("opt/atlas/new.dat", b"new\n"),
+        ])]);
+        let d = diff_images(&layered, &flat).unwrap();
+        assert!(d.matches, "{}", d.report);
+        assert_eq!(d.left, layered);
+        assert_eq!(d.right, flat);
+        assert_eq!(d.findings, vec!("images are tagged pipeline:1 and \
+                                     (untagged)"));
+        // Without the whiteouts, the deleted files are still there
+        let unmerged = docker_image(dir, "unmerged.tar", "pipeline:1",
+                                    &[base_layer()]);
+        let d = diff_images(&unmerged, &layered).unwrap();
+        assert!(!d.matches);
+        assert!(d.findings.is_empty());
+    }
+
+    #[test]
+    fn tarballs_that_arent_images_are_errors() {
+        let scratch = test_scratch("image-not-an-image");
+        let dir = scratch.path();
+        let path = dir.join("plain.tar");
+        fs::write(&path, tar(&[("README", b"hello\n")])).unwrap();
+        let path = path.to_string_lossy();
+        assert!(matches!(diff_images(&path, &path),
+                         Err(RsdiffError::Image { .. })));
+    }
+}
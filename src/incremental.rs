@@ -0,0 +1,94 @@
+//! Incremental comparison of growing files for rsdiff
+//!
+//! Logs and streaming acquisitions only ever grow at their ends, so once
+//! two such files have been compared up to some offset, the bytes before
+//! it need not be read again. An incremental comparison reads only what
+//! lies beyond that offset and folds the result into the earlier Diff, so
+//! files can be re-verified periodically at the cost of what was appended
+//! since.
+
+use std::{fs, ops::Range};
+
+use crate::{
+    diff_byte_ranges, subtract_ranges, Diff, DiffOptions, Result, RsdiffError,
+    Unit,
+};
+
+/// Compare the bytes of two files beyond `from_offset`, merged with the
+/// `prior` comparison of the bytes before it.
+pub fn diff_incremental(left: &str, right: &str, from_offset: u64,
+                        prior: &Diff) -> Result<Diff> {
+    diff_incremental_with_options(left, right, from_offset, prior,
+                                  &DiffOptions::default())
+}
+
+/// Compare the bytes of two files beyond `from_offset` with custom options,
+/// merged with the `prior` comparison of the bytes before it. The prior
+/// comparison should be a byte-wise one of the same files, such as
+/// `diff_bytes` or an earlier incremental comparison gives; when the files
+/// matched, the next comparison can start from their length.
+///
+/// Bytes that only one file has yet count as mismatches, so a file that
+/// has grown further than the other differs until the other catches up.
+/// Files aren't hashed, since only part of them is read.
+pub fn diff_incremental_with_options(left: &str, right: &str,
+                                     from_offset: u64, prior: &Diff,
+                                     opts: &DiffOptions) -> Result<Diff> {
+    let left_len = file_len(left)?;
+    let right_len = file_len(right)?;
+    let mut d = Diff::new(left, right);
+    d.findings = prior.findings.clone();
+    d.max_relative_difference = prior.max_relative_difference;
+
+    // Files that were truncated no longer hold what was verified
+    let shorter = [("left", left_len), ("right", right_len)].iter()
+        .find(|(_, len)| *len < from_offset)
+        .copied();
+    if let Some((side, len)) = shorter {
+        d.set_counts(prior.matched, prior.total, Unit::Bytes);
+        d.additional_info = format!(
+            "the {} file is {} bytes long, shorter than the {} bytes \
+             verified before", side, len, from_offset
+        );
+        d.report = format!("{} vs {}: {}", d.left, d.right, d.additional_info);
+        return Ok(d);
+    }
+
+    // Compare what the requested ranges hold past the offset, as a full
+    // comparison would have
+    let requested = if opts.byte_ranges.is_empty() {
+        vec!(Range { start: 0, end: u64::MAX })
+    }
+    else {
+        opts.byte_ranges.clone()
+    };
+    let verified = Range { start: 0, end: from_offset };
+    let ranges = subtract_ranges(&subtract_ranges(&requested, &[verified]),
+                                 &opts.ignore_ranges);
+    let (matched, total) = diff_byte_ranges(left, right, left_len, right_len,
+                                            &ranges, opts.chunk_size)?;
+    d.set_counts(prior.matched + matched, prior.total + total, Unit::Bytes);
+    d.matches = prior.matches && matched == total;
+    if !d.matches {
+        d.additional_info = format!(
+            "{} of {} bytes match ({:.1}%)",
+            d.matched, d.total, d.similarity * 100.0
+        );
+        if left_len != right_len {
+            d.additional_info.push_str(&format!(
+                "; file sizes differ: {} vs. {}", left_len, right_len
+            ));
+        }
+        d.report = format!("{} vs {}: {}", d.left, d.right, d.additional_info);
+    }
+    Ok(d)
+}
+
+/// The length of a file, which must be one.
+fn file_len(path: &str) -> Result<u64> {
+    let meta = fs::metadata(path).map_err(|e| RsdiffError::io(path, e))?;
+    if !meta.is_file() {
+        return Err(RsdiffError::NotAFile(String::from(path)));
+    }
+    Ok(meta.len())
+}
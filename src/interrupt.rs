@@ -0,0 +1,21 @@
+//! Interruption handling for rsdiff
+//!
+//! Comparing large trees can take hours. When the user interrupts a run,
+//! comparisons already finished are still worth reporting, so instead of
+//! dying on the spot, the signal handler raises a flag that directory
+//! comparisons check before starting each entry. The diffs unwind with what
+//! they have, marked as interrupted.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Ask running comparisons to stop before starting anything new.
+pub fn request() {
+    REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// Whether an interruption has been requested.
+pub fn requested() -> bool {
+    REQUESTED.load(Ordering::SeqCst)
+}
@@ -0,0 +1,512 @@
+//! JSON comparison for rsdiff
+//!
+//! Tools that write JSON sidecars and reports are free to order keys and
+//! format numbers however they like, so two files holding the same data
+//! can differ byte for byte. JSON files are parsed and compared value by
+//! value instead, in canonical form: object keys are unordered, and
+//! numbers are compared by value, so `1`, `1.0`, and `1e0` are the same.
+//! Numbers that aren't integers may also differ by a tolerance, set for
+//! all of them or per path, since floats like an EchoTime in a sidecar
+//! tend to drift in their last digits. Arrays written in no particular
+//! order, like task event lists, can be compared as multisets.
+//! When the exact serialization matters, canonical comparison can be
+//! turned off and the files are compared byte by byte.
+
+use std::fs;
+
+use globset::{GlobBuilder, GlobMatcher};
+use serde_json::Value;
+
+use crate::{diff_bytes_with_options, drift, schema, Diff, DiffOptions,
+            Result, RsdiffError, Unit};
+
+/// Most differences to list under a report.
+const MAX_REPORTED: usize = 20;
+/// Longest rendering of a value in a report, in characters.
+const MAX_VALUE_WIDTH: usize = 40;
+
+/// Whether a file is JSON.
+pub fn is_json(path: &str) -> bool {
+    path.ends_with(".json")
+}
+
+/// Compare two JSON files value by value.
+pub fn diff_json(left: &str, right: &str) -> Result<Diff> {
+    diff_json_with_options(left, right, &DiffOptions::default())
+}
+
+/// Compare two JSON files value by value with custom options.
+pub fn diff_json_with_options(left: &str, right: &str, opts: &DiffOptions)
+    -> Result<Diff> {
+    if !opts.canonical_json {
+        return diff_bytes_with_options(left, right, opts);
+    }
+    let left_value = load(left)?;
+    let right_value = load(right)?;
+    if opts.schema_only {
+        let mut d = Diff::new(left, right);
+        schema::compare(&mut d, &schema::json_schema(&left_value),
+                        &schema::json_schema(&right_value));
+        return Ok(d);
+    }
+    let rules = Rules::new(opts);
+    let mut tally = Tally::new(&rules);
+    tally.compare(&left_value, &right_value, "");
+
+    let mut d = Diff::new(left, right);
+    d.set_counts(tally.matched, tally.total, Unit::Values);
+    if opts.drift {
+        d.max_relative_difference = tally.max_relative_difference;
+    }
+    d.matches = tally.differing.is_empty();
+    if !d.matches {
+        let count = |kind: Kind| {
+            tally.differing.iter().filter(|c| c.kind() == kind).count()
+        };
+        d.additional_info = format!(
+            "{} of {} values match ({:.1}%); {} added, {} removed, {} \
+             changed", tally.matched, tally.total, d.similarity * 100.0,
+            count(Kind::Added), count(Kind::Removed), count(Kind::Changed)
+        );
+        d.report = format!("{} vs {}: {}", left, right, d.additional_info);
+        add_changes(&mut d, &tally.differing);
+    }
+    Ok(d)
+}
+
+/// Record each change as a sub-diff, and list the first few under the
+/// report.
+fn add_changes(d: &mut Diff, changes: &[Change]) {
+    for (i, change) in changes.iter().enumerate() {
+        let pointer = if change.pointer.is_empty() { "/" } else {
+            &change.pointer
+        };
+        let mut subdiff = Diff::new(&format!("{}:{}", d.left, pointer),
+                                    &format!("{}:{}", d.right, pointer));
+        subdiff.additional_info = match (&change.left, &change.right) {
+            (Some(l), Some(r)) => format!("{} vs. {}", l, r),
+            (Some(l), None) => format!("removed ({})", l),
+            (None, Some(r)) => format!("added ({})", r),
+            (None, None) => unreachable!(),
+        };
+        subdiff.report = format!("  {}: {}", pointer, subdiff.additional_info);
+        if i < MAX_REPORTED {
+            d.report.push('\n');
+            d.report.push_str(&subdiff.report);
+        }
+        d.sub_diffs.push(Box::new(subdiff));
+    }
+    if changes.len() > MAX_REPORTED {
+        d.report.push_str(&format!("\n  and {} more",
+                                   changes.len() - MAX_REPORTED));
+    }
+}
+
+/// Change
+/// A place where two documents differ, with the value on each side that
+/// has one, rendered for reporting.
+struct Change {
+    pointer: String,
+    left: Option<String>,
+    right: Option<String>,
+}
+
+/// Kind
+/// Whether a change adds, removes, or changes a value.
+#[derive(PartialEq, Clone, Copy)]
+enum Kind {
+    Added,
+    Removed,
+    Changed,
+}
+
+impl Change {
+    fn new(pointer: &str, left: Option<&Value>, right: Option<&Value>)
+        -> Change {
+        Change {
+            pointer: String::from(pointer),
+            left: left.map(render),
+            right: right.map(render),
+        }
+    }
+
+    fn kind(&self) -> Kind {
+        match (&self.left, &self.right) {
+            (None, _) => Kind::Added,
+            (_, None) => Kind::Removed,
+            _ => Kind::Changed,
+        }
+    }
+}
+
+/// Render a value compactly, shortened if it is long.
+fn render(value: &Value) -> String {
+    let text = value.to_string();
+    if text.chars().count() <= MAX_VALUE_WIDTH {
+        return text;
+    }
+    let short: String = text.chars().take(MAX_VALUE_WIDTH - 3).collect();
+    format!("{}...", short)
+}
+
+/// Read and parse a JSON file.
+fn load(path: &str) -> Result<Value> {
+    let text = fs::read(path).map_err(|e| RsdiffError::io(path, e))?;
+    serde_json::from_slice(&text).map_err(|e| RsdiffError::Corrupt(
+        format!("{} is not valid JSON: {}", path, e)
+    ))
+}
+
+/// Rules
+/// How values at each place in a document are compared, with the globs of
+/// the options compiled.
+struct Rules {
+    tolerance: f64,
+    path_tolerances: Vec<(GlobMatcher, f64)>,
+    unordered_arrays: Vec<GlobMatcher>,
+    ignored_keys: Vec<GlobMatcher>,
+}
+
+impl Rules {
+    fn new(opts: &DiffOptions) -> Rules {
+        Rules {
+            tolerance: opts.tolerance,
+            path_tolerances: opts.path_tolerances.iter()
+                .filter_map(|(p, tolerance)| Some((pointer_glob(p)?,
+                                                   *tolerance)))
+                .collect(),
+            unordered_arrays: opts.unordered_arrays.iter()
+                .filter_map(|p| pointer_glob(p))
+                .collect(),
+            ignored_keys: opts.ignore_keys.iter()
+                .filter_map(|p| pointer_glob(p))
+                .collect(),
+        }
+    }
+
+    /// The tolerance for numbers at `pointer`.
+    fn tolerance_at(&self, pointer: &str) -> f64 {
+        self.path_tolerances.iter().rev()
+            .find(|(glob, _)| glob.is_match(pointer))
+            .map_or(self.tolerance, |&(_, tolerance)| tolerance)
+    }
+
+    /// Whether the array at `pointer` is compared without regard to order.
+    fn is_unordered(&self, pointer: &str) -> bool {
+        self.unordered_arrays.iter().any(|glob| glob.is_match(pointer))
+    }
+
+    /// Whether the value at `pointer` is left out of the comparison.
+    fn is_ignored(&self, pointer: &str) -> bool {
+        self.ignored_keys.iter().any(|glob| glob.is_match(pointer))
+    }
+}
+
+/// Compile a glob over JSON pointers. Patterns without a leading `/` are
+/// bare keys, which match at any depth.
+fn pointer_glob(pattern: &str) -> Option<GlobMatcher> {
+    let pattern = if pattern.starts_with('/') {
+        String::from(pattern)
+    }
+    else {
+        format!("**/{}", pattern)
+    };
+    let glob = GlobBuilder::new(&pattern)
+        .literal_separator(true)
+        .build()
+        .ok()?;
+    Some(glob.compile_matcher())
+}
+
+/// Tally
+/// Running counts of a value-by-value comparison, and where the values
+/// differ.
+struct Tally<'a> {
+    matched: usize,
+    total: usize,
+    differing: Vec<Change>,
+    max_relative_difference: Option<f64>,
+    rules: &'a Rules,
+}
+
+impl<'a> Tally<'a> {
+    fn new(rules: &'a Rules) -> Tally<'a> {
+        Tally {
+            matched: 0, total: 0, differing: vec!(),
+            max_relative_difference: None, rules
+        }
+    }
+
+    /// Whether two values found at `pointer` are the same, under the rules
+    /// that apply there.
+    fn same(&self, left: &Value, right: &Value, pointer: &str) -> bool {
+        let mut scratch = Tally::new(self.rules);
+        scratch.compare(left, right, pointer);
+        scratch.differing.is_empty()
+    }
+
+    /// Compare two arrays as multisets. Elements with an equal on the other
+    /// side match; the rest are compared pairwise in the order they come.
+    fn compare_unordered(&mut self, left: &[Value], right: &[Value],
+                         pointer: &str) {
+        let mut unpaired: Vec<usize> = (0..right.len()).collect();
+        let mut leftover = vec!();
+        for (i, l) in left.iter().enumerate() {
+            let child = format!("{}/{}", pointer, i);
+            let equal = unpaired.iter()
+                .position(|&j| self.same(l, &right[j], &child));
+            match equal {
+                Some(at) => {
+                    let j = unpaired.remove(at);
+                    self.compare(l, &right[j], &child);
+                }
+                None => leftover.push(i),
+            }
+        }
+        for (n, &i) in leftover.iter().enumerate() {
+            let child = format!("{}/{}", pointer, i);
+            match unpaired.get(n) {
+                Some(&j) => self.compare(&left[i], &right[j], &child),
+                None => {
+                    self.total += leaves(&left[i]);
+                    self.differing.push(Change::new(&child, Some(&left[i]),
+                                                    None));
+                }
+            }
+        }
+        for &j in unpaired.iter().skip(leftover.len()) {
+            self.total += leaves(&right[j]);
+            self.differing.push(Change::new(&format!("{}/{}", pointer, j),
+                                            None, Some(&right[j])));
+        }
+    }
+
+    /// Compare two values found at `pointer`, counting their leaves.
+    fn compare(&mut self, left: &Value, right: &Value, pointer: &str) {
+        match (left, right) {
+            (Value::Object(l), Value::Object(r)) if !l.is_empty()
+                || !r.is_empty() => {
+                let mut keys: Vec<&String> = l.keys().chain(r.keys()).collect();
+                keys.sort();
+                keys.dedup();
+                for key in keys {
+                    let child = format!("{}/{}", pointer, escape(key));
+                    if self.rules.is_ignored(&child) {
+                        continue;
+                    }
+                    match (l.get(key), r.get(key)) {
+                        (Some(lv), Some(rv)) => self.compare(lv, rv, &child),
+                        (lv, rv) => self.one_sided(lv, rv, &child),
+                    }
+                }
+            }
+            (Value::Array(l), Value::Array(r))
+                if self.rules.is_unordered(pointer) => {
+                self.compare_unordered(l, r, pointer);
+            }
+            (Value::Array(l), Value::Array(r)) if !l.is_empty()
+                || !r.is_empty() => {
+                for i in 0..l.len().max(r.len()) {
+                    let child = format!("{}/{}", pointer, i);
+                    match (l.get(i), r.get(i)) {
+                        (Some(lv), Some(rv)) => self.compare(lv, rv, &child),
+                        (lv, rv) => self.one_sided(lv, rv, &child),
+                    }
+                }
+            }
+            _ => {
+                if leaves(left) == 1 && leaves(right) == 1 {
+                    self.total += 1;
+                    if let (Some(a), Some(b)) = (left.as_f64(), right.as_f64()) {
+                        drift::record(&mut self.max_relative_difference,
+                                      drift::relative_difference(a, b));
+                    }
+                    let tolerance = self.rules.tolerance_at(pointer);
+                    if same_scalar(left, right, tolerance) {
+                        self.matched += 1;
+                        return;
+                    }
+                }
+                else {
+                    // A container on one side and something else on the
+                    // other; nothing in it matches
+                    self.total += leaves(left).max(leaves(right));
+                }
+                self.differing.push(Change::new(pointer, Some(left),
+                                                Some(right)));
+            }
+        }
+    }
+
+    /// Count a value only one side has as differing.
+    fn one_sided(&mut self, left: Option<&Value>, right: Option<&Value>,
+                 pointer: &str) {
+        self.total += left.or(right).map_or(0, leaves);
+        self.differing.push(Change::new(pointer, left, right));
+    }
+}
+
+/// How many values a comparison of `value` counts: one per scalar, with
+/// empty containers counted as a value of their own.
+fn leaves(value: &Value) -> usize {
+    let sum = match value {
+        Value::Object(map) => map.values().map(leaves).sum(),
+        Value::Array(items) => items.iter().map(leaves).sum(),
+        _ => 1,
+    };
+    sum.max(1)
+}
+
+/// Whether two scalars are the same in canonical form. Integers are
+/// compared exactly, so large ones don't collide through rounding; other
+/// numbers are compared by value, and match when they differ by less than
+/// `tolerance`.
+fn same_scalar(left: &Value, right: &Value, tolerance: f64) -> bool {
+    match (left, right) {
+        (Value::Number(l), Value::Number(r)) => {
+            match (l.as_i64(), r.as_i64(), l.as_u64(), r.as_u64()) {
+                (Some(a), Some(b), _, _) => a == b,
+                (_, _, Some(a), Some(b)) => a == b,
+                _ => match (l.as_f64(), r.as_f64()) {
+                    (Some(a), Some(b)) => a == b || (a - b).abs() < tolerance,
+                    _ => false,
+                },
+            }
+        }
+        _ => left == right,
+    }
+}
+
+/// Escape an object key for use in a JSON pointer.
+fn escape(key: &str) -> String {
+    key.replace('~', "~0").replace('/', "~1")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    /// Compare two documents under `opts`, returning the values matched,
+    /// the values counted, and the pointers of the changes.
+    fn compare(left: Value, right: Value, opts: &DiffOptions)
+        -> (usize, usize, Vec<String>) {
+        let rules = Rules::new(opts);
+        let mut tally = Tally::new(&rules);
+        tally.compare(&left, &right, "");
+        let pointers = tally.differing.iter()
+            .map(|c| c.pointer.clone())
+            .collect();
+        (tally.matched, tally.total, pointers)
+    }
+
+    fn defaults() -> DiffOptions {
+        DiffOptions::default()
+    }
+
+    #[test]
+    fn key_order_and_number_spelling_do_not_matter() {
+        let left: Value = serde_json::from_str(
+            r#"{"b": 1, "a": [1.0, 2.5e0], "c": {"d": null}}"#
+        ).unwrap();
+        let right: Value = serde_json::from_str(
+            r#"{"c": {"d": null}, "a": [1e0, 2.5], "b": 1}"#
+        ).unwrap();
+        assert_eq!(compare(left, right, &defaults()), (4, 4, vec!()));
+    }
+
+    #[test]
+    fn large_integers_are_compared_exactly() {
+        let left: Value = serde_json::from_str("9007199254740993").unwrap();
+        let right: Value = serde_json::from_str("9007199254740992").unwrap();
+        assert_eq!(compare(left, right, &defaults()).2, vec!(""));
+        let left: Value = serde_json::from_str("18446744073709551615")
+            .unwrap();
+        let right: Value = serde_json::from_str("18446744073709551614")
+            .unwrap();
+        assert_eq!(compare(left, right, &defaults()).2, vec!(""));
+    }
+
+    #[test]
+    fn floats_match_within_the_tolerance_for_their_path() {
+        let opts = DiffOptions {
+            tolerance: 1e-9,
+            path_tolerances: vec!((String::from("EchoTime"), 1e-3)),
+            ..defaults()
+        };
+        let left = json!({"EchoTime": 0.0300, "RepetitionTime": 2.0});
+        let right = json!({"EchoTime": 0.0301, "RepetitionTime": 2.0001});
+        assert_eq!(compare(left, right, &opts),
+                   (1, 2, vec!(String::from("/RepetitionTime"))));
+    }
+
+    #[test]
+    fn equal_floats_match_at_zero_tolerance() {
+        let opts = DiffOptions { tolerance: 0.0, ..defaults() };
+        assert_eq!(compare(json!(0.1), json!(0.1), &opts), (1, 1, vec!()));
+    }
+
+    #[test]
+    fn unordered_arrays_are_compared_as_multisets() {
+        let opts = DiffOptions {
+            unordered_arrays: vec!(String::from("/events")),
+            ..defaults()
+        };
+        let same = compare(json!({"events": [1, 1, 2]}),
+                           json!({"events": [2, 1, 1]}), &opts);
+        assert_eq!(same, (3, 3, vec!()));
+        // Duplicates count: the extra 1 has only a 2 left to pair with
+        let (matched, total, pointers) = compare(
+            json!({"events": [1, 1, 2]}), json!({"events": [1, 2, 2]}), &opts
+        );
+        assert_eq!((matched, total), (2, 3));
+        assert_eq!(pointers, vec!("/events/1"));
+        // Elements only one side has are added or removed
+        let (_, total, pointers) = compare(json!({"events": [1, 2, 3]}),
+                                           json!({"events": [3]}), &opts);
+        assert_eq!(total, 3);
+        assert_eq!(pointers, vec!("/events/0", "/events/1"));
+    }
+
+    #[test]
+    fn ignored_keys_are_left_out_at_any_depth() {
+        let opts = DiffOptions {
+            ignore_keys: vec!(String::from("/GeneratedBy"),
+                              String::from("Date")),
+            ..defaults()
+        };
+        let left = json!({"GeneratedBy": [{"Name": "a"}], "Name": "x",
+                          "Sub": {"Date": "2020"}});
+        let right = json!({"GeneratedBy": [{"Name": "b"}], "Name": "x",
+                           "Sub": {"Date": "2021"}});
+        assert_eq!(compare(left, right, &opts), (1, 1, vec!()));
+    }
+
+    #[test]
+    fn changes_are_found_by_escaped_pointer() {
+        let left = json!({"a/b": 1, "c~d": [1, 2], "gone": true});
+        let right = json!({"a/b": 2, "c~d": [1], "new": false});
+        let (matched, total, pointers) = compare(left, right, &defaults());
+        assert_eq!((matched, total), (1, 5));
+        assert_eq!(pointers, vec!("/a~1b", "/c~0d/1", "/gone", "/new"));
+    }
+
+    #[test]
+    fn empty_containers_count_as_values() {
+        assert_eq!(compare(json!({}), json!({}), &defaults()), (1, 1, vec!()));
+        assert_eq!(compare(json!([]), json!({}), &defaults()).2, vec!(""));
+        // A container against a scalar differs in everything it holds
+        assert_eq!(compare(json!([1, 2, 3]), json!(1), &defaults()),
+                   (0, 3, vec!(String::from(""))));
+    }
+
+    #[test]
+    fn long_values_are_shortened_in_reports() {
+        let long = Value::String("x".repeat(100));
+        let rendered = render(&long);
+        assert_eq!(rendered.chars().count(), MAX_VALUE_WIDTH);
+        assert!(rendered.ends_with("..."));
+        assert_eq!(render(&json!([1, 2])), "[1,2]");
+    }
+}
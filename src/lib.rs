@@ -1,21 +1,103 @@
-/// Library for rsdiff
+//! Library for rsdiff
 
 // ----------
 // Public API
 // ----------
 
 use std::{
+    cell::{Cell, RefCell},
     fs::{self, File},
-    io::{self, BufRead, BufReader, Cursor, prelude::*},
-    convert::TryInto,
+    io::{self, BufRead, BufReader, SeekFrom, prelude::*},
+    ops::Range,
     path::Path,
+    sync::atomic::{AtomicBool, Ordering},
     time,
 };
 
-use nifti::{NiftiObject, ReaderOptions};
-use byteorder::{LittleEndian, ReadBytesExt};
-use flate2::read::GzDecoder;
+use nifti::{Endianness, NiftiHeader};
+use byteorder::{ByteOrder, LittleEndian};
 use colored::*;
+use rayon::prelude::*;
+
+pub mod options;
+pub mod affinity;
+pub mod archive;
+pub mod badge;
+#[cfg(feature = "capi")]
+mod capi;
+pub mod checksums;
+pub mod code;
+pub mod config;
+pub mod database;
+pub mod datalad;
+pub mod diffimage;
+pub mod diffset;
+pub mod drift;
+pub mod env;
+pub mod error;
+pub mod expect;
+pub mod explain;
+pub mod events;
+pub mod fds;
+pub mod fingerprint;
+pub mod gifti;
+pub mod gz;
+pub mod hash;
+pub mod heatmap;
+pub mod header;
+pub mod hint;
+pub mod hooks;
+pub mod image;
+pub mod incremental;
+pub mod interrupt;
+pub mod json;
+pub mod manifest;
+pub mod metrics;
+pub mod mmap;
+pub mod notebook;
+pub mod notify;
+pub mod precheck;
+pub mod preset;
+pub mod progress;
+pub mod provenance;
+#[cfg(feature = "python")]
+mod python;
+pub mod registry;
+pub mod schema;
+pub mod report;
+pub mod sequence;
+pub mod sign;
+pub mod similarity;
+pub mod stream;
+pub mod symlink;
+pub mod table;
+pub mod tarstream;
+pub mod text;
+pub mod triage;
+pub mod voxels;
+pub mod watch;
+pub mod workspace;
+
+pub use error::{Result, RsdiffError};
+pub use options::{
+    DiffOptions, FloatComparison, Metric, MixedCompression, Shard,
+    SymlinkPolicy, Unit,
+};
+pub use registry::{register, Differ};
+pub use rsdiff_core::diff_buffer;
+use hash::{HashingReader, hash_bytes, hash_bytes_with, hash_file};
+use heatmap::MismatchBuckets;
+use mmap::Mmap;
+use progress::{FileProgress, ProgressEvent};
+
+/// Hex digits of a node ID; 64 bits keeps collisions out of reach for any
+/// tree rsdiff could compare.
+const ID_LENGTH: usize = 16;
+/// How many diverging volumes of a 4D image are listed in its report.
+const MAX_REPORTED_VOLUMES: usize = 20;
+/// The path given for the missing side of an entry only one directory
+/// holds, when such entries are compared against an empty file.
+pub const NULL_PATH: &str = "/dev/null";
 
 /// Diff
 /// Generalized object for performing abstract diffs.
@@ -33,8 +115,22 @@ pub struct Diff {
     pub right_only: Vec<String>,
     /// Objects which are common to the left and right objects.
     pub common: Vec<String>,
-    /// Generalized similarity index.
+    /// Paths of entries left out of the comparison: excluded, skipped as
+    /// links, or dataset bookkeeping.
+    pub skipped: Vec<String>,
+    /// Entries common to both objects that couldn't be compared because
+    /// one side may not be read.
+    pub inaccessible: Vec<String>,
+    /// Generalized similarity index, the fraction of matching units. With
+    /// no units to compare, as between empty files, empty directories, or
+    /// images without voxels, nothing differs and it is 1.
     pub similarity: f32,
+    /// The unit the similarity index is counted in, if one was computed.
+    pub unit: Option<Unit>,
+    /// How many units match.
+    pub matched: usize,
+    /// How many units were compared; the similarity denominator.
+    pub total: usize,
     /// Any additional information.
     pub additional_info: String,
     /// Any sub-diffs that may want to be represented, for example, if this
@@ -43,6 +139,40 @@ pub struct Diff {
     pub sub_diffs: Vec<Box<Diff>>,
     /// The string report that may be printed.
     pub report: String,
+    /// Observations that don't affect whether the objects match, but are
+    /// worth reporting.
+    pub findings: Vec<String>,
+    /// Hash of the left object's contents, if hashing was requested.
+    pub left_hash: Option<String>,
+    /// Hash of the right object's contents, if hashing was requested.
+    pub right_hash: Option<String>,
+    /// Whether the left file has the hash recorded for it in a checksum
+    /// database, if it has one there.
+    pub left_verified: Option<bool>,
+    /// Whether the right file has the hash recorded for it in a checksum
+    /// database, if it has one there.
+    pub right_verified: Option<bool>,
+    /// Wall-clock seconds spent comparing the objects.
+    pub seconds: f64,
+    /// Whether the comparison was interrupted before every object was
+    /// compared. Interrupted diffs never match.
+    pub interrupted: bool,
+    /// The largest relative difference between numbers in the objects, if
+    /// drift was measured and they hold numbers.
+    pub max_relative_difference: Option<f64>,
+    /// The part of the comparison whose entries were compared, if the
+    /// comparison was split across tasks.
+    pub shard: Option<Shard>,
+    /// How far apart the numbers in the objects are, if they hold numbers
+    /// that were compared one by one.
+    pub numeric_differences: Option<drift::NumericDifferences>,
+    /// The fraction of mismatching bytes in each of equal stretches of the
+    /// objects, start to end, if asked for and they were compared byte by
+    /// byte.
+    pub mismatch_density: Option<Vec<f64>>,
+    /// What alone keeps the objects from matching, or which change of
+    /// options would make them match, if rsdiff could tell while comparing.
+    pub hint: Option<String>,
 }
 
 impl Diff {
@@ -55,550 +185,2251 @@ impl Diff {
             left_only: vec!(),
             right_only: vec!(),
             common: vec!(),
+            skipped: vec!(),
+            inaccessible: vec!(),
             similarity: -1.0,
+            unit: None,
+            matched: 0,
+            total: 0,
             additional_info: String::from(""),
             sub_diffs: vec!(),
             report: String::from(""),
+            findings: vec!(),
+            left_hash: None,
+            right_hash: None,
+            left_verified: None,
+            right_verified: None,
+            seconds: 0.0,
+            interrupted: false,
+            max_relative_difference: None,
+            shard: None,
+            numeric_differences: None,
+            mismatch_density: None,
+            hint: None,
         }
     }
+
+    /// Record the match counts and derive the similarity index from them.
+    /// A total of zero gives a similarity of 1 rather than dividing by
+    /// zero; the total still shows that nothing was counted.
+    pub fn set_counts(&mut self, matched: usize, total: usize, unit: Unit) {
+        self.unit = Some(unit);
+        self.matched = matched;
+        self.total = total;
+        self.similarity = if total == 0 {
+            1.0
+        }
+        else {
+            matched as f32 / total as f32
+        };
+    }
+
+    /// Flatten this diff and all of its sub-diffs into a list, parents
+    /// before children.
+    pub fn flatten(&self) -> Vec<&Diff> {
+        let mut nodes = vec!(self);
+        for subdiff in self.sub_diffs.iter() {
+            nodes.extend(subdiff.flatten());
+        }
+        nodes
+    }
+
+    /// A stable identifier for this diff, derived from the objects it
+    /// compares. The same comparison gets the same IDs in every run, in
+    /// whatever order its entries were compared.
+    pub fn id(&self) -> String {
+        let key = format!("{}\0{}", self.left, self.right);
+        hash_bytes(key.as_bytes())[..ID_LENGTH].to_string()
+    }
+
+    /// Pair this diff and all of its sub-diffs with their parents, parents
+    /// before children. The root has no parent.
+    pub fn flatten_with_parents(&self) -> Vec<(Option<&Diff>, &Diff)> {
+        let mut nodes = vec!((None, self));
+        for subdiff in self.sub_diffs.iter() {
+            let mut below = subdiff.flatten_with_parents();
+            below[0].0 = Some(self);
+            nodes.extend(below);
+        }
+        nodes
+    }
+
+    /// Represent this diff and all of its sub-diffs as a JSON tree, for
+    /// consumption by other tools. Similarities that couldn't be computed
+    /// are null. Each node carries its ID and its parent's, so the tree can
+    /// be stored as rows.
+    pub fn to_json(&self) -> serde_json::Value {
+        self.to_json_under(None)
+    }
+
+    /// As `to_json`, for a diff under the parent with the given ID.
+    fn to_json_under(&self, parent: Option<&str>) -> serde_json::Value {
+        let id = self.id();
+        let sub_diffs: Vec<serde_json::Value> = self.sub_diffs.iter()
+            .map(|s| s.to_json_under(Some(&id)))
+            .collect();
+        serde_json::json!({
+            "id": id,
+            "parent": parent,
+            "left": self.left,
+            "right": self.right,
+            "matches": self.matches,
+            "similarity": self.unit.map(|_| self.similarity),
+            "unit": self.unit,
+            "matched": self.matched,
+            "total": self.total,
+            "additional_info": self.additional_info,
+            "report": self.report,
+            "findings": self.findings,
+            "left_only": self.left_only,
+            "right_only": self.right_only,
+            "common": self.common,
+            "skipped": self.skipped,
+            "inaccessible": self.inaccessible,
+            "left_hash": self.left_hash,
+            "right_hash": self.right_hash,
+            "left_verified": self.left_verified,
+            "right_verified": self.right_verified,
+            "seconds": self.seconds,
+            "interrupted": self.interrupted,
+            "max_relative_difference": self.max_relative_difference,
+            "shard": self.shard,
+            "numeric_differences": self.numeric_differences,
+            "mismatch_density": self.mismatch_density,
+            "hint": self.hint,
+            "sub_diffs": sub_diffs,
+        })
+    }
+
+    /// Collect (path, hash) pairs for every hashed object in this diff and
+    /// its sub-diffs, in the order they were compared.
+    pub fn hashes(&self) -> Vec<(String, String)> {
+        let mut hashes = vec!();
+        if let Some(h) = &self.left_hash {
+            hashes.push((self.left.clone(), h.clone()));
+        }
+        if let Some(h) = &self.right_hash {
+            hashes.push((self.right.clone(), h.clone()));
+        }
+        for subdiff in self.sub_diffs.iter() {
+            hashes.extend(subdiff.hashes());
+        }
+        hashes
+    }
+}
+
+/// Diffs serialize as their JSON trees, as `to_json` gives them, so what is
+/// saved reads back with `rsdiff merge` and the like. They deserialize from
+/// any self-describing format holding such a tree.
+#[cfg(feature = "serde")]
+impl serde::Serialize for Diff {
+    fn serialize<S: serde::Serializer>(&self, serializer: S)
+        -> std::result::Result<S::Ok, S::Error> {
+        serde::Serialize::serialize(&self.to_json(), serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Diff {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D)
+        -> std::result::Result<Diff, D::Error> {
+        <diffset::DiffRecord as serde::Deserialize>::deserialize(deserializer)
+            .map(diffset::DiffRecord::into_diff)
+    }
 }
 
 /// Calculate an abstract diff between two files.
-pub fn differ(left: &str, right: &str) -> Diff {
-    let left_meta = fs::metadata(left).expect("Left doesn't exist");
-    let _right_meta = fs::metadata(right).expect("Right doesn't exist");
+pub fn differ(left: &str, right: &str) -> Result<Diff> {
+    differ_with_options(left, right, &DiffOptions::default())
+}
+
+/// Calculate an abstract diff between two files with custom options.
+pub fn differ_with_options(left: &str, right: &str, opts: &DiffOptions)
+    -> Result<Diff> {
+    let started = time::Instant::now();
+    let mut d = dispatch(left, right, opts)?;
+    d.seconds = started.elapsed().as_secs_f64();
+    Ok(d)
+}
+
+/// Route
+/// How a pair of objects is compared.
+pub enum Route<'a> {
+    /// As symbolic links, by their targets.
+    Links,
+    /// Byte-wise, since at least one is an empty file.
+    EmptyFile,
+    /// After converting both with a preprocessing hook.
+    Hook(&'a hooks::Hook),
+    /// As text, since every file is to be compared as text.
+    Text,
+    /// By the first registered differ that handles them.
+    Differ(std::sync::Arc<dyn registry::Differ>),
+}
+
+impl Route<'_> {
+    /// Describe how the objects are compared.
+    pub fn describe(&self) -> String {
+        match self {
+            Route::Links => String::from("as symbolic links, by their \
+                                          targets"),
+            Route::EmptyFile => String::from("byte by byte, since a file is \
+                                              empty"),
+            Route::Hook(hook) => format!("after converting files matching \
+                                          {}", hook.pattern),
+            Route::Text => String::from("as text, line by line, as every \
+                                         file is"),
+            Route::Differ(differ) => format!("as {}", differ.name()),
+        }
+    }
+}
+
+/// Work out how two objects would be compared.
+pub fn route<'a>(left: &str, right: &str, opts: &'a DiffOptions)
+    -> Result<Route<'a>> {
+    if symlink::compares_as_links(left, right, opts)? {
+        return Ok(Route::Links);
+    }
+    let left_meta = fs::metadata(left)
+        .map_err(|e| RsdiffError::io(left, e))?;
+    let right_meta = fs::metadata(right)
+        .map_err(|e| RsdiffError::io(right, e))?;
 
-    if left_meta.is_dir() {
-        return diff_directory(left, right);
+    // An empty file can't be parsed as any format, so empty files are
+    // compared byte-wise: two match, and one differs from any other file
+    if left_meta.is_file() && right_meta.is_file()
+        && (left_meta.len() == 0 || right_meta.len() == 0) {
+        return Ok(Route::EmptyFile);
+    }
+
+    // Convert both sides first if a hook asks for it
+    if !left_meta.is_dir() {
+        if let Some(hook) = hooks::find_hook(&opts.hooks, left) {
+            return Ok(Route::Hook(hook));
+        }
+        if opts.force_text {
+            return Ok(Route::Text);
+        }
+    }
+    Ok(Route::Differ(registry::find(left, right)))
+}
+
+/// Pick the differ for two objects and run it.
+fn dispatch(left: &str, right: &str, opts: &DiffOptions) -> Result<Diff> {
+    let route = route(left, right, opts)?;
+    let prechecked = if opts.precheck && !matches!(route, Route::Links) {
+        precheck::identical(left, right, opts)?
     }
     else {
-        // Check for specializations
-        if left.ends_with(".nii.gz") || left.ends_with(".nii") {
-            return diff_nii(left, right);
+        None
+    };
+    let mut d = match prechecked {
+        Some(d) => d,
+        None => match &route {
+            // Either side may stop being a link after it was routed
+            Route::Links => symlink::diff_links(left, right, opts)?
+                .ok_or_else(|| RsdiffError::Corrupt(format!(
+                    "{} or {} stopped being a symbolic link while being \
+                     compared", left, right
+                ))),
+            Route::EmptyFile => diff_bytes_with_options(left, right, opts),
+            Route::Hook(hook) => diff_converted(left, right, hook, opts),
+            Route::Text => text::diff_text_with_options(left, right, opts),
+            Route::Differ(differ) => differ.diff(left, right, opts),
+        }?,
+    };
+    let compares_files = match &route {
+        Route::Links => false,
+        Route::Differ(differ) => !differ.walks_directories(),
+        _ => true,
+    };
+    if compares_files {
+        // Not every differ streams the files it compares, but every file
+        // compared is hashed, e.g. for --emit-hashes
+        hash_both(&mut d, opts)?;
+        if let Some(checksums) = &opts.checksums {
+            checksums.verify(&mut d, opts)?;
         }
-        return diff_bytes(left, right);
     }
+    Ok(d)
 }
 
+/// Diff two files after converting both with a preprocessing hook. The
+/// result is reported against the original paths.
+fn diff_converted(left: &str, right: &str, hook: &hooks::Hook,
+                  opts: &DiffOptions) -> Result<Diff> {
+    let converted_left = hook.convert(left, &opts.cache_dir)
+        .map_err(|e| RsdiffError::Conversion {
+            path: String::from(left), source: e
+        })?;
+    let converted_right = hook.convert(right, &opts.cache_dir)
+        .map_err(|e| RsdiffError::Conversion {
+            path: String::from(right), source: e
+        })?;
+    let mut d = diff_stand_ins(left, right, &converted_left.to_string_lossy(),
+                               &converted_right.to_string_lossy(), opts)?;
+    d.findings.push(format!("compared after converting files matching {}",
+                            hook.pattern));
+    Ok(d)
+}
+
+/// Diff two files by comparing stand-ins for them, such as converted
+/// copies, and report the result against the original paths.
+fn diff_stand_ins(left: &str, right: &str, stand_in_left: &str,
+                  stand_in_right: &str, opts: &DiffOptions) -> Result<Diff> {
+    // Stand-ins are compared as they are; no hooks apply to them, and it
+    // is the original files that are checked against recorded checksums
+    let mut inner_opts = opts.clone();
+    inner_opts.hooks = vec!();
+    inner_opts.checksums = None;
+    let mut d = differ_with_options(stand_in_left, stand_in_right,
+                                    &inner_opts)?;
+    d.report = d.report.replace(stand_in_left, left)
+        .replace(stand_in_right, right);
+    d.left = String::from(left);
+    d.right = String::from(right);
+    // Any hashes are of the stand-ins, not of the original files
+    d.left_hash = None;
+    d.right_hash = None;
+    Ok(d)
+}
+
+/// Perform a diff on the contents of two files, either or both gzipped,
+/// so that differences in compression settings or the timestamp in the
+/// gzip header don't count. The contents are compared by the differ for
+/// their own type, e.g. `.tsv.gz` as tables.
+pub fn diff_gzip_contents(left: &str, right: &str) -> Result<Diff> {
+    diff_gzip_contents_with_options(left, right, &DiffOptions::default())
+}
+
+/// Perform a diff on the contents of two files, either or both gzipped,
+/// with custom options. If either gzip stream is damaged, the files are
+/// compared byte by byte instead.
+pub fn diff_gzip_contents_with_options(left: &str, right: &str,
+                                       opts: &DiffOptions) -> Result<Diff> {
+    if opts.compressed_bytes {
+        return diff_bytes_with_options(left, right, opts);
+    }
+    let hook = hooks::gunzip_hook();
+    let decompress = |path: &str, side: &str| {
+        if !path.ends_with(".gz") {
+            return Ok(String::from(path));
+        }
+        hook.convert(path, &opts.cache_dir)
+            .map(|p| p.to_string_lossy().into_owned())
+            .map_err(|e| gz::describe_corruption(side, &e))
+    };
+    match (decompress(left, "left"), decompress(right, "right")) {
+        (Ok(l), Ok(r)) => {
+            let mut d = diff_stand_ins(left, right, &l, &r, opts)?;
+            d.findings.push(String::from("compared decompressed contents"));
+            Ok(d)
+        }
+        (Err(corruption), _) | (_, Err(corruption)) => {
+            let mut d = diff_bytes_with_options(left, right, opts)?;
+            d.findings.push(format!("{}; compared compressed bytes",
+                                    corruption));
+            Ok(d)
+        }
+    }
+}
 
-// TODO: clean this mess up
 /// Calculate an abstract diff between two directories
-pub fn diff_directory(left: &str, right: &str) -> Diff {
-    // Obtain metadata
-    let left_meta = fs::metadata(left).expect("Left dir didn't exist");
-    let right_meta = fs::metadata(right).expect("Right dir didn't exist");
+pub fn diff_directory(left: &str, right: &str) -> Result<Diff> {
+    diff_directory_with_options(left, right, &DiffOptions::default())
+}
 
-    // Check that both left and right are files
-    if !(left_meta.is_dir()) {
-        if !(right_meta.is_dir()) {
-            panic!("Left and right are not dirs!")
+/// Calculate an abstract diff between two directories with custom options.
+pub fn diff_directory_with_options(left: &str, right: &str,
+                                   opts: &DiffOptions) -> Result<Diff> {
+    // Every generation of the walk shares one pool
+    if opts.jobs > 1 {
+        in_pool(opts, || walk_directories(left, right, opts))
+    }
+    else {
+        walk_directories(left, right, opts)
+    }
+}
+
+/// Walk two trees from a worklist rather than by recursion, so that no
+/// depth of nesting can overflow the stack. The entries of a generation of
+/// directories, those as deep as each other, are compared together, in
+/// parallel if asked to, listing their subdirectories as the next
+/// generation. Every directory is then finished after those under it.
+fn walk_directories(left: &str, right: &str, opts: &DiffOptions)
+    -> Result<Diff> {
+    let mut levels = vec!(Level::new(list_directory(left, right, opts)?,
+                                     opts.clone(), None));
+    let mut generation = 0..1;
+    while !generation.is_empty() {
+        // A difference found under a directory failing fast stops what is
+        // left of it
+        for i in generation.clone() {
+            let skipped = interrupt::requested()
+                || (opts.fail_fast && parents(&levels, i)
+                    .any(|p| levels[p].differs.load(Ordering::Relaxed)));
+            levels[i].skipped = skipped;
+        }
+        let work: Vec<(usize, usize)> = generation.clone()
+            .filter(|&i| !levels[i].skipped)
+            .flat_map(|i| (0..levels[i].d.common.len()).map(move |j| (i, j)))
+            .collect();
+        // Results are collected in order either way, so parallel and serial
+        // runs produce identical diffs
+        let results: Vec<Option<Entry>> = if opts.jobs > 1 {
+            in_pool(opts, || {
+                work.par_iter().map(|&(i, j)| levels[i].diff_entry(j))
+                    .collect()
+            })?
         }
         else {
-            panic!("Left is not a dir!")
+            work.iter().map(|&(i, j)| levels[i].diff_entry(j))
+                .collect::<Result<_>>()?
+        };
+        let next = levels.len();
+        for ((i, j), result) in work.into_iter().zip(results) {
+            match result {
+                Some(Entry::Listed(d)) => {
+                    let opts = levels[i].opts.descend(&levels[i].d.common[j]);
+                    levels.push(Level::new(d, opts, Some((i, j))));
+                }
+                Some(Entry::Compared(compared)) => {
+                    levels[i].entries[j] = Some(compared);
+                }
+                None => (),
+            }
+        }
+        for i in generation {
+            if levels[i].differs.load(Ordering::Relaxed) {
+                for p in parents(&levels, i) {
+                    levels[p].differs.store(true, Ordering::Relaxed);
+                }
+            }
+        }
+        generation = next..levels.len();
+    }
+
+    // Levels come after their parents, so finishing them from the last
+    // finishes every directory after those under it
+    while let Some(level) = levels.pop() {
+        let (parent, skipped) = (level.parent, level.skipped);
+        let d = level.finish()?;
+        match parent {
+            Some((i, j)) if !skipped => {
+                if progress::enabled() {
+                    progress::emit(ProgressEvent::Compared {
+                        left: d.left.clone(), matches: d.matches,
+                    });
+                }
+                levels[i].entries[j] = Some(Ok(d));
+            }
+            Some(_) => (),
+            None => return Ok(d),
+        }
+    }
+    unreachable!("The walk lost the top of the tree!")
+}
+
+/// The levels above a level of a walk, nearest first.
+fn parents(levels: &[Level], i: usize) -> impl Iterator<Item = usize> + '_ {
+    std::iter::successors(levels[i].parent.map(|(p, _)| p),
+                          move |&p| levels[p].parent.map(|(p, _)| p))
+}
+
+/// An entry of a directory compared, or, if it may not be read, why not,
+/// unless entries that may not be read are to fail the comparison.
+type Compared = std::result::Result<Diff, String>;
+
+/// Entry
+/// What became of an entry of a directory being walked.
+enum Entry {
+    /// It was compared, or couldn't be read.
+    Compared(Compared),
+    /// It is a directory, listed for the walk to go on into.
+    Listed(Diff),
+}
+
+/// Level
+/// A directory of a walk: the diff it is listed into, the options its
+/// entries are compared with, and what became of each common entry. The
+/// position of its entry in the directory above it, if any, is kept for
+/// finishing it.
+struct Level {
+    d: Diff,
+    opts: DiffOptions,
+    parent: Option<(usize, usize)>,
+    entries: Vec<Option<Compared>>,
+    differs: AtomicBool,
+    skipped: bool,
+}
+
+impl Level {
+    fn new(d: Diff, opts: DiffOptions, parent: Option<(usize, usize)>)
+        -> Level {
+        let differs = !d.left_only.is_empty() || !d.right_only.is_empty();
+        Level {
+            entries: d.common.iter().map(|_| None).collect(),
+            differs: AtomicBool::new(differs),
+            skipped: false,
+            d, opts, parent,
+        }
+    }
+
+    /// Compare the `j`th common entry, or list it if it is a directory to
+    /// walk into. Returns None for entries left for failing fast or being
+    /// interrupted.
+    fn diff_entry(&self, j: usize) -> Result<Option<Entry>> {
+        let opts = &self.opts;
+        // Keep what has been compared so far if the user asks to stop
+        if interrupt::requested() {
+            return Ok(None);
+        }
+        if opts.fail_fast && self.differs.load(Ordering::Relaxed) {
+            return Ok(None);
+        }
+        let f = &self.d.common[j];
+        let left_entry = Path::new(&self.d.left).join(f);
+        let right_entry = Path::new(&self.d.right).join(f);
+        let (left_entry, right_entry) = (left_entry.to_string_lossy(),
+                                         right_entry.to_string_lossy());
+        let entry_opts = opts.descend(f);
+        let left_dir = symlink::is_descended_dir(Path::new(&*left_entry),
+                                                 opts);
+        let right_dir = symlink::is_descended_dir(Path::new(&*right_entry),
+                                                  opts);
+        let subdiff = if opts.max_depth == Some(0) && left_dir && right_dir {
+            let mut subdiff = Diff::new(&left_entry, &right_entry);
+            subdiff.matches = true;
+            subdiff.findings.push(String::from(
+                "not descended into; below the maximum depth"
+            ));
+            subdiff
+        }
+        else {
+            // Directories hold nothing open while their entries are
+            // compared, so only other entries wait for descriptors
+            let walked = matches!(
+                route(&left_entry, &right_entry, &entry_opts),
+                Ok(Route::Differ(differ)) if differ.walks_directories()
+            );
+            let _fds = (!(walked || (left_dir && right_dir)))
+                .then(|| fds::acquire(descriptors_needed(
+                    Path::new(&*left_entry), opts
+                )));
+            let compared = if walked {
+                let started = time::Instant::now();
+                list_directory(&left_entry, &right_entry, &entry_opts)
+                    .map(|mut listed| {
+                        listed.seconds = started.elapsed().as_secs_f64();
+                        listed
+                    })
+            }
+            else {
+                differ_with_options(&left_entry, &right_entry, &entry_opts)
+            };
+            match compared {
+                Ok(listed) if walked => {
+                    return Ok(Some(Entry::Listed(listed)));
+                }
+                Ok(subdiff) => subdiff,
+                Err(e) if e.is_permission_denied()
+                    && !opts.fail_on_inaccessible => {
+                    return Ok(Some(Entry::Compared(Err(e.to_string()))));
+                }
+                Err(e) => return Err(e),
+            }
+        };
+        if !subdiff.matches {
+            self.differs.store(true, Ordering::Relaxed);
+        }
+        if progress::enabled() {
+            progress::emit(ProgressEvent::Compared {
+                left: subdiff.left.clone(), matches: subdiff.matches,
+            });
+        }
+        Ok(Some(Entry::Compared(Ok(subdiff))))
+    }
+
+    /// Gather the diffs of the directory's entries, compare what only one
+    /// side holds if asked to, and report on it.
+    fn finish(self) -> Result<Diff> {
+        let Level { mut d, opts, entries, .. } = self;
+        let opts = &opts;
+        let mut diffs: Vec<Box<Diff>> = Vec::with_capacity(d.common.len());
+        for (name, entry) in d.common.iter().zip(entries) {
+            match entry {
+                Some(Ok(subdiff)) => {
+                    d.interrupted |= subdiff.interrupted;
+                    d.seconds += subdiff.seconds;
+                    diffs.push(Box::new(subdiff));
+                }
+                Some(Err(why)) => {
+                    d.inaccessible.push(name.clone());
+                    d.findings.push(format!("not compared: {}", why));
+                }
+                // Entries skipped to fail fast leave the diff incomplete,
+                // but not interrupted
+                None => d.interrupted |= interrupt::requested(),
+            }
+        }
+        d.sub_diffs = diffs;
+        let inaccessible = &d.inaccessible;
+        d.common.retain(|x| !inaccessible.contains(x));
+
+        // Entries only one side holds are compared against nothing, so that
+        // what they hold counts against the match
+        if opts.new_file && !d.interrupted {
+            let left_only = std::mem::take(&mut d.left_only);
+            let right_only = std::mem::take(&mut d.right_only);
+            for (x, on_left) in left_only.into_iter().map(|x| (x, true))
+                .chain(right_only.into_iter().map(|x| (x, false))) {
+                let subdiff = if on_left {
+                    diff_against_nothing(
+                        &Path::new(&d.left).join(&x).to_string_lossy(), NULL_PATH,
+                        opts
+                    )?
+                }
+                else {
+                    diff_against_nothing(
+                        NULL_PATH, &Path::new(&d.right).join(&x).to_string_lossy(),
+                        opts
+                    )?
+                };
+                d.common.push(x);
+                d.sub_diffs.push(Box::new(subdiff));
+            }
         }
+        summarize_entries(&mut d, opts);
+        Ok(d)
+    }
+}
+
+/// List two directories and sort their entries into common and one-sided
+/// ones, leaving out any that are excluded or not in this shard.
+fn list_directory(left: &str, right: &str, opts: &DiffOptions)
+    -> Result<Diff> {
+    // Obtain metadata
+    let left_meta = fs::metadata(left)
+        .map_err(|e| RsdiffError::io(left, e))?;
+    let right_meta = fs::metadata(right)
+        .map_err(|e| RsdiffError::io(right, e))?;
+
+    // Check that both left and right are directories
+    if !left_meta.is_dir() {
+        return Err(RsdiffError::NotADirectory(String::from(left)));
+    }
+    if !right_meta.is_dir() {
+        return Err(RsdiffError::NotADirectory(String::from(right)));
     }
 
     // Initialize the Diff object, since one may be computed
     let mut d = Diff::new(left, right);
 
     // Get PathBuf objects for the contents of left and right
-    let left_contents = fs::read_dir(left).expect("Boo")
-        .map(|res| res.map(|e| e.path()))
-        .collect::<Result<Vec<_>, io::Error>>().expect("Boo");
-    let right_contents = fs::read_dir(right).expect("Boo")
-        .map(|res| res.map(|e| e.path()))
-        .collect::<Result<Vec<_>, io::Error>>().expect("Boo");
+    let left_contents = fs::read_dir(left)
+        .and_then(|entries| {
+            entries.map(|res| res.map(|e| e.path()))
+                .collect::<io::Result<Vec<_>>>()
+        })
+        .map_err(|e| RsdiffError::io(left, e))?;
+    let right_contents = fs::read_dir(right)
+        .and_then(|entries| {
+            entries.map(|res| res.map(|e| e.path()))
+                .collect::<io::Result<Vec<_>>>()
+        })
+        .map_err(|e| RsdiffError::io(right, e))?;
 
     // Get the object names only to compare
-    let left_onames: Vec<String> = left_contents.iter()
-        .map(|o| String::from(o.file_name().unwrap().to_str().unwrap()))
+    let mut left_onames: Vec<String> = left_contents.iter()
+        .map(|o| o.file_name().unwrap().to_string_lossy().into_owned())
         .collect();
-    let right_onames: Vec<String> = right_contents.iter()
-        .map(|o| String::from(o.file_name().unwrap().to_str().unwrap()))
+    let mut right_onames: Vec<String> = right_contents.iter()
+        .map(|o| o.file_name().unwrap().to_string_lossy().into_owned())
         .collect();
 
+    // Dataset bookkeeping differs between any two datasets, so it is left
+    // out along with anything the caller asked to exclude
+    let datasets = opts.datalad && datalad::is_dataset(left)
+        && datalad::is_dataset(right);
+    if datasets {
+        d.findings.push(datalad::describe_ids(left, right));
+    }
+    for (tree, names) in [(left, &mut left_onames), (right, &mut right_onames)] {
+        names.retain(|x| {
+            let path = Path::new(tree).join(x);
+            let skipped = (datasets
+                           && datalad::METADATA_ENTRIES.contains(&x.as_str()))
+                || opts.is_excluded(tree, x)
+                || symlink::is_skipped(&path, opts);
+            if skipped {
+                d.skipped.push(path.to_string_lossy().into_owned());
+            }
+            !skipped
+        });
+    }
+
     // This is inefficient, but we don't expect to deal with more than a
     // few hundred files per directory in this case
     // TODO: come up with a more efficient algorithm
     for x in left_onames.into_iter() {
         if right_onames.contains(&x) {
-            d.common.push(String::from(x));
+            d.common.push(x);
         }
         else {
-            d.left_only.push(String::from(x));
+            d.left_only.push(x);
         }
     }
     for x in right_onames.into_iter() {
         if !d.common.contains(&x) {
-            d.right_only.push(String::from(x));
+            d.right_only.push(x);
         }
     }
 
-    // Iterate only over common files to perform diffs
-    let mut diffs: Vec<Box<Diff>> = Vec::with_capacity(d.common.len());
-    for f in d.common.iter() {
-        diffs.push(Box::new(
-            differ(
-                Path::new(left).join(f).to_str().unwrap(),
-                Path::new(right).join(f).to_str().unwrap()
-            )
-        ));
+    // Keep only this shard's files, and every directory to be descended,
+    // so that the shards' trees can be merged back into one
+    if let Some(shard) = opts.shard {
+        let owned = |x: &String| shard.owns(&opts.relative_dir.join(x));
+        let descended = |x: &String| {
+            opts.max_depth != Some(0)
+                && symlink::is_descended_dir(&Path::new(left).join(x), opts)
+                && symlink::is_descended_dir(&Path::new(right).join(x), opts)
+        };
+        d.common.retain(|x| owned(x) || descended(x));
+        d.left_only.retain(owned);
+        d.right_only.retain(owned);
+        d.shard = Some(shard);
+    }
+    if progress::enabled() {
+        progress::emit(ProgressEvent::Directory {
+            left: String::from(left), entries: d.common.len(),
+        });
+    }
+    Ok(d)
+}
+
+/// Compare a directory entry only one side holds against an empty file
+/// standing in for the other side, named `NULL_PATH`, so that every byte
+/// the entry holds, in all its files for a directory, counts as differing.
+fn diff_against_nothing(left: &str, right: &str, opts: &DiffOptions)
+    -> Result<Diff> {
+    let path = if right == NULL_PATH { left } else { right };
+    let (files, bytes) = disk_usage(Path::new(path), opts)
+        .map_err(|e| RsdiffError::io(path, e))?;
+    let mut d = Diff::new(left, right);
+    d.set_counts(0, bytes as usize, Unit::Bytes);
+    d.additional_info = if symlink::is_descended_dir(Path::new(path), opts) {
+        format!("{} {}, {} bytes", files,
+                if files == 1 { "file" } else { "files" }, bytes)
+    }
+    else {
+        format!("{} bytes", bytes)
+    };
+    d.report = format!("{} vs {}: {}", left, right, d.additional_info);
+    Ok(d)
+}
+
+/// How many files there are under a path and how many bytes they hold,
+/// descending into directories as a comparison would, from a worklist.
+fn disk_usage(path: &Path, opts: &DiffOptions) -> io::Result<(usize, u64)> {
+    let mut usage = (0, 0);
+    let mut paths = vec!(path.to_path_buf());
+    while let Some(path) = paths.pop() {
+        if !symlink::is_descended_dir(&path, opts) {
+            usage.0 += 1;
+            usage.1 += fs::metadata(&path).map_or(0, |m| m.len());
+            continue;
+        }
+        for entry in fs::read_dir(&path)? {
+            let entry = entry?.path();
+            if !symlink::is_skipped(&entry, opts) {
+                paths.push(entry);
+            }
+        }
+    }
+    Ok(usage)
+}
+
+
+/// Perform a diff on the decompressed payloads of two BGZF files.
+pub fn diff_bgzf(left: &str, right: &str) -> Result<Diff> {
+    diff_bgzf_with_options(left, right, &DiffOptions::default())
+}
+
+/// Perform a diff on the decompressed payloads of two BGZF files with
+/// custom options. Either side may also be ordinary gzip. Differences in
+/// how the payload is split into blocks are reported as findings, separate
+/// from differences in the payload itself.
+pub fn diff_bgzf_with_options(left: &str, right: &str, opts: &DiffOptions)
+    -> Result<Diff> {
+    let mut d = Diff::new(left, right);
+    let left_file = File::open(left).map_err(|e| RsdiffError::io(left, e))?;
+    let right_file = File::open(right)
+        .map_err(|e| RsdiffError::io(right, e))?;
+    let mut left_gz = gz::BackgroundDecoder::spawn(left_file, opts);
+    let mut right_gz = gz::BackgroundDecoder::spawn(right_file, opts);
+    let mut left_buffer = vec![0u8; opts.chunk_size];
+    let mut right_buffer = vec![0u8; opts.chunk_size];
+    let mut total_matches: usize = 0;
+    let mut left_len: usize = 0;
+    let mut right_len: usize = 0;
+    let streamed = loop {
+        let nl = match read_chunk(&mut left_gz, &mut left_buffer) {
+            Ok(n) => n,
+            Err(e) => break Err(gz::describe_corruption("left", &e)),
+        };
+        let nr = match read_chunk(&mut right_gz, &mut right_buffer) {
+            Ok(n) => n,
+            Err(e) => break Err(gz::describe_corruption("right", &e)),
+        };
+        let n = nl.min(nr);
+        total_matches += diff_buffer(&left_buffer[..n], &right_buffer[..n]);
+        left_len += nl;
+        right_len += nr;
+        if nl < opts.chunk_size || nr < opts.chunk_size {
+            // Account for the tail of the longer payload
+            match io::copy(&mut left_gz, &mut io::sink()) {
+                Ok(n) => left_len += n as usize,
+                Err(e) => break Err(gz::describe_corruption("left", &e)),
+            }
+            match io::copy(&mut right_gz, &mut io::sink()) {
+                Ok(n) => right_len += n as usize,
+                Err(e) => break Err(gz::describe_corruption("right", &e)),
+            }
+            break Ok(());
+        }
+    };
+    if let Err(corruption) = streamed {
+        d.findings.push(corruption);
+        d.additional_info = String::from("could not be compared");
+        d.report = format!("{} vs {}: {}", left, right, d.additional_info);
+        return Ok(d);
+    }
+    d.left_hash = left_gz.finish().map_err(|e| RsdiffError::io(left, e))?;
+    d.right_hash = right_gz.finish()
+        .map_err(|e| RsdiffError::io(right, e))?;
+
+    let total = left_len.max(right_len);
+    d.set_counts(total_matches, total, Unit::Bytes);
+    d.matches = total_matches == total;
+    if left_len != right_len {
+        d.additional_info = format!(
+            "decompressed sizes differ: {} vs. {}", left_len, right_len
+        );
+    }
+    else if !d.matches {
+        d.additional_info = format!(
+            "{} of {} decompressed bytes match ({:.1}%)",
+            total_matches, total, d.similarity * 100.0
+        );
+    }
+
+    if opts.bgzf_blocks {
+        let left_blocks = gz::bgzf_blocks(left).unwrap_or_default();
+        let right_blocks = gz::bgzf_blocks(right).unwrap_or_default();
+        if let Some(finding) =
+            gz::describe_block_differences(&left_blocks, &right_blocks) {
+            d.findings.push(finding);
+        }
+    }
+
+    if !d.matches {
+        d.report = format!("{} vs {}: {}", left, right, d.additional_info);
+    }
+    Ok(d)
+}
+
+/// Run a computation on a thread pool of `opts.jobs` threads, pinned to
+/// the options' CPUs if any were given. Nested calls, as for the
+/// subdirectories of a directory being diffed, reuse the pool they run on.
+pub(crate) fn in_pool<T: Send>(opts: &DiffOptions,
+                               f: impl FnOnce() -> Result<T> + Send)
+    -> Result<T> {
+    if rayon::current_thread_index().is_some() {
+        return f();
     }
-    d.sub_diffs = diffs;
+    let cpus = opts.cpus.clone();
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(opts.jobs)
+        .start_handler(move |_| {
+            if let Some(cpu) = affinity::next_cpu(&cpus) {
+                // Pinning is an optimization; carry on unpinned if it fails
+                let _ = affinity::pin_current_thread(cpu);
+            }
+        })
+        .build()
+        .map_err(|e| RsdiffError::ThreadPool(e.to_string()))?;
+    pool.install(f)
+}
+
+/// Count up, decide the match for, and report on a diff of two trees whose
+/// entries have been sorted into common and one-sided ones, and whose
+/// common entries have been diffed.
+pub(crate) fn summarize_entries(d: &mut Diff, opts: &DiffOptions) {
+    aggregate_counts(d);
+    let paint = |text: String, color: Color| {
+        if opts.color {
+            text.color(color).to_string()
+        }
+        else {
+            text
+        }
+    };
 
-    // Determine if there is a match
-    if d.left_only.len() == 0 && d.right_only.len() == 0 && 
+    // Determine if there is a match; entries left uncompared to fail fast
+    // leave it undecided
+    if !d.interrupted && d.left_only.is_empty() && d.right_only.is_empty() &&
+        d.sub_diffs.len() == d.common.len() &&
         d.sub_diffs.iter().all(|a| a.matches) {
-            // Match
-            d.matches = true;
-        }    
+        // Match
+        d.matches = true;
+    }
     else {
         // No match, build report
-        let mut report = format!("{} vs. {}\n", left, right);
-        if d.left_only.len() != 0 {
-            report.push_str(&format!("{}",
-                    format!("Only in {}: {}\n", left, d.left_only.join(", ")
-            ).bright_red()));
-        }
-        if d.right_only.len() != 0 {
-            report.push_str(&format!("{}",
-                format!("Only in {}: {}\n", right, d.right_only.join(", "))
-            .bright_green()));
-        }
-        // Band cyan and magenta for easy reading
-        let mut counts = 0;
-        for subdiff in d.sub_diffs.iter() {
-            if !subdiff.matches {
-                counts += 1;
-                if counts % 2 == 0 {
-                    // Bright cyan
-                    report.push_str(&format!("{}",
-                    format!("{}\n", subdiff.report).bright_cyan()));
-                }
-                else {
-                    // Not-bright cyan
-                    report.push_str(&format!("{}",
-                    format!("{}\n", subdiff.report).bright_magenta()));
+        let compared = d.sub_diffs.len() == d.common.len();
+        if !d.interrupted && compared && d.sub_diffs.iter().all(|a| a.matches) {
+            d.hint = hint::exclusions(d.left_only.iter()
+                                      .chain(d.right_only.iter()));
+        }
+        let mut report = format!("{} vs. {}\n", d.left, d.right);
+        if d.interrupted {
+            report.push_str(&paint(format!(
+                "Interrupted: compared {} of {} common entries\n",
+                d.sub_diffs.len(), d.common.len()
+            ), Color::Yellow));
+        }
+        else if d.sub_diffs.len() < d.common.len() {
+            report.push_str(&paint(format!(
+                "Stopped at the first difference: compared {} of {} common \
+                 entries\n", d.sub_diffs.len(), d.common.len()
+            ), Color::Yellow));
+        }
+        // Lay the entries out as a tree, in name order: ± for entries that
+        // differ, ✗ for entries only one side has, and a ✓ for the rest
+        let mut nodes: Vec<(String, String)> = vec!();
+        for (names, side, color) in [
+            (&d.left_only, &d.left, Color::BrightRed),
+            (&d.right_only, &d.right, Color::BrightGreen),
+        ] {
+            for name in names.iter() {
+                nodes.push((name.clone(), format!(
+                    "{} {} (only in {})", paint(String::from("✗"), color),
+                    name, side
+                )));
+            }
+        }
+        for subdiff in d.sub_diffs.iter().filter(|s| !s.matches) {
+            let name = entry_name(d, subdiff);
+            // Entries compared against nothing are still only on one side
+            let label = if subdiff.right == NULL_PATH {
+                format!("{} {} (only in {})",
+                        paint(String::from("✗"), Color::BrightRed), name,
+                        d.left)
+            }
+            else if subdiff.left == NULL_PATH {
+                format!("{} {} (only in {})",
+                        paint(String::from("✗"), Color::BrightGreen), name,
+                        d.right)
+            }
+            else {
+                let marker = paint(String::from("±"), Color::Yellow);
+                // A directory whose only entry differs is shown as a step
+                // on the path to that entry, e.g. `sub-01/anat/T1w.nii`,
+                // rather than as a level of the tree of its own
+                if let Some(chain) = chained(subdiff, &marker) {
+                    nodes.push((name.clone(),
+                                format!("{} {}{}", marker, name, chain)));
+                    continue;
                 }
+                format!("{} {}", marker, name)
+            };
+            nodes.push((name.clone(), tree_node(&label, subdiff)));
+        }
+        nodes.sort_by(|a, b| a.0.cmp(&b.0));
+        let matching = d.sub_diffs.iter().filter(|s| s.matches).count();
+        if matching > 0 {
+            nodes.push((String::new(), format!(
+                "{} {} other {}", paint(String::from("✓"), Color::Green),
+                matching,
+                if matching == 1 { "entry matches" } else { "entries match" }
+            )));
+        }
+        for (i, (_, node)) in nodes.iter().enumerate() {
+            let (branch, indent) = if i + 1 == nodes.len() {
+                ("└── ", "    ")
+            }
+            else {
+                ("├── ", "│   ")
+            };
+            for (j, line) in node.lines().enumerate() {
+                report.push_str(if j == 0 { branch } else { indent });
+                report.push_str(line);
+                report.push('\n');
             }
         }
-        d.report = report
+        d.report = report;
     }
+}
 
+/// The name of a tree's entry, relative to the tree, with a trailing slash
+/// if the entry is a tree itself.
+fn entry_name(tree: &Diff, entry: &Diff) -> String {
+    // Entries only the right side holds may be compared against nothing
+    let (tree, path) = if entry.left == NULL_PATH {
+        (&tree.right, &entry.right)
+    }
+    else {
+        (&tree.left, &entry.left)
+    };
+    let name = path.strip_prefix(tree.as_str())
+        .map(|n| n.trim_start_matches(['/', ':']))
+        .filter(|n| !n.is_empty())
+        .unwrap_or(path);
+    let is_tree = !entry.common.is_empty() || !entry.left_only.is_empty()
+        || !entry.right_only.is_empty();
+    if is_tree {
+        format!("{}/", name.trim_end_matches('/'))
+    }
+    else {
+        String::from(name)
+    }
+}
+
+/// The node of the only entry of a tree, less its marker, if the entry
+/// differs and the tree holds nothing else, so that the tree can be shown
+/// as a step on the path to it.
+fn chained(tree: &Diff, marker: &str) -> Option<String> {
+    if tree.interrupted || !tree.left_only.is_empty()
+        || !tree.right_only.is_empty() || tree.common.len() != 1
+        || tree.sub_diffs.len() != 1 {
+        return None;
+    }
+    let mut lines = tree.report.lines().skip(1);
+    let mut node = String::from(lines.next()?
+                                .strip_prefix("└── ")?
+                                .strip_prefix(marker)?
+                                .strip_prefix(' ')?);
+    for line in lines {
+        node.push('\n');
+        node.push_str(line.strip_prefix("    ")?);
+    }
+    Some(node)
+}
 
-    return d;
+/// A tree node for an entry that differs: `label`, then what the entry's
+/// report says about it, without the paths it starts with. Nested trees
+/// keep the lines of their own reports below their labels.
+fn tree_node(label: &str, entry: &Diff) -> String {
+    let report = if entry.report.is_empty() {
+        &entry.additional_info
+    }
+    else {
+        &entry.report
+    };
+    let mut lines = report.lines();
+    let first = lines.next().unwrap_or_default();
+    let about = [" vs. ", " vs "].iter()
+        .find_map(|vs| {
+            first.strip_prefix(&format!("{}{}{}", entry.left, vs, entry.right))
+        })
+        .map(|rest| rest.trim_start_matches(':').trim_start())
+        .unwrap_or(first);
+    let mut node = String::from(label);
+    if !about.is_empty() {
+        node.push_str(": ");
+        node.push_str(about);
+    }
+    for line in lines {
+        node.push('\n');
+        node.push_str(line);
+    }
+    node
 }
 
+/// Aggregate the counts of a directory's sub-diffs. Counts are only summed
+/// when every entry was compared in the same unit; otherwise the directory
+/// falls back to counting matching entries.
+fn aggregate_counts(d: &mut Diff) {
+    let unit = d.sub_diffs.first().and_then(|s| s.unit);
+    let homogeneous = unit.is_some()
+        && d.left_only.is_empty() && d.right_only.is_empty()
+        && d.sub_diffs.iter().all(|s| s.unit == unit);
+    if homogeneous {
+        let matched = d.sub_diffs.iter().map(|s| s.matched).sum();
+        let total = d.sub_diffs.iter().map(|s| s.total).sum();
+        d.set_counts(matched, total, unit.unwrap());
+    }
+    else {
+        let matched = d.sub_diffs.iter().filter(|s| s.matches).count();
+        let total = d.sub_diffs.len() + d.left_only.len()
+            + d.right_only.len();
+        d.set_counts(matched, total, Unit::Entries);
+    }
+}
 
 /// Perform a diff on two files of unknown or binary encoding.
-pub fn diff_bytes(left: &str, right: &str) -> Diff {
+pub fn diff_bytes(left: &str, right: &str) -> Result<Diff> {
+    diff_bytes_with_options(left, right, &DiffOptions::default())
+}
+
+/// Perform a diff on two files of unknown or binary encoding with custom
+/// options.
+pub fn diff_bytes_with_options(left: &str, right: &str, opts: &DiffOptions)
+    -> Result<Diff> {
     // Obtain metadata
-    let left_meta = fs::metadata(left).expect("Left file didn't exist");
-    let right_meta = fs::metadata(right).expect("Right file didn't exist");
+    let left_meta = fs::metadata(left)
+        .map_err(|e| RsdiffError::io(left, e))?;
+    let right_meta = fs::metadata(right)
+        .map_err(|e| RsdiffError::io(right, e))?;
 
     // Check that both left and right are files
-    if !(left_meta.is_file()) {
-        if !(right_meta.is_file()) {
-            panic!("{} and {} are not files!", left, right)
-        }
-        else {
-            panic!("Left is not a file!")
-        }
+    if !left_meta.is_file() {
+        return Err(RsdiffError::NotAFile(String::from(left)));
+    }
+    if !right_meta.is_file() {
+        return Err(RsdiffError::NotAFile(String::from(right)));
     }
 
     // Initialize the Diff object, since one may be computed
     let mut d = Diff::new(left, right);
 
-    // Check to see if file sizes match; if so, figure out the total number
-    // of matching bytes.
-    if left_meta.len() == right_meta.len() {
-        // Iterate over 256kB chunks to compare bytes. Tested with a MacOS
-        // system using an SSD, picked the smallest chunk size that seemed
-        // to not reduce performance.
-        const KILOBYTE: usize = 1024;
-        const CHUNK_SIZE: usize = 256 * KILOBYTE;
+    // Compare only the requested byte ranges if there are any; otherwise
+    // check to see if file sizes match and, if so, figure out the total
+    // number of matching bytes.
+    if !opts.byte_ranges.is_empty() || !opts.ignore_ranges.is_empty() {
+        let ranges = if opts.byte_ranges.is_empty() {
+            let whole_file = Range { start: 0, end: u64::MAX };
+            subtract_ranges(&[whole_file], &opts.ignore_ranges)
+        }
+        else {
+            subtract_ranges(&opts.byte_ranges, &opts.ignore_ranges)
+        };
+        let (total_matches, total) = diff_byte_ranges(
+            left, right, left_meta.len(), right_meta.len(), &ranges,
+            opts.chunk_size
+        )?;
+        d.set_counts(total_matches, total, Unit::Bytes);
+        d.matches = total_matches == total;
+        if !d.matches {
+            d.additional_info = format!(
+                "{} of {} bytes in {} range(s) match ({:.1}%)",
+                total_matches,
+                total,
+                ranges.len(),
+                d.similarity * 100.0
+            );
+        }
+        if opts.hash {
+            hash_both(&mut d, opts)?;
+        }
+    }
+    else if left_meta.len() == right_meta.len() {
+        // Iterate over chunks to compare bytes. The default of 256kB was
+        // tested with a MacOS system using an SSD, picked the smallest chunk
+        // size that seemed to not reduce performance.
         // Track the length of the files with a convenient alias
-        let fsize: usize = left_meta.len().try_into().unwrap();
-        // File pointers and buffer readers
-        let left_file = File::open(left).expect("Uh-oh!");
-        let right_file = File::open(right).expect("Uh-oh!");
-        let mut total_matches: usize = 0;
-        let mut left_reader = BufReader::with_capacity(
-            CHUNK_SIZE, left_file
-        );
-        let mut right_reader = BufReader::with_capacity(
-            CHUNK_SIZE, right_file
-        );
-        // Track total matches
-        loop {
-            // Ask to read, get a length for how many bytes were read
-            let length = {
-                let left_buffer = left_reader.fill_buf().expect("Uh-oh 2!");
-                let right_buffer = right_reader.fill_buf().expect("Uh-h 3!");
-                if left_buffer.len() != 0 {
-                    total_matches += diff_buffer(
-                        left_buffer,
-                        right_buffer);
-                }
-                left_buffer.len()
-            };
-            left_reader.consume(length);
-            right_reader.consume(length);
-            if length == 0 {
-                break;
-            }
+        let fsize = left_meta.len() as usize;
+        let mut buckets = opts.heatmap_buckets
+            .map(|n| MismatchBuckets::new(fsize as u64, n));
+        let (total_matches, left_hash, right_hash) = if opts.mmap {
+            diff_mapped_bytes(left, right, opts, buckets.as_mut())?
         }
+        else {
+            diff_streamed_bytes(left, right, opts, buckets.as_mut())?
+        };
+        d.left_hash = left_hash;
+        d.right_hash = right_hash;
         // See if it's a complete match
         d.matches = total_matches == fsize;
         // Fill in similarity index
-        let similarity = total_matches as f32 / fsize as f32;
-        d.similarity = similarity;
+        d.set_counts(total_matches, fsize, Unit::Bytes);
+        let similarity = d.similarity;
         // If not a complete match, need to fill in additional info
         if !d.matches {
             let percentage = similarity * 100.0;
-            d.additional_info = String::from(
-                format!(
-                    "{} of {} bytes match ({2:.1}%)",
-                    total_matches,
-                    fsize,
-                    percentage
-                )
+            d.additional_info = format!(
+                "{} of {} bytes match ({2:.1}%)",
+                total_matches,
+                fsize,
+                percentage
             );
+            d.mismatch_density = buckets.map(|b| b.density());
         }
     }
     else {
-        // File size mismatch
-        d.additional_info = String::from(
-            format!(
-                "file sizes differ: {} vs. {}",
-                left_meta.len(),
-                right_meta.len()
-            )
+        // File size mismatch; nothing was streamed, so hash separately
+        if opts.hash {
+            hash_both(&mut d, opts)?;
+        }
+        d.additional_info = format!(
+            "file sizes differ: {} vs. {}",
+            left_meta.len(),
+            right_meta.len()
         );
+        // A size difference is often a truncated or extended header in
+        // front of otherwise identical content
+        let shift = detect_shift(left, right, left_meta.len(),
+                                 right_meta.len(), opts.max_shift,
+                                 opts.chunk_size)?;
+        if let Some((offset, start)) = shift {
+            d.additional_info.push_str(&format!(
+                "; content matches with {:+}-byte offset from byte {} of \
+                 the shorter file",
+                offset, start
+            ));
+        }
     }
 
     if !d.matches {
         // Generate report
-        d.report = String::from(
-            format!("{} vs {}: {}", d.left, d.right, d.additional_info)
-        );
+        d.report = format!("{} vs {}: {}", d.left, d.right,
+                           d.additional_info);
+        if let Some(density) = &d.mismatch_density {
+            d.report.push_str(&format!(
+                "\n  mismatches by position: {}", heatmap::ascii(density)
+            ));
+        }
     }
 
-    return d;
+    Ok(d)
 }
 
-pub fn diff_transmute_buffers_f32(left: &[u8], right: &[u8], tolerance: f32 ) -> usize {
-    // Verify arrays match in size
-    if !(left.len() == right.len()) {
-        panic!("Buffers supplied to rsdiff::diff_buffer must have the \
-               same length! Instead, left is size {} and right is size {}",
-               left.len(), right.len());
+/// Perform a diff on the bytes of two streams, such as sockets, in-memory
+/// buffers, or decompression streams, without writing them anywhere. The
+/// sides are labeled "left" and "right"; set the diff's `left` and `right`
+/// to name them otherwise. Streams of different lengths are read to their
+/// ends, and the bytes only the longer one has count as mismatches.
+pub fn diff_readers<R: Read, S: Read>(left: R, right: S, opts: &DiffOptions)
+    -> Result<Diff> {
+    let mut d = Diff::new("left", "right");
+    let streamed = diff_streams(left, right, ("left", "right"), opts, None,
+                                None)?;
+    let total = streamed.left_len.max(streamed.right_len) as usize;
+    d.left_hash = streamed.left_hash;
+    d.right_hash = streamed.right_hash;
+    d.set_counts(streamed.matches, total, Unit::Bytes);
+    d.matches = streamed.matches == total;
+    if !d.matches {
+        d.additional_info = format!("{} of {} bytes match ({:.1}%)",
+                                    streamed.matches, total,
+                                    d.similarity * 100.0);
+        if streamed.left_len != streamed.right_len {
+            d.additional_info.push_str(&format!(
+                "; lengths differ: {} vs. {}", streamed.left_len,
+                streamed.right_len
+            ));
+        }
+        d.report = format!("{} vs {}: {}", d.left, d.right,
+                           d.additional_info);
     }
-    // Iterate and compare bytes
-    let mut matches: usize = 0;
-    let mut left_rdr = Cursor::new(left);
-    let mut right_rdr = Cursor::new(right);
-    while let Ok(a) = left_rdr.read_f32::<LittleEndian>() {
-        if let Ok(b) = right_rdr.read_f32::<LittleEndian>() {
-            matches += ((a - b).abs() < tolerance) as usize;
+    Ok(d)
+}
+
+/// Streamed
+/// What a byte-wise comparison of two streams found: how many bytes of the
+/// length they share match, how long each was, and, if hashing was
+/// requested, their hashes.
+struct Streamed {
+    matches: usize,
+    left_len: u64,
+    right_len: u64,
+    left_hash: Option<String>,
+    right_hash: Option<String>,
+}
+
+/// Compare two streams byte by byte through buffers, reading both to their
+/// ends. Read errors are reported against the streams' labels.
+fn diff_streams<R: Read, S: Read>(left: R, right: S,
+                                  (left_label, right_label): (&str, &str),
+                                  opts: &DiffOptions,
+                                  progress: Option<&FileProgress>,
+                                  mut buckets: Option<&mut MismatchBuckets>)
+    -> Result<Streamed> {
+    let mut total_matches: usize = 0;
+    let mut shared: u64 = 0;
+    let mut left_reader = BufReader::with_capacity(
+        opts.chunk_size, HashingReader::new(left, opts.hasher())
+    );
+    let mut right_reader = BufReader::with_capacity(
+        opts.chunk_size, HashingReader::new(right, opts.hasher())
+    );
+    loop {
+        // Ask to read, get a length for how many bytes were read
+        let length = {
+            let left_buffer = left_reader.fill_buf()
+                .map_err(|e| RsdiffError::io(left_label, e))?;
+            let right_buffer = right_reader.fill_buf()
+                .map_err(|e| RsdiffError::io(right_label, e))?;
+            // The buffers can fill unevenly; compare what both have
+            let n = left_buffer.len().min(right_buffer.len());
+            total_matches += diff_buffer(
+                &left_buffer[..n],
+                &right_buffer[..n]);
+            if let Some(buckets) = buckets.as_deref_mut() {
+                buckets.add(shared, &left_buffer[..n], &right_buffer[..n]);
+            }
+            n
+        };
+        if let Some(p) = progress {
+            p.advance(length);
         }
-        else {
-            panic!("Catastrophic buffer mismatch failure");
+        left_reader.consume(length);
+        right_reader.consume(length);
+        shared += length as u64;
+        if length == 0 {
+            break;
         }
     }
-    return matches
+    // Whatever is left of the longer stream has nothing to match
+    let left_rest = io::copy(&mut left_reader, &mut io::sink())
+        .map_err(|e| RsdiffError::io(left_label, e))?;
+    let right_rest = io::copy(&mut right_reader, &mut io::sink())
+        .map_err(|e| RsdiffError::io(right_label, e))?;
+    Ok(Streamed {
+        matches: total_matches,
+        left_len: shared + left_rest,
+        right_len: shared + right_rest,
+        left_hash: left_reader.into_inner().finish()
+            .map_err(|e| RsdiffError::io(left_label, e))?,
+        right_hash: right_reader.into_inner().finish()
+            .map_err(|e| RsdiffError::io(right_label, e))?,
+    })
 }
 
-pub fn diff_transmute_buffers_f64(left: &[u8], right: &[u8], tolerance: f64 ) -> usize {
-    // Verify arrays match in size
-    if !(left.len() == right.len()) {
-        panic!("Buffers supplied to rsdiff::diff_buffer must have the \
-               same length! Instead, left is size {} and right is size {}",
-               left.len(), right.len());
+/// Matches between two equally long files, along with the hash of each
+/// file if hashing was requested.
+type ByteMatches = (usize, Option<String>, Option<String>);
+
+/// Compare two equally long files byte by byte, streaming both, and count
+/// where they mismatch if asked to.
+fn diff_streamed_bytes(left: &str, right: &str, opts: &DiffOptions,
+                       buckets: Option<&mut MismatchBuckets>)
+    -> Result<ByteMatches> {
+    let left_file = File::open(left)
+        .map_err(|e| RsdiffError::io(left, e))?;
+    let right_file = File::open(right)
+        .map_err(|e| RsdiffError::io(right, e))?;
+    let size = left_file.metadata()
+        .map_err(|e| RsdiffError::io(left, e))?
+        .len();
+    let progress = FileProgress::new(left, size);
+    // Hashes take the files read in order, so hashed files aren't split
+    if splits(size, opts) && !opts.hash {
+        let total_matches = diff_in_ranges(size, opts, buckets, |range, b| {
+            diff_file_range(left, right, range, opts, progress.as_ref(), b)
+        })?;
+        return Ok((total_matches, None, None));
     }
-    // Iterate and compare bytes
-    let mut matches: usize = 0;
-    let mut left_rdr = Cursor::new(left);
-    let mut right_rdr = Cursor::new(right);
-    while let Ok(a) = left_rdr.read_f64::<LittleEndian>() {
-        if let Ok(b) = right_rdr.read_f64::<LittleEndian>() {
-            matches += ((a - b).abs() < tolerance) as usize;
+    let streamed = diff_streams(left_file, right_file, (left, right), opts,
+                                progress.as_ref(), buckets)?;
+    Ok((streamed.matches, streamed.left_hash, streamed.right_hash))
+}
+
+/// Compare two equally long files byte by byte, mapping both into memory,
+/// and count where they mismatch if asked to.
+fn diff_mapped_bytes(left: &str, right: &str, opts: &DiffOptions,
+                     buckets: Option<&mut MismatchBuckets>)
+    -> Result<ByteMatches> {
+    let left_map = map_file(left)?;
+    let right_map = map_file(right)?;
+    let (left_hash, right_hash) = hash_maps(&left_map, &right_map, opts);
+    // Compare in chunks only to have progress to announce
+    let progress = FileProgress::new(left, left_map.len() as u64);
+    let within = |map: &'_ Mmap, range: &Range<u64>| {
+        let end = (range.end as usize).min(map.len());
+        (range.start as usize).min(end)..end
+    };
+    let total_matches = diff_in_ranges(
+        left_map.len() as u64, opts, buckets, |range, mut buckets| {
+            let start = range.start as usize;
+            Ok(left_map[within(&left_map, &range)].chunks(opts.chunk_size)
+               .zip(right_map[within(&right_map, &range)]
+                    .chunks(opts.chunk_size))
+               .enumerate()
+               .map(|(i, (a, b))| {
+                   if let Some(p) = &progress {
+                       p.advance(a.len());
+                   }
+                   if let Some(buckets) = buckets.as_deref_mut() {
+                       buckets.add((start + i * opts.chunk_size) as u64, a, b);
+                   }
+                   diff_buffer(a, b)
+               })
+               .sum())
         }
-        else {
-            panic!("Catastrophic buffer mismatch failure");
+    )?;
+    Ok((total_matches, left_hash, right_hash))
+}
+
+/// Most file descriptors a comparison of a file with another holds open
+/// at once, with a pair for each range of a file split across threads.
+fn descriptors_needed(path: &Path, opts: &DiffOptions) -> usize {
+    match fs::metadata(path) {
+        Ok(meta) if splits(meta.len(), opts) => {
+            fds::PER_COMPARISON + 2 * opts.jobs
         }
+        _ => fds::PER_COMPARISON,
     }
-    return matches
 }
 
-pub fn diff_transmute_buffers_u16(left: &[u8], right: &[u8]) -> usize {
-    // Verify arrays match in size
-    if !(left.len() == right.len()) {
-        panic!("Buffers supplied to rsdiff::diff_buffer must have the \
-               same length! Instead, left is size {} and right is size {}",
-               left.len(), right.len());
+/// Whether a file of `len` bytes is large enough to compare in ranges on
+/// several threads.
+fn splits(len: u64, opts: &DiffOptions) -> bool {
+    opts.jobs > 1 && opts.split_above > 0 && len >= opts.split_above
+}
+
+/// Count the matching bytes of two equally long files of `len` bytes with
+/// `diff_range`, which compares a range of them. Files too small to split
+/// are compared as one range; larger ones in a range for each of
+/// `opts.jobs` threads, each counting mismatches into buckets of its own
+/// that are added into the given ones, still empty, at the end.
+fn diff_in_ranges<F>(len: u64, opts: &DiffOptions,
+                     mut buckets: Option<&mut MismatchBuckets>, diff_range: F)
+    -> Result<usize>
+    where F: Fn(Range<u64>, Option<&mut MismatchBuckets>) -> Result<usize>
+        + Sync {
+    if !splits(len, opts) {
+        return diff_range(0..len, buckets);
     }
-    // Iterate and compare bytes
-    let mut matches: usize = 0;
-    let mut left_rdr = Cursor::new(left);
-    let mut right_rdr = Cursor::new(right);
-    while let Ok(a) = left_rdr.read_u16::<LittleEndian>() {
-        if let Ok(b) = right_rdr.read_u16::<LittleEndian>() {
-            matches += (a == b) as usize;
+    let ranges = split_ranges(len, opts.jobs, opts.chunk_size);
+    let empty = buckets.as_deref().cloned();
+    let counted: Vec<(usize, Option<MismatchBuckets>)> = in_pool(opts, || {
+        ranges.into_par_iter()
+            .map(|range| {
+                let mut counts = empty.clone();
+                Ok((diff_range(range, counts.as_mut())?, counts))
+            })
+            .collect()
+    })?;
+    let mut total_matches = 0;
+    for (matches, counts) in counted {
+        total_matches += matches;
+        if let (Some(buckets), Some(counts)) = (buckets.as_deref_mut(),
+                                                counts) {
+            buckets.merge(&counts);
         }
-        else {
-            panic!("Catastrophic buffer mismatch failure");
+    }
+    Ok(total_matches)
+}
+
+/// Split `len` bytes into `parts` ranges, each a whole number of chunks
+/// long but for the last.
+fn split_ranges(len: u64, parts: usize, chunk_size: usize)
+    -> Vec<Range<u64>> {
+    let chunk_size = chunk_size.max(1) as u64;
+    let part = len.div_ceil(parts.max(1) as u64)
+        .div_ceil(chunk_size)
+        .max(1) * chunk_size;
+    (0..len.div_ceil(part))
+        .map(|i| i * part..((i + 1) * part).min(len))
+        .collect()
+}
+
+/// Compare a range of two files byte by byte, reading both through
+/// buffers from the range's start.
+fn diff_file_range(left: &str, right: &str, range: Range<u64>,
+                   opts: &DiffOptions, progress: Option<&FileProgress>,
+                   mut buckets: Option<&mut MismatchBuckets>)
+    -> Result<usize> {
+    let open = |path: &str| -> Result<io::Take<File>> {
+        let mut file = File::open(path)
+            .map_err(|e| RsdiffError::io(path, e))?;
+        file.seek(SeekFrom::Start(range.start))
+            .map_err(|e| RsdiffError::io(path, e))?;
+        Ok(file.take(range.end - range.start))
+    };
+    let mut left_file = open(left)?;
+    let mut right_file = open(right)?;
+    let mut left_buffer = vec![0u8; opts.chunk_size];
+    let mut right_buffer = vec![0u8; opts.chunk_size];
+    let mut offset = range.start;
+    let mut total_matches = 0;
+    loop {
+        let left_len = read_chunk(&mut left_file, &mut left_buffer)
+            .map_err(|e| RsdiffError::io(left, e))?;
+        let right_len = read_chunk(&mut right_file, &mut right_buffer)
+            .map_err(|e| RsdiffError::io(right, e))?;
+        let n = left_len.min(right_len);
+        total_matches += diff_buffer(&left_buffer[..n], &right_buffer[..n]);
+        if let Some(buckets) = buckets.as_deref_mut() {
+            buckets.add(offset, &left_buffer[..n], &right_buffer[..n]);
+        }
+        if let Some(p) = progress {
+            p.advance(n);
+        }
+        offset += n as u64;
+        // Only the end of the range reads short
+        if n < opts.chunk_size {
+            break;
         }
     }
-    return matches
+    Ok(total_matches)
+}
+
+/// Map a whole file into memory.
+fn map_file(path: &str) -> Result<Mmap> {
+    let file = File::open(path).map_err(|e| RsdiffError::io(path, e))?;
+    mmap::map(&file).map_err(|e| RsdiffError::io(path, e))
+}
+
+/// Hash two mapped files if hashing was requested.
+fn hash_maps(left: &Mmap, right: &Mmap, opts: &DiffOptions)
+    -> (Option<String>, Option<String>) {
+    (opts.hasher().map(|h| hash_bytes_with(left, h)),
+     opts.hasher().map(|h| hash_bytes_with(right, h)))
 }
 
-pub fn diff_transmute_buffers_u32(left: &[u8], right: &[u8]) -> usize {
-    // Verify arrays match in size
-    if !(left.len() == right.len()) {
-        panic!("Buffers supplied to rsdiff::diff_buffer must have the \
-               same length! Instead, left is size {} and right is size {}",
-               left.len(), right.len());
+/// Check whether right is left shifted by a constant offset, as happens
+/// when a header is truncated or extended. The only offset that can explain
+/// a size difference is the size difference itself, so only it is tested,
+/// and only if it is no larger than `max_shift`. Returns the offset and the
+/// position in the shorter file from which the content matches, provided
+/// at least half of the shorter file matches.
+fn detect_shift(left: &str, right: &str, left_len: u64, right_len: u64,
+                max_shift: u64, chunk_size: usize)
+    -> Result<Option<(i64, u64)>> {
+    let offset = right_len as i64 - left_len as i64;
+    if offset == 0 || offset.unsigned_abs() > max_shift {
+        return Ok(None);
     }
-    // Iterate and compare bytes
-    let mut matches: usize = 0;
-    let mut left_rdr = Cursor::new(left);
-    let mut right_rdr = Cursor::new(right);
-    while let Ok(a) = left_rdr.read_u32::<LittleEndian>() {
-        if let Ok(b) = right_rdr.read_u32::<LittleEndian>() {
-            matches += (a == b) as usize;
-        }
-        else {
-            panic!("Catastrophic buffer mismatch failure");
+    // Line the shorter file up against the longer one past the offset
+    let (shorter, longer, shorter_len) = if offset > 0 {
+        (left, right, left_len)
+    }
+    else {
+        (right, left, right_len)
+    };
+    let mut shorter_file = File::open(shorter)
+        .map_err(|e| RsdiffError::io(shorter, e))?;
+    let mut longer_file = File::open(longer)
+        .map_err(|e| RsdiffError::io(longer, e))?;
+    longer_file.seek(SeekFrom::Start(offset.unsigned_abs()))
+        .map_err(|e| RsdiffError::io(longer, e))?;
+    let mut shorter_buffer = vec![0u8; chunk_size];
+    let mut longer_buffer = vec![0u8; chunk_size];
+    // Find where the last mismatch is; everything after it matches
+    let mut matching_from: u64 = 0;
+    let mut position: u64 = 0;
+    while position < shorter_len {
+        let n = ((shorter_len - position) as usize).min(chunk_size);
+        shorter_file.read_exact(&mut shorter_buffer[..n])
+            .map_err(|e| RsdiffError::io(shorter, e))?;
+        longer_file.read_exact(&mut longer_buffer[..n])
+            .map_err(|e| RsdiffError::io(longer, e))?;
+        let last_mismatch = shorter_buffer[..n].iter()
+            .zip(longer_buffer[..n].iter())
+            .rposition(|(a, b)| a != b);
+        if let Some(i) = last_mismatch {
+            matching_from = position + i as u64 + 1;
         }
+        position += n as u64;
+    }
+    if shorter_len - matching_from >= shorter_len.div_ceil(2) {
+        Ok(Some((offset, matching_from)))
+    }
+    else {
+        Ok(None)
     }
-    return matches
 }
 
-pub fn diff_transmute_buffers_i16(left: &[u8], right: &[u8]) -> usize {
-    // Verify arrays match in size
-    if !(left.len() == right.len()) {
-        panic!("Buffers supplied to rsdiff::diff_buffer must have the \
-               same length! Instead, left is size {} and right is size {}",
-               left.len(), right.len());
+/// Remove the ignored ranges from a set of ranges.
+fn subtract_ranges(ranges: &[Range<u64>], ignored: &[Range<u64>])
+    -> Vec<Range<u64>> {
+    let mut result: Vec<Range<u64>> = ranges.to_vec();
+    for skip in ignored.iter() {
+        result = result.into_iter()
+            .flat_map(|r| {
+                let before = r.start..r.end.min(skip.start);
+                let after = r.start.max(skip.end)..r.end;
+                vec!(before, after)
+            })
+            .filter(|r| r.start < r.end)
+            .collect();
     }
-    // Iterate and compare bytes
-    let mut matches: usize = 0;
-    let mut left_rdr = Cursor::new(left);
-    let mut right_rdr = Cursor::new(right);
-    while let Ok(a) = left_rdr.read_i16::<LittleEndian>() {
-        if let Ok(b) = right_rdr.read_i16::<LittleEndian>() {
-            matches += (a == b) as usize;
+    result
+}
+
+/// Compare the same byte ranges of two files. Ranges are clipped to the
+/// longer file; bytes that only one file has count as mismatches. Returns
+/// the number of matching bytes and the number of bytes considered.
+fn diff_byte_ranges(left: &str, right: &str, left_len: u64, right_len: u64,
+                    ranges: &[Range<u64>], chunk_size: usize)
+    -> Result<(usize, usize)> {
+    let mut left_file = File::open(left)
+        .map_err(|e| RsdiffError::io(left, e))?;
+    let mut right_file = File::open(right)
+        .map_err(|e| RsdiffError::io(right, e))?;
+    let mut left_buffer = vec![0u8; chunk_size];
+    let mut right_buffer = vec![0u8; chunk_size];
+    let shared_len = left_len.min(right_len);
+    let longest_len = left_len.max(right_len);
+    let mut total_matches: usize = 0;
+    let mut total: usize = 0;
+    for range in ranges.iter() {
+        let end = range.end.min(longest_len);
+        if range.start >= end {
+            continue;
         }
-        else {
-            panic!("Catastrophic buffer mismatch failure");
+        total += (end - range.start) as usize;
+        // Only bytes both files have can match
+        let shared_end = end.min(shared_len);
+        if range.start >= shared_end {
+            continue;
+        }
+        left_file.seek(SeekFrom::Start(range.start))
+            .map_err(|e| RsdiffError::io(left, e))?;
+        right_file.seek(SeekFrom::Start(range.start))
+            .map_err(|e| RsdiffError::io(right, e))?;
+        let mut remaining = (shared_end - range.start) as usize;
+        while remaining > 0 {
+            let n = remaining.min(chunk_size);
+            left_file.read_exact(&mut left_buffer[..n])
+                .map_err(|e| RsdiffError::io(left, e))?;
+            right_file.read_exact(&mut right_buffer[..n])
+                .map_err(|e| RsdiffError::io(right, e))?;
+            total_matches += diff_buffer(&left_buffer[..n], &right_buffer[..n]);
+            remaining -= n;
         }
     }
-    return matches
+    Ok((total_matches, total))
+}
+
+/// Count the f32 values of two buffers that match within an absolute
+/// tolerance. Fails if the buffers differ in length.
+pub fn diff_transmute_buffers_f32(left: &[u8], right: &[u8], tolerance: f32)
+    -> Result<usize> {
+    diff_transmute_buffers_f32_by(left, right, |a, b| {
+        a == b || (a - b).abs() < tolerance
+    })
+}
+
+/// Count the f32 values of two buffers that `same` counts as matching.
+/// Fails if the buffers differ in length.
+pub fn diff_transmute_buffers_f32_by(left: &[u8], right: &[u8],
+                                     same: impl Fn(f32, f32) -> bool)
+    -> Result<usize> {
+    check_buffer_lengths(left, right)?;
+    Ok(count_matching(left, right, 4, LittleEndian::read_f32, same))
+}
+
+/// Count the f64 values of two buffers that match within an absolute
+/// tolerance. Fails if the buffers differ in length.
+pub fn diff_transmute_buffers_f64(left: &[u8], right: &[u8], tolerance: f64)
+    -> Result<usize> {
+    diff_transmute_buffers_f64_by(left, right, |a, b| {
+        a == b || (a - b).abs() < tolerance
+    })
+}
+
+/// Count the f64 values of two buffers that `same` counts as matching.
+/// Fails if the buffers differ in length.
+pub fn diff_transmute_buffers_f64_by(left: &[u8], right: &[u8],
+                                     same: impl Fn(f64, f64) -> bool)
+    -> Result<usize> {
+    check_buffer_lengths(left, right)?;
+    Ok(count_matching(left, right, 8, LittleEndian::read_f64, same))
 }
 
-pub fn diff_transmute_buffers_i32(left: &[u8], right: &[u8]) -> usize {
-    // Verify arrays match in size
-    if !(left.len() == right.len()) {
-        panic!("Buffers supplied to rsdiff::diff_buffer must have the \
-               same length! Instead, left is size {} and right is size {}",
-               left.len(), right.len());
+/// Count the u16 values two buffers share. Fails if the buffers differ in
+/// length.
+pub fn diff_transmute_buffers_u16(left: &[u8], right: &[u8])
+    -> Result<usize> {
+    check_buffer_lengths(left, right)?;
+    Ok(count_equal_voxels(left, right, 2))
+}
+
+/// Count the u32 values two buffers share. Fails if the buffers differ in
+/// length.
+pub fn diff_transmute_buffers_u32(left: &[u8], right: &[u8])
+    -> Result<usize> {
+    check_buffer_lengths(left, right)?;
+    Ok(count_equal_voxels(left, right, 4))
+}
+
+/// Count the i16 values two buffers share. Fails if the buffers differ in
+/// length.
+pub fn diff_transmute_buffers_i16(left: &[u8], right: &[u8])
+    -> Result<usize> {
+    check_buffer_lengths(left, right)?;
+    Ok(count_equal_voxels(left, right, 2))
+}
+
+/// Count the i32 values two buffers share. Fails if the buffers differ in
+/// length.
+pub fn diff_transmute_buffers_i32(left: &[u8], right: &[u8])
+    -> Result<usize> {
+    check_buffer_lengths(left, right)?;
+    Ok(count_equal_voxels(left, right, 4))
+}
+
+/// Count the i64 values two buffers share. Fails if the buffers differ in
+/// length.
+pub fn diff_transmute_buffers_i64(left: &[u8], right: &[u8])
+    -> Result<usize> {
+    check_buffer_lengths(left, right)?;
+    Ok(count_equal_voxels(left, right, 8))
+}
+
+/// Count the u64 values two buffers share. Fails if the buffers differ in
+/// length.
+pub fn diff_transmute_buffers_u64(left: &[u8], right: &[u8])
+    -> Result<usize> {
+    check_buffer_lengths(left, right)?;
+    Ok(count_equal_voxels(left, right, 8))
+}
+
+/// Check that two buffers of voxels to compare are equally long.
+fn check_buffer_lengths(left: &[u8], right: &[u8]) -> Result<()> {
+    if left.len() != right.len() {
+        return Err(RsdiffError::Corrupt(format!(
+            "buffers to compare differ in length: {} vs. {} bytes",
+            left.len(), right.len()
+        )));
     }
-    // Iterate and compare bytes
-    let mut matches: usize = 0;
-    let mut left_rdr = Cursor::new(left);
-    let mut right_rdr = Cursor::new(right);
-    while let Ok(a) = left_rdr.read_i32::<LittleEndian>() {
-        if let Ok(b) = right_rdr.read_i32::<LittleEndian>() {
-            matches += (a == b) as usize;
+    Ok(())
+}
+
+/// Count the values of two equally long buffers that `same` counts as
+/// matching, reading each from `width` bytes with `read`.
+fn count_matching<T>(left: &[u8], right: &[u8], width: usize,
+                     read: impl Fn(&[u8]) -> T, same: impl Fn(T, T) -> bool)
+    -> usize {
+    left.chunks_exact(width)
+        .zip(right.chunks_exact(width))
+        .filter(|(a, b)| same(read(a), read(b)))
+        .count()
+}
+
+/// Count the voxels of `width` bytes that two equally long buffers hold
+/// the same bytes for, which is when integers match.
+fn count_equal_voxels(left: &[u8], right: &[u8], width: usize) -> usize {
+    left.chunks_exact(width)
+        .zip(right.chunks_exact(width))
+        .filter(|(a, b)| a == b)
+        .count()
+}
+
+/// Count the voxels of two buffers whose scaled values `same` counts as
+/// matching. Each side's voxels are read by the reader, which takes so
+/// many bytes at a time, and scaled by its own slope and intercept. Voxels
+/// stored as one of the `sentinels` are missing rather than scaled: two
+/// missing voxels match, and a missing voxel never matches one that isn't.
+fn diff_scaled_buffers(left: &[u8], right: &[u8],
+                       (read, width): (drift::VoxelRead, usize),
+                       left_scaling: (f64, f64), right_scaling: (f64, f64),
+                       sentinels: &[f64], same: impl Fn(f64, f64) -> bool)
+    -> usize {
+    left.chunks_exact(width)
+        .zip(right.chunks_exact(width))
+        .filter(|(a, b)| {
+            let x = scale_voxel(read(a), left_scaling, sentinels);
+            let y = scale_voxel(read(b), right_scaling, sentinels);
+            match (x, y) {
+                (Some(x), Some(y)) => same(x, y),
+                (None, None) => true,
+                _ => false,
+            }
+        })
+        .count()
+}
+
+/// Scale a stored voxel value by a slope and intercept, or None if it is
+/// one of the `sentinels` standing in for missing data.
+fn scale_voxel(stored: f64, (slope, inter): (f64, f64), sentinels: &[f64])
+    -> Option<f64> {
+    if sentinels.contains(&stored) {
+        None
+    }
+    else {
+        Some(stored * slope + inter)
+    }
+}
+
+/// The slope and intercept a header scales stored voxel values by. A slope
+/// of zero, or one that isn't finite, means the values aren't scaled.
+fn voxel_scaling(hdr: &NiftiHeader) -> (f64, f64) {
+    let slope = hdr.scl_slope as f64;
+    if slope == 0.0 || !slope.is_finite() {
+        (1.0, 0.0)
+    }
+    else {
+        (slope, hdr.scl_inter as f64)
+    }
+}
+
+/// Matches between two voxel streams, along with the hash of each file if
+/// hashing was requested.
+type VoxelMatches = (usize, Option<String>, Option<String>);
+
+/// Counts the matching elements of two equally long buffers of
+/// little-endian voxels.
+type Transmuter = dyn Fn(&[u8], &[u8]) -> usize;
+
+/// Counts the matching elements of two equally long buffers of voxels,
+/// which it may reorder in place.
+type BufferDiffer<'a> = dyn Fn(&mut [u8], &mut [u8]) -> usize + 'a;
+
+/// VoxelReader
+/// Reads a NIfTI file's bytes, decompressing them if it is gzipped, and
+/// hashes them if asked to.
+enum VoxelReader {
+    Gzipped(gz::BackgroundDecoder),
+    Plain(HashingReader<File>),
+}
+
+impl VoxelReader {
+    /// Open a NIfTI file to be read the way it is stored.
+    fn open(path: &str, gzipped: bool, opts: &DiffOptions)
+        -> Result<VoxelReader> {
+        let file = File::open(path).map_err(|e| RsdiffError::io(path, e))?;
+        if gzipped {
+            Ok(VoxelReader::Gzipped(gz::BackgroundDecoder::spawn(file, opts)))
         }
         else {
-            panic!("Catastrophic buffer mismatch failure");
+            Ok(VoxelReader::Plain(HashingReader::new(file, opts.hasher())))
+        }
+    }
+
+    /// Finish reading, returning the hash of the file's contents if one was
+    /// asked for. A gzipped file is hashed as stored, not decompressed.
+    fn finish(self) -> io::Result<Option<String>> {
+        match self {
+            VoxelReader::Gzipped(decoder) => decoder.finish(),
+            VoxelReader::Plain(reader) => reader.finish(),
         }
     }
-    return matches
 }
 
-pub fn diff_transmute_buffers_i64(left: &[u8], right: &[u8]) -> usize {
-    // Verify arrays match in size
-    if !(left.len() == right.len()) {
-        panic!("Buffers supplied to rsdiff::diff_buffer must have the \
-               same length! Instead, left is size {} and right is size {}",
-               left.len(), right.len());
+impl Read for VoxelReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            VoxelReader::Gzipped(decoder) => decoder.read(buf),
+            VoxelReader::Plain(reader) => reader.read(buf),
+        }
     }
-    // Iterate and compare bytes
-    let mut matches: usize = 0;
-    let mut left_rdr = Cursor::new(left);
-    let mut right_rdr = Cursor::new(right);
-    while let Ok(a) = left_rdr.read_i64::<LittleEndian>() {
-        if let Ok(b) = right_rdr.read_i64::<LittleEndian>() {
-            matches += (a == b) as usize;
+}
+
+/// Compare the voxels of two niftis by streaming them, decompressing
+/// either side that is gzipped. Data that ends on one side before the
+/// other is reported as corrupt.
+fn diff_voxels_streamed(left: &str, left_gzipped: bool, right: &str,
+                        right_gzipped: bool, vox_offset: usize,
+                        buffer_differ: &BufferDiffer,
+                        opts: &DiffOptions) -> Result<VoxelMatches> {
+    // A gzip stream that fails to decompress, including one failing its
+    // CRC or length check, is reported as corrupt
+    let read_error = |side: &str, e: io::Error| {
+        let (path, gzipped) = if side == "left" {
+            (left, left_gzipped)
         }
         else {
-            panic!("Catastrophic buffer mismatch failure");
+            (right, right_gzipped)
+        };
+        if gzipped {
+            RsdiffError::Corrupt(gz::describe_corruption(side, &e))
         }
-    }
-    return matches
+        else {
+            RsdiffError::io(path, e)
+        }
+    };
+    let mut left_rdr = VoxelReader::open(left, left_gzipped, opts)?;
+    let mut right_rdr = VoxelReader::open(right, right_gzipped, opts)?;
+    // Skip past the header to the appropriate voxel offset
+    io::copy(&mut (&mut left_rdr).take(vox_offset as u64), &mut io::sink())
+        .map_err(|e| read_error("left", e))?;
+    io::copy(&mut (&mut right_rdr).take(vox_offset as u64), &mut io::sink())
+        .map_err(|e| read_error("right", e))?;
+    let total_matches = diff_voxel_streams(
+        &mut left_rdr, &mut right_rdr, buffer_differ, opts.chunk_size,
+        read_error
+    )?;
+    let left_hash = left_rdr.finish().map_err(|e| RsdiffError::io(left, e))?;
+    let right_hash = right_rdr.finish()
+        .map_err(|e| RsdiffError::io(right, e))?;
+    Ok((total_matches, left_hash, right_hash))
 }
 
-pub fn diff_transmute_buffers_u64(left: &[u8], right: &[u8]) -> usize {
-    // Verify arrays match in size
-    if !(left.len() == right.len()) {
-        panic!("Buffers supplied to rsdiff::diff_buffer must have the \
-               same length! Instead, left is size {} and right is size {}",
-               left.len(), right.len());
-    }
-    // Iterate and compare bytes
-    let mut matches: usize = 0;
-    let mut left_rdr = Cursor::new(left);
-    let mut right_rdr = Cursor::new(right);
-    while let Ok(a) = left_rdr.read_u64::<LittleEndian>() {
-        if let Ok(b) = right_rdr.read_u64::<LittleEndian>() {
-            matches += (a == b) as usize;
+/// Compare the voxels of two uncompressed niftis, mapping both into memory.
+/// Data that ends on one side before the other is reported as corrupt.
+fn diff_voxels_mapped(left: &str, right: &str, vox_offset: usize,
+                      buffer_differ: &BufferDiffer,
+                      opts: &DiffOptions) -> Result<VoxelMatches> {
+    let mut left_map = map_file(left)?;
+    let mut right_map = map_file(right)?;
+    // Hash first, since comparing may reorder bytes in place
+    let (left_hash, right_hash) = hash_maps(&left_map, &right_map, opts);
+    let left_voxels = left_map.get_mut(vox_offset..).unwrap_or_default();
+    let right_voxels = right_map.get_mut(vox_offset..).unwrap_or_default();
+    if left_voxels.len() != right_voxels.len() {
+        let shorter = if left_voxels.len() < right_voxels.len() {
+            "left"
         }
         else {
-            panic!("Catastrophic buffer mismatch failure");
-        }
-    }
-    return matches
-}
-
-fn diff_voxels_nii_gz(left: &str, right: &str, vox_offset: usize, buffer_differ: fn(&[u8], &[u8]) -> usize) -> usize {
-    const KILOBYTE: usize = 1024;
-    const CHUNK_SIZE: usize = 256 * KILOBYTE;
-    const TOLERANCE: f32 = 1e-16;
-    let left_file = File::open(left).expect("Uh-oh!");
-    let right_file = File::open(right).expect("Uh-oh!");
-    let mut left_buffer = [0u8; CHUNK_SIZE];
-    let mut right_buffer = [0u8; CHUNK_SIZE];
-    let mut place_holder_buffer = Vec::with_capacity(vox_offset);
-    let mut left_gz = GzDecoder::new(left_file);
-    let mut right_gz = GzDecoder::new(right_file);
-    // Clear out offsets
-    let _offset_left = left_gz.read_exact(&mut place_holder_buffer)
-        .expect("I can't read the GZ file!");
-    let _offset_right = right_gz.read_exact(&mut place_holder_buffer)
-        .expect("I can't read the right GZ file!");
+            "right"
+        };
+        return Err(RsdiffError::Corrupt(format!(
+            "{} file's voxel data ends early", shorter
+        )));
+    }
+    // Compare in the same chunks as streamed data, so values never
+    // straddle two calls
+    let total_matches = left_voxels.chunks_mut(opts.chunk_size)
+        .zip(right_voxels.chunks_mut(opts.chunk_size))
+        .map(|(a, b)| buffer_differ(a, b))
+        .sum();
+    Ok((total_matches, left_hash, right_hash))
+}
+
+/// Count matching voxels in two streams of voxel data, reading them in
+/// chunks. Read errors are turned into RsdiffErrors by `read_error`, which
+/// is told the side the error happened on.
+fn diff_voxel_streams(left: &mut impl Read, right: &mut impl Read,
+                      buffer_differ: &BufferDiffer,
+                      chunk_size: usize,
+                      read_error: impl Fn(&str, io::Error) -> RsdiffError)
+    -> Result<usize> {
+    let mut left_buffer = vec![0u8; chunk_size];
+    let mut right_buffer = vec![0u8; chunk_size];
     let mut total_matches = 0;
-    // Loop and compare
     loop {
-        if let Ok(nl) = left_gz.read(&mut left_buffer) {
-            if let Ok(nr) = right_gz.read(&mut right_buffer) {
-                if nl != nr {
-                    panic!("Unexpected file size difference! \
-                    {} reads {}, {} reads {}!",
-                    left, nl,
-                    right, nr
-                    );
-                }
-                if nl == 0 {
-                    break;
-                }
-                total_matches += buffer_differ(&left_buffer[..nl], &right_buffer[..nl]);
-            }
-            else {
-                panic!("Can't read from right buffer!");
-            }
+        let nl = read_chunk(left, &mut left_buffer)
+            .map_err(|e| read_error("left", e))?;
+        let nr = read_chunk(right, &mut right_buffer)
+            .map_err(|e| read_error("right", e))?;
+        if nl != nr {
+            let shorter = if nl < nr { "left" } else { "right" };
+            return Err(RsdiffError::Corrupt(format!(
+                "{} file's voxel data ends early", shorter
+            )));
         }
-        else {
-            panic!("Can't read from left buffer!");
+        if nl == 0 {
+            break;
         }
+        total_matches += buffer_differ(&mut left_buffer[..nl],
+                                       &mut right_buffer[..nl]);
     }
-    total_matches
+    Ok(total_matches)
 }
 
-fn diff_voxels_nii(left: &str, right: &str, vox_offset: usize, buffer_differ: fn(&[u8], &[u8]) -> usize) -> usize {
-    const KILOBYTE: usize = 1024;
-    const CHUNK_SIZE: usize = 256 * KILOBYTE;
-    const TOLERANCE: f32 = 1e-16;
-    let left_file = File::open(left).expect("Uh-oh!");
-    let right_file = File::open(right).expect("Uh-oh!");
+/// Fill a buffer as far as possible, since decompressors may return short
+/// reads long before the end of the stream. Returns the number of bytes
+/// read, which is only short of the buffer length at the end of the stream.
+pub(crate) fn read_chunk<R: Read>(reader: &mut R, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match reader.read(&mut buffer[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
 
-    let mut left_rdr = BufReader::with_capacity(
-        CHUNK_SIZE, left_file
-    );
-    let mut right_rdr = BufReader::with_capacity(
-        CHUNK_SIZE, right_file
-    );
-    let mut total_matches = 0;
-    // Consume the appropriate voxel offset
-    left_rdr.consume(vox_offset);
-    right_rdr.consume(vox_offset);
-    loop {
-        let length = {
-            let left_buffer = left_rdr.fill_buf().expect("UO");
-            let right_buffer = right_rdr.fill_buf().expect("UO");
-            if left_buffer.len() !=  0 {
-                total_matches += buffer_differ(&left_buffer, &right_buffer);
-            }
-            left_buffer.len()
-        };
-        left_rdr.consume(length);
-        right_rdr.consume(length);
-        if length == 0 { break; }
+/// Read only the header of a NIfTI file, decompressing it if gzipped,
+/// whatever its name says.
+pub(crate) fn read_nii_header(path: &str) -> nifti::Result<NiftiHeader> {
+    let gzipped = gz::is_gzip(path)?;
+    let file = BufReader::new(File::open(path)?);
+    if gzipped {
+        NiftiHeader::from_reader(gz::decoder(file))
+    }
+    else {
+        NiftiHeader::from_reader(file)
     }
-    total_matches
 }
 
 /// Diff two niftis
-pub fn diff_nii(left: &str, right: &str) -> Diff {
-    const TOLERANCE: f32 = 1e-16;
+pub fn diff_nii(left: &str, right: &str) -> Result<Diff> {
+    diff_nii_with_options(left, right, &DiffOptions::default())
+}
+
+/// Diff two niftis with custom options
+pub fn diff_nii_with_options(left: &str, right: &str, opts: &DiffOptions)
+    -> Result<Diff> {
     // Load headers
-    let left_reader = ReaderOptions::new().read_file(left)
-        .expect("Cannot read left file as nifti!");
-    let right_reader = ReaderOptions::new().read_file(right)
-        .expect("Cannot read right file as nifti!");
+    let left_hdr = read_nii_header(left)
+        .map_err(|e| RsdiffError::Nifti { path: String::from(left), source: e })?;
+    let right_hdr = read_nii_header(right)
+        .map_err(|e| RsdiffError::Nifti { path: String::from(right), source: e })?;
+
+    let mut header_differences = header::differences(&left_hdr, &right_hdr);
+    // Geometry written in other units is the same geometry, if the units
+    // can be converted
+    let mut unit_finding = None;
+    if opts.normalize_units && left_hdr.xyzt_units != right_hdr.xyzt_units {
+        let normalized = header::differences(
+            &header::normalize_units(&left_hdr),
+            &header::normalize_units(&right_hdr)
+        );
+        if !normalized.iter().any(|f| f.field == "xyzt_units") {
+            header_differences = normalized;
+            unit_finding = Some(format!(
+                "units differ: {} vs. {}; header compared in mm and s",
+                header::describe_units(left_hdr.xyzt_units),
+                header::describe_units(right_hdr.xyzt_units)
+            ));
+        }
+    }
+    if opts.schema_only {
+        return Ok(diff_nii_schemas(left, right, header_differences));
+    }
 
     // Since both files exist, make a new Diff object
     let mut d = Diff::new(left, right);
+    d.findings.extend(unit_finding);
+    // Scaling is part of the values compared, not a difference of its own
+    let left_scaling = voxel_scaling(&left_hdr);
+    let right_scaling = voxel_scaling(&right_hdr);
+    if opts.scaled_voxels {
+        header_differences.retain(|f| {
+            f.field != "scl_slope" && f.field != "scl_inter"
+        });
+        if left_scaling != right_scaling {
+            d.findings.push(format!(
+                "voxels compared after scaling by {} + {} vs. {} + {}",
+                left_scaling.0, left_scaling.1, right_scaling.0,
+                right_scaling.1
+            ));
+        }
+    }
+    // Either side may be gzipped, whatever the other is
+    let left_gzipped = gz::is_gzip(left)
+        .map_err(|e| RsdiffError::io(left, e))?;
+    let right_gzipped = gz::is_gzip(right)
+        .map_err(|e| RsdiffError::io(right, e))?;
+    let mixed_compression = if left_gzipped == right_gzipped {
+        None
+    }
+    else {
+        Some(if left_gzipped { "left" } else { "right" })
+    };
+    if let Some(side) = mixed_compression {
+        if opts.mixed_compression != MixedCompression::Compare {
+            d.findings.push(format!("only the {} file is gzipped", side));
+        }
+    }
     // Check to see if shapes match
-    let shapes_match = 
-        left_reader.header().dim == right_reader.header().dim;
+    let shapes_match = left_hdr.dim == right_hdr.dim;
     if shapes_match {
         // Check to see if data types match
-        if left_reader.header().datatype != right_reader.header().datatype {
+        if left_hdr.datatype != right_hdr.datatype {
             d.report = format!("{} vs {}: Shapes match, types diverge \
                                ({:?} vs. {:?})",
                                left, right,
-                               left_reader.header().datatype,
-                               right_reader.header().datatype
+                               left_hdr.datatype,
+                               right_hdr.datatype
                         );
-            return d;
+            add_header_differences(&mut d, header_differences);
+            if opts.hash {
+                hash_both(&mut d, opts)?;
+            }
+            return Ok(d);
         }
-        let hdr = left_reader.header();
+        let hdr = &left_hdr;
         let dtype = hdr.datatype;
         let vox_offset = hdr.vox_offset as usize;
         // Build a function to run the correct buffer transmuter
-        let buffer_differ = match dtype {
-            4 => |a: &[u8], b: &[u8]| diff_transmute_buffers_i16(a, b),
-            8 => |a: &[u8], b: &[u8]| diff_transmute_buffers_i32(a, b),
-            16 => |a: &[u8], b: &[u8]| diff_transmute_buffers_f32(a, b, TOLERANCE),
-            64 => |a: &[u8], b: &[u8]| diff_transmute_buffers_f64(a, b, TOLERANCE as f64),
-            512 => |a: &[u8], b: &[u8]| diff_transmute_buffers_u16(a, b),
-            768 => |a: &[u8], b: &[u8]| diff_transmute_buffers_u32(a, b),
-            1024 => |a: &[u8], b: &[u8]| diff_transmute_buffers_i64(a, b),
-            1280 => |a: &[u8], b: &[u8]| diff_transmute_buffers_i64(a, b),
-            _ => panic!("Unsupported data type {}, sorry!", dtype),
+        let tolerance = opts.tolerance;
+        let comparison = opts.float_comparison;
+        let transmuter: Box<Transmuter> = match dtype {
+            _ if opts.scaled_voxels => {
+                let reader = drift::voxel_reader(dtype)
+                    .ok_or(RsdiffError::UnsupportedDatatype(dtype))?;
+                let sentinels = opts.sentinels.clone();
+                Box::new(move |a: &[u8], b: &[u8]| {
+                    diff_scaled_buffers(a, b, reader, left_scaling,
+                                        right_scaling, &sentinels, |x, y| {
+                        comparison.same_f64(x, y, tolerance)
+                    })
+                })
+            }
+            // Integers match when their bytes do
+            2 | 256 => Box::new(diff_buffer),
+            4 | 512 => Box::new(|a: &[u8], b: &[u8]| {
+                count_equal_voxels(a, b, 2)
+            }),
+            8 | 768 => Box::new(|a: &[u8], b: &[u8]| {
+                count_equal_voxels(a, b, 4)
+            }),
+            1024 | 1280 => Box::new(|a: &[u8], b: &[u8]| {
+                count_equal_voxels(a, b, 8)
+            }),
+            16 => Box::new(move |a: &[u8], b: &[u8]| {
+                count_matching(a, b, 4, LittleEndian::read_f32, |x, y| {
+                    comparison.same_f32(x, y, tolerance as f32)
+                })
+            }),
+            64 => Box::new(move |a: &[u8], b: &[u8]| {
+                count_matching(a, b, 8, LittleEndian::read_f64, |x, y| {
+                    comparison.same_f64(x, y, tolerance)
+                })
+            }),
+            _ => return Err(RsdiffError::UnsupportedDatatype(dtype)),
+        };
+        // The transmuters read little-endian values, so voxels stored
+        // big-endian are swapped into that order first
+        if left_hdr.endianness != right_hdr.endianness {
+            d.findings.push(format!("voxels stored {} vs. {}",
+                                    describe_endianness(left_hdr.endianness),
+                                    describe_endianness(right_hdr.endianness)));
+        }
+        let width = (hdr.bitpix as usize / 8).max(1);
+        let swap_left = left_hdr.endianness == Endianness::Big;
+        let swap_right = right_hdr.endianness == Endianness::Big;
+        let drift = Cell::new(None);
+        // Differences are measured in the values compared
+        let (left_measured, right_measured, sentinels) = if opts.scaled_voxels {
+            (left_scaling, right_scaling, &opts.sentinels[..])
+        }
+        else {
+            ((1.0, 0.0), (1.0, 0.0), &[][..])
+        };
+        let reader = drift::voxel_reader(dtype);
+        let differences = Cell::new(drift::DifferenceTally::default());
+        // Other metrics need every voxel, even of identical chunks
+        let measure = opts.metric != Metric::Matches;
+        let metrics = Cell::new(similarity::MetricTally::default());
+        // dim[0] holds the number of dimensions in use
+        let dims: Vec<usize> = left_hdr.dim()
+            .map_err(|e| RsdiffError::Nifti {
+                path: String::from(left), source: e
+            })?
+            .iter()
+            .map(|&n| n as usize)
+            .collect();
+        // Dimensions too large to address make the header unusable
+        let Some(total_voxels) = dims.iter()
+            .try_fold(1usize, |n, &dim| n.checked_mul(dim))
+            .filter(|n| n.checked_mul(width).is_some()) else {
+            d.findings.push(format!(
+                "header dimensions {:?} give more voxels than can be \
+                 addressed", dims
+            ));
+            d.additional_info = String::from("could not be compared");
+            d.report = format!("{} vs. {}: {}", left, right, d.additional_info);
+            add_header_differences(&mut d, header_differences);
+            return Ok(d);
         };
-        let total_matches = {
-            if left.ends_with("gz") {
-                diff_voxels_nii_gz(left, right, vox_offset, buffer_differ)
+        // Every dimension past the third indexes volumes, which are
+        // counted separately so divergence can be placed in time
+        let volumes: usize = dims.iter().skip(3).product();
+        let volume_bytes = total_voxels / volumes.max(1) * width;
+        let volume_tally = if volumes > 1 && volume_bytes > 0 {
+            Some(VolumeTally::new(volume_bytes, volumes))
+        }
+        else {
+            // A volume of no voxels can't be told from the next one
+            if volumes > 1 {
+                d.findings.push(format!(
+                    "header dimensions {:?} give {} volumes of no voxels, \
+                     so volumes aren't compared separately", dims, volumes
+                ));
             }
-            else {
-                diff_voxels_nii(left, right, vox_offset, buffer_differ)
+            None
+        };
+        let progress = FileProgress::new(left, (total_voxels * width) as u64);
+        let buffer_differ = |a: &mut [u8], b: &mut [u8]| {
+            if let Some(p) = &progress {
+                p.advance(a.len());
+            }
+            if swap_left {
+                swap_byte_order(a, width);
+            }
+            if swap_right {
+                swap_byte_order(b, width);
+            }
+            if opts.drift {
+                if let Some(m) = drift::max_voxel_difference(a, b, dtype) {
+                    let mut max = drift.get();
+                    drift::record(&mut max, m);
+                    drift.set(max);
+                }
+            }
+            if let Some((read, width)) = reader {
+                let mut tally = differences.get();
+                let mut metric_tally = metrics.get();
+                // Identical bytes can only hold identical numbers
+                if a == b && sentinels.is_empty() && !measure {
+                    tally.add_equal(a.len() / width);
+                }
+                else {
+                    for (x, y) in a.chunks_exact(width)
+                        .zip(b.chunks_exact(width)) {
+                        let x = scale_voxel(read(x), left_measured, sentinels);
+                        let y = scale_voxel(read(y), right_measured,
+                                            sentinels);
+                        if let (Some(x), Some(y)) = (x, y) {
+                            tally.add(x, y);
+                            if measure {
+                                metric_tally.add(x, y);
+                            }
+                        }
+                    }
+                }
+                differences.set(tally);
+                metrics.set(metric_tally);
             }
+            match &volume_tally {
+                Some(tally) => tally.count(a, b, &*transmuter),
+                None => transmuter(a, b),
+            }
+        };
+        let voxel_matches = if opts.mmap && !left_gzipped && !right_gzipped {
+            diff_voxels_mapped(left, right, vox_offset, &buffer_differ, opts)
+        }
+        else {
+            diff_voxels_streamed(left, left_gzipped, right, right_gzipped,
+                                 vox_offset, &buffer_differ, opts)
         };
-        let mut total_voxels: usize = 1;
-        for  d in left_reader.header().dim.iter() {
-            let mut value = *d;
-            if value == 0 {
-                value = 1;
+        let (total_matches, left_hash, right_hash) = match voxel_matches {
+            Ok(m) => m,
+            Err(RsdiffError::Corrupt(corruption)) => {
+                d.findings.push(corruption);
+                d.additional_info = String::from("could not be compared");
+                d.report = format!(
+                    "{} vs. {}: {}", left, right, d.additional_info
+                );
+                add_header_differences(&mut d, header_differences);
+                return Ok(d);
+            }
+            Err(e) => return Err(e),
+        };
+        d.left_hash = left_hash;
+        d.right_hash = right_hash;
+        d.max_relative_difference = drift.get();
+        d.numeric_differences = differences.get().finish();
+        // Count in bytes if asked to, so voxel counts aggregate with
+        // byte-wise comparisons
+        let bytes_per_voxel = (hdr.bitpix as usize / 8).max(1);
+        match opts.voxel_unit {
+            Unit::Bytes => d.set_counts(total_matches * bytes_per_voxel,
+                                        total_voxels * bytes_per_voxel,
+                                        Unit::Bytes),
+            _ => d.set_counts(total_matches, total_voxels, Unit::Elements),
+        }
+        if let Some(tally) = volume_tally {
+            add_volumes(&mut d, tally, bytes_per_voxel, opts.voxel_unit);
+        }
+        if measure {
+            let tally = metrics.get();
+            let metric = tally.resolve(opts.metric);
+            match tally.value(metric) {
+                Some(value) => {
+                    d.similarity = value as f32;
+                    d.findings.push(format!(
+                        "similarity is {}", similarity::describe(metric)
+                    ));
+                }
+                None => d.findings.push(format!(
+                    "no {} metric for these images; similarity is {}",
+                    metric, similarity::describe(Metric::Matches)
+                )),
             }
-            total_voxels *= value as usize;
         }
         if total_voxels == total_matches {
             // Complete match
-            d.matches = true
+            d.matches = true;
         }
         else {
             // We can build a report
@@ -610,15 +2441,53 @@ pub fn diff_nii(left: &str, right: &str) -> Diff {
                 total_voxels,
                 percentage_match
             );
+            if let Some(differences) = d.numeric_differences {
+                d.additional_info.push_str(&format!("; {}", differences));
+                // Only float voxels are compared with a tolerance
+                let tolerant = opts.scaled_voxels || dtype == 16 || dtype == 64;
+                if tolerant && comparison == FloatComparison::Absolute {
+                    d.hint = hint::tolerance(differences.max_abs);
+                }
+            }
         }
     }
     else {
         // We can build a report for shape mismatch
         d.additional_info = format!(
             "Shapes diverge: {:#?} vs. {:#?}",
-            left_reader.header().dim().expect("Bad dimensions"),
-            right_reader.header().dim().expect("Bad dimensions"),
+            left_hdr.dim().unwrap_or(&left_hdr.dim[..]),
+            right_hdr.dim().unwrap_or(&right_hdr.dim[..]),
         );
+        if opts.hash {
+            hash_both(&mut d, opts)?;
+        }
+    }
+
+    // The same voxels in a different space, or scaled differently, are
+    // still different images
+    if d.matches && !header_differences.is_empty() {
+        d.matches = false;
+        d.additional_info = format!("Voxels match, headers diverge in {} \
+                                     field(s)", header_differences.len());
+        let fields: Vec<&str> = header_differences.iter()
+            .map(|f| f.field)
+            .collect();
+        d.hint = hint::header_fields(&fields);
+    }
+    // Voxels that diverge in an image whose header differs too need more
+    // than a tolerance to match
+    else if !header_differences.is_empty() {
+        d.hint = None;
+    }
+    if let Some(side) = mixed_compression {
+        if d.matches && opts.mixed_compression == MixedCompression::Differ {
+            d.matches = false;
+            d.additional_info = format!("Voxels and headers match, but only \
+                                         the {} file is gzipped", side);
+            d.hint = Some(String::from(
+                "would pass with --mixed-compression compare"
+            ));
+        }
     }
 
     // Build report
@@ -626,27 +2495,166 @@ pub fn diff_nii(left: &str, right: &str) -> Diff {
         d.report = format!(
             "{} vs. {}: {}", left, right, d.additional_info
         );
+        // Header fields are sub-diffs too, so list volumes before them
+        report_volumes(&mut d);
+        add_header_differences(&mut d, header_differences);
     }
 
-    return d;
+    Ok(d)
 }
 
+/// VolumeTally
+/// Matching voxels per volume of a 4D image, counted as chunks of voxel
+/// data go past in order.
+struct VolumeTally {
+    volume_bytes: usize,
+    /// How far into the voxel data the chunks have got, in bytes.
+    position: Cell<usize>,
+    matches: RefCell<Vec<usize>>,
+}
 
+impl VolumeTally {
+    fn new(volume_bytes: usize, volumes: usize) -> VolumeTally {
+        VolumeTally {
+            volume_bytes,
+            position: Cell::new(0),
+            matches: RefCell::new(vec![0; volumes]),
+        }
+    }
 
-/// Calculate how many bytes match between two buffers. The buffers must be
-/// of equal size.
-pub fn diff_buffer(left: &[u8], right: &[u8]) -> usize {
-    // Verify arrays match in size
-    if !(left.len() == right.len()) {
-        panic!("Buffers supplied to rsdiff::diff_buffer must have the \
-               same length! Instead, left is size {} and right is size {}",
-               left.len(), right.len());
+    /// Count the matching voxels in the next chunk, splitting it at volume
+    /// boundaries. Chunks hold whole voxels, so the pieces do too.
+    fn count(&self, a: &[u8], b: &[u8], transmuter: &Transmuter) -> usize {
+        let mut matches = self.matches.borrow_mut();
+        let mut total = 0;
+        let mut start = 0;
+        while start < a.len() {
+            let position = self.position.get();
+            let volume = position / self.volume_bytes;
+            let end = a.len()
+                .min(start + self.volume_bytes - position % self.volume_bytes);
+            let n = transmuter(&a[start..end], &b[start..end]);
+            if let Some(m) = matches.get_mut(volume) {
+                *m += n;
+            }
+            total += n;
+            self.position.set(position + end - start);
+            start = end;
+        }
+        total
     }
-    // Iterate and compare bytes
-    let mut matches: usize = 0;
-    for it in left.iter().zip(right.iter()) {
-        let (a, b) = it;
-        matches += (a == b) as usize;
+}
+
+/// Record the counts of each volume of a 4D image as a sub-diff, in the
+/// same unit as the image's counts.
+fn add_volumes(d: &mut Diff, tally: VolumeTally, bytes_per_voxel: usize,
+               unit: Unit) {
+    let volume_voxels = tally.volume_bytes / bytes_per_voxel;
+    for (t, matched) in tally.matches.into_inner().into_iter().enumerate() {
+        let mut subdiff = Diff::new(&format!("{}:volume-{}", d.left, t),
+                                    &format!("{}:volume-{}", d.right, t));
+        match unit {
+            Unit::Bytes => subdiff.set_counts(matched * bytes_per_voxel,
+                                              tally.volume_bytes,
+                                              Unit::Bytes),
+            _ => subdiff.set_counts(matched, volume_voxels, Unit::Elements),
+        }
+        subdiff.matches = matched == volume_voxels;
+        if !subdiff.matches {
+            subdiff.additional_info = format!(
+                "Voxels diverge: {} of {} match ({:04.2}%)",
+                matched, volume_voxels, subdiff.similarity * 100.0
+            );
+            subdiff.report = format!("  volume {}: {}", t,
+                                     subdiff.additional_info);
+        }
+        d.sub_diffs.push(Box::new(subdiff));
+    }
+}
+
+/// List the first few diverging volumes of a 4D image under its report.
+fn report_volumes(d: &mut Diff) {
+    let diverging: Vec<&str> = d.sub_diffs.iter()
+        .filter(|s| !s.matches)
+        .map(|s| s.report.as_str())
+        .collect();
+    for line in diverging.iter().take(MAX_REPORTED_VOLUMES) {
+        d.report.push('\n');
+        d.report.push_str(line);
+    }
+    if diverging.len() > MAX_REPORTED_VOLUMES {
+        d.report.push_str(&format!("\n  and {} more diverging volumes",
+                                   diverging.len() - MAX_REPORTED_VOLUMES));
+    }
+}
+
+/// Reverse the bytes of each `width`-byte value in a buffer, switching it
+/// between little- and big-endian order.
+fn swap_byte_order(buffer: &mut [u8], width: usize) {
+    for value in buffer.chunks_exact_mut(width) {
+        value.reverse();
+    }
+}
+
+/// Name a byte order for a report.
+fn describe_endianness(endianness: Endianness) -> &'static str {
+    match endianness {
+        Endianness::Little => "little-endian",
+        Endianness::Big => "big-endian",
+    }
+}
+
+/// Compare only the shapes and data types of two niftis, given how their
+/// headers differ.
+fn diff_nii_schemas(left: &str, right: &str,
+                    differences: Vec<header::FieldDifference>) -> Diff {
+    let differences: Vec<header::FieldDifference> = differences.into_iter()
+        .filter(|f| ["dim", "datatype", "bitpix"].contains(&f.field))
+        .collect();
+    let mut d = Diff::new(left, right);
+    d.matches = differences.is_empty();
+    if !d.matches {
+        d.additional_info = format!("Schemas diverge in {} header field(s)",
+                                    differences.len());
+        d.report = format!("{} vs. {}: {}", left, right, d.additional_info);
+        add_header_differences(&mut d, differences);
+    }
+    d
+}
+
+/// Record the header fields two images differ in as sub-diffs, one per
+/// field, and list them under the report.
+fn add_header_differences(d: &mut Diff,
+                          differences: Vec<header::FieldDifference>) {
+    for difference in differences {
+        let mut subdiff = Diff::new(
+            &format!("{}:{}", d.left, difference.field),
+            &format!("{}:{}", d.right, difference.field)
+        );
+        subdiff.additional_info = format!("{} vs. {}", difference.left,
+                                          difference.right);
+        subdiff.report = format!("  {}: {}", difference.field,
+                                 subdiff.additional_info);
+        d.report.push('\n');
+        d.report.push_str(&subdiff.report);
+        d.sub_diffs.push(Box::new(subdiff));
     }
-    return matches
 }
+
+/// Hash each side of a diff whose contents weren't hashed as they were
+/// streamed, if hashing is on.
+fn hash_both(d: &mut Diff, opts: &DiffOptions) -> Result<()> {
+    let hash = |path: &str| {
+        opts.hasher()
+            .map(|h| hash_file(path, h).map_err(|e| RsdiffError::io(path, e)))
+            .transpose()
+    };
+    if d.left_hash.is_none() {
+        d.left_hash = hash(&d.left)?;
+    }
+    if d.right_hash.is_none() {
+        d.right_hash = hash(&d.right)?;
+    }
+    Ok(())
+}
+
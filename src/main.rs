@@ -1,10 +1,73 @@
-/// rsdiff
-/// Will use Rust to perform abstracted diff
+//! rsdiff
+//! Will use Rust to perform abstracted diff
+
+use std::{
+    collections::BTreeMap,
+    env,
+    fs::File,
+    io::{self, BufReader, BufWriter, IsTerminal, Write},
+    path::{Path, PathBuf},
+    process,
+    thread,
+    time::Duration,
+};
 
 // Build a friendly CLI
-use clap::{Arg, App};
+use clap::{
+    Arg, App, AppSettings, ArgGroup, ArgMatches, SubCommand, value_t,
+};
+use globset::Glob;
+use indicatif::{ProgressBar, ProgressStyle};
 // Use our own library
-use rsdiff::differ;
+use rsdiff::{
+    affinity::{self, parse_cpu_list},
+    checksums::Checksums,
+    badge,
+    hash,
+    heatmap,
+    differ_with_options, Diff, DiffOptions, FloatComparison,
+    Metric, MixedCompression, RsdiffError, SymlinkPolicy, Unit,
+    diffset::DiffSet,
+    drift::DriftSummary,
+    config::Config,
+    database,
+    env::diff_envs,
+    image::diff_images_with_options,
+    options::{
+        load_ignore_offsets, parse_byte, parse_byte_range, parse_path_tolerance,
+        parse_shard, parse_size,
+    },
+    progress::{self, ProgressEvent},
+    diffimage::VoxelDifference,
+    expect::{diff_expected_with_options, Expected},
+    explain::explain,
+    fingerprint::{diff_fingerprints, Fingerprint},
+    manifest::{check_manifest, Manifest},
+    preset::Preset,
+    provenance::Provenance,
+    stream::EventStream,
+    report::{self, Format, ReportWriter},
+    sign::MinisignKey,
+    tarstream::diff_tar_with_options,
+    interrupt,
+    metrics::{self, Metrics},
+    notify::{self, Summary},
+    triage::triage,
+    watch::{self, Watch},
+    workspace::Workspace,
+};
+
+/// Exit status for objects that differ
+const EXIT_DIFFERENT: i32 = 1;
+/// Exit status for a comparison that couldn't be carried out
+const EXIT_ERROR: i32 = 2;
+/// Exit status for a run cut short by the user, as for SIGINT in shells
+const EXIT_INTERRUPTED: i32 = 130;
+/// Verbosity without -q or -v: 0 prints no report, and each -v adds to it
+const DEFAULT_VERBOSITY: u64 = 1;
+/// How long files must go unchanged in watch mode before they are compared
+/// again, so that files being written are compared whole
+const WATCH_SETTLE: Duration = Duration::from_millis(500);
 
 /// Run a differ on two objects
 fn main() {
@@ -13,26 +76,1875 @@ fn main() {
                     .version("0.1")
                     .author("Joshua B. Teves <joshua.teves@nih.gov>")
                     .about("Performs abstract diffs")
+                    .setting(AppSettings::SubcommandsNegateReqs)
+                    .setting(AppSettings::ArgsNegateSubcommands)
                     .arg(Arg::with_name("left")
                          .help("The left object to diff")
-                         .required(true))
+                         .required_unless("left-tar"))
                     .arg(Arg::with_name("right")
                          .help("The right object to diff")
-                         .required(true))
+                         .required_unless("left-tar"))
+                    .arg(Arg::with_name("mode")
+                         .long("mode")
+                         .takes_value(true)
+                         .possible_values(&["auto", "image", "text"])
+                         .default_value("auto")
+                         .help("How to compare: pick by file type, \
+                                compare the filesystems of two container \
+                                image tarballs, or compare every file as \
+                                text")
+                         .required(false))
+                    .arg(Arg::with_name("left-tar")
+                         .long("left-tar")
+                         .takes_value(true)
+                         .value_name("FILE")
+                         .help("Use the tar archive FILE, or - for standard \
+                                input, as the left tree; then only the right \
+                                directory is given")
+                         .required(false))
+                    .arg(Arg::with_name("tar-strip-components")
+                         .long("tar-strip-components")
+                         .takes_value(true)
+                         .value_name("N")
+                         .help("Strip N leading directories from the paths \
+                                in the --left-tar archive, as tar \
+                                --strip-components does, rather than \
+                                stripping the directory it looks to have \
+                                been made from")
+                         .requires("left-tar")
+                         .required(false))
+                    .arg(Arg::with_name("explain")
+                         .long("explain")
+                         .takes_value(false)
+                         .help("Also explain the verdict: how the objects \
+                                were compared, which options were in \
+                                effect, and what was ignored")
+                         .conflicts_with("left-tar")
+                         .required(false))
                     .arg(Arg::with_name("debug")
                          .long("debug")
                          .takes_value(false)
                          .help("Run in debug mode")
                          .required(false))
-                    .get_matches();
+                    .arg(Arg::with_name("quiet")
+                         .long("quiet")
+                         .short("q")
+                         .takes_value(false)
+                         .help("Print no report; the exit status says \
+                                whether the objects match. Files asked \
+                                for, like --output, are still written")
+                         .conflicts_with("verbose")
+                         .required(false))
+                    .arg(Arg::with_name("verbose")
+                         .long("verbose")
+                         .short("v")
+                         .multiple(true)
+                         .takes_value(false)
+                         .help("Also list the files that match; given \
+                                twice, also how long each file took and \
+                                the entries that were skipped")
+                         .required(false))
+                    .arg(Arg::with_name("emit-hashes")
+                         .long("emit-hashes")
+                         .takes_value(true)
+                         .value_name("FILE")
+                         .help("Write the hashes computed during comparison \
+                                to FILE in the format of sha256sum, or of \
+                                md5sum and the like for other algorithms")
+                         .required(false))
+                    .arg(Arg::with_name("hash-algorithm")
+                         .long("hash-algorithm")
+                         .takes_value(true)
+                         .value_name("NAME")
+                         .validator(|s| match hash::hasher(&s) {
+                             Some(_) => Ok(()),
+                             None => Err(format!(
+                                 "known algorithms are {}",
+                                 hash::algorithms().join(", ")
+                             )),
+                         })
+                         .help("Hash with NAME: sha256, the default, \
+                                blake3, xxh3, md5, or crc32, to match \
+                                existing manifests")
+                         .required(false))
+                    .arg(Arg::with_name("precheck")
+                         .long("precheck")
+                         .takes_value(false)
+                         .help("Hash both files of each pair first, and \
+                                compare in full only those whose hashes \
+                                differ; faster for mostly identical copies")
+                         .required(false))
+                    .arg(Arg::with_name("checksums")
+                         .long("checksums")
+                         .takes_value(true)
+                         .value_name("FILE")
+                         .help("Also check each file against the hash \
+                                recorded for its path in FILE, a CSV file \
+                                or SQLite database of path, algorithm, and \
+                                hash; files that don't match make the \
+                                comparison fail")
+                         .required(false))
+                    .arg(Arg::with_name("output")
+                         .long("output")
+                         .short("o")
+                         .takes_value(true)
+                         .value_name("FILE")
+                         .help("Write the report to FILE instead of standard \
+                                output, compressed if FILE ends in .gz or \
+                                .zst, e.g. report.json.zst")
+                         .required(false))
+                    .arg(Arg::with_name("output-db")
+                         .long("output-db")
+                         .takes_value(true)
+                         .value_name("FILE")
+                         .help("Record the results in the SQLite database \
+                                FILE, creating it if need be; re-runs update \
+                                each file's result and add to its history")
+                         .required(false))
+                    .arg(Arg::with_name("diff-image")
+                         .long("diff-image")
+                         .takes_value(true)
+                         .value_name("FILE")
+                         .help("Write left - right per voxel of two NIfTI \
+                                images to FILE, e.g. diff.nii.gz")
+                         .required(false))
+                    .arg(Arg::with_name("mismatch-mask")
+                         .long("mismatch-mask")
+                         .takes_value(true)
+                         .value_name("FILE")
+                         .help("Write a mask of the voxels that differ \
+                                between two NIfTI images to FILE")
+                         .required(false))
+                    .arg(Arg::with_name("metrics")
+                         .long("metrics")
+                         .takes_value(true)
+                         .value_name("FILE")
+                         .help("Write Prometheus metrics for the run to FILE, \
+                                e.g. in node_exporter's textfile collector \
+                                directory; failed runs are recorded too")
+                         .required(false))
+                    .arg(Arg::with_name("badge")
+                         .long("badge")
+                         .takes_value(true)
+                         .value_name("FILE")
+                         .help("Write an SVG badge of how much of the \
+                                comparison matches to FILE, e.g. for a \
+                                README; failed runs get one too")
+                         .required(false))
+                    .arg(Arg::with_name("badge-label")
+                         .long("badge-label")
+                         .takes_value(true)
+                         .value_name("TEXT")
+                         .default_value("dataset match")
+                         .help("Label the badge with TEXT")
+                         .required(false))
+                    .arg(Arg::with_name("notify-webhook")
+                         .long("notify-webhook")
+                         .takes_value(true)
+                         .value_name("URL")
+                         .help("Post a JSON summary of the run to URL when \
+                                it finishes or fails, e.g. a Slack or Teams \
+                                incoming webhook")
+                         .required(false))
+                    .arg(Arg::with_name("provenance")
+                         .long("provenance")
+                         .takes_value(true)
+                         .value_name("FILE")
+                         .help("Write a JSON audit record of the comparison \
+                                to FILE")
+                         .required(false))
+                    .arg(Arg::with_name("voxel-unit")
+                         .long("voxel-unit")
+                         .takes_value(true)
+                         .possible_values(&["elements", "bytes"])
+                         .default_value("elements")
+                         .help("Count voxel similarity per element or per \
+                                byte")
+                         .required(false))
+                    .arg(Arg::with_name("scaled-voxels")
+                         .long("scaled-voxels")
+                         .takes_value(false)
+                         .help("Compare NIfTI voxels after applying each \
+                                header's scl_slope and scl_inter, rather \
+                                than as stored")
+                         .required(false))
+                    .arg(Arg::with_name("sentinel")
+                         .long("sentinel")
+                         .takes_value(true)
+                         .multiple(true)
+                         .number_of_values(1)
+                         .value_name("VALUE")
+                         .requires("scaled-voxels")
+                         .validator(|s| {
+                             s.parse::<f64>().map(|_| ())
+                                 .map_err(|e| e.to_string())
+                         })
+                         .help("Treat NIfTI voxels stored as VALUE, such as \
+                                -32768, as missing rather than scaling them; \
+                                may be repeated")
+                         .required(false))
+                    .arg(Arg::with_name("normalize-units")
+                         .long("normalize-units")
+                         .takes_value(false)
+                         .help("Compare NIfTI headers in mm and s, noting \
+                                rather than reporting differences in \
+                                xyzt_units alone")
+                         .required(false))
+                    .arg(Arg::with_name("metric")
+                         .long("metric")
+                         .takes_value(true)
+                         .possible_values(&["matches", "pearson", "dice",
+                                            "jaccard", "auto"])
+                         .default_value("matches")
+                         .help("Measure NIfTI images' similarity by the \
+                                fraction of matching voxels, their Pearson \
+                                correlation, the Dice or Jaccard overlap of \
+                                their nonzero voxels, or Dice for masks and \
+                                Pearson otherwise")
+                         .required(false))
+                    .arg(Arg::with_name("mixed-compression")
+                         .long("mixed-compression")
+                         .takes_value(true)
+                         .possible_values(&["compare", "note", "differ"])
+                         .default_value("compare")
+                         .help("When only one NIfTI file is gzipped, compare \
+                                its voxels, also note it, or also count the \
+                                files as different")
+                         .required(false))
+                    .arg(Arg::with_name("tolerance")
+                         .long("tolerance")
+                         .takes_value(true)
+                         .value_name("EPSILON")
+                         .default_value("1e-16")
+                         .help("Count floating point voxels, and numbers \
+                                in JSON and tables, as matching when they \
+                                differ by less than EPSILON")
+                         .required(false))
+                    .arg(Arg::with_name("rtol")
+                         .long("rtol")
+                         .takes_value(true)
+                         .value_name("RTOL")
+                         .conflicts_with("max-ulps")
+                         .help("Count floating point voxels as matching when \
+                                they differ by at most RTOL times the \
+                                larger of their magnitudes, instead of by \
+                                --tolerance")
+                         .required(false))
+                    .arg(Arg::with_name("max-ulps")
+                         .long("max-ulps")
+                         .takes_value(true)
+                         .value_name("N")
+                         .help("Count floating point voxels as matching when \
+                                at most N units in the last place apart, \
+                                instead of by --tolerance")
+                         .required(false))
+                    .arg(Arg::with_name("path-tolerance")
+                         .long("path-tolerance")
+                         .takes_value(true)
+                         .multiple(true)
+                         .number_of_values(1)
+                         .value_name("PATTERN=EPSILON")
+                         .validator(|s| parse_path_tolerance(&s).map(|_| ()))
+                         .help("Use EPSILON as the tolerance for JSON \
+                                numbers at pointers matching PATTERN, e.g. \
+                                EchoTime=1e-6; may be repeated")
+                         .required(false))
+                    .arg(Arg::with_name("onset-tolerance")
+                         .long("onset-tolerance")
+                         .takes_value(true)
+                         .value_name("SECONDS")
+                         .default_value("0.001")
+                         .help("Count onsets and durations in BIDS events \
+                                files as matching when they differ by less \
+                                than SECONDS")
+                         .required(false))
+                    .arg(Arg::with_name("table-key")
+                         .long("table-key")
+                         .takes_value(true)
+                         .multiple(true)
+                         .number_of_values(1)
+                         .value_name("COLUMN")
+                         .help("Align the rows of TSV and CSV tables that \
+                                have COLUMN by it, rather than by order; \
+                                may be repeated")
+                         .required(false))
+                    .arg(Arg::with_name("table-ignore-columns")
+                         .long("table-ignore-columns")
+                         .takes_value(true)
+                         .multiple(true)
+                         .number_of_values(1)
+                         .value_name("COLUMNS")
+                         .help("Leave the comma-separated COLUMNS out of \
+                                comparisons of tables; may be repeated")
+                         .required(false))
+                    .arg(Arg::with_name("drift")
+                         .long("drift")
+                         .help("Measure the largest relative difference \
+                                between numbers in each file and summarize \
+                                how they are distributed")
+                         .required(false))
+                    .arg(Arg::with_name("schema-only")
+                         .long("schema-only")
+                         .help("Compare only the structure of JSON, TSV and \
+                                CSV tables, and NIfTI images: keys, \
+                                columns, types, and shapes, not values")
+                         .required(false))
+                    .arg(Arg::with_name("column-tolerance")
+                         .long("column-tolerance")
+                         .takes_value(true)
+                         .multiple(true)
+                         .number_of_values(1)
+                         .value_name("PATTERN=EPSILON")
+                         .validator(|s| parse_path_tolerance(&s).map(|_| ()))
+                         .help("Use EPSILON as the tolerance for numeric \
+                                cells of TSV and CSV tables in columns \
+                                matching PATTERN, e.g. a_comp_cor_*=1e-5; \
+                                may be repeated")
+                         .required(false))
+                    .arg(Arg::with_name("unordered-rows")
+                         .long("unordered-rows")
+                         .help("Compare TSV and CSV tables without key \
+                                columns as multisets of rows, ignoring \
+                                their order")
+                         .required(false))
+                    .arg(Arg::with_name("unordered-array")
+                         .long("unordered-array")
+                         .takes_value(true)
+                         .multiple(true)
+                         .number_of_values(1)
+                         .value_name("PATTERN")
+                         .validator(|s| {
+                             Glob::new(&s).map(|_| ()).map_err(|e| e.to_string())
+                         })
+                         .help("Compare JSON arrays at pointers matching \
+                                PATTERN, e.g. SliceTiming, regardless of \
+                                the order of their elements; may be \
+                                repeated")
+                         .required(false))
+                    .arg(Arg::with_name("ignore-comments")
+                         .long("ignore-comments")
+                         .takes_value(false)
+                         .help("Leave comments out of comparisons of \
+                                scripts")
+                         .required(false))
+                    .arg(Arg::with_name("ignore-whitespace")
+                         .long("ignore-whitespace")
+                         .takes_value(false)
+                         .help("Leave whitespace out of comparisons of \
+                                scripts")
+                         .required(false))
+                    .arg(Arg::with_name("ignore-outputs")
+                         .long("ignore-outputs")
+                         .takes_value(false)
+                         .help("Leave cell outputs and execution counts out \
+                                of comparisons of notebooks")
+                         .required(false))
+                    .arg(Arg::with_name("ignore-metadata")
+                         .long("ignore-metadata")
+                         .takes_value(false)
+                         .help("Leave metadata out of comparisons of \
+                                notebooks")
+                         .required(false))
+                    .arg(Arg::with_name("exact-json")
+                         .long("exact-json")
+                         .takes_value(false)
+                         .help("Compare JSON files byte by byte rather than \
+                                by value, for when their exact \
+                                serialization matters")
+                         .required(false))
+                    .arg(Arg::with_name("max-depth")
+                         .long("max-depth")
+                         .takes_value(true)
+                         .value_name("N")
+                         .help("Descend at most N levels of subdirectories; \
+                                deeper ones are only checked for presence")
+                         .required(false))
+                    .arg(Arg::with_name("symlinks")
+                         .long("symlinks")
+                         .takes_value(true)
+                         .possible_values(&["follow", "compare-targets",
+                                            "skip"])
+                         .default_value("follow")
+                         .help("Follow symbolic links, compare them by \
+                                where they point, or skip them inside \
+                                directories; dangling links are always \
+                                compared by where they point")
+                         .required(false))
+                    .arg(Arg::with_name("new-file")
+                         .long("new-file")
+                         .short("N")
+                         .takes_value(false)
+                         .help("Compare entries only one directory holds \
+                                against an empty file, reporting their \
+                                sizes rather than only their names")
+                         .required(false))
+                    .arg(Arg::with_name("progress")
+                         .long("progress")
+                         .takes_value(false)
+                         .help("Show a progress bar on standard error while \
+                                comparing, if it is a terminal")
+                         .required(false))
+                    .arg(Arg::with_name("stream")
+                         .long("stream")
+                         .takes_value(true)
+                         .value_name("URL")
+                         .validator(|s| {
+                             if s.starts_with("tcp://")
+                                 || s.starts_with("unix://") {
+                                 Ok(())
+                             }
+                             else {
+                                 Err(String::from("expected tcp://host:port \
+                                                   or unix:///path"))
+                             }
+                         })
+                         .help("Stream progress events and the final \
+                                summary as JSON lines to the socket at \
+                                URL, tcp://host:port or unix:///path, for \
+                                live dashboards")
+                         .required(false))
+                    .arg(Arg::with_name("fail-fast")
+                         .long("fail-fast")
+                         .takes_value(false)
+                         .help("Stop comparing a directory at its first \
+                                difference")
+                         .required(false))
+                    .arg(Arg::with_name("fail-on-inaccessible")
+                         .long("fail-on-inaccessible")
+                         .takes_value(false)
+                         .help("Fail on directory entries that may not be \
+                                read, rather than leaving them out")
+                         .required(false))
+                    .arg(Arg::with_name("watch")
+                         .long("watch")
+                         .takes_value(false)
+                         .help("Compare again whenever files on either side \
+                                change, printing how the results changed, \
+                                until interrupted")
+                         .required(false))
+                    .arg(Arg::with_name("shard")
+                         .long("shard")
+                         .takes_value(true)
+                         .value_name("I/N")
+                         .validator(|s| parse_shard(&s).map(|_| ()))
+                         .help("Compare only shard I, counting from 0, of \
+                                N disjoint shards of a directory comparison, \
+                                e.g. --shard $SLURM_ARRAY_TASK_ID/16; \
+                                combine the shards' JSON reports with \
+                                rsdiff merge")
+                         .required(false))
+                    .arg(Arg::with_name("color")
+                         .long("color")
+                         .takes_value(true)
+                         .value_name("WHEN")
+                         .possible_values(&["auto", "always", "never"])
+                         .default_value("auto")
+                         .help("Color the text report always, never, or \
+                                only when it goes to a terminal and \
+                                NO_COLOR isn't set")
+                         .required(false))
+                    .arg(Arg::with_name("no-color")
+                         .long("no-color")
+                         .takes_value(false)
+                         .help("Don't color the text report; the same as \
+                                --color never")
+                         .required(false))
+                    .arg(Arg::with_name("byte-range")
+                         .long("byte-range")
+                         .takes_value(true)
+                         .multiple(true)
+                         .number_of_values(1)
+                         .value_name("START..END")
+                         .validator(|s| parse_byte_range(&s).map(|_| ()))
+                         .help("Only compare this byte range of both files; \
+                                may be repeated")
+                         .required(false))
+                    .arg(Arg::with_name("exclude")
+                         .long("exclude")
+                         .takes_value(true)
+                         .multiple(true)
+                         .number_of_values(1)
+                         .value_name("PATTERN")
+                         .validator(|s| {
+                             Glob::new(&s).map(|_| ()).map_err(|e| e.to_string())
+                         })
+                         .help("Leave directory entries matching the glob \
+                                PATTERN out of the comparison; may be \
+                                repeated")
+                         .required(false))
+                    .arg(Arg::with_name("ignore-key")
+                         .long("ignore-key")
+                         .takes_value(true)
+                         .multiple(true)
+                         .number_of_values(1)
+                         .value_name("PATTERN")
+                         .validator(|s| {
+                             Glob::new(&s).map(|_| ()).map_err(|e| e.to_string())
+                         })
+                         .help("Leave values of JSON files at pointers \
+                                matching PATTERN, e.g. /GeneratedBy, out of \
+                                the comparison; may be repeated")
+                         .required(false))
+                    .arg(Arg::with_name("preset")
+                         .long("preset")
+                         .takes_value(true)
+                         .multiple(true)
+                         .number_of_values(1)
+                         .value_name("NAME")
+                         .help("Use the settings of the preset NAME: \
+                                strict, ci, fmriprep, archive-verify, or \
+                                neuroimaging, or one from the config, \
+                                which replaces a built-in one of the same \
+                                name. Options given here take precedence; \
+                                see `rsdiff presets show NAME`. May be \
+                                repeated")
+                         .required(false))
+                    .arg(Arg::with_name("ignore-offsets")
+                         .long("ignore-offsets")
+                         .takes_value(true)
+                         .value_name("FILE")
+                         .help("Leave the byte ranges listed in the JSON \
+                                FILE out of byte-wise comparisons")
+                         .required(false))
+                    .arg(Arg::with_name("max-shift")
+                         .long("max-shift")
+                         .takes_value(true)
+                         .value_name("BYTES")
+                         .default_value("65536")
+                         .help("Largest size difference to test as a \
+                                constant offset between files; 0 disables")
+                         .required(false))
+                    .arg(Arg::with_name("heatmap")
+                         .long("heatmap")
+                         .takes_value(true)
+                         .value_name("N")
+                         .validator(|s| match s.parse::<usize>() {
+                             Ok(0) | Err(_) => Err(format!(
+                                 "{} is not a positive number", s
+                             )),
+                             Ok(_) => Ok(()),
+                         })
+                         .help("Chart where files of equal size that \
+                                differ mismatch, dividing them into N \
+                                stretches")
+                         .required(false))
+                    .arg(Arg::with_name("heatmap-png")
+                         .long("heatmap-png")
+                         .takes_value(true)
+                         .value_name("FILE")
+                         .requires("heatmap")
+                         .help("Also draw the chart of the two files \
+                                compared to FILE as a PNG")
+                         .required(false))
+                    .arg(Arg::with_name("bgzf-blocks")
+                         .long("bgzf-blocks")
+                         .takes_value(false)
+                         .help("Also report differences in BGZF block \
+                                boundaries")
+                         .required(false))
+                    .arg(Arg::with_name("compressed-bytes")
+                         .long("compressed-bytes")
+                         .takes_value(false)
+                         .help("Compare gzipped files by their compressed \
+                                bytes rather than their contents")
+                         .required(false))
+                    .arg(Arg::with_name("format")
+                         .long("format")
+                         .takes_value(true)
+                         .possible_values(&["text", "tsv", "json", "msgpack",
+                                            "cbor"])
+                         .default_value("text")
+                         .help("Report differences as text, as a TSV \
+                                table of file, status, similarity, and \
+                                seconds for workflow managers, or as a \
+                                JSON tree, or that tree encoded more \
+                                compactly as MessagePack or CBOR")
+                         .required(false))
+                    .arg(Arg::with_name("datalad")
+                         .long("datalad")
+                         .takes_value(false)
+                         .help("Compare DataLad datasets by content, \
+                                skipping run records and dataset config, \
+                                and report dataset IDs")
+                         .required(false))
+                    .arg(Arg::with_name("jobs")
+                         .long("jobs")
+                         .short("j")
+                         .takes_value(true)
+                         .value_name("N")
+                         .default_value("1")
+                         .help("Compare up to N directory entries, or \
+                                ranges of a large file, at once; 0 uses \
+                                every available CPU")
+                         .required(false))
+                    .arg(Arg::with_name("split-above")
+                         .long("split-above")
+                         .takes_value(true)
+                         .value_name("SIZE")
+                         .default_value("1G")
+                         .validator(|s| parse_size(&s).map(|_| ()))
+                         .help("Compare files of at least SIZE bytes in \
+                                ranges on the --jobs threads; accepts K, M, \
+                                G, and T suffixes, and 0 never splits files")
+                         .required(false))
+                    .arg(Arg::with_name("max-memory")
+                         .long("max-memory")
+                         .takes_value(true)
+                         .value_name("SIZE")
+                         .validator(|s| parse_size(&s).map(|_| ()))
+                         .help("Keep comparison buffers within SIZE bytes; \
+                                accepts K, M, G, and T suffixes")
+                         .required(false))
+                    .arg(Arg::with_name("workspace")
+                         .long("workspace")
+                         .takes_value(true)
+                         .value_name("DIR")
+                         .help("Write intermediate files, such as unpacked \
+                                container images, under DIR rather than the \
+                                system's temporary directory")
+                         .required(false))
+                    .arg(Arg::with_name("max-workspace-size")
+                         .long("max-workspace-size")
+                         .takes_value(true)
+                         .value_name("SIZE")
+                         .validator(|s| parse_size(&s).map(|_| ()))
+                         .help("Fail comparisons whose intermediate files \
+                                would take more than SIZE bytes altogether; \
+                                accepts K, M, G, and T suffixes")
+                         .required(false))
+                    .arg(Arg::with_name("mmap")
+                         .long("mmap")
+                         .takes_value(false)
+                         .help("Map uncompressed files into memory to \
+                                compare them, which is faster for very \
+                                large files; a file truncated during the \
+                                comparison aborts rsdiff")
+                         .required(false))
+                    .arg(Arg::with_name("cpus")
+                         .long("cpus")
+                         .takes_value(true)
+                         .value_name("LIST")
+                         .validator(|s| parse_cpu_list(&s).map(|_| ()))
+                         .help("Pin comparison threads to the CPUs in LIST, \
+                                e.g. 0-7,16-23, to keep them and their \
+                                buffers on one NUMA node")
+                         .required(false))
+                    .arg(Arg::with_name("exit-zero")
+                         .long("exit-zero")
+                         .takes_value(false)
+                         .help("Exit with status 0 even if the objects \
+                                differ; errors still exit with status 2")
+                         .required(false))
+                    .arg(Arg::with_name("min-similarity")
+                         .long("min-similarity")
+                         .takes_value(true)
+                         .value_name("S")
+                         .validator(|s| match s.parse::<f32>() {
+                             Ok(s) if (0.0..=1.0).contains(&s) => Ok(()),
+                             Ok(_) => Err(String::from("must be from 0 to 1")),
+                             Err(e) => Err(e.to_string()),
+                         })
+                         .help("Exit with status 0 if the objects differ \
+                                but their similarity, as measured by \
+                                --metric, is at least S, e.g. 0.999; \
+                                differences are still reported")
+                         .required(false))
+                    .arg(Arg::with_name("config")
+                         .long("config")
+                         .takes_value(true)
+                         .value_name("FILE")
+                         .help("Read settings from FILE instead of the \
+                                default config location")
+                         .required(false))
+                    .group(ArgGroup::with_name("signed")
+                           .args(&["output", "provenance"])
+                           .multiple(true))
+                    .arg(Arg::with_name("sign")
+                         .long("sign")
+                         .takes_value(false)
+                         .requires("signed")
+                         .conflicts_with("watch")
+                         .help("Sign the report written with --output and \
+                                the --provenance record, each with a \
+                                .minisig file next to it, using the \
+                                minisign key named by signing_key in the \
+                                config")
+                         .required(false))
+                    .subcommand(SubCommand::with_name("env")
+                                .about("Compares two conda or virtualenv \
+                                        environments, ignoring caches and \
+                                        install bookkeeping")
+                                .arg(Arg::with_name("left")
+                                     .help("The left environment prefix")
+                                     .required(true))
+                                .arg(Arg::with_name("right")
+                                     .help("The right environment prefix")
+                                     .required(true)))
+                    .subcommand(SubCommand::with_name("merge")
+                                .about("Combines JSON reports from separate \
+                                        runs, such as array jobs or the \
+                                        shards of one comparison, into one")
+                                .arg(Arg::with_name("format")
+                                     .long("format")
+                                     .takes_value(true)
+                                     .possible_values(&["text", "tsv", "json",
+                                                        "msgpack", "cbor"])
+                                     .default_value("text")
+                                     .help("Report the combined result as \
+                                            text, TSV, JSON, MessagePack, \
+                                            or CBOR")
+                                     .required(false))
+                                .arg(Arg::with_name("output")
+                                     .long("output")
+                                     .short("o")
+                                     .takes_value(true)
+                                     .value_name("FILE")
+                                     .help("Write the combined result to \
+                                            FILE, compressed if it ends in \
+                                            .gz or .zst")
+                                     .required(false))
+                                .arg(Arg::with_name("reports")
+                                     .help("Reports written with --format \
+                                            json, msgpack, or cbor, \
+                                            compressed or not")
+                                     .multiple(true)
+                                     .required(true)))
+                    .subcommand(SubCommand::with_name("fingerprint")
+                                .about("Summarizes a directory tree by its \
+                                        files' count, sizes, and extensions, \
+                                        or compares two such summaries")
+                                .arg(Arg::with_name("output")
+                                     .long("output")
+                                     .short("o")
+                                     .takes_value(true)
+                                     .value_name("FILE")
+                                     .help("Write the fingerprint to FILE \
+                                            rather than standard output")
+                                     .required(false))
+                                .arg(exclude_arg())
+                                .arg(Arg::with_name("paths")
+                                     .help("A directory to fingerprint, or \
+                                            two directories or saved \
+                                            fingerprints to compare")
+                                     .multiple(true)
+                                     .min_values(1)
+                                     .max_values(2)
+                                     .required(true)))
+                    .subcommand(SubCommand::with_name("manifest")
+                                .about("Records the size and hash of every \
+                                        file in a directory tree, for \
+                                        checking a copy of it elsewhere")
+                                .arg(Arg::with_name("output")
+                                     .long("output")
+                                     .short("o")
+                                     .takes_value(true)
+                                     .value_name("FILE")
+                                     .help("Write the manifest to FILE \
+                                            rather than standard output")
+                                     .required(false))
+                                .arg(Arg::with_name("hash-algorithm")
+                                     .long("hash-algorithm")
+                                     .takes_value(true)
+                                     .value_name("NAME")
+                                     .validator(|s| match hash::hasher(&s) {
+                                         Some(_) => Ok(()),
+                                         None => Err(format!(
+                                             "known algorithms are {}",
+                                             hash::algorithms().join(", ")
+                                         )),
+                                     })
+                                     .help("Hash with NAME rather than \
+                                            sha256")
+                                     .required(false))
+                                .arg(exclude_arg())
+                                .arg(jobs_arg("Hash up to N files at once; \
+                                               0 uses every available CPU"))
+                                .arg(Arg::with_name("dir")
+                                     .help("The directory to record")
+                                     .required(true)))
+                    .subcommand(SubCommand::with_name("check")
+                                .about("Checks a directory tree against a \
+                                        manifest of another")
+                                .arg(Arg::with_name("format")
+                                     .long("format")
+                                     .takes_value(true)
+                                     .possible_values(&["text", "tsv", "json",
+                                                        "msgpack", "cbor"])
+                                     .default_value("text")
+                                     .help("Report the result as text, TSV, \
+                                            JSON, MessagePack, or CBOR")
+                                     .required(false))
+                                .arg(Arg::with_name("output")
+                                     .long("output")
+                                     .short("o")
+                                     .takes_value(true)
+                                     .value_name("FILE")
+                                     .help("Write the result to FILE, \
+                                            compressed if it ends in .gz or \
+                                            .zst")
+                                     .required(false))
+                                .arg(exclude_arg())
+                                .arg(jobs_arg("Hash up to N files at once; \
+                                               0 uses every available CPU"))
+                                .arg(Arg::with_name("dir")
+                                     .help("The directory to check")
+                                     .required(true))
+                                .arg(Arg::with_name("manifest")
+                                     .help("The manifest to check it \
+                                            against")
+                                     .required(true)))
+                    .subcommand(SubCommand::with_name("expect")
+                                .about("Checks that a file holds nothing \
+                                        but a constant byte or a repeated \
+                                        pattern, such as zeros")
+                                .arg(Arg::with_name("all-zero")
+                                     .long("all-zero")
+                                     .takes_value(false)
+                                     .help("Expect every byte to be zero")
+                                     .required(false))
+                                .arg(Arg::with_name("fill-value")
+                                     .long("fill-value")
+                                     .takes_value(true)
+                                     .value_name("BYTE")
+                                     .validator(|s| parse_byte(&s).map(|_| ()))
+                                     .help("Expect every byte to be BYTE, \
+                                            in decimal or 0x-prefixed hex")
+                                     .required(false))
+                                .arg(Arg::with_name("pattern-file")
+                                     .long("pattern-file")
+                                     .takes_value(true)
+                                     .value_name("FILE")
+                                     .help("Expect the contents of FILE \
+                                            repeated throughout")
+                                     .required(false))
+                                .group(ArgGroup::with_name("expected")
+                                       .args(&["all-zero", "fill-value",
+                                               "pattern-file"])
+                                       .required(true))
+                                .arg(Arg::with_name("format")
+                                     .long("format")
+                                     .takes_value(true)
+                                     .possible_values(&["text", "tsv", "json",
+                                                        "msgpack", "cbor"])
+                                     .default_value("text")
+                                     .help("Report the result as text, TSV, \
+                                            JSON, MessagePack, or CBOR")
+                                     .required(false))
+                                .arg(Arg::with_name("output")
+                                     .long("output")
+                                     .short("o")
+                                     .takes_value(true)
+                                     .value_name("FILE")
+                                     .help("Write the result to FILE, \
+                                            compressed if it ends in .gz or \
+                                            .zst")
+                                     .required(false))
+                                .arg(Arg::with_name("heatmap")
+                                     .long("heatmap")
+                                     .takes_value(true)
+                                     .value_name("N")
+                                     .validator(|s| match s.parse::<usize>() {
+                                         Ok(0) | Err(_) => Err(format!(
+                                             "{} is not a positive number", s
+                                         )),
+                                         Ok(_) => Ok(()),
+                                     })
+                                     .help("Chart where the file strays \
+                                            from what was expected, \
+                                            dividing it into N stretches")
+                                     .required(false))
+                                .arg(Arg::with_name("file")
+                                     .help("The file to check")
+                                     .required(true)))
+                    .subcommand(SubCommand::with_name("presets")
+                                .about("Shows what presets do")
+                                .setting(
+                                    AppSettings::SubcommandRequiredElseHelp
+                                )
+                                .subcommand(SubCommand::with_name("show")
+                                    .about("Prints the settings of a \
+                                            preset, as it would be written \
+                                            in the config")
+                                    .arg(Arg::with_name("config")
+                                         .long("config")
+                                         .takes_value(true)
+                                         .value_name("FILE")
+                                         .help("Read presets from FILE \
+                                                instead of the default \
+                                                config location")
+                                         .required(false))
+                                    .arg(Arg::with_name("name")
+                                         .help("The preset to show")
+                                         .required(true))))
+                    .subcommand(SubCommand::with_name("triage")
+                                .about("Checks a single file for damage")
+                                .arg(Arg::with_name("file")
+                                     .help("The file to check")
+                                     .required(true)))
+                    .get_matches_safe()
+                    .unwrap_or_else(|e| usage_error(e));
 
-    let left = matches.value_of("left").unwrap();
-    let right = matches.value_of("right").unwrap();
-    let d = differ(left, right);
-    if !d.matches {
-        println!("{}", d.report);
+    if let Some(sub) = matches.subcommand_matches("triage") {
+        run_triage(sub);
+    }
+    if let Some(sub) = matches.subcommand_matches("env") {
+        run_env(sub);
+    }
+    if let Some(sub) = matches.subcommand_matches("merge") {
+        run_merge(sub);
+    }
+    if let Some(sub) = matches.subcommand_matches("fingerprint") {
+        run_fingerprint(sub);
+    }
+    if let Some(sub) = matches.subcommand_matches("manifest") {
+        run_manifest(sub);
+    }
+    if let Some(sub) = matches.subcommand_matches("check") {
+        run_check(sub);
+    }
+    if let Some(sub) = matches.subcommand_matches("expect") {
+        run_expect(sub);
+    }
+    if let Some(sub) = matches.subcommand_matches("presets") {
+        run_presets(sub);
+    }
+
+    // With a tar stream on the left, the only path given is the right one
+    let left_tar = matches.value_of("left-tar");
+    let (left, right) = match left_tar {
+        Some(tar) => {
+            if matches.is_present("right") {
+                eprintln!("Give only the right directory with --left-tar");
+                process::exit(EXIT_ERROR);
+            }
+            (tar, matches.value_of("left").unwrap())
+        }
+        None => (matches.value_of("left").unwrap(),
+                 matches.value_of("right").unwrap()),
+    };
+    let config = Config::load(matches.value_of("config"))
+        .unwrap_or_else(|e| {
+            eprintln!("rsdiff: {}", e);
+            process::exit(EXIT_ERROR);
+        });
+    let presets = presets(&matches, &config);
+    let format = presets.iter().rev()
+        .find_map(|preset| preset.format)
+        .unwrap_or_else(|| {
+            value_t!(matches, "format", Format)
+                .unwrap_or_else(|e| usage_error(e))
+        });
+    let defaults = DiffOptions::default();
+    let mut opts = DiffOptions {
+        hash: matches.is_present("emit-hashes"),
+        hash_algorithm: matches.value_of("hash-algorithm")
+            .map(String::from)
+            .unwrap_or_else(|| defaults.hash_algorithm.clone()),
+        precheck: matches.is_present("precheck"),
+        voxel_unit: value_t!(matches, "voxel-unit", Unit)
+            .unwrap_or_else(|e| usage_error(e)),
+        scaled_voxels: matches.is_present("scaled-voxels"),
+        sentinels: matches.values_of("sentinel")
+            .map(|v| v.map(|s| s.parse().unwrap()).collect())
+            .unwrap_or_default(),
+        normalize_units: matches.is_present("normalize-units"),
+        metric: value_t!(matches, "metric", Metric)
+            .unwrap_or_else(|e| usage_error(e)),
+        mixed_compression: value_t!(matches, "mixed-compression",
+                                    MixedCompression)
+            .unwrap_or_else(|e| usage_error(e)),
+        tolerance: value_t!(matches, "tolerance", f64)
+            .unwrap_or_else(|e| usage_error(e)),
+        float_comparison: if matches.is_present("rtol") {
+            FloatComparison::Relative(value_t!(matches, "rtol", f64)
+                .unwrap_or_else(|e| usage_error(e)))
+        }
+        else if matches.is_present("max-ulps") {
+            FloatComparison::Ulps(value_t!(matches, "max-ulps", u64)
+                .unwrap_or_else(|e| usage_error(e)))
+        }
+        else {
+            FloatComparison::Absolute
+        },
+        path_tolerances: matches.values_of("path-tolerance")
+            .map(|v| v.map(|t| parse_path_tolerance(t).unwrap()).collect())
+            .unwrap_or_default(),
+        onset_tolerance: value_t!(matches, "onset-tolerance", f64)
+            .unwrap_or_else(|e| usage_error(e)),
+        table_keys: matches.values_of("table-key")
+            .map(|v| v.map(String::from).collect())
+            .unwrap_or_default(),
+        ignore_columns: matches.values_of("table-ignore-columns")
+            .map(|v| v.flat_map(|c| c.split(',')).map(String::from).collect())
+            .unwrap_or_default(),
+        column_tolerances: matches.values_of("column-tolerance")
+            .map(|v| v.map(|t| parse_path_tolerance(t).unwrap()).collect())
+            .unwrap_or_default(),
+        unordered_rows: matches.is_present("unordered-rows"),
+        schema_only: matches.is_present("schema-only"),
+        drift: matches.is_present("drift"),
+        unordered_arrays: matches.values_of("unordered-array")
+            .map(|v| v.map(String::from).collect())
+            .unwrap_or_default(),
+        ignore_keys: matches.values_of("ignore-key")
+            .map(|v| v.map(String::from).collect())
+            .unwrap_or_default(),
+        ignore_comments: matches.is_present("ignore-comments"),
+        ignore_whitespace: matches.is_present("ignore-whitespace"),
+        ignore_outputs: matches.is_present("ignore-outputs"),
+        ignore_metadata: matches.is_present("ignore-metadata"),
+        canonical_json: !matches.is_present("exact-json"),
+        force_text: matches.value_of("mode") == Some("text"),
+        max_depth: matches.value_of("max-depth")
+            .map(|_| value_t!(matches, "max-depth", usize)
+                 .unwrap_or_else(|e| usage_error(e))),
+        fail_fast: matches.is_present("fail-fast"),
+        fail_on_inaccessible: matches.is_present("fail-on-inaccessible"),
+        symlinks: value_t!(matches, "symlinks", SymlinkPolicy)
+            .unwrap_or_else(|e| usage_error(e)),
+        new_file: matches.is_present("new-file"),
+        shard: matches.value_of("shard").map(|s| parse_shard(s).unwrap()),
+        tar_strip_components: matches.value_of("tar-strip-components")
+            .map(|_| value_t!(matches, "tar-strip-components", usize)
+                 .unwrap_or_else(|e| usage_error(e))),
+        // Reports are also kept in JSON, where escape codes don't belong
+        color: format == Format::Text && wants_color(&matches),
+        byte_ranges: matches.values_of("byte-range")
+            .map(|v| v.map(|r| parse_byte_range(r).unwrap()).collect())
+            .unwrap_or_default(),
+        ignore_ranges: matches.value_of("ignore-offsets")
+            .map(|path| load_ignore_offsets(path).unwrap_or_else(|e| {
+                eprintln!("rsdiff: {}", e);
+                process::exit(EXIT_ERROR);
+            }))
+            .unwrap_or_default(),
+        max_shift: value_t!(matches, "max-shift", u64)
+            .unwrap_or_else(|e| usage_error(e)),
+        heatmap_buckets: matches.value_of("heatmap")
+            .map(|n| n.parse().unwrap()),
+        bgzf_blocks: matches.is_present("bgzf-blocks"),
+        compressed_bytes: matches.is_present("compressed-bytes"),
+        hooks: config.hooks.clone(),
+        cache_dir: config.cache_dir.as_ref()
+            .map(PathBuf::from)
+            .unwrap_or_else(|| defaults.cache_dir.clone()),
+        workspace: Workspace {
+            dir: matches.value_of("workspace")
+                .map(PathBuf::from)
+                .unwrap_or_else(|| defaults.workspace.dir.clone()),
+            max_size: matches.value_of("max-workspace-size")
+                .map(|s| parse_size(s).unwrap()),
+        },
+        datalad: matches.is_present("datalad"),
+        jobs: jobs(&matches),
+        exclude: excludes(&matches),
+        mmap: matches.is_present("mmap"),
+        split_above: parse_size(matches.value_of("split-above").unwrap())
+            .unwrap(),
+        cpus: matches.value_of("cpus")
+            .map(|c| parse_cpu_list(c).unwrap())
+            .unwrap_or_default(),
+        ..defaults
+    };
+    for preset in presets.iter() {
+        preset.apply(&mut opts);
     }
-    if matches.is_present("debug") {
+    if let Some(path) = matches.value_of("checksums") {
+        match Checksums::load(path) {
+            Ok(checksums) => opts.check_against(checksums),
+            Err(e) => {
+                eprintln!("rsdiff: {}", e);
+                process::exit(EXIT_ERROR);
+            }
+        }
+    }
+    if let Some(size) = matches.value_of("max-memory") {
+        if let Err(e) = opts.limit_memory(parse_size(size).unwrap()) {
+            eprintln!("{}", e);
+            process::exit(EXIT_ERROR);
+        }
+    }
+    let signing_key = if matches.is_present("sign") {
+        let Some(path) = config.signing_key.as_ref() else {
+            eprintln!("rsdiff: --sign needs signing_key set in the config");
+            process::exit(EXIT_ERROR);
+        };
+        let key = MinisignKey::from_file(path).unwrap_or_else(|e| {
+            eprintln!("rsdiff: {}", e);
+            process::exit(EXIT_ERROR);
+        });
+        Some(key)
+    }
+    else {
+        None
+    };
+    let verbosity = if matches.is_present("quiet") {
+        0
+    }
+    else {
+        DEFAULT_VERBOSITY + matches.occurrences_of("verbose")
+    };
+    // The first Ctrl-C winds the comparison down, the second abandons it
+    ctrlc::set_handler(|| {
+        if interrupt::requested() {
+            process::exit(EXIT_INTERRUPTED);
+        }
+        eprintln!("Interrupted; finishing the current comparison. \
+                   Press Ctrl-C again to quit now.");
+        interrupt::request();
+    }).expect("Can't set Ctrl-C handler!");
+    // Byte-wise comparisons run on this thread, so it is pinned too
+    if let Some(cpu) = affinity::next_cpu(&opts.cpus) {
+        if let Err(e) = affinity::pin_current_thread(cpu) {
+            eprintln!("Can't pin to CPU {}: {}", cpu, e);
+        }
+    }
+    if matches.is_present("watch") {
+        run_watch(&matches, left, right, &opts, format, verbosity);
+    }
+    let prov = Provenance::start(env::args().collect(), &opts, &config);
+    let stream = matches.value_of("stream").and_then(|url| {
+        match EventStream::connect(url) {
+            Ok(stream) => Some(stream),
+            Err(e) => {
+                eprintln!("rsdiff: can't stream to {}: {}", url, e);
+                None
+            }
+        }
+    });
+    if let Some(stream) = &stream {
+        stream.start(left_tar.unwrap_or(left), right);
+    }
+    let following = matches.is_present("progress") || stream.is_some();
+    let bar = if following {
+        follow_progress(matches.is_present("progress"), stream.clone())
+    }
+    else {
+        None
+    };
+    let result = compare(&matches, left, right, &opts);
+    if following {
+        progress::clear_callback();
+    }
+    if let Some(bar) = bar {
+        bar.finish_and_clear();
+    }
+    if let Some(path) = matches.value_of("metrics") {
+        let text = match &result {
+            Ok(d) => Metrics::from_diff(d).to_textfile(left, right),
+            Err(_) => metrics::failure_textfile(left, right),
+        };
+        if let Err(e) = metrics::write_textfile(path, &text) {
+            eprintln!("rsdiff: can't write {}: {}", path, e);
+        }
+    }
+    if let Some(path) = matches.value_of("badge") {
+        let label = matches.value_of("badge-label").unwrap_or_default();
+        let svg = match &result {
+            Ok(d) => badge::render(label, d),
+            Err(_) => badge::render_failure(label),
+        };
+        // Replaced whole, so a page being served never shows half a badge
+        if let Err(e) = metrics::write_textfile(path, &svg) {
+            eprintln!("rsdiff: can't write {}: {}", path, e);
+        }
+    }
+    let webhook = matches.value_of("notify-webhook");
+    let d = match result {
+        Ok(d) => d,
+        Err(e) => {
+            eprintln!("rsdiff: {}", e);
+            let summary = Summary::of_failure(
+                left, right, &e.to_string(), EXIT_ERROR, &prov.hostname
+            );
+            if let Some(stream) = &stream {
+                stream.finish(&summary);
+            }
+            send_notice(webhook, summary);
+            process::exit(EXIT_ERROR);
+        }
+    };
+    emit_report(matches.value_of("output"), &d, format, opts.drift,
+                verbosity);
+    if let (Some(path), Some(key)) = (matches.value_of("output"),
+                                      &signing_key) {
+        if let Err(e) = key.sign_file(path) {
+            eprintln!("rsdiff: {}", e);
+            process::exit(EXIT_ERROR);
+        }
+    }
+    if matches.is_present("explain") && verbosity > 0 {
+        explain_verdict(left, right, &d, &opts,
+                        matches.value_of("mode") == Some("image"),
+                        format == Format::Text
+                            && !matches.is_present("output"));
+    }
+    if matches.is_present("debug") && verbosity > 0 {
         println!("{:?}", d);
     }
+    let inaccessible: usize = d.flatten().iter()
+        .map(|sub| sub.inaccessible.len())
+        .sum();
+    if inaccessible > 0 && verbosity > 0 {
+        eprintln!("rsdiff: {} {} couldn't be read and {} left out; \
+                   --fail-on-inaccessible makes that an error",
+                  inaccessible,
+                  if inaccessible == 1 { "entry" } else { "entries" },
+                  if inaccessible == 1 { "was" } else { "were" });
+    }
+    let unverified: usize = d.flatten().iter()
+        .flat_map(|sub| [sub.left_verified, sub.right_verified])
+        .filter(|&verified| verified == Some(false))
+        .count();
+    if unverified > 0 && verbosity > 0 {
+        eprintln!("rsdiff: {} {} match the checksums in {}", unverified,
+                  if unverified == 1 { "file doesn't" } else { "files don't" },
+                  matches.value_of("checksums").unwrap());
+    }
+    if let Some(path) = matches.value_of("emit-hashes") {
+        let written = File::create(path).and_then(|out| {
+            let mut out = BufWriter::new(out);
+            for (object, hash) in d.hashes() {
+                writeln!(out, "{}  {}", hash, object)?;
+            }
+            out.flush()
+        });
+        if let Err(e) = written {
+            eprintln!("rsdiff: can't write {}: {}", path, e);
+            process::exit(EXIT_ERROR);
+        }
+    }
+    if let Some(path) = matches.value_of("provenance") {
+        let written = File::create(path).and_then(|out| {
+            let mut out = BufWriter::new(out);
+            serde_json::to_writer_pretty(&mut out, &prov.finish(&d))?;
+            out.flush()
+        });
+        if let Err(e) = written {
+            eprintln!("rsdiff: can't write {}: {}", path, e);
+            process::exit(EXIT_ERROR);
+        }
+        if let Some(key) = &signing_key {
+            if let Err(e) = key.sign_file(path) {
+                eprintln!("rsdiff: {}", e);
+                process::exit(EXIT_ERROR);
+            }
+        }
+    }
+    if let Some(path) = matches.value_of("output-db") {
+        if let Err(e) = database::save(path, &d, &prov) {
+            eprintln!("rsdiff: can't write {}: {}", path, e);
+            process::exit(EXIT_ERROR);
+        }
+    }
+    if let Some(path) = matches.value_of("heatmap-png") {
+        match &d.mismatch_density {
+            Some(density) => {
+                if let Err(e) = heatmap::write_png(path, density) {
+                    eprintln!("rsdiff: can't write {}: {}", path, e);
+                    process::exit(EXIT_ERROR);
+                }
+            }
+            None => eprintln!("rsdiff: no heatmap to draw, since the files \
+                               don't differ byte by byte"),
+        }
+    }
+    let difference_image = matches.value_of("diff-image");
+    let mismatch_mask = matches.value_of("mismatch-mask");
+    if difference_image.is_some() || mismatch_mask.is_some() {
+        write_voxel_difference(left, right, difference_image, mismatch_mask,
+                               &opts);
+    }
+    // Objects without a similarity, at -1, never meet a threshold
+    let min_similarity = matches.value_of("min-similarity")
+        .map(|s| s.parse::<f32>().unwrap());
+    let status = if d.interrupted {
+        eprintln!("Comparison was interrupted; results are incomplete");
+        EXIT_INTERRUPTED
+    }
+    else if !matches.is_present("exit-zero") && (unverified > 0
+        || !d.matches && !min_similarity.is_some_and(|s| d.similarity >= s)) {
+        EXIT_DIFFERENT
+    }
+    else {
+        0
+    };
+    let summary = Summary::of_diff(&d, status, &prov.hostname);
+    if let Some(stream) = &stream {
+        stream.finish(&summary);
+    }
+    send_notice(webhook, summary);
+    process::exit(status);
+}
+
+/// Write the difference between two NIfTI images and the mask of where
+/// they differ, as asked. Images that can't be read are an error, but
+/// images of different shapes have no difference to write.
+fn write_voxel_difference(left: &str, right: &str, image: Option<&str>,
+                          mask: Option<&str>, opts: &DiffOptions) {
+    let difference = match VoxelDifference::read(left, right) {
+        Ok(Some(difference)) => difference,
+        Ok(None) => {
+            eprintln!("rsdiff: shapes diverge, so there is no voxel \
+                       difference to write");
+            return;
+        }
+        Err(e) => {
+            eprintln!("rsdiff: {}", e);
+            process::exit(EXIT_ERROR);
+        }
+    };
+    let write_error = |path: &str, e: nifti::NiftiError| -> ! {
+        eprintln!("rsdiff: can't write {}: {}", path, e);
+        process::exit(EXIT_ERROR);
+    };
+    if let Some(path) = image {
+        if let Err(e) = difference.write_difference(path) {
+            write_error(path, e);
+        }
+    }
+    if let Some(path) = mask {
+        if let Err(e) = difference.write_mask(path, opts) {
+            write_error(path, e);
+        }
+    }
+}
+
+/// Explain how a verdict was reached, after the report if that went to
+/// standard output as text, and to standard error otherwise, so it doesn't
+/// get into machine-readable output
+fn explain_verdict(left: &str, right: &str, d: &Diff, opts: &DiffOptions,
+                   images: bool, to_stdout: bool) {
+    let explanation = if images {
+        Ok(format!("{} vs. {}\n  compared as container images, by their \
+                    merged filesystems\n", left, right))
+    }
+    else {
+        explain(left, right, d, opts)
+    };
+    match explanation {
+        Ok(text) if to_stdout => print!("{}", text),
+        Ok(text) => eprint!("{}", text),
+        Err(e) => eprintln!("rsdiff: can't explain the verdict: {}", e),
+    }
+}
+
+/// Post a run's summary to the webhook, if one was given. A webhook that
+/// can't be reached is warned about but doesn't change the exit status
+fn send_notice(webhook: Option<&str>, summary: Summary) {
+    if let Some(url) = webhook {
+        if let Err(e) = notify::post(url, &summary) {
+            eprintln!("rsdiff: can't notify {}: {}", url, e);
+        }
+    }
+}
+
+/// Whether to color a text report, going by --color and --no-color, or by
+/// default only when the report is printed to a terminal and NO_COLOR
+/// isn't set. Coloring is then forced on or off, so it doesn't depend on
+/// how the color library reads the environment.
+fn wants_color(matches: &ArgMatches) -> bool {
+    let color = match matches.value_of("color") {
+        _ if matches.is_present("no-color") => false,
+        Some("always") => true,
+        Some("never") => false,
+        _ => !matches.is_present("output")
+            && env::var_os("NO_COLOR").is_none_or(|v| v.is_empty())
+            && io::stdout().is_terminal(),
+    };
+    colored::control::set_override(color);
+    color
+}
+
+/// The --exclude option of subcommands that walk a tree.
+fn exclude_arg() -> Arg<'static, 'static> {
+    Arg::with_name("exclude")
+        .long("exclude")
+        .takes_value(true)
+        .multiple(true)
+        .number_of_values(1)
+        .value_name("PATTERN")
+        .validator(|s| Glob::new(&s).map(|_| ()).map_err(|e| e.to_string()))
+        .help("Leave entries matching the glob PATTERN out; may be repeated")
+        .required(false)
+}
+
+/// The --jobs option of subcommands that work on several files at once.
+fn jobs_arg(help: &'static str) -> Arg<'static, 'static> {
+    Arg::with_name("jobs")
+        .long("jobs")
+        .short("j")
+        .takes_value(true)
+        .value_name("N")
+        .default_value("1")
+        .help(help)
+        .required(false)
+}
+
+/// The patterns given with --exclude.
+fn excludes(matches: &ArgMatches) -> Vec<String> {
+    matches.values_of("exclude")
+        .map(|v| v.map(String::from).collect())
+        .unwrap_or_default()
+}
+
+/// The number of jobs given with --jobs, where 0 means one for each CPU.
+fn jobs(matches: &ArgMatches) -> usize {
+    match value_t!(matches, "jobs", usize).unwrap_or_else(|e| usage_error(e)) {
+        0 => thread::available_parallelism().map_or(1, |n| n.get()),
+        n => n,
+    }
+}
+
+/// Report a bad command line and exit with the error status, so that it
+/// isn't mistaken for a difference. Help and version output exit cleanly.
+fn usage_error(e: clap::Error) -> ! {
+    if e.use_stderr() {
+        eprintln!("{}", e.message);
+        process::exit(EXIT_ERROR);
+    }
+    e.exit()
+}
+
+/// Follow the comparison's progress events, passing them on to the event
+/// stream if there is one, and if `show_bar`, drawing a progress bar on
+/// standard error from them: entries compared out of those found so far,
+/// and how far into the current large file the comparison is. Comparisons
+/// of single files count their bytes instead
+fn follow_progress(show_bar: bool, stream: Option<EventStream>)
+    -> Option<ProgressBar> {
+    let bar = if show_bar {
+        ProgressBar::new(0)
+    }
+    else {
+        ProgressBar::hidden()
+    };
+    let bar = bar.with_style(bar_style(
+        "{elapsed_precise} [{bar:30}] {pos}/{len} entries {wide_msg}"
+    ));
+    let events = bar.clone();
+    let mut directories = false;
+    progress::set_callback(move |event| {
+        if let Some(stream) = &stream {
+            stream.progress(&event);
+        }
+        update_bar(&events, &mut directories, event);
+    });
+    show_bar.then_some(bar)
+}
+
+/// Move a progress bar along for an event. Once a directory has been
+/// listed, the bar counts entries rather than bytes.
+fn update_bar(events: &ProgressBar, directories: &mut bool,
+              event: ProgressEvent) {
+    match event {
+        ProgressEvent::Directory { entries, .. } => {
+            *directories = true;
+            events.inc_length(entries as u64);
+        }
+        ProgressEvent::Compared { .. } => events.inc(1),
+        ProgressEvent::Bytes { left, done, total } if !*directories => {
+            if events.length() != Some(total) {
+                events.set_style(bar_style("{elapsed_precise} [{bar:30}] \
+                                            {bytes}/{total_bytes} \
+                                            {wide_msg}"));
+                events.set_length(total);
+                events.set_message(left);
+            }
+            events.set_position(done);
+        }
+        ProgressEvent::Bytes { left, done, total } => {
+            events.set_message(format!("{} ({}%)", left, done * 100 / total));
+        }
+    }
+}
+
+/// A progress bar's style, from its template.
+fn bar_style(template: &str) -> ProgressStyle {
+    ProgressStyle::with_template(template)
+        .expect("Can't parse progress template!")
+        .progress_chars("=> ")
+}
+
+/// Write a diff's report, if it didn't match, and its findings, then as
+/// much more detail as the verbosity asks for
+fn write_text(out: &mut dyn Write, d: &Diff, verbosity: u64)
+    -> io::Result<()> {
+    if !d.matches {
+        writeln!(out, "{}", d.report)?;
+    }
+    for node in d.flatten() {
+        for finding in node.findings.iter() {
+            writeln!(out, "note: {} vs {}: {}", node.left, node.right,
+                     finding)?;
+        }
+    }
+    if verbosity > DEFAULT_VERBOSITY {
+        let timings = verbosity > DEFAULT_VERBOSITY + 1;
+        write!(out, "{}", report::details(d, timings))?;
+    }
+    Ok(())
+}
+
+/// Write a diff in the given format, with the drift summary after a text
+/// report if drift was measured
+fn write_report(out: &mut dyn Write, d: &Diff, format: Format, drift: bool,
+                verbosity: u64) -> io::Result<()> {
+    match format {
+        Format::Text => {
+            write_text(out, d, verbosity)?;
+            if drift {
+                write!(out, "{}", DriftSummary::from_diff(d))?;
+            }
+        }
+        Format::Tsv => write!(out, "{}", report::tsv(d))?,
+        Format::Json => {
+            writeln!(out, "{}", serde_json::to_string_pretty(&d.to_json())
+                .expect("Can't serialize diff!"))?;
+        }
+        Format::Msgpack => out.write_all(&report::msgpack(d))?,
+        Format::Cbor => out.write_all(&report::cbor(d))?,
+    }
+    Ok(())
+}
+
+/// Write a diff's report to the output file, if one was given, or else to
+/// standard output unless quiet, exiting if it can't be written
+fn emit_report(output: Option<&str>, d: &Diff, format: Format, drift: bool,
+               verbosity: u64) {
+    let written = match output {
+        Some(path) => ReportWriter::create(path).and_then(|mut out| {
+            write_report(&mut out, d, format, drift, verbosity)?;
+            out.finish()
+        }),
+        None if verbosity == 0 => Ok(()),
+        None => write_report(&mut io::stdout().lock(), d, format, drift,
+                             verbosity),
+    };
+    match written {
+        Ok(()) => {}
+        // Whoever was reading the report stopped, as `head` does
+        Err(e) if output.is_none() && e.kind() == io::ErrorKind::BrokenPipe => {
+            process::exit(EXIT_ERROR);
+        }
+        Err(e) => {
+            eprintln!("rsdiff: can't write {}: {}",
+                      output.unwrap_or("report"), e);
+            process::exit(EXIT_ERROR);
+        }
+    }
+}
+
+/// The presets named with --preset, less the settings also given on the
+/// command line, which take precedence
+fn presets(matches: &ArgMatches, config: &Config) -> Vec<Preset> {
+    let given = |name| matches.occurrences_of(name) > 0;
+    matches.values_of("preset").into_iter().flatten()
+        .map(|name| {
+            let mut preset = Preset::named(name, &config.presets)
+                .unwrap_or_else(|e| {
+                    eprintln!("rsdiff: {}", e);
+                    process::exit(EXIT_ERROR);
+                });
+            if given("tolerance") || given("rtol") || given("max-ulps") {
+                preset.tolerance = None;
+                preset.rtol = None;
+            }
+            if given("metric") {
+                preset.metric = None;
+            }
+            if given("format") {
+                preset.format = None;
+            }
+            preset
+        })
+        .collect()
+}
+
+/// Compare the two sides as the main arguments ask: a tar with a directory,
+/// two images, or two objects of any kind.
+fn compare(matches: &ArgMatches, left: &str, right: &str, opts: &DiffOptions)
+    -> rsdiff::Result<Diff> {
+    match matches.value_of("left-tar") {
+        Some("-") => diff_tar_with_options(io::stdin().lock(), "stdin",
+                                           right, opts),
+        Some(tar) => File::open(tar)
+            .map_err(|e| RsdiffError::io(tar, e))
+            .and_then(|f| {
+                diff_tar_with_options(BufReader::new(f), tar, right, opts)
+            }),
+        None if matches.value_of("mode") == Some("image") => {
+            diff_images_with_options(left, right, opts)
+        }
+        None => differ_with_options(left, right, opts),
+    }
+}
+
+/// Compare, then compare again each time either side changes, reporting
+/// the first comparison in full and later ones by how their results
+/// changed, until interrupted
+fn run_watch(matches: &ArgMatches, left: &str, right: &str,
+             opts: &DiffOptions, format: Format, verbosity: u64) -> ! {
+    if left == "-" {
+        eprintln!("rsdiff: can't watch a tar read from standard input");
+        process::exit(EXIT_ERROR);
+    }
+    let watch = Watch::new(&[left, right]).unwrap_or_else(|e| {
+        eprintln!("rsdiff: {}", e);
+        process::exit(EXIT_ERROR);
+    });
+    let output = matches.value_of("output");
+    let mut last: Option<Diff> = None;
+    loop {
+        match compare(matches, left, right, opts) {
+            Ok(d) if d.interrupted => break,
+            Ok(d) => {
+                // A report file is kept up to date; the terminal gets the
+                // first report and then only the changes
+                if last.is_none() || output.is_some() {
+                    emit_report(output, &d, format, opts.drift, verbosity);
+                }
+                if let Some(earlier) = last.as_ref().filter(|_| verbosity > 0) {
+                    print_changes(left, right, &watch::changes(earlier, &d));
+                }
+                last = Some(d);
+            }
+            Err(e) => eprintln!("rsdiff: {}", e),
+        }
+        if !watch.wait(WATCH_SETTLE) {
+            break;
+        }
+    }
+    process::exit(EXIT_INTERRUPTED);
+}
+
+/// Print how the results of a comparison in watch mode changed.
+fn print_changes(left: &str, right: &str, changes: &[watch::Change]) {
+    let now = chrono::Local::now().format("%H:%M:%S");
+    match changes.len() {
+        0 => println!("[{}] {} vs. {}: no changes", now, left, right),
+        1 => println!("[{}] {} vs. {}: 1 change", now, left, right),
+        n => println!("[{}] {} vs. {}: {} changes", now, left, right, n),
+    }
+    for change in changes {
+        println!("  {}", change);
+    }
+}
+
+/// Show what a preset does
+fn run_presets(matches: &ArgMatches) {
+    if let Some(sub) = matches.subcommand_matches("show") {
+        let config = Config::load(sub.value_of("config"))
+            .unwrap_or_else(|e| {
+                eprintln!("rsdiff: {}", e);
+                process::exit(EXIT_ERROR);
+            });
+        let name = sub.value_of("name").unwrap();
+        let preset = Preset::named(name, &config.presets)
+            .unwrap_or_else(|e| {
+                eprintln!("rsdiff: {}", e);
+                process::exit(EXIT_ERROR);
+            });
+        let table = BTreeMap::from([
+            ("presets", BTreeMap::from([(name, preset)])),
+        ]);
+        print!("{}", toml::to_string(&table)
+            .expect("Can't write preset as TOML!"));
+    }
+    process::exit(0);
+}
+
+/// Compare two software environments, exiting nonzero if they differ
+fn run_env(matches: &ArgMatches) {
+    let result = diff_envs(matches.value_of("left").unwrap(),
+                           matches.value_of("right").unwrap());
+    match result {
+        Ok(d) => {
+            emit_report(None, &d, Format::Text, false, DEFAULT_VERBOSITY);
+            process::exit(if d.matches { 0 } else { EXIT_DIFFERENT });
+        }
+        Err(e) => {
+            eprintln!("rsdiff: {}", e);
+            process::exit(EXIT_ERROR);
+        }
+    }
+}
+
+/// Combine JSON reports into one, exiting nonzero if any run differed
+fn run_merge(matches: &ArgMatches) {
+    let mut set = DiffSet::new();
+    for path in matches.values_of("reports").unwrap() {
+        if let Err(e) = set.load(path) {
+            eprintln!("rsdiff: {}", e);
+            process::exit(EXIT_ERROR);
+        }
+    }
+    let format = value_t!(matches, "format", Format)
+        .unwrap_or_else(|e| usage_error(e));
+    let opts = DiffOptions {
+        color: matches!(format, Format::Text) && wants_color(matches),
+        ..DiffOptions::default()
+    };
+    let d = set.combine(&opts);
+    emit_report(matches.value_of("output"), &d, format, false,
+                DEFAULT_VERBOSITY);
+    if d.interrupted {
+        process::exit(EXIT_INTERRUPTED);
+    }
+    process::exit(if d.matches { 0 } else { EXIT_DIFFERENT });
+}
+
+/// Take a directory's fingerprint, or compare two, exiting nonzero if they
+/// differ
+fn run_fingerprint(matches: &ArgMatches) {
+    let opts = DiffOptions {
+        exclude: excludes(matches),
+        ..DiffOptions::default()
+    };
+    // Directories are fingerprinted; anything else is a saved fingerprint
+    let fingerprints: Result<Vec<Fingerprint>, RsdiffError> = matches
+        .values_of("paths").unwrap()
+        .map(|path| if Path::new(path).is_dir() {
+            Fingerprint::of(path, &opts)
+        }
+        else {
+            Fingerprint::load(path)
+        })
+        .collect();
+    let fingerprints = fingerprints.unwrap_or_else(|e| {
+        eprintln!("rsdiff: {}", e);
+        process::exit(EXIT_ERROR);
+    });
+    if let [left, right] = &fingerprints[..] {
+        let d = diff_fingerprints(left, right);
+        emit_report(None, &d, Format::Text, false, DEFAULT_VERBOSITY);
+        process::exit(if d.matches { 0 } else { EXIT_DIFFERENT });
+    }
+    let output = matches.value_of("output");
+    let written = match output {
+        Some(path) => File::create(path).and_then(|out| {
+            serde_json::to_writer_pretty(out, &fingerprints[0])
+                .map_err(io::Error::from)
+        }),
+        None => serde_json::to_writer_pretty(io::stdout().lock(),
+                                             &fingerprints[0])
+            .map_err(io::Error::from)
+            .and_then(|()| writeln!(io::stdout())),
+    };
+    if let Err(e) = written {
+        eprintln!("rsdiff: can't write {}: {}",
+                  output.unwrap_or("fingerprint"), e);
+        process::exit(EXIT_ERROR);
+    }
+    process::exit(0);
+}
+
+/// Record a manifest of a directory tree
+fn run_manifest(matches: &ArgMatches) {
+    let opts = DiffOptions {
+        exclude: excludes(matches),
+        jobs: jobs(matches),
+        hash_algorithm: matches.value_of("hash-algorithm")
+            .map_or_else(|| String::from(hash::DEFAULT_ALGORITHM),
+                         String::from),
+        ..DiffOptions::default()
+    };
+    let manifest = Manifest::of(matches.value_of("dir").unwrap(), &opts)
+        .unwrap_or_else(|e| {
+            eprintln!("rsdiff: {}", e);
+            process::exit(EXIT_ERROR);
+        });
+    let output = matches.value_of("output");
+    let written = match output {
+        Some(path) => File::create(path).and_then(|out| {
+            serde_json::to_writer_pretty(BufWriter::new(out), &manifest)
+                .map_err(io::Error::from)
+        }),
+        None => serde_json::to_writer_pretty(io::stdout().lock(), &manifest)
+            .map_err(io::Error::from)
+            .and_then(|()| writeln!(io::stdout())),
+    };
+    if let Err(e) = written {
+        eprintln!("rsdiff: can't write {}: {}", output.unwrap_or("manifest"),
+                  e);
+        process::exit(EXIT_ERROR);
+    }
+    process::exit(0);
+}
+
+/// Check a directory tree against a manifest, exiting nonzero if it
+/// doesn't match
+fn run_check(matches: &ArgMatches) {
+    let format = value_t!(matches, "format", Format)
+        .unwrap_or_else(|e| usage_error(e));
+    let opts = DiffOptions {
+        exclude: excludes(matches),
+        jobs: jobs(matches),
+        color: matches!(format, Format::Text) && wants_color(matches),
+        ..DiffOptions::default()
+    };
+    let d = Manifest::load(matches.value_of("manifest").unwrap())
+        .and_then(|manifest| {
+            check_manifest(&manifest, matches.value_of("dir").unwrap(), &opts)
+        })
+        .unwrap_or_else(|e| {
+            eprintln!("rsdiff: {}", e);
+            process::exit(EXIT_ERROR);
+        });
+    emit_report(matches.value_of("output"), &d, format, false,
+                DEFAULT_VERBOSITY);
+    process::exit(if d.matches { 0 } else { EXIT_DIFFERENT });
+}
+
+/// Check that a file holds the contents expected of it, exiting nonzero
+/// if it doesn't
+fn run_expect(matches: &ArgMatches) {
+    let format = value_t!(matches, "format", Format)
+        .unwrap_or_else(|e| usage_error(e));
+    let opts = DiffOptions {
+        color: matches!(format, Format::Text) && wants_color(matches),
+        heatmap_buckets: matches.value_of("heatmap")
+            .map(|n| n.parse().unwrap()),
+        ..DiffOptions::default()
+    };
+    let expected = if let Some(byte) = matches.value_of("fill-value") {
+        Ok(Expected::fill(parse_byte(byte).unwrap()))
+    }
+    else if let Some(path) = matches.value_of("pattern-file") {
+        Expected::from_file(path)
+    }
+    else {
+        Ok(Expected::zeros())
+    };
+    let d = expected
+        .and_then(|expected| {
+            diff_expected_with_options(matches.value_of("file").unwrap(),
+                                       &expected, &opts)
+        })
+        .unwrap_or_else(|e| {
+            eprintln!("rsdiff: {}", e);
+            process::exit(EXIT_ERROR);
+        });
+    emit_report(matches.value_of("output"), &d, format, false,
+                DEFAULT_VERBOSITY);
+    process::exit(if d.matches { 0 } else { EXIT_DIFFERENT });
+}
+
+/// Check a single file's integrity, exiting nonzero if it is damaged
+fn run_triage(matches: &ArgMatches) {
+    let t = triage(matches.value_of("file").unwrap());
+    if t.checks.is_empty() {
+        println!("{}: {} (no integrity checks available)", t.path, t.format);
+    }
+    else {
+        println!("{}: {} ({})", t.path, t.format, t.checks.join(", "));
+    }
+    for problem in t.problems.iter() {
+        println!("  {}", problem);
+    }
+    if t.ok() {
+        println!("  OK");
+        process::exit(0);
+    }
+    process::exit(1);
 }
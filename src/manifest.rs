@@ -0,0 +1,136 @@
+//! Manifests for rsdiff
+//!
+//! Comparing a dataset with its copy on another machine needs both trees
+//! in one place, which for terabytes of data means copying one over first.
+//! A manifest records the size and hash of every file in a tree instead,
+//! so that it can be taken where the data is, carried over as a small JSON
+//! file, and checked against the other tree there. A check reports files
+//! that differ, are missing, or are new, like a comparison of the trees
+//! would, though without saying how files differ.
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::Path,
+};
+
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    fingerprint::list_files, hash::{self, hash_file}, in_pool,
+    summarize_entries, Diff, DiffOptions, Result, RsdiffError, Unit,
+};
+
+/// ManifestEntry
+/// What a manifest records of a file.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    pub size: u64,
+    pub hash: String,
+}
+
+/// Manifest
+/// The sizes and hashes of a directory tree's files.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Manifest {
+    /// The directory the manifest was made of.
+    pub path: String,
+    /// The name of the algorithm the files were hashed with.
+    pub algorithm: String,
+    /// The files, by their paths relative to the directory.
+    pub files: BTreeMap<String, ManifestEntry>,
+}
+
+impl Manifest {
+    /// Make the manifest of a directory tree, hashing its files with the
+    /// options' algorithm, on `opts.jobs` threads. Excluded entries are
+    /// left out; only regular files are recorded, and symbolic links
+    /// aren't followed.
+    pub fn of(dir: &str, opts: &DiffOptions) -> Result<Manifest> {
+        Ok(Manifest {
+            path: String::from(dir),
+            algorithm: opts.hash_algorithm.clone(),
+            files: hash_tree(dir, &opts.hash_algorithm, opts)?,
+        })
+    }
+
+    /// Read a manifest saved as JSON.
+    pub fn load(path: &str) -> Result<Manifest> {
+        let bytes = fs::read(path).map_err(|e| RsdiffError::io(path, e))?;
+        serde_json::from_slice(&bytes).map_err(|e| RsdiffError::Corrupt(
+            format!("{} is not an rsdiff manifest: {}", path, e)
+        ))
+    }
+}
+
+/// Check a directory tree against a manifest, hashing its files with the
+/// manifest's algorithm. The manifest is the left side of the result, and
+/// each of its files is reported as `<manifest path>:<file>`.
+pub fn check_manifest(manifest: &Manifest, dir: &str, opts: &DiffOptions)
+    -> Result<Diff> {
+    let live = hash_tree(dir, &manifest.algorithm, opts)?;
+    let mut d = Diff::new(&manifest.path, dir);
+    for (name, recorded) in manifest.files.iter() {
+        let Some(found) = live.get(name) else {
+            d.left_only.push(name.clone());
+            continue;
+        };
+        let mut subdiff = Diff::new(&format!("{}:{}", manifest.path, name),
+                                    &Path::new(dir).join(name)
+                                        .to_string_lossy());
+        subdiff.left_hash = Some(recorded.hash.clone());
+        subdiff.right_hash = Some(found.hash.clone());
+        subdiff.matches = recorded == found;
+        subdiff.set_counts(subdiff.matches as usize, 1, Unit::Entries);
+        if !subdiff.matches {
+            subdiff.additional_info = if recorded.size != found.size {
+                format!("sizes differ: {} vs. {}", recorded.size, found.size)
+            }
+            else {
+                format!("{} hashes differ: {} vs. {}", manifest.algorithm,
+                        recorded.hash, found.hash)
+            };
+            subdiff.report = format!("{} vs {}: {}", subdiff.left,
+                                     subdiff.right, subdiff.additional_info);
+        }
+        d.common.push(name.clone());
+        d.sub_diffs.push(Box::new(subdiff));
+    }
+    d.right_only = live.into_keys()
+        .filter(|name| !manifest.files.contains_key(name))
+        .collect();
+    summarize_entries(&mut d, opts);
+    Ok(d)
+}
+
+/// Record the size and hash of every file under a directory, by path
+/// relative to it.
+fn hash_tree(dir: &str, algorithm: &str, opts: &DiffOptions)
+    -> Result<BTreeMap<String, ManifestEntry>> {
+    if !fs::metadata(dir).map_err(|e| RsdiffError::io(dir, e))?.is_dir() {
+        return Err(RsdiffError::NotADirectory(String::from(dir)));
+    }
+    let Some(new_hasher) = hash::new_hasher(algorithm) else {
+        return Err(RsdiffError::Corrupt(format!(
+            "files are hashed with {}, which isn't a known algorithm",
+            algorithm
+        )));
+    };
+    let mut sizes = BTreeMap::new();
+    list_files(Path::new(dir), Path::new(""), opts, &mut sizes)
+        .map_err(|e| RsdiffError::io(dir, e))?;
+    let hash_entry = |(name, size): (String, u64)| {
+        let path = Path::new(dir).join(&name);
+        let path = path.to_string_lossy();
+        hash_file(&path, new_hasher())
+            .map(|hash| (name, ManifestEntry { size, hash }))
+            .map_err(|e| RsdiffError::io(&path, e))
+    };
+    if opts.jobs > 1 {
+        in_pool(opts, || sizes.into_par_iter().map(hash_entry).collect())
+    }
+    else {
+        sizes.into_iter().map(hash_entry).collect()
+    }
+}
@@ -0,0 +1,148 @@
+//! Prometheus metrics for rsdiff
+//!
+//! Nightly validation jobs are watched from the same dashboards as the
+//! rest of a cluster. A run can write its metrics in the Prometheus text
+//! format for node_exporter's textfile collector to pick up: how many
+//! files were compared and how many differ, how many bytes that took and
+//! how long, and whether the run succeeded at all. Every series is labeled
+//! with the compared paths, so one collector directory can serve several
+//! jobs.
+
+use std::{
+    fmt::Write as _,
+    fs,
+    io,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::Diff;
+
+/// Metrics
+/// Counts and timings of one comparison.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Metrics {
+    /// Files compared, not counting directories.
+    pub files_compared: usize,
+    /// Files compared that differ.
+    pub files_different: usize,
+    /// Entries only in the left directories.
+    pub left_only: usize,
+    /// Entries only in the right directories.
+    pub right_only: usize,
+    /// Size of the left files compared, in bytes.
+    pub bytes_compared: u64,
+    /// Wall-clock seconds the comparison took.
+    pub seconds: f64,
+    /// Whether the objects match.
+    pub matches: bool,
+    /// Whether the comparison was interrupted.
+    pub interrupted: bool,
+}
+
+impl Metrics {
+    /// Count up a diff. Files are the nodes whose left path is a file on
+    /// disk, so parts of files reported as sub-diffs aren't counted.
+    pub fn from_diff(d: &Diff) -> Metrics {
+        let mut m = Metrics {
+            seconds: d.seconds,
+            matches: d.matches,
+            interrupted: d.interrupted,
+            ..Metrics::default()
+        };
+        for node in d.flatten() {
+            m.left_only += node.left_only.len();
+            m.right_only += node.right_only.len();
+            let meta = match fs::metadata(&node.left) {
+                Ok(meta) if meta.is_file() => meta,
+                _ => continue,
+            };
+            m.files_compared += 1;
+            m.files_different += !node.matches as usize;
+            m.bytes_compared += meta.len();
+        }
+        m
+    }
+
+    /// Render the metrics of a successful run in the text format.
+    pub fn to_textfile(&self, left: &str, right: &str) -> String {
+        let labels = labels(left, right);
+        let mut out = header(&labels, true);
+        let series = [
+            ("rsdiff_files_compared", "Files compared.",
+             self.files_compared.to_string()),
+            ("rsdiff_files_different", "Files compared that differ.",
+             self.files_different.to_string()),
+            ("rsdiff_bytes_compared", "Bytes of left files compared.",
+             self.bytes_compared.to_string()),
+            ("rsdiff_duration_seconds", "Seconds the comparison took.",
+             self.seconds.to_string()),
+            ("rsdiff_match", "Whether the objects match.",
+             (self.matches as u8).to_string()),
+            ("rsdiff_interrupted", "Whether the comparison was interrupted.",
+             (self.interrupted as u8).to_string()),
+        ];
+        for (name, help, value) in series.iter() {
+            gauge(&mut out, name, help);
+            let _ = writeln!(out, "{}{{{}}} {}", name, labels, value);
+        }
+        gauge(&mut out, "rsdiff_one_sided_entries",
+              "Entries present on only one side.");
+        for (side, count) in [("left", self.left_only),
+                              ("right", self.right_only)] {
+            let _ = writeln!(out,
+                             "rsdiff_one_sided_entries{{{},side=\"{}\"}} {}",
+                             labels, side, count);
+        }
+        out
+    }
+}
+
+/// Render the metrics of a run that failed before producing a result.
+pub fn failure_textfile(left: &str, right: &str) -> String {
+    header(&labels(left, right), false)
+}
+
+/// Start a gauge with its help and type lines.
+fn gauge(out: &mut String, name: &str, help: &str) {
+    let _ = write!(out, "# HELP {} {}\n# TYPE {} gauge\n", name, help, name);
+}
+
+/// Write a textfile atomically, through a temporary file renamed into
+/// place, so the collector never reads half a file.
+pub fn write_textfile(path: &str, text: &str) -> io::Result<()> {
+    let file_name = Path::new(path).file_name().unwrap_or_default();
+    let temporary = Path::new(path).with_file_name(
+        format!(".{}.{}.tmp", file_name.to_string_lossy(), std::process::id())
+    );
+    fs::write(&temporary, text)?;
+    fs::rename(&temporary, path).inspect_err(|_| {
+        let _ = fs::remove_file(&temporary);
+    })
+}
+
+/// The metrics every run reports: whether it succeeded, and when.
+fn header(labels: &str, success: bool) -> String {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)
+        .map_or(0.0, |t| t.as_secs_f64());
+    let mut out = String::new();
+    gauge(&mut out, "rsdiff_success",
+          "Whether the comparison could be carried out.");
+    let _ = writeln!(out, "rsdiff_success{{{}}} {}", labels, success as u8);
+    gauge(&mut out, "rsdiff_last_run_timestamp_seconds",
+          "When the run finished.");
+    let _ = writeln!(out, "rsdiff_last_run_timestamp_seconds{{{}}} {}",
+                     labels, now);
+    out
+}
+
+/// Labels identifying a comparison, escaped for the text format, without
+/// their braces so more can be added.
+fn labels(left: &str, right: &str) -> String {
+    format!("left=\"{}\",right=\"{}\"", escape(left), escape(right))
+}
+
+/// Escape a label value: backslashes, quotes, and newlines.
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
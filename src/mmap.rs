@@ -0,0 +1,29 @@
+//! Memory-mapped files for rsdiff
+//!
+//! Reading a multi-gigabyte file through a buffered reader copies every
+//! byte from the page cache into a buffer, a chunk and a syscall at a
+//! time. Mapping the file instead lets comparisons read the page cache
+//! directly. Mappings are copy-on-write, so callers may reorder bytes in
+//! place, as for big-endian voxels, without touching the file.
+//!
+//! A mapped file that is truncated while being compared kills the process
+//! with SIGBUS rather than returning an error, so mapping is only done on
+//! request.
+
+use std::{fs::File, io};
+
+use memmap2::MmapOptions;
+
+/// A private, writable mapping of a whole file.
+pub type Mmap = memmap2::MmapMut;
+
+/// Map a file copy-on-write, for reading from start to end.
+pub fn map(file: &File) -> io::Result<Mmap> {
+    // SAFETY: the mapping is private, so writes through it never reach the
+    // file; a file truncated under it is the documented hazard above
+    let map = unsafe { MmapOptions::new().map_copy(file)? };
+    // Advice only, which not every system takes
+    #[cfg(unix)]
+    let _ = map.advise(memmap2::Advice::Sequential);
+    Ok(map)
+}
@@ -0,0 +1,104 @@
+//! Jupyter notebook comparison for rsdiff
+//!
+//! Running a notebook rewrites its outputs and execution counts, and
+//! often its metadata, even when none of its code changed. Notebooks are
+//! compared cell by cell instead of byte by byte, optionally leaving out
+//! what execution touches, and the similarity is the share of cells that
+//! line up.
+
+use std::fs;
+
+use serde_json::{Map, Value};
+
+use crate::{sequence::lcs_len, Diff, DiffOptions, Result, RsdiffError, Unit};
+
+/// Most cell insertions and deletions to consider when aligning cells.
+const MAX_EDITS: usize = 10_000;
+
+/// Whether a file is a Jupyter notebook.
+pub fn is_notebook(path: &str) -> bool {
+    path.ends_with(".ipynb")
+}
+
+/// Compare two notebooks cell by cell.
+pub fn diff_notebooks(left: &str, right: &str) -> Result<Diff> {
+    diff_notebooks_with_options(left, right, &DiffOptions::default())
+}
+
+/// Compare two notebooks cell by cell with custom options.
+pub fn diff_notebooks_with_options(left: &str, right: &str,
+                                   opts: &DiffOptions) -> Result<Diff> {
+    let left_notebook = load(left)?;
+    let right_notebook = load(right)?;
+    let left_cells = cells(&left_notebook, opts);
+    let right_cells = cells(&right_notebook, opts);
+
+    let mut d = Diff::new(left, right);
+    let total = left_cells.len().max(right_cells.len());
+    let cell_counts = format!("{} vs. {} cells", left_cells.len(),
+                              right_cells.len());
+    match lcs_len(&left_cells, &right_cells, MAX_EDITS) {
+        Some(matched) => {
+            d.set_counts(matched, total, Unit::Cells);
+            d.additional_info = format!("{}; {} of {} cells match",
+                                        cell_counts, matched, total);
+        }
+        None => {
+            d.additional_info = format!("{}; too different to align",
+                                        cell_counts);
+        }
+    }
+    let metadata_matches = opts.ignore_metadata
+        || left_notebook.get("metadata") == right_notebook.get("metadata");
+    if !metadata_matches {
+        d.additional_info.push_str("; notebook metadata differs");
+    }
+    d.matches = left_cells == right_cells && metadata_matches;
+    if !d.matches {
+        d.report = format!("{} vs {}: {}", left, right, d.additional_info);
+    }
+    Ok(d)
+}
+
+/// Read and parse a notebook.
+fn load(path: &str) -> Result<Value> {
+    let text = fs::read(path).map_err(|e| RsdiffError::io(path, e))?;
+    serde_json::from_slice(&text).map_err(|e| RsdiffError::Corrupt(
+        format!("{} is not a valid notebook: {}", path, e)
+    ))
+}
+
+/// The cells of a notebook, reduced to the parts being compared. Sources
+/// stored as lists of lines are joined, since writers split them
+/// differently.
+fn cells(notebook: &Value, opts: &DiffOptions) -> Vec<Value> {
+    let cells = match notebook.get("cells").and_then(Value::as_array) {
+        Some(cells) => cells,
+        None => return vec!(),
+    };
+    cells.iter()
+        .map(|cell| {
+            let mut kept = Map::new();
+            for (key, value) in cell.as_object().into_iter().flatten() {
+                let ignored = match key.as_str() {
+                    "outputs" | "execution_count" => opts.ignore_outputs,
+                    "metadata" => opts.ignore_metadata,
+                    // Cell IDs are regenerated freely by some writers
+                    "id" => true,
+                    _ => false,
+                };
+                if ignored {
+                    continue;
+                }
+                let value = match (key.as_str(), value) {
+                    ("source", Value::Array(lines)) => Value::String(
+                        lines.iter().filter_map(Value::as_str).collect()
+                    ),
+                    _ => value.clone(),
+                };
+                kept.insert(key.clone(), value);
+            }
+            Value::Object(kept)
+        })
+        .collect()
+}
@@ -0,0 +1,124 @@
+//! Completion notices for rsdiff
+//!
+//! Validation jobs run for hours on a cluster, and nobody watches them
+//! finish. A run can post a summary to a webhook when it is done, whether
+//! it found differences or failed outright. The payload's `text` field is
+//! what Slack and Teams incoming webhooks display; the other fields carry
+//! the same summary for receivers that want to act on it.
+
+use std::time::Duration;
+
+use serde::Serialize;
+
+use crate::{metrics::Metrics, Diff};
+
+/// How long to wait for a webhook before giving up on it.
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Summary
+/// What a finished run reports to a webhook.
+#[derive(Debug, Clone, Serialize)]
+pub struct Summary {
+    /// One line for people to read.
+    pub text: String,
+    pub left: String,
+    pub right: String,
+    /// Whether the comparison could be carried out.
+    pub success: bool,
+    pub matches: bool,
+    pub interrupted: bool,
+    pub similarity: Option<f32>,
+    pub files_compared: usize,
+    pub files_different: usize,
+    pub left_only: usize,
+    pub right_only: usize,
+    pub seconds: f64,
+    /// Why the comparison couldn't be carried out, if it couldn't.
+    pub error: Option<String>,
+    /// The status rsdiff exits with.
+    pub exit_status: i32,
+    pub hostname: String,
+}
+
+impl Summary {
+    /// Summarize a finished comparison.
+    pub fn of_diff(d: &Diff, exit_status: i32, hostname: &str) -> Summary {
+        let m = Metrics::from_diff(d);
+        let verdict = if d.interrupted {
+            "were interrupted"
+        }
+        else if d.matches {
+            "match"
+        }
+        else {
+            "differ"
+        };
+        let mut text = format!("rsdiff on {}: {} vs. {} {}", hostname, d.left,
+                               d.right, verdict);
+        if m.files_compared > 0 {
+            text.push_str(&format!("; {} of {} file(s) differ",
+                                   m.files_different, m.files_compared));
+        }
+        if m.left_only + m.right_only > 0 {
+            text.push_str(&format!(", {} only on the left, {} only on the \
+                                    right", m.left_only, m.right_only));
+        }
+        text.push_str(&format!(" ({:.1} s)", d.seconds));
+        Summary {
+            text,
+            left: d.left.clone(),
+            right: d.right.clone(),
+            success: true,
+            matches: d.matches,
+            interrupted: d.interrupted,
+            similarity: d.unit.map(|_| d.similarity),
+            files_compared: m.files_compared,
+            files_different: m.files_different,
+            left_only: m.left_only,
+            right_only: m.right_only,
+            seconds: d.seconds,
+            error: None,
+            exit_status,
+            hostname: String::from(hostname),
+        }
+    }
+
+    /// Summarize a comparison that couldn't be carried out.
+    pub fn of_failure(left: &str, right: &str, error: &str, exit_status: i32,
+                      hostname: &str) -> Summary {
+        Summary {
+            text: format!("rsdiff on {}: {} vs. {} failed: {}", hostname,
+                          left, right, error),
+            left: String::from(left),
+            right: String::from(right),
+            success: false,
+            matches: false,
+            interrupted: false,
+            similarity: None,
+            files_compared: 0,
+            files_different: 0,
+            left_only: 0,
+            right_only: 0,
+            seconds: 0.0,
+            error: Some(String::from(error)),
+            exit_status,
+            hostname: String::from(hostname),
+        }
+    }
+}
+
+/// Post a summary to a webhook as JSON. Responses other than success are
+/// errors.
+pub fn post(url: &str, summary: &Summary) -> Result<(), String> {
+    let agent: ureq::Agent = ureq::Agent::config_builder()
+        .timeout_global(Some(TIMEOUT))
+        .build()
+        .into();
+    let body = serde_json::to_string(summary)
+        .expect("Can't serialize summary!");
+    agent.post(url)
+        .header("Content-Type", "application/json")
+        .send(&body)
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
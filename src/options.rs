@@ -0,0 +1,1036 @@
+//! Options for rsdiff
+
+use std::{
+    fmt,
+    fs,
+    iter,
+    ops::Range,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+};
+
+use globset::{Glob, GlobMatcher};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    checksums::Checksums,
+    gz::BackgroundDecoder,
+    hash::{self, Hasher},
+    hooks::{self, Hook},
+    preset::Preset,
+    workspace::Workspace,
+    RsdiffError,
+};
+
+pub use rsdiff_core::{FloatComparison, Metric};
+
+/// Default size of the buffers files are read into for comparison.
+pub const DEFAULT_CHUNK_SIZE: usize = 256 * 1024;
+/// Smallest buffer size a memory ceiling may shrink buffers to.
+const MIN_CHUNK_SIZE: usize = 4 * 1024;
+/// Most buffers a single comparison holds at once: for each side of a
+/// gzip comparison, the chunks queued by the background decoder, the one
+/// it is filling, the one being read from, and the comparison buffer.
+const BUFFERS_PER_COMPARISON: usize = 2 * (BackgroundDecoder::QUEUE_DEPTH + 3);
+
+/// Unit
+/// What a similarity index counts. Similarities are only comparable, and
+/// only aggregated, when they share a unit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Unit {
+    /// Raw bytes of the files compared.
+    Bytes,
+    /// Typed data elements, such as NIfTI voxels.
+    #[default]
+    Elements,
+    /// Directory entries.
+    Entries,
+    /// Tokens of source code.
+    Tokens,
+    /// Notebook cells.
+    Cells,
+    /// Lines of text.
+    Lines,
+    /// Scalar values of structured data, such as JSON.
+    Values,
+    /// Rows of tables.
+    Rows,
+}
+
+/// SymlinkPolicy
+/// How symbolic links are compared.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SymlinkPolicy {
+    /// Compare what links point to, as if they were the objects
+    /// themselves. Dangling links are compared by their targets.
+    #[default]
+    Follow,
+    /// Compare links by where they point, without following them, so a
+    /// link to a derivatives directory is one entry, not its whole tree.
+    CompareTargets,
+    /// Leave links inside directories out of comparisons.
+    Skip,
+}
+
+impl fmt::Display for SymlinkPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            SymlinkPolicy::Follow => "follow",
+            SymlinkPolicy::CompareTargets => "compare-targets",
+            SymlinkPolicy::Skip => "skip",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for SymlinkPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<SymlinkPolicy, String> {
+        match s {
+            "follow" => Ok(SymlinkPolicy::Follow),
+            "compare-targets" => Ok(SymlinkPolicy::CompareTargets),
+            "skip" => Ok(SymlinkPolicy::Skip),
+            _ => Err(format!("Unknown symlink policy {}", s)),
+        }
+    }
+}
+
+/// MixedCompression
+/// What to make of two NIfTI files of which only one is gzipped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MixedCompression {
+    /// Compare the voxels as if both were stored the same way.
+    #[default]
+    Compare,
+    /// Compare the voxels, noting which file is gzipped.
+    Note,
+    /// Compare the voxels, but count the files as different even if the
+    /// voxels and headers match.
+    Differ,
+}
+
+impl fmt::Display for MixedCompression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            MixedCompression::Compare => "compare",
+            MixedCompression::Note => "note",
+            MixedCompression::Differ => "differ",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for MixedCompression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<MixedCompression, String> {
+        match s {
+            "compare" => Ok(MixedCompression::Compare),
+            "note" => Ok(MixedCompression::Note),
+            "differ" => Ok(MixedCompression::Differ),
+            _ => Err(format!("Unknown mixed compression handling {}", s)),
+        }
+    }
+}
+
+/// Shard
+/// One of `count` disjoint parts of a directory comparison, numbered from
+/// zero, as array job tasks are.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Shard {
+    /// Which part this is, below `count`.
+    pub index: usize,
+    /// How many parts the comparison is split into.
+    pub count: usize,
+}
+
+impl Shard {
+    /// Whether the entry at `relative`, under the top of the comparison,
+    /// belongs to this shard. Entries are assigned by a hash of their
+    /// path, so every task agrees on the assignment without coordinating.
+    pub fn owns(&self, relative: &Path) -> bool {
+        let path = relative.components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        let digest = Sha256::digest(path.as_bytes());
+        let mut bits = [0u8; 8];
+        bits.copy_from_slice(&digest[..8]);
+        (u64::from_be_bytes(bits) % self.count as u64) as usize == self.index
+    }
+}
+
+impl fmt::Display for Shard {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.index, self.count)
+    }
+}
+
+impl fmt::Display for Unit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Unit::Bytes => "bytes",
+            Unit::Elements => "elements",
+            Unit::Entries => "entries",
+            Unit::Tokens => "tokens",
+            Unit::Cells => "cells",
+            Unit::Lines => "lines",
+            Unit::Values => "values",
+            Unit::Rows => "rows",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for Unit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Unit, String> {
+        match s {
+            "bytes" => Ok(Unit::Bytes),
+            "elements" => Ok(Unit::Elements),
+            "entries" => Ok(Unit::Entries),
+            "tokens" => Ok(Unit::Tokens),
+            "cells" => Ok(Unit::Cells),
+            "lines" => Ok(Unit::Lines),
+            "values" => Ok(Unit::Values),
+            "rows" => Ok(Unit::Rows),
+            _ => Err(format!("Unknown unit {}", s)),
+        }
+    }
+}
+
+/// DiffOptions
+/// Knobs controlling how a diff is computed. The default options reproduce
+/// the behavior of the plain `differ` family of functions.
+#[derive(Debug, Clone, Serialize)]
+pub struct DiffOptions {
+    /// Whether to hash file contents while they are read for comparison.
+    /// The hashes are recorded on the resulting Diff objects.
+    pub hash: bool,
+    /// The name of the algorithm to hash with, one of `hash::algorithms()`.
+    pub hash_algorithm: String,
+    /// Whether to hash both files of a pair first, comparing them in full
+    /// only if their hashes differ.
+    pub precheck: bool,
+    /// Recorded hashes to check each file against as it is compared, with
+    /// hashing on and set to their algorithm by `check_against`.
+    #[serde(skip)]
+    pub checksums: Option<Arc<Checksums>>,
+    /// The unit voxel similarities are counted in: elements (voxels), or
+    /// bytes to make them consistent with byte-wise comparisons.
+    pub voxel_unit: Unit,
+    /// What to make of NIfTI files of which only one is gzipped.
+    pub mixed_compression: MixedCompression,
+    /// Whether to compare NIfTI voxels by their scaled values, applying
+    /// each header's `scl_slope` and `scl_inter`, rather than as stored.
+    /// Files storing the same values with different scaling then match.
+    pub scaled_voxels: bool,
+    /// Stored voxel values that stand in for missing data, such as -32768
+    /// in integer images, when voxels are compared by their scaled values.
+    /// Missing voxels aren't scaled, and only match other missing voxels.
+    pub sentinels: Vec<f64>,
+    /// Whether to convert NIfTI headers' spatial fields to millimeters and
+    /// temporal fields to seconds before comparing them, so headers that
+    /// only differ in their units match.
+    pub normalize_units: bool,
+    /// What the similarity of NIfTI images is measured by. The counts of
+    /// matching voxels are kept whichever it is.
+    pub metric: Metric,
+    /// Byte ranges to restrict byte-wise comparisons to, applied to both
+    /// files. Empty means compare whole files.
+    pub byte_ranges: Vec<Range<u64>>,
+    /// Byte ranges to leave out of byte-wise comparisons, such as embedded
+    /// timestamps or UUIDs at fixed offsets.
+    pub ignore_ranges: Vec<Range<u64>>,
+    /// The largest size difference, in bytes, to test as a constant offset
+    /// between otherwise identical files. Zero disables the check.
+    pub max_shift: u64,
+    /// How many equal stretches to divide files that differ byte-wise into,
+    /// charting how many bytes mismatch in each, if any.
+    pub heatmap_buckets: Option<usize>,
+    /// Whether to report differences in how BGZF payloads are split into
+    /// blocks, in addition to differences in the payloads themselves.
+    pub bgzf_blocks: bool,
+    /// Whether to compare gzipped files other than NIfTI and BGZF by their
+    /// compressed bytes, rather than by their decompressed contents.
+    pub compressed_bytes: bool,
+    /// Conversions to apply to files before comparing them.
+    pub hooks: Vec<Hook>,
+    /// Where to cache files converted by hooks.
+    pub cache_dir: PathBuf,
+    /// Where intermediate files are written while comparing, and how much
+    /// room they may take.
+    pub workspace: Workspace,
+    /// Whether to treat DataLad datasets as datasets, skipping their git
+    /// and `.datalad` bookkeeping and reporting their IDs.
+    pub datalad: bool,
+    /// Size in bytes of the buffers files are read into for comparison.
+    pub chunk_size: usize,
+    /// Size in bytes from which files are split into ranges that are
+    /// compared on `jobs` threads at once, when there is more than one.
+    /// Zero never splits files.
+    pub split_above: u64,
+    /// Map uncompressed files into memory to compare them, rather than
+    /// reading them through buffers. Faster for very large files on Unix
+    /// systems, but a file truncated mid-comparison kills the process.
+    pub mmap: bool,
+    /// Ceiling on memory used for comparison buffers, if one was set.
+    pub max_memory: Option<u64>,
+    /// CPUs to pin the threads doing comparisons to. Empty leaves thread
+    /// placement to the operating system.
+    pub cpus: Vec<usize>,
+    /// Glob patterns for directory entries to leave out of comparisons.
+    /// Patterns are matched against entry names, against paths relative to
+    /// the directories compared, and against whole paths, so `*.pyc`,
+    /// `tmp/**`, and `*.dist-info/INSTALLER` all work. A pattern ending in
+    /// `/**` also excludes the directory itself.
+    pub exclude: Vec<String>,
+    /// How many entries of a directory to compare at once. One compares
+    /// them one after another.
+    pub jobs: usize,
+    /// Largest absolute difference at which floating point voxels, and
+    /// numbers in structured data such as JSON, still count as matching.
+    pub tolerance: f64,
+    /// How floating point voxels are compared. Absolute comparisons use
+    /// `tolerance`.
+    pub float_comparison: FloatComparison,
+    /// Tolerances for numbers at particular places in structured data,
+    /// overriding `tolerance`, as pairs of a glob over JSON pointers and
+    /// the tolerance. A pattern without a leading `/` matches a key at any
+    /// depth. Later pairs take precedence.
+    pub path_tolerances: Vec<(String, f64)>,
+    /// Globs over JSON pointers of arrays to compare as multisets, without
+    /// regard to the order of their elements, as in `path_tolerances`.
+    pub unordered_arrays: Vec<String>,
+    /// Globs over JSON pointers of values to leave out of comparisons, as
+    /// in `path_tolerances`, like provenance records that differ between
+    /// any two runs.
+    pub ignore_keys: Vec<String>,
+    /// Largest difference, in seconds, at which onsets and durations in
+    /// events files still count as matching.
+    pub onset_tolerance: f64,
+    /// Key columns to align the rows of tables by. Each table is aligned by
+    /// those of the columns it has; tables with none are aligned by order.
+    pub table_keys: Vec<String>,
+    /// Columns of tables to leave out of comparisons, such as acquisition
+    /// dates.
+    pub ignore_columns: Vec<String>,
+    /// Tolerances for numeric cells of tables in particular columns,
+    /// overriding `tolerance`, as pairs of a glob over column names and the
+    /// tolerance. Later pairs take precedence.
+    pub column_tolerances: Vec<(String, f64)>,
+    /// Compare tables without key columns as multisets of rows, ignoring
+    /// the order the rows come in.
+    pub unordered_rows: bool,
+    /// Compare only the structure of files that have one, not their
+    /// values: the keys of JSON and the types under them, the columns of
+    /// tables and the types of their cells, and the shapes and data types
+    /// of NIfTI images. Other files are compared in full.
+    pub schema_only: bool,
+    /// Measure the largest relative difference between the numbers of
+    /// each pair of files holding them: NIfTI voxels, JSON numbers, and
+    /// numeric cells of tables.
+    pub drift: bool,
+    /// Whether to leave comments out of source code comparisons.
+    pub ignore_comments: bool,
+    /// Whether to leave whitespace out of source code comparisons.
+    pub ignore_whitespace: bool,
+    /// Whether to leave cell outputs and execution counts out of notebook
+    /// comparisons, so that re-executed notebooks compare by their code.
+    pub ignore_outputs: bool,
+    /// Whether to leave notebook and cell metadata out of notebook
+    /// comparisons.
+    pub ignore_metadata: bool,
+    /// Whether to compare JSON files in canonical form, ignoring key order
+    /// and how numbers are written. Otherwise they are compared byte by
+    /// byte.
+    pub canonical_json: bool,
+    /// Whether to compare every file as text, line by line, whatever its
+    /// type.
+    pub force_text: bool,
+    /// How many levels of subdirectories to descend into. Subdirectories
+    /// below that are only checked for presence on both sides. None
+    /// descends all the way.
+    pub max_depth: Option<usize>,
+    /// Whether to color reports with terminal escape codes.
+    pub color: bool,
+    /// Whether to stop comparing a directory's entries at the first one
+    /// that differs, when only whether the directories match is wanted.
+    pub fail_fast: bool,
+    /// Whether entries of a directory that may not be read fail the
+    /// comparison, rather than being noted and left out.
+    pub fail_on_inaccessible: bool,
+    /// How symbolic links are compared.
+    pub symlinks: SymlinkPolicy,
+    /// Whether to compare entries only one directory holds against an
+    /// empty file, as `git diff --new-file` does, so that reports say what
+    /// they hold rather than only that they are missing.
+    pub new_file: bool,
+    /// The part of a directory comparison to carry out, if it is split
+    /// across tasks. Files outside the shard are left out; directories on
+    /// both sides are descended by every shard.
+    pub shard: Option<Shard>,
+    /// How many leading directories to strip from the paths in a tar
+    /// stream compared against a directory, as `tar --strip-components`
+    /// does. None strips the directory the archive was made from, if it
+    /// looks to have been made from one.
+    pub tar_strip_components: Option<usize>,
+    /// Where the objects being compared sit under the directories the
+    /// comparison started from, for matching exclude patterns. Normally
+    /// left empty; directory comparisons set it for their entries.
+    #[serde(skip)]
+    pub relative_dir: PathBuf,
+}
+
+impl DiffOptions {
+    /// Start building options from the defaults.
+    pub fn builder() -> DiffOptionsBuilder {
+        DiffOptionsBuilder::default()
+    }
+
+    /// The options to compare the entry `name` of a directory with, one
+    /// level further down.
+    pub(crate) fn descend(&self, name: &str) -> DiffOptions {
+        let mut opts = self.clone();
+        opts.max_depth = self.max_depth.map(|d| d.saturating_sub(1));
+        opts.relative_dir = self.relative_dir.join(name);
+        opts
+    }
+
+    /// A hasher for the contents of an object, if they are to be hashed.
+    /// An algorithm that isn't registered hashes nothing.
+    pub fn hasher(&self) -> Option<Box<dyn Hasher>> {
+        if self.hash {
+            hash::hasher(&self.hash_algorithm)
+        }
+        else {
+            None
+        }
+    }
+
+    /// Whether an entry of a directory is excluded from comparison.
+    pub fn is_excluded(&self, dir: &str, name: &str) -> bool {
+        let path = Path::new(dir).join(name);
+        self.excludes_path(&self.relative_dir.join(name))
+            || self.exclude_matchers().any(|(m, _)| m.is_match(&path))
+    }
+
+    /// Whether the entry at `relative`, under the top of the comparison, is
+    /// excluded, by its name or by its relative path.
+    pub(crate) fn excludes_path(&self, relative: &Path) -> bool {
+        let name = relative.file_name().unwrap_or_default();
+        self.exclude_matchers().any(|(m, whole_path)| {
+            (!whole_path && m.is_match(name)) || m.is_match(relative)
+        })
+    }
+
+    /// Matchers for the exclude patterns, with patterns like `tmp/**`
+    /// extended to the directory they cover. The extensions only match
+    /// whole paths, so `tmp/**` doesn't exclude `src/tmp`.
+    fn exclude_matchers(&self)
+        -> impl Iterator<Item = (GlobMatcher, bool)> + '_ {
+        self.exclude.iter()
+            .flat_map(|p| {
+                iter::once((p.as_str(), false))
+                    .chain(p.strip_suffix("/**").map(|dir| (dir, true)))
+            })
+            .filter_map(|(p, whole_path)| {
+                Some((Glob::new(p).ok()?.compile_matcher(), whole_path))
+            })
+    }
+
+    /// Check every file compared against the hash recorded for it, if any,
+    /// hashing files with the checksums' algorithm as they are read.
+    pub fn check_against(&mut self, checksums: Checksums) {
+        self.hash = true;
+        self.hash_algorithm = checksums.algorithm.clone();
+        self.checksums = Some(Arc::new(checksums));
+    }
+
+    /// Keep comparison buffers within `max_memory` bytes by shrinking them
+    /// as needed, allowing for `jobs` comparisons at once. Fails if the
+    /// ceiling is too low to leave workable buffers; reducing `jobs` first
+    /// may make room.
+    pub fn limit_memory(&mut self, max_memory: u64) -> Result<(), String> {
+        let buffers = (BUFFERS_PER_COMPARISON * self.jobs.max(1)) as u64;
+        let per_buffer = max_memory / buffers;
+        // Keep buffers a whole number of pages, so that they also hold a
+        // whole number of elements of any data type
+        let chunk_size = per_buffer.min(DEFAULT_CHUNK_SIZE as u64) as usize
+            / MIN_CHUNK_SIZE * MIN_CHUNK_SIZE;
+        if chunk_size < MIN_CHUNK_SIZE {
+            return Err(format!(
+                "A memory ceiling of {} bytes is too low for {} job(s); at \
+                 least {} are needed", max_memory, self.jobs.max(1),
+                buffers * MIN_CHUNK_SIZE as u64
+            ));
+        }
+        self.chunk_size = chunk_size;
+        self.max_memory = Some(max_memory);
+        Ok(())
+    }
+}
+
+impl Default for DiffOptions {
+    fn default() -> DiffOptions {
+        DiffOptions {
+            hash: false,
+            hash_algorithm: String::from(hash::DEFAULT_ALGORITHM),
+            precheck: false,
+            checksums: None,
+            voxel_unit: Unit::default(),
+            mixed_compression: MixedCompression::default(),
+            scaled_voxels: false,
+            sentinels: vec!(),
+            normalize_units: false,
+            metric: Metric::default(),
+            byte_ranges: vec!(),
+            ignore_ranges: vec!(),
+            max_shift: 64 * 1024,
+            heatmap_buckets: None,
+            bgzf_blocks: false,
+            compressed_bytes: false,
+            hooks: vec!(),
+            cache_dir: hooks::default_cache_dir(),
+            workspace: Workspace::default(),
+            datalad: false,
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            split_above: 1 << 30,
+            mmap: false,
+            max_memory: None,
+            cpus: vec!(),
+            exclude: vec!(),
+            jobs: 1,
+            tolerance: 1e-16,
+            float_comparison: FloatComparison::Absolute,
+            path_tolerances: vec!(),
+            unordered_arrays: vec!(),
+            ignore_keys: vec!(),
+            onset_tolerance: 1e-3,
+            table_keys: vec!(),
+            ignore_columns: vec!(),
+            column_tolerances: vec!(),
+            unordered_rows: false,
+            schema_only: false,
+            drift: false,
+            ignore_comments: false,
+            ignore_whitespace: false,
+            ignore_outputs: false,
+            ignore_metadata: false,
+            canonical_json: true,
+            force_text: false,
+            max_depth: None,
+            color: true,
+            fail_fast: false,
+            fail_on_inaccessible: false,
+            symlinks: SymlinkPolicy::Follow,
+            new_file: false,
+            shard: None,
+            tar_strip_components: None,
+            relative_dir: PathBuf::new(),
+        }
+    }
+}
+
+/// DiffOptionsBuilder
+/// Builds DiffOptions one setting at a time, starting from the defaults,
+/// e.g. `DiffOptions::builder().jobs(8).tolerance(1e-6).build()`.
+#[derive(Debug, Clone, Default)]
+pub struct DiffOptionsBuilder {
+    opts: DiffOptions,
+    max_memory: Option<u64>,
+}
+
+impl DiffOptionsBuilder {
+    /// Hash file contents while reading them.
+    pub fn hash(mut self, hash: bool) -> Self {
+        self.opts.hash = hash;
+        self
+    }
+
+    /// Hash with the algorithm registered under this name.
+    pub fn hash_algorithm(mut self, name: &str) -> Self {
+        self.opts.hash_algorithm = String::from(name);
+        self
+    }
+
+    /// Compare only files whose hashes differ in full.
+    pub fn precheck(mut self, precheck: bool) -> Self {
+        self.opts.precheck = precheck;
+        self
+    }
+
+    /// Check files against the hashes recorded for them.
+    pub fn checksums(mut self, checksums: Checksums) -> Self {
+        self.opts.check_against(checksums);
+        self
+    }
+
+    /// Count voxel similarities in this unit.
+    pub fn voxel_unit(mut self, unit: Unit) -> Self {
+        self.opts.voxel_unit = unit;
+        self
+    }
+
+    /// Compare NIfTI voxels by their scaled values.
+    pub fn scaled_voxels(mut self, scaled_voxels: bool) -> Self {
+        self.opts.scaled_voxels = scaled_voxels;
+        self
+    }
+
+    /// Treat NIfTI voxels stored as these values as missing when comparing
+    /// scaled values.
+    pub fn sentinels(mut self, sentinels: Vec<f64>) -> Self {
+        self.opts.sentinels = sentinels;
+        self
+    }
+
+    /// Compare NIfTI headers in common units.
+    pub fn normalize_units(mut self, normalize_units: bool) -> Self {
+        self.opts.normalize_units = normalize_units;
+        self
+    }
+
+    /// Measure the similarity of NIfTI images by this metric.
+    pub fn metric(mut self, metric: Metric) -> Self {
+        self.opts.metric = metric;
+        self
+    }
+
+    /// Handle NIfTI files of which only one is gzipped this way.
+    pub fn mixed_compression(mut self, handling: MixedCompression) -> Self {
+        self.opts.mixed_compression = handling;
+        self
+    }
+
+    /// Restrict byte-wise comparisons to these ranges.
+    pub fn byte_ranges(mut self, ranges: Vec<Range<u64>>) -> Self {
+        self.opts.byte_ranges = ranges;
+        self
+    }
+
+    /// Leave these ranges out of byte-wise comparisons.
+    pub fn ignore_ranges(mut self, ranges: Vec<Range<u64>>) -> Self {
+        self.opts.ignore_ranges = ranges;
+        self
+    }
+
+    /// Chart where files that differ byte-wise mismatch, in this many
+    /// stretches.
+    pub fn heatmap(mut self, buckets: usize) -> Self {
+        self.opts.heatmap_buckets = Some(buckets);
+        self
+    }
+
+    /// Test size differences up to this many bytes as constant offsets.
+    pub fn max_shift(mut self, max_shift: u64) -> Self {
+        self.opts.max_shift = max_shift;
+        self
+    }
+
+    /// Report differences in how BGZF payloads are split into blocks.
+    pub fn bgzf_blocks(mut self, bgzf_blocks: bool) -> Self {
+        self.opts.bgzf_blocks = bgzf_blocks;
+        self
+    }
+
+    /// Compare gzipped files by their compressed bytes.
+    pub fn compressed_bytes(mut self, compressed_bytes: bool) -> Self {
+        self.opts.compressed_bytes = compressed_bytes;
+        self
+    }
+
+    /// Apply a conversion to matching files before comparing them.
+    pub fn hook(mut self, hook: Hook) -> Self {
+        self.opts.hooks.push(hook);
+        self
+    }
+
+    /// Cache converted files in this directory.
+    pub fn cache_dir(mut self, cache_dir: impl Into<PathBuf>) -> Self {
+        self.opts.cache_dir = cache_dir.into();
+        self
+    }
+
+    /// Write intermediate files under this directory.
+    pub fn workspace_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.opts.workspace.dir = dir.into();
+        self
+    }
+
+    /// Keep intermediate files within this many bytes altogether.
+    pub fn max_workspace_size(mut self, max_size: u64) -> Self {
+        self.opts.workspace.max_size = Some(max_size);
+        self
+    }
+
+    /// Treat DataLad datasets as datasets.
+    pub fn datalad(mut self, datalad: bool) -> Self {
+        self.opts.datalad = datalad;
+        self
+    }
+
+    /// Read files in buffers of this many bytes.
+    pub fn chunk_size(mut self, chunk_size: usize) -> Self {
+        self.opts.chunk_size = chunk_size;
+        self
+    }
+
+    /// Compare files of at least this many bytes in ranges, on several
+    /// threads at once.
+    pub fn split_above(mut self, split_above: u64) -> Self {
+        self.opts.split_above = split_above;
+        self
+    }
+
+    /// Map uncompressed files into memory to compare them.
+    pub fn mmap(mut self, mmap: bool) -> Self {
+        self.opts.mmap = mmap;
+        self
+    }
+
+    /// Keep comparison buffers within this many bytes. The ceiling is
+    /// applied when building, once the number of jobs is known.
+    pub fn max_memory(mut self, max_memory: u64) -> Self {
+        self.max_memory = Some(max_memory);
+        self
+    }
+
+    /// Pin comparison threads to these CPUs.
+    pub fn cpus(mut self, cpus: Vec<usize>) -> Self {
+        self.opts.cpus = cpus;
+        self
+    }
+
+    /// Leave directory entries matching this glob out of comparisons.
+    pub fn exclude(mut self, pattern: &str) -> Self {
+        self.opts.exclude.push(String::from(pattern));
+        self
+    }
+
+    /// Compare this many directory entries at once.
+    pub fn jobs(mut self, jobs: usize) -> Self {
+        self.opts.jobs = jobs;
+        self
+    }
+
+    /// Let floating point voxels and numbers in structured data differ by
+    /// this much and still match.
+    pub fn tolerance(mut self, tolerance: f64) -> Self {
+        self.opts.tolerance = tolerance;
+        self
+    }
+
+    /// Count floating point voxels as matching when they differ by at most
+    /// `rtol` times the larger of their magnitudes, rather than by an
+    /// absolute tolerance.
+    pub fn relative_tolerance(mut self, rtol: f64) -> Self {
+        self.opts.float_comparison = FloatComparison::Relative(rtol);
+        self
+    }
+
+    /// Count floating point voxels as matching when at most `max` units in
+    /// the last place apart, rather than by an absolute tolerance.
+    pub fn max_ulps(mut self, max: u64) -> Self {
+        self.opts.float_comparison = FloatComparison::Ulps(max);
+        self
+    }
+
+    /// Let onsets and durations in events files differ by this many seconds
+    /// and still match.
+    pub fn onset_tolerance(mut self, seconds: f64) -> Self {
+        self.opts.onset_tolerance = seconds;
+        self
+    }
+
+    /// Align the rows of tables that have this column by it.
+    pub fn table_key(mut self, column: &str) -> Self {
+        self.opts.table_keys.push(String::from(column));
+        self
+    }
+
+    /// Leave this column out of comparisons of tables.
+    pub fn ignore_column(mut self, column: &str) -> Self {
+        self.opts.ignore_columns.push(String::from(column));
+        self
+    }
+
+    /// Let numeric cells of tables in columns matching `pattern` differ by
+    /// less than `tolerance` and still match.
+    pub fn column_tolerance(mut self, pattern: &str, tolerance: f64) -> Self {
+        self.opts.column_tolerances.push((String::from(pattern), tolerance));
+        self
+    }
+
+    /// Ignore the order of rows in tables without key columns.
+    pub fn unordered_rows(mut self, unordered: bool) -> Self {
+        self.opts.unordered_rows = unordered;
+        self
+    }
+
+    /// Compare only the structure of JSON, tables, and NIfTI images.
+    pub fn schema_only(mut self, schema_only: bool) -> Self {
+        self.opts.schema_only = schema_only;
+        self
+    }
+
+    /// Measure the largest relative difference between numbers in files.
+    pub fn drift(mut self, drift: bool) -> Self {
+        self.opts.drift = drift;
+        self
+    }
+
+    /// Compare arrays in structured data at places matching `pattern`
+    /// without regard to order.
+    pub fn unordered_array(mut self, pattern: &str) -> Self {
+        self.opts.unordered_arrays.push(String::from(pattern));
+        self
+    }
+
+    /// Leave values in structured data at places matching `pattern` out of
+    /// comparisons.
+    pub fn ignore_key(mut self, pattern: &str) -> Self {
+        self.opts.ignore_keys.push(String::from(pattern));
+        self
+    }
+
+    /// Exclude the entries and ignore the keys a preset names.
+    pub fn preset(mut self, preset: &Preset) -> Self {
+        preset.apply(&mut self.opts);
+        self
+    }
+
+    /// Let numbers in structured data at places matching `pattern` differ
+    /// by this much instead.
+    pub fn path_tolerance(mut self, pattern: &str, tolerance: f64) -> Self {
+        self.opts.path_tolerances.push((String::from(pattern), tolerance));
+        self
+    }
+
+    /// Leave comments out of source code comparisons.
+    pub fn ignore_comments(mut self, ignore: bool) -> Self {
+        self.opts.ignore_comments = ignore;
+        self
+    }
+
+    /// Leave whitespace out of source code comparisons.
+    pub fn ignore_whitespace(mut self, ignore: bool) -> Self {
+        self.opts.ignore_whitespace = ignore;
+        self
+    }
+
+    /// Leave cell outputs and execution counts out of notebook
+    /// comparisons.
+    pub fn ignore_outputs(mut self, ignore: bool) -> Self {
+        self.opts.ignore_outputs = ignore;
+        self
+    }
+
+    /// Leave metadata out of notebook comparisons.
+    pub fn ignore_metadata(mut self, ignore: bool) -> Self {
+        self.opts.ignore_metadata = ignore;
+        self
+    }
+
+    /// Compare JSON files in canonical form rather than byte by byte.
+    pub fn canonical_json(mut self, canonical: bool) -> Self {
+        self.opts.canonical_json = canonical;
+        self
+    }
+
+    /// Compare every file as text.
+    pub fn force_text(mut self, force: bool) -> Self {
+        self.opts.force_text = force;
+        self
+    }
+
+    /// Descend at most this many levels of subdirectories.
+    pub fn max_depth(mut self, max_depth: usize) -> Self {
+        self.opts.max_depth = Some(max_depth);
+        self
+    }
+
+    /// Color reports with terminal escape codes.
+    pub fn color(mut self, color: bool) -> Self {
+        self.opts.color = color;
+        self
+    }
+
+    /// Stop comparing a directory at its first differing entry.
+    pub fn fail_fast(mut self, fail_fast: bool) -> Self {
+        self.opts.fail_fast = fail_fast;
+        self
+    }
+
+    /// Fail on directory entries that may not be read.
+    pub fn fail_on_inaccessible(mut self, fail: bool) -> Self {
+        self.opts.fail_on_inaccessible = fail;
+        self
+    }
+
+    /// Compare symbolic links by this policy.
+    pub fn symlinks(mut self, policy: SymlinkPolicy) -> Self {
+        self.opts.symlinks = policy;
+        self
+    }
+
+    /// Compare entries only one directory holds against an empty file.
+    pub fn new_file(mut self, new_file: bool) -> Self {
+        self.opts.new_file = new_file;
+        self
+    }
+
+    /// Carry out only this part of directory comparisons.
+    pub fn shard(mut self, shard: Shard) -> Self {
+        self.opts.shard = Some(shard);
+        self
+    }
+
+    /// Strip this many leading directories from paths in a tar stream.
+    pub fn tar_strip_components(mut self, components: usize) -> Self {
+        self.opts.tar_strip_components = Some(components);
+        self
+    }
+
+    /// Finish building. Fails if a memory ceiling was set too low for the
+    /// number of jobs.
+    pub fn build(self) -> Result<DiffOptions, String> {
+        let mut opts = self.opts;
+        if hash::hasher(&opts.hash_algorithm).is_none() {
+            return Err(format!("unknown hash algorithm '{}'; known are {}",
+                               opts.hash_algorithm,
+                               hash::algorithms().join(", ")));
+        }
+        if let Some(max_memory) = self.max_memory {
+            opts.limit_memory(max_memory)?;
+        }
+        Ok(opts)
+    }
+}
+
+/// IgnoredOffsets
+/// One entry of an ignore-offsets file: a range given either by its end or
+/// its length, with an optional name documenting what lives there.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct IgnoredOffsets {
+    start: u64,
+    end: Option<u64>,
+    length: Option<u64>,
+    #[allow(dead_code)]
+    name: Option<String>,
+}
+
+/// Load byte ranges to ignore from a JSON file listing them, e.g.
+/// `[{"start": 16, "length": 16, "name": "uuid"}, {"start": 64, "end": 72}]`
+pub fn load_ignore_offsets(path: &str)
+    -> Result<Vec<Range<u64>>, RsdiffError> {
+    let text = fs::read_to_string(path)
+        .map_err(|e| RsdiffError::io(path, e))?;
+    let entries: Vec<IgnoredOffsets> = serde_json::from_str(&text)
+        .map_err(|e| RsdiffError::Corrupt(format!(
+            "can't parse ignored offsets in {}: {}", path, e
+        )))?;
+    entries.iter()
+        .map(|e| match (e.end, e.length) {
+            (Some(end), None) => Ok(e.start..end),
+            (None, Some(length)) => {
+                Ok(e.start..e.start.saturating_add(length))
+            }
+            _ => Err(RsdiffError::Corrupt(format!(
+                "ignored offsets at {} in {} need exactly one of end or \
+                 length", e.start, path
+            ))),
+        })
+        .collect()
+}
+
+/// Parse a byte range written as `START..END` or `START..` (to the end of
+/// the file). Offsets may be decimal or `0x`-prefixed hexadecimal.
+pub fn parse_byte_range(s: &str) -> Result<Range<u64>, String> {
+    let (start, end) = match s.find("..") {
+        Some(i) => (&s[..i], &s[i + 2..]),
+        None => return Err(format!("{} is not of the form START..END", s)),
+    };
+    let start = parse_offset(start)?;
+    let end = if end.is_empty() { u64::MAX } else { parse_offset(end)? };
+    if end < start {
+        return Err(format!("Range {} ends before it starts", s));
+    }
+    Ok(start..end)
+}
+
+/// Parse a per-path or per-column tolerance written as `PATTERN=EPSILON`,
+/// e.g. `EchoTime=1e-6`, `/*/RepetitionTime=1e-3`, or `a_comp_cor_*=1e-5`.
+pub fn parse_path_tolerance(s: &str) -> Result<(String, f64), String> {
+    let (pattern, tolerance) = match s.rfind('=') {
+        Some(i) => (&s[..i], &s[i + 1..]),
+        None => return Err(format!("{} is not of the form PATTERN=EPSILON",
+                                   s)),
+    };
+    Glob::new(pattern).map_err(|e| e.to_string())?;
+    let tolerance = tolerance.trim().parse::<f64>()
+        .map_err(|_| format!("{} is not a valid tolerance", tolerance))?;
+    Ok((String::from(pattern), tolerance))
+}
+
+/// Parse a shard written as `INDEX/COUNT`, e.g. `3/16`, with the index
+/// counted from zero.
+pub fn parse_shard(s: &str) -> Result<Shard, String> {
+    let (index, count) = match s.find('/') {
+        Some(i) => (&s[..i], &s[i + 1..]),
+        None => return Err(format!("{} is not of the form INDEX/COUNT", s)),
+    };
+    let index = index.trim().parse::<usize>()
+        .map_err(|_| format!("{} is not a valid shard index", index))?;
+    let count = count.trim().parse::<usize>()
+        .map_err(|_| format!("{} is not a valid shard count", count))?;
+    if index >= count {
+        return Err(format!("Shard {} is out of range; shards are numbered \
+                            from 0 to {}", index, count.saturating_sub(1)));
+    }
+    Ok(Shard { index, count })
+}
+
+/// Parse a size in bytes, optionally with a binary K, M, G, or T suffix as
+/// used by schedulers like SLURM, e.g. `512M`.
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let (digits, scale) = match s.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&s[..s.len() - 1], 1u64 << 10),
+        Some('M') => (&s[..s.len() - 1], 1 << 20),
+        Some('G') => (&s[..s.len() - 1], 1 << 30),
+        Some('T') => (&s[..s.len() - 1], 1 << 40),
+        _ => (s, 1),
+    };
+    digits.parse::<u64>().ok()
+        .and_then(|n| n.checked_mul(scale))
+        .ok_or_else(|| format!("{} is not a valid size", s))
+}
+
+/// Parse a decimal or `0x`-prefixed hexadecimal byte value, e.g. `0xff`.
+pub fn parse_byte(s: &str) -> Result<u8, String> {
+    let s = s.trim();
+    let parsed = match s.strip_prefix("0x") {
+        Some(hex) => u8::from_str_radix(hex, 16),
+        None => s.parse(),
+    };
+    parsed.map_err(|_| format!("{} is not a byte value from 0 to 255", s))
+}
+
+/// Parse a decimal or `0x`-prefixed hexadecimal byte offset.
+fn parse_offset(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let parsed = match s.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => s.parse(),
+    };
+    parsed.map_err(|_| format!("{} is not a valid byte offset", s))
+}
@@ -0,0 +1,69 @@
+//! Hash pre-checks for rsdiff
+//!
+//! A copy of a dataset is usually identical to the original but for a
+//! handful of files, and comparing it in full reads every pair of files in
+//! lockstep, seeking back and forth between two disks. With a pre-check,
+//! each side of a pair is hashed on a thread of its own first, reading each
+//! disk straight through, and only pairs whose hashes differ go on to the
+//! full byte or voxel comparison. Files that differ are read twice, so
+//! pre-checks pay off only when most files are the same.
+
+use std::fs::{self, File};
+
+use crate::{
+    error::{Result, RsdiffError},
+    hash::HashingReader,
+    read_chunk, Diff, DiffOptions, Unit,
+};
+
+/// The algorithm files are pre-checked with, fast and strong enough that
+/// files with the same hash can be taken to be the same.
+const ALGORITHM: &str = "blake3";
+
+/// Hashes of a file: the pre-check's, and the requested one, if any.
+type Digests = (blake3::Hash, Option<String>);
+
+/// Hash two equally long regular files side by side and, if their hashes
+/// match, make a diff of them as identical. Any other pair is left to a
+/// full comparison, as is reporting what can't be read.
+pub(crate) fn identical(left: &str, right: &str, opts: &DiffOptions)
+    -> Result<Option<Diff>> {
+    let len = match (fs::metadata(left), fs::metadata(right)) {
+        (Ok(l), Ok(r)) if l.is_file() && r.is_file() && l.len() == r.len()
+            => l.len(),
+        _ => return Ok(None),
+    };
+    let (left_digests, right_digests) = rayon::join(|| digest(left, opts),
+                                                    || digest(right, opts));
+    let ((left_hash, left_requested), (right_hash, right_requested)) =
+        (left_digests?, right_digests?);
+    if left_hash != right_hash {
+        return Ok(None);
+    }
+    let mut d = Diff::new(left, right);
+    d.matches = true;
+    d.set_counts(len as usize, len as usize, Unit::Bytes);
+    d.additional_info = format!("identical by {} hash", ALGORITHM);
+    d.left_hash = left_requested;
+    d.right_hash = right_requested;
+    Ok(Some(d))
+}
+
+/// Hash a file for the pre-check, and with the requested algorithm in the
+/// same pass if hashing was requested.
+fn digest(path: &str, opts: &DiffOptions) -> Result<Digests> {
+    let file = File::open(path).map_err(|e| RsdiffError::io(path, e))?;
+    let mut reader = HashingReader::new(file, opts.hasher());
+    let mut hasher = blake3::Hasher::new();
+    let mut buffer = vec![0u8; opts.chunk_size];
+    loop {
+        let n = read_chunk(&mut reader, &mut buffer)
+            .map_err(|e| RsdiffError::io(path, e))?;
+        hasher.update(&buffer[..n]);
+        if n < buffer.len() {
+            break;
+        }
+    }
+    let requested = reader.finish().map_err(|e| RsdiffError::io(path, e))?;
+    Ok((hasher.finalize(), requested))
+}
@@ -0,0 +1,167 @@
+//! Presets for rsdiff
+//!
+//! Pipeline outputs carry provenance that differs from run to run whatever
+//! the data: logs, citation files, and the `GeneratedBy` record of a BIDS
+//! `dataset_description.json`, which notes when and by what the dataset
+//! was made. Compared as they are, two runs of a pipeline differ in every
+//! one of them. Workflows also tend to want the same handful of settings
+//! every time, such as a tolerance and a report format. A preset is a named
+//! bundle of such settings, exclude patterns, and JSON keys to leave out of
+//! comparisons, so a single `--preset` sets them all. Presets of the same
+//! name in the configuration file replace the built-in ones, and new ones
+//! may be added there.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    report::Format, DiffOptions, FloatComparison, Metric, Result, RsdiffError,
+};
+
+/// Preset
+/// Settings for a kind of comparison. Settings that are left out, or
+/// false, leave the options as they were.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Preset {
+    /// Patterns of entries to exclude, as for `--exclude`.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub exclude: Vec<String>,
+    /// Globs over JSON pointers of values to ignore, as for `--ignore-key`.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub ignore_keys: Vec<String>,
+    /// Largest difference at which numbers still match.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tolerance: Option<f64>,
+    /// Largest difference at which numbers still match, relative to the
+    /// larger of them, overriding `tolerance`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rtol: Option<f64>,
+    /// What the similarity of images is measured by.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metric: Option<Metric>,
+    /// How the report is written, when the command line doesn't say.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub format: Option<Format>,
+    /// Whether to compare JSON byte by byte rather than in canonical form.
+    #[serde(skip_serializing_if = "is_false")]
+    pub exact_json: bool,
+    /// Whether to measure how far apart numbers drift.
+    #[serde(skip_serializing_if = "is_false")]
+    pub drift: bool,
+    /// Whether to pre-check files by hash.
+    #[serde(skip_serializing_if = "is_false")]
+    pub precheck: bool,
+    /// Whether entries that can't be read are an error.
+    #[serde(skip_serializing_if = "is_false")]
+    pub fail_on_inaccessible: bool,
+}
+
+/// The names of the built-in presets.
+pub const BUILT_IN: &[&str] = &[
+    "archive-verify", "ci", "fmriprep", "neuroimaging", "strict",
+];
+
+/// Provenance files of neuroimaging pipelines. fMRIPrep writes its citation
+/// boilerplate as logs/CITATION.*.
+const PROVENANCE_FILES: &[&str] = &["*.log", "*_citation.bib", "CITATION.*"];
+/// JSON keys recording when and by what a BIDS dataset was made.
+const PROVENANCE_KEYS: &[&str] = &["/GeneratedBy"];
+
+impl Preset {
+    /// A built-in preset by name.
+    pub fn built_in(name: &str) -> Option<Preset> {
+        let strings = |s: &[&str]| {
+            s.iter().map(|&s| String::from(s)).collect()
+        };
+        Some(match name {
+            // Any difference at all counts, down to how JSON is written
+            "strict" => Preset {
+                tolerance: Some(0.0),
+                exact_json: true,
+                fail_on_inaccessible: true,
+                ..Preset::default()
+            },
+            // A row per file reads well in a job's log, and nothing may go
+            // unchecked
+            "ci" => Preset {
+                format: Some(Format::Tsv),
+                fail_on_inaccessible: true,
+                ..Preset::default()
+            },
+            "neuroimaging" => Preset {
+                exclude: strings(PROVENANCE_FILES),
+                ignore_keys: strings(PROVENANCE_KEYS),
+                ..Preset::default()
+            },
+            // Reruns of fMRIPrep drift in the last digits of floats, and
+            // its HTML reports are stamped with when they were made
+            "fmriprep" => Preset {
+                exclude: strings(&[PROVENANCE_FILES, &["sub-*.html"]]
+                                 .concat()),
+                ignore_keys: strings(PROVENANCE_KEYS),
+                tolerance: Some(1e-6),
+                metric: Some(Metric::Auto),
+                drift: true,
+                ..Preset::default()
+            },
+            // Copies of an archive should be identical, and most of their
+            // files are, so hashing first saves comparing them in full
+            "archive-verify" => Preset {
+                tolerance: Some(0.0),
+                format: Some(Format::Json),
+                exact_json: true,
+                precheck: true,
+                fail_on_inaccessible: true,
+                ..Preset::default()
+            },
+            _ => return None,
+        })
+    }
+
+    /// A preset by name, from `configured` if it is there and otherwise
+    /// built in.
+    pub fn named(name: &str, configured: &BTreeMap<String, Preset>)
+        -> Result<Preset> {
+        configured.get(name).cloned()
+            .or_else(|| Preset::built_in(name))
+            .ok_or_else(|| {
+                let mut known: Vec<&str> = configured.keys()
+                    .map(String::as_str)
+                    .chain(BUILT_IN.iter().copied())
+                    .collect();
+                known.sort();
+                known.dedup();
+                RsdiffError::Corrupt(format!(
+                    "there is no preset named {}; known presets are {}",
+                    name, known.join(", ")
+                ))
+            })
+    }
+
+    /// Apply the preset's settings to options. Exclude patterns and JSON
+    /// keys are added to those already there.
+    pub fn apply(&self, opts: &mut DiffOptions) {
+        opts.exclude.extend(self.exclude.iter().cloned());
+        opts.ignore_keys.extend(self.ignore_keys.iter().cloned());
+        if let Some(tolerance) = self.tolerance {
+            opts.tolerance = tolerance;
+        }
+        if let Some(rtol) = self.rtol {
+            opts.float_comparison = FloatComparison::Relative(rtol);
+        }
+        if let Some(metric) = self.metric {
+            opts.metric = metric;
+        }
+        opts.canonical_json &= !self.exact_json;
+        opts.drift |= self.drift;
+        opts.precheck |= self.precheck;
+        opts.fail_on_inaccessible |= self.fail_on_inaccessible;
+    }
+}
+
+/// Whether a flag is unset, and so left out of a preset written out.
+fn is_false(b: &bool) -> bool {
+    !b
+}
@@ -0,0 +1,104 @@
+//! Progress reporting for rsdiff
+//!
+//! Comparing a large dataset can take hours, and until now said nothing
+//! until the end. Comparisons announce how far along they are as events,
+//! which go to a single callback the caller sets: the command line draws a
+//! progress bar from them, and a GUI wrapper can show status its own way.
+//! With no callback set, nothing is announced.
+
+use std::sync::{
+    atomic::{AtomicBool, AtomicU64, Ordering},
+    Mutex,
+};
+
+use serde::Serialize;
+
+/// Files smaller than this are compared quickly enough that their progress
+/// isn't worth announcing.
+const LARGE_FILE: u64 = 64 << 20;
+
+type Callback = Box<dyn FnMut(ProgressEvent) + Send>;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static CALLBACK: Mutex<Option<Callback>> = Mutex::new(None);
+
+/// ProgressEvent
+/// How far along a comparison is. Serialized with its kind in `event`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum ProgressEvent {
+    /// A directory was listed, and this many of its entries will be
+    /// compared.
+    Directory { left: String, entries: usize },
+    /// An entry of a directory has been compared.
+    Compared { left: String, matches: bool },
+    /// Part of a large file has been compared: `done` of `total` bytes.
+    Bytes { left: String, done: u64, total: u64 },
+}
+
+/// Send progress events to `callback`, replacing any callback set before.
+/// Comparisons run in parallel take turns calling it.
+pub fn set_callback(callback: impl FnMut(ProgressEvent) + Send + 'static) {
+    *CALLBACK.lock().unwrap() = Some(Box::new(callback));
+    ENABLED.store(true, Ordering::SeqCst);
+}
+
+/// Stop sending progress events.
+pub fn clear_callback() {
+    ENABLED.store(false, Ordering::SeqCst);
+    *CALLBACK.lock().unwrap() = None;
+}
+
+/// Whether anyone is listening, so events needn't be built otherwise.
+pub(crate) fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Announce an event, if a callback is set.
+pub(crate) fn emit(event: ProgressEvent) {
+    if let Some(callback) = CALLBACK.lock().unwrap().as_mut() {
+        callback(event);
+    }
+}
+
+/// FileProgress
+/// Announces the progress of a comparison through one large file, chunk
+/// by chunk, from however many threads share the file.
+pub(crate) struct FileProgress {
+    left: String,
+    done: AtomicU64,
+    announced: AtomicU64,
+    total: u64,
+}
+
+impl FileProgress {
+    /// Track a comparison of `total` bytes, or None if nobody is listening
+    /// or the file is too small to bother.
+    pub(crate) fn new(left: &str, total: u64) -> Option<FileProgress> {
+        if !enabled() || total < LARGE_FILE {
+            return None;
+        }
+        Some(FileProgress { left: String::from(left), done: AtomicU64::new(0),
+                            announced: AtomicU64::new(0), total })
+    }
+
+    /// Record that another `bytes` bytes have been compared, announcing
+    /// it every thousandth of the file and at the end.
+    pub(crate) fn advance(&self, bytes: usize) {
+        let done = (self.done.fetch_add(bytes as u64, Ordering::Relaxed)
+                    + bytes as u64).min(self.total);
+        if done < self.total {
+            // Of threads passing the same mark together, one announces it
+            let announced = self.announced.load(Ordering::Relaxed);
+            if done < announced + self.total / 1000
+                || self.announced.compare_exchange(
+                    announced, done, Ordering::Relaxed, Ordering::Relaxed
+                ).is_err() {
+                return;
+            }
+        }
+        emit(ProgressEvent::Bytes {
+            left: self.left.clone(), done, total: self.total,
+        });
+    }
+}
@@ -0,0 +1,110 @@
+//! Provenance records for rsdiff
+//!
+//! A provenance record documents who ran a comparison, where, when, with
+//! which options, and what the result was for every object compared. It is
+//! meant to be archived as an audit trail alongside verified data.
+
+use chrono::{SecondsFormat, Utc};
+use serde_json::{json, Value};
+
+use crate::{Diff, DiffOptions, config::Config, datalad};
+
+/// Provenance
+/// Tracks the context of a comparison run from start to finish.
+#[derive(Debug)]
+pub struct Provenance {
+    /// The command line used to invoke the comparison.
+    pub command_line: Vec<String>,
+    /// The options the comparison was run with.
+    pub options: DiffOptions,
+    /// The configuration in effect.
+    pub config: Config,
+    /// The host the comparison ran on.
+    pub hostname: String,
+    /// When the comparison started, as an RFC 3339 timestamp.
+    pub started: String,
+}
+
+impl Provenance {
+    /// Start a provenance record for a comparison about to run.
+    pub fn start(command_line: Vec<String>, options: &DiffOptions,
+                 config: &Config) -> Provenance {
+        let hostname = hostname::get()
+            .map(|h| h.to_string_lossy().into_owned())
+            .unwrap_or_else(|_| String::from("unknown"));
+        Provenance {
+            command_line,
+            options: options.clone(),
+            config: config.clone(),
+            hostname,
+            started: now(),
+        }
+    }
+
+    /// Finish the record with the completed diff, producing the JSON
+    /// document to archive.
+    pub fn finish(&self, d: &Diff) -> Value {
+        let datasets = if self.options.datalad {
+            datalad_datasets(d)
+        }
+        else {
+            vec!()
+        };
+        let results: Vec<Value> = d.flatten_with_parents().iter()
+            .map(|(parent, node)| json!({
+                "id": node.id(),
+                "parent": parent.map(|p| p.id()),
+                "left": node.left,
+                "right": node.right,
+                "matches": node.matches,
+                "similarity": node.similarity,
+                "unit": node.unit,
+                "matched": node.matched,
+                "total": node.total,
+                "additional_info": node.additional_info,
+                "findings": node.findings,
+                "left_only": node.left_only,
+                "right_only": node.right_only,
+                "left_hash": node.left_hash,
+                "right_hash": node.right_hash,
+                "seconds": node.seconds,
+                "interrupted": node.interrupted,
+            }))
+            .collect();
+        json!({
+            "rsdiff_version": env!("CARGO_PKG_VERSION"),
+            "command_line": self.command_line,
+            "options": self.options,
+            "config": self.config,
+            "hostname": self.hostname,
+            "started": self.started,
+            "finished": now(),
+            "left": d.left,
+            "right": d.right,
+            "matches": d.matches,
+            "results": results,
+            "datalad_datasets": datasets,
+        })
+    }
+}
+
+/// The DataLad datasets among the compared directories, with their IDs.
+fn datalad_datasets(d: &Diff) -> Vec<Value> {
+    let mut datasets = vec!();
+    for node in d.flatten() {
+        for path in [&node.left, &node.right] {
+            if datalad::is_dataset(path) {
+                datasets.push(json!({
+                    "path": path,
+                    "id": datalad::dataset_id(path),
+                }));
+            }
+        }
+    }
+    datasets
+}
+
+/// The current time as an RFC 3339 timestamp in UTC.
+fn now() -> String {
+    Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true)
+}
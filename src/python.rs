@@ -0,0 +1,243 @@
+//! Python bindings for rsdiff
+//!
+//! Much neuroimaging scripting is done in Python, where shelling out to
+//! rsdiff and parsing its text report is fragile. With the `python`
+//! feature, rsdiff builds as a Python extension module, e.g. with
+//! `maturin develop --features python`, offering
+//!
+//! ```python
+//! import rsdiff
+//! d = rsdiff.diff("left.nii.gz", "right.nii.gz", tolerance=1e-6)
+//! if not d.matches:
+//!     print(d.report)
+//! ```
+//!
+//! Options are given as keyword arguments named like the fields of
+//! `DiffOptions`. The result reads like the dict of a JSON report. Objects
+//! that can't be read raise OSError, and comparisons that can't be carried
+//! out otherwise raise ValueError.
+
+use std::{collections::BTreeMap, str::FromStr};
+
+use pyo3::{
+    exceptions::{PyKeyError, PyOSError, PyTypeError, PyValueError},
+    prelude::*,
+    types::{PyDict, PyList},
+    IntoPyObjectExt,
+};
+use serde_json::Value;
+
+use crate::{
+    checksums::Checksums, differ_with_options, options::DiffOptionsBuilder,
+    preset::Preset, DiffOptions, Metric, MixedCompression, RsdiffError,
+    SymlinkPolicy, Unit,
+};
+
+/// Diff
+/// A comparison's result, read like the dict of its JSON report, with the
+/// most used keys also as attributes. Sub-diffs are Diffs too.
+#[pyclass(name = "Diff", module = "rsdiff")]
+struct PyDiff {
+    json: Value,
+}
+
+#[pymethods]
+impl PyDiff {
+    fn __getitem__(&self, py: Python, key: &str) -> PyResult<Py<PyAny>> {
+        match (key, self.json.get(key)) {
+            ("sub_diffs", Some(Value::Array(subs))) => {
+                let subs: Vec<PyDiff> = subs.iter()
+                    .map(|s| PyDiff { json: s.clone() })
+                    .collect();
+                subs.into_py_any(py)
+            }
+            (_, Some(value)) => to_python(py, value),
+            (_, None) => Err(PyKeyError::new_err(String::from(key))),
+        }
+    }
+
+    fn __contains__(&self, key: &str) -> bool {
+        self.json.get(key).is_some()
+    }
+
+    fn __len__(&self) -> usize {
+        self.json.as_object().map_or(0, |o| o.len())
+    }
+
+    fn __repr__(&self) -> String {
+        let side = |key| self.json[key].as_str().unwrap_or_default();
+        let verdict = if self.matches() { "match" } else { "differ" };
+        format!("<rsdiff.Diff {} vs. {}: {}>", side("left"), side("right"),
+                verdict)
+    }
+
+    /// The report's keys.
+    fn keys(&self) -> Vec<String> {
+        self.json.as_object()
+            .map(|o| o.keys().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// The whole report as nested dicts and lists.
+    fn to_dict(&self, py: Python) -> PyResult<Py<PyAny>> {
+        to_python(py, &self.json)
+    }
+
+    #[getter]
+    fn matches(&self) -> bool {
+        self.json["matches"].as_bool().unwrap_or(false)
+    }
+
+    #[getter]
+    fn similarity(&self) -> Option<f64> {
+        self.json["similarity"].as_f64()
+    }
+
+    #[getter]
+    fn report(&self) -> String {
+        String::from(self.json["report"].as_str().unwrap_or_default())
+    }
+}
+
+/// Compare two objects, with options as keyword arguments.
+#[pyfunction]
+#[pyo3(signature = (left, right, **options))]
+fn diff(py: Python, left: &str, right: &str,
+        options: Option<&Bound<PyDict>>) -> PyResult<PyDiff> {
+    let opts = parse_options(options)?;
+    let d = py.allow_threads(|| differ_with_options(left, right, &opts))
+        .map_err(|e| match e {
+            RsdiffError::Io { .. } => PyOSError::new_err(e.to_string()),
+            _ => PyValueError::new_err(e.to_string()),
+        })?;
+    Ok(PyDiff { json: d.to_json() })
+}
+
+/// The rsdiff Python module.
+#[pymodule]
+fn rsdiff(m: &Bound<PyModule>) -> PyResult<()> {
+    m.add_function(wrap_pyfunction!(diff, m)?)?;
+    m.add_class::<PyDiff>()?;
+    Ok(())
+}
+
+/// Build options from keyword arguments.
+fn parse_options(options: Option<&Bound<PyDict>>) -> PyResult<DiffOptions> {
+    let mut builder = DiffOptions::builder();
+    for (key, value) in options.into_iter().flat_map(|o| o.iter()) {
+        let key: String = key.extract()?;
+        builder = set_option(builder, &key, &value)?;
+    }
+    builder.build().map_err(PyValueError::new_err)
+}
+
+/// Set one option from a keyword argument.
+fn set_option(builder: DiffOptionsBuilder, key: &str, value: &Bound<PyAny>)
+    -> PyResult<DiffOptionsBuilder> {
+    Ok(match key {
+        "tolerance" => builder.tolerance(value.extract()?),
+        "rtol" => builder.relative_tolerance(value.extract()?),
+        "max_ulps" => builder.max_ulps(value.extract()?),
+        "hash" => builder.hash(value.extract()?),
+        "precheck" => builder.precheck(value.extract()?),
+        "jobs" => builder.jobs(value.extract()?),
+        "max_depth" => builder.max_depth(value.extract()?),
+        "chunk_size" => builder.chunk_size(value.extract()?),
+        "split_above" => builder.split_above(value.extract()?),
+        "mmap" => builder.mmap(value.extract()?),
+        "fail_fast" => builder.fail_fast(value.extract()?),
+        "fail_on_inaccessible" => {
+            builder.fail_on_inaccessible(value.extract()?)
+        }
+        "drift" => builder.drift(value.extract()?),
+        "datalad" => builder.datalad(value.extract()?),
+        "new_file" => builder.new_file(value.extract()?),
+        "schema_only" => builder.schema_only(value.extract()?),
+        "force_text" => builder.force_text(value.extract()?),
+        "scaled_voxels" => builder.scaled_voxels(value.extract()?),
+        "sentinels" => builder.sentinels(value.extract()?),
+        "normalize_units" => builder.normalize_units(value.extract()?),
+        "canonical_json" => builder.canonical_json(value.extract()?),
+        "unordered_rows" => builder.unordered_rows(value.extract()?),
+        "ignore_comments" => builder.ignore_comments(value.extract()?),
+        "ignore_whitespace" => builder.ignore_whitespace(value.extract()?),
+        "ignore_outputs" => builder.ignore_outputs(value.extract()?),
+        "ignore_metadata" => builder.ignore_metadata(value.extract()?),
+        "voxel_unit" => builder.voxel_unit(named::<Unit>(value)?),
+        "metric" => builder.metric(named::<Metric>(value)?),
+        "symlinks" => builder.symlinks(named::<SymlinkPolicy>(value)?),
+        "mixed_compression" => {
+            builder.mixed_compression(named::<MixedCompression>(value)?)
+        }
+        "hash_algorithm" => {
+            let name: String = value.extract()?;
+            builder.hash_algorithm(&name)
+        }
+        "checksums" => {
+            let path: String = value.extract()?;
+            let checksums = Checksums::load(&path)
+                .map_err(|e| PyValueError::new_err(e.to_string()))?;
+            builder.checksums(checksums)
+        }
+        "exclude" => {
+            let patterns: Vec<String> = value.extract()?;
+            patterns.iter().fold(builder, |b, p| b.exclude(p))
+        }
+        "ignore_keys" => {
+            let patterns: Vec<String> = value.extract()?;
+            patterns.iter().fold(builder, |b, p| b.ignore_key(p))
+        }
+        "preset" => {
+            let name: String = value.extract()?;
+            let preset = Preset::named(&name, &BTreeMap::new())
+                .map_err(|e| PyValueError::new_err(e.to_string()))?;
+            builder.preset(&preset)
+        }
+        "table_key" => {
+            let column: String = value.extract()?;
+            builder.table_key(&column)
+        }
+        "ignore_columns" => {
+            let columns: Vec<String> = value.extract()?;
+            columns.iter().fold(builder, |b, c| b.ignore_column(c))
+        }
+        _ => {
+            return Err(PyTypeError::new_err(format!(
+                "diff() got an unexpected keyword argument '{}'", key
+            )));
+        }
+    })
+}
+
+/// Parse an option given by name, as on the command line.
+fn named<T: FromStr<Err = String>>(value: &Bound<PyAny>) -> PyResult<T> {
+    let name: String = value.extract()?;
+    name.parse().map_err(PyValueError::new_err)
+}
+
+/// Convert a JSON value into the Python value json.loads would give.
+fn to_python(py: Python, value: &Value) -> PyResult<Py<PyAny>> {
+    match value {
+        Value::Null => Ok(py.None()),
+        Value::Bool(b) => b.into_py_any(py),
+        Value::Number(n) => match (n.as_u64(), n.as_i64()) {
+            (Some(u), _) => u.into_py_any(py),
+            (None, Some(i)) => i.into_py_any(py),
+            _ => n.as_f64().unwrap_or(f64::NAN).into_py_any(py),
+        },
+        Value::String(s) => s.into_py_any(py),
+        Value::Array(items) => {
+            let items = items.iter()
+                .map(|v| to_python(py, v))
+                .collect::<PyResult<Vec<_>>>()?;
+            PyList::new(py, items)?.into_py_any(py)
+        }
+        Value::Object(map) => {
+            let dict = PyDict::new(py);
+            for (key, value) in map.iter() {
+                dict.set_item(key, to_python(py, value)?)?;
+            }
+            dict.into_py_any(py)
+        }
+    }
+}
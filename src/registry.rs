@@ -0,0 +1,458 @@
+//! Pluggable differs for rsdiff
+//!
+//! Every kind of object rsdiff knows how to compare is handled by a
+//! `Differ`. `differ()` asks each registered differ in turn whether it can
+//! handle a pair of paths and hands the pair to the first that can.
+//! Downstream crates can register their own differs, for formats such as
+//! proprietary scanner output, and these are asked before the built-in
+//! ones, so they can also take over formats rsdiff already handles.
+
+use std::sync::{Arc, OnceLock, RwLock};
+
+use crate::{
+    archive, code, diff_bgzf_with_options, diff_bytes_with_options,
+    diff_directory_with_options, diff_gzip_contents_with_options,
+    diff_nii_with_options, events, gifti, gz, json,
+    notebook, table, text, Diff, DiffOptions, Result,
+};
+
+/// Differ
+/// A way of comparing one kind of object.
+pub trait Differ: Send + Sync {
+    /// Whether this differ can compare the object at `path`.
+    fn can_handle(&self, path: &str) -> bool;
+
+    /// Compare two objects this differ can handle.
+    fn diff(&self, left: &str, right: &str, opts: &DiffOptions)
+        -> Result<Diff>;
+
+    /// Whether this differ can compare a pair of objects. By default this
+    /// goes by the left one.
+    fn can_handle_pair(&self, left: &str, _right: &str) -> bool {
+        self.can_handle(left)
+    }
+
+    /// What this differ compares and how, for explaining a comparison. By
+    /// default this is the differ's type name.
+    fn name(&self) -> &str {
+        std::any::type_name::<Self>()
+    }
+
+    /// Whether this differ compares directories entry by entry, so that
+    /// directories it would compare below another are walked into in its
+    /// place. Only the built-in directory differ does.
+    fn walks_directories(&self) -> bool {
+        false
+    }
+}
+
+/// The registered differs, in the order they are asked.
+fn registry() -> &'static RwLock<Vec<Arc<dyn Differ>>> {
+    static REGISTRY: OnceLock<RwLock<Vec<Arc<dyn Differ>>>> = OnceLock::new();
+    REGISTRY.get_or_init(|| RwLock::new(vec!(
+        Arc::new(DirectoryDiffer),
+        Arc::new(NiftiDiffer),
+        Arc::new(GiftiDiffer),
+        Arc::new(NotebookDiffer),
+        Arc::new(JsonDiffer),
+        Arc::new(EventsDiffer),
+        Arc::new(TableDiffer),
+        Arc::new(CodeDiffer),
+        Arc::new(ArchiveDiffer),
+        Arc::new(BgzfDiffer),
+        Arc::new(GzipDiffer),
+        Arc::new(TextDiffer),
+        Arc::new(BytesDiffer),
+    )))
+}
+
+/// Register a differ. It is asked before every differ registered earlier,
+/// including the built-in ones.
+pub fn register(differ: impl Differ + 'static) {
+    registry().write().unwrap().insert(0, Arc::new(differ));
+}
+
+/// Find the differ for a pair of objects. Byte-wise comparison handles
+/// anything no other differ does.
+pub(crate) fn find(left: &str, right: &str) -> Arc<dyn Differ> {
+    registry().read().unwrap().iter()
+        .find(|d| d.can_handle_pair(left, right))
+        .cloned()
+        .unwrap_or_else(|| Arc::new(BytesDiffer))
+}
+
+/// DirectoryDiffer
+/// Compares directories entry by entry.
+pub struct DirectoryDiffer;
+
+impl Differ for DirectoryDiffer {
+    fn can_handle(&self, path: &str) -> bool {
+        std::path::Path::new(path).is_dir()
+    }
+
+    fn diff(&self, left: &str, right: &str, opts: &DiffOptions)
+        -> Result<Diff> {
+        diff_directory_with_options(left, right, opts)
+    }
+
+    fn name(&self) -> &str {
+        "directories, entry by entry"
+    }
+
+    fn walks_directories(&self) -> bool {
+        true
+    }
+}
+
+/// NiftiDiffer
+/// Compares NIfTI-1 images by header and voxels.
+pub struct NiftiDiffer;
+
+impl Differ for NiftiDiffer {
+    fn can_handle(&self, path: &str) -> bool {
+        path.ends_with(".nii.gz") || path.ends_with(".nii")
+    }
+
+    fn diff(&self, left: &str, right: &str, opts: &DiffOptions)
+        -> Result<Diff> {
+        diff_nii_with_options(left, right, opts)
+    }
+
+    fn name(&self) -> &str {
+        "NIfTI images, header and voxels"
+    }
+}
+
+/// GiftiDiffer
+/// Compares GIFTI files by their decoded data arrays and metadata.
+pub struct GiftiDiffer;
+
+impl Differ for GiftiDiffer {
+    fn can_handle(&self, path: &str) -> bool {
+        gifti::is_gifti(path)
+    }
+
+    fn diff(&self, left: &str, right: &str, opts: &DiffOptions)
+        -> Result<Diff> {
+        gifti::diff_gifti_with_options(left, right, opts)
+    }
+
+    fn name(&self) -> &str {
+        "GIFTI files, data array by data array"
+    }
+}
+
+/// NotebookDiffer
+/// Compares Jupyter notebooks cell by cell.
+pub struct NotebookDiffer;
+
+impl Differ for NotebookDiffer {
+    fn can_handle(&self, path: &str) -> bool {
+        notebook::is_notebook(path)
+    }
+
+    fn diff(&self, left: &str, right: &str, opts: &DiffOptions)
+        -> Result<Diff> {
+        notebook::diff_notebooks_with_options(left, right, opts)
+    }
+
+    fn name(&self) -> &str {
+        "Jupyter notebooks, cell by cell"
+    }
+}
+
+/// JsonDiffer
+/// Compares JSON files value by value.
+pub struct JsonDiffer;
+
+impl Differ for JsonDiffer {
+    fn can_handle(&self, path: &str) -> bool {
+        json::is_json(path)
+    }
+
+    fn diff(&self, left: &str, right: &str, opts: &DiffOptions)
+        -> Result<Diff> {
+        json::diff_json_with_options(left, right, opts)
+    }
+
+    fn name(&self) -> &str {
+        "JSON documents, value by value"
+    }
+}
+
+/// EventsDiffer
+/// Compares BIDS events files trial by trial.
+pub struct EventsDiffer;
+
+impl Differ for EventsDiffer {
+    fn can_handle(&self, path: &str) -> bool {
+        events::is_events(path)
+    }
+
+    fn diff(&self, left: &str, right: &str, opts: &DiffOptions)
+        -> Result<Diff> {
+        events::diff_events_with_options(left, right, opts)
+    }
+
+    fn name(&self) -> &str {
+        "BIDS events files, event by event"
+    }
+}
+
+/// TableDiffer
+/// Compares TSV and CSV tables row by row.
+pub struct TableDiffer;
+
+impl Differ for TableDiffer {
+    fn can_handle(&self, path: &str) -> bool {
+        table::is_table(path)
+    }
+
+    fn diff(&self, left: &str, right: &str, opts: &DiffOptions)
+        -> Result<Diff> {
+        table::diff_tables_with_options(left, right, opts)
+    }
+
+    fn name(&self) -> &str {
+        "tables, row by row"
+    }
+}
+
+/// CodeDiffer
+/// Compares scripts token by token.
+pub struct CodeDiffer;
+
+impl Differ for CodeDiffer {
+    fn can_handle(&self, path: &str) -> bool {
+        code::is_code(path)
+    }
+
+    fn diff(&self, left: &str, right: &str, opts: &DiffOptions)
+        -> Result<Diff> {
+        code::diff_code_with_options(left, right, opts)
+    }
+
+    fn name(&self) -> &str {
+        "source code, token by token"
+    }
+}
+
+/// ArchiveDiffer
+/// Compares the members of zip and tar archives.
+pub struct ArchiveDiffer;
+
+impl Differ for ArchiveDiffer {
+    fn can_handle(&self, path: &str) -> bool {
+        archive::is_archive(path)
+    }
+
+    fn diff(&self, left: &str, right: &str, opts: &DiffOptions)
+        -> Result<Diff> {
+        archive::diff_archives_with_options(left, right, opts)
+    }
+
+    /// Both sides must be archives, though not necessarily of one kind.
+    fn can_handle_pair(&self, left: &str, right: &str) -> bool {
+        self.can_handle(left) && self.can_handle(right)
+    }
+
+    fn name(&self) -> &str {
+        "archives, member by member"
+    }
+}
+
+/// BgzfDiffer
+/// Compares the decompressed payloads of BGZF files.
+pub struct BgzfDiffer;
+
+impl Differ for BgzfDiffer {
+    fn can_handle(&self, path: &str) -> bool {
+        gz::is_bgzf(path).unwrap_or(false)
+    }
+
+    fn diff(&self, left: &str, right: &str, opts: &DiffOptions)
+        -> Result<Diff> {
+        diff_bgzf_with_options(left, right, opts)
+    }
+
+    /// Either side being BGZF is enough, since the other may be ordinary
+    /// gzip of the same payload.
+    fn can_handle_pair(&self, left: &str, right: &str) -> bool {
+        self.can_handle(left) || self.can_handle(right)
+    }
+
+    fn name(&self) -> &str {
+        "BGZF files, by their decompressed payloads"
+    }
+}
+
+/// GzipDiffer
+/// Compares gzipped files by their decompressed contents.
+pub struct GzipDiffer;
+
+impl Differ for GzipDiffer {
+    fn can_handle(&self, path: &str) -> bool {
+        path.ends_with(".gz")
+    }
+
+    fn diff(&self, left: &str, right: &str, opts: &DiffOptions)
+        -> Result<Diff> {
+        diff_gzip_contents_with_options(left, right, opts)
+    }
+
+    /// Either side being gzipped is enough, since the other may hold the
+    /// same contents uncompressed.
+    fn can_handle_pair(&self, left: &str, right: &str) -> bool {
+        self.can_handle(left) || self.can_handle(right)
+    }
+
+    fn name(&self) -> &str {
+        "gzipped files, by their decompressed contents"
+    }
+}
+
+/// TextDiffer
+/// Compares text files line by line.
+pub struct TextDiffer;
+
+impl Differ for TextDiffer {
+    fn can_handle(&self, path: &str) -> bool {
+        text::is_text(path)
+    }
+
+    fn diff(&self, left: &str, right: &str, opts: &DiffOptions)
+        -> Result<Diff> {
+        // Byte ranges only mean something to a byte-wise comparison
+        if !opts.byte_ranges.is_empty() || !opts.ignore_ranges.is_empty() {
+            return diff_bytes_with_options(left, right, opts);
+        }
+        text::diff_text_with_options(left, right, opts)
+    }
+
+    /// Both sides need to be text, so text isn't compared with binary.
+    fn can_handle_pair(&self, left: &str, right: &str) -> bool {
+        self.can_handle(left) && self.can_handle(right)
+    }
+
+    fn name(&self) -> &str {
+        "text, line by line"
+    }
+}
+
+/// BytesDiffer
+/// Compares files byte by byte.
+pub struct BytesDiffer;
+
+impl Differ for BytesDiffer {
+    fn can_handle(&self, _path: &str) -> bool {
+        true
+    }
+
+    fn diff(&self, left: &str, right: &str, opts: &DiffOptions)
+        -> Result<Diff> {
+        diff_bytes_with_options(left, right, opts)
+    }
+
+    fn name(&self) -> &str {
+        "files, byte by byte"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+
+    use crate::workspace::{test_scratch, Scratch};
+
+    /// Write `files` under a scratch directory for one test and return the
+    /// directory.
+    fn scratch_with(test: &str, files: &[(&str, &[u8])]) -> Scratch {
+        let dir = test_scratch(&format!("registry-{}", test));
+        for (name, contents) in files {
+            fs::write(dir.path().join(name), contents).unwrap();
+        }
+        dir
+    }
+
+    /// The name of the differ found for two files under `dir`.
+    fn found(dir: &std::path::Path, left: &str, right: &str) -> String {
+        let path = |name| dir.join(name).to_string_lossy().into_owned();
+        String::from(find(&path(left), &path(right)).name())
+    }
+
+    #[test]
+    fn differs_are_asked_most_specific_first() {
+        let gz = b"\x1f\x8b\x08\x00\x00\x00\x00\x00\x00\x03\x03\x00\
+                   \x00\x00\x00\x00\x00\x00\x00\x00";
+        let scratch = scratch_with("order", &[
+            ("a.nii.gz", gz),
+            ("a.gii", b"<GIFTI/>"),
+            ("task_events.tsv", b"onset\tduration\n"),
+            ("a.tsv", b"a\tb\n"),
+            ("a.json", b"{}"),
+            ("a.py", b"pass\n"),
+            ("a.zip", b""),
+            ("a.tar", b""),
+            ("a.txt.gz", gz),
+            ("a.txt", b"text\n"),
+            ("a.bin", b"\x00\x01\x02\xff"),
+        ]);
+        let dir = scratch.path();
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        assert_eq!(found(dir, "sub", "sub"), DirectoryDiffer.name());
+        // NIfTI and events files are more than gzip and tables
+        assert_eq!(found(dir, "a.nii.gz", "a.nii.gz"), NiftiDiffer.name());
+        assert_eq!(found(dir, "task_events.tsv", "task_events.tsv"),
+                   EventsDiffer.name());
+        assert_eq!(found(dir, "a.tsv", "a.tsv"), TableDiffer.name());
+        assert_eq!(found(dir, "a.gii", "a.gii"), GiftiDiffer.name());
+        assert_eq!(found(dir, "a.json", "a.json"), JsonDiffer.name());
+        assert_eq!(found(dir, "a.py", "a.py"), CodeDiffer.name());
+        assert_eq!(found(dir, "a.zip", "a.tar"), ArchiveDiffer.name());
+        assert_eq!(found(dir, "a.txt.gz", "a.txt"), GzipDiffer.name());
+        assert_eq!(found(dir, "a.txt", "a.txt"), TextDiffer.name());
+        // Some pairs need both sides to qualify
+        assert_eq!(found(dir, "a.zip", "a.bin"), BytesDiffer.name());
+        assert_eq!(found(dir, "a.txt", "a.bin"), BytesDiffer.name());
+    }
+
+    /// Takes over one test's JSON files, which the built-in JSON differ
+    /// would otherwise compare.
+    struct OverridingDiffer;
+
+    impl Differ for OverridingDiffer {
+        fn can_handle(&self, path: &str) -> bool {
+            path.ends_with("overridden.json")
+        }
+
+        fn diff(&self, left: &str, right: &str, _opts: &DiffOptions)
+            -> Result<Diff> {
+            let mut d = Diff::new(left, right);
+            d.additional_info = String::from("compared by the override");
+            Ok(d)
+        }
+
+        fn name(&self) -> &str {
+            "overridden JSON"
+        }
+    }
+
+    #[test]
+    fn registered_differs_are_asked_before_built_in_ones() {
+        let scratch = scratch_with("override", &[
+            ("overridden.json", b"{}"),
+            ("other.json", b"{}"),
+        ]);
+        let dir = scratch.path();
+        register(OverridingDiffer);
+        assert_eq!(found(dir, "overridden.json", "overridden.json"),
+                   "overridden JSON");
+        assert_eq!(found(dir, "other.json", "other.json"),
+                   JsonDiffer.name());
+        let path = dir.join("overridden.json").to_string_lossy().into_owned();
+        let d = crate::differ(&path, &path).unwrap();
+        assert_eq!(d.additional_info, "compared by the override");
+    }
+}
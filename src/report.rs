@@ -0,0 +1,239 @@
+//! Machine-readable reports for rsdiff
+//!
+//! Workflow managers (Snakemake, Nipype, and the like) collect per-step
+//! tables in their reporting stages. The TSV report has one row per
+//! compared file with its status, similarity, and how long it took, in the
+//! spirit of Snakemake's benchmark files, so a validation step can hand it
+//! straight to the workflow's report without glue code.
+//!
+//! The full diff tree is written as JSON, or, for comparisons with enough
+//! findings that JSON gets unwieldy, as MessagePack or CBOR. All three
+//! encode the same tree, field for field.
+//!
+//! Reports saved to files named `.gz` or `.zst` are compressed, and read
+//! back transparently.
+
+use std::{
+    fmt,
+    fs::{self, File},
+    io::{self, BufWriter, Read, Write},
+    path::Path,
+    str::FromStr,
+};
+
+use flate2::{read::MultiGzDecoder, write::GzEncoder, Compression};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{Diff, RsdiffError};
+
+/// Magic bytes starting a gzip stream.
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+/// Magic bytes starting a zstd frame.
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+/// zstd compression level; reports compress well at the default.
+const ZSTD_LEVEL: i32 = 3;
+
+/// Format
+/// How the result of a comparison is written out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize,
+         Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    /// Human-readable report of what differs.
+    #[default]
+    Text,
+    /// One tab-separated row per file: file, status, similarity, seconds.
+    Tsv,
+    /// The full diff tree as JSON.
+    Json,
+    /// The JSON tree, encoded as MessagePack.
+    Msgpack,
+    /// The JSON tree, encoded as CBOR.
+    Cbor,
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Format::Text => write!(f, "text"),
+            Format::Tsv => write!(f, "tsv"),
+            Format::Json => write!(f, "json"),
+            Format::Msgpack => write!(f, "msgpack"),
+            Format::Cbor => write!(f, "cbor"),
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Format, String> {
+        match s {
+            "text" => Ok(Format::Text),
+            "tsv" => Ok(Format::Tsv),
+            "json" => Ok(Format::Json),
+            "msgpack" => Ok(Format::Msgpack),
+            "cbor" => Ok(Format::Cbor),
+            _ => Err(format!("Unknown format {}", s)),
+        }
+    }
+}
+
+/// Render a diff as a TSV table with a header row. Files present on only
+/// one side are listed with their status and empty measurements.
+pub fn tsv(d: &Diff) -> String {
+    let mut out = String::from("file\tstatus\tsimilarity\tseconds\n");
+    for node in d.flatten() {
+        for x in node.left_only.iter() {
+            let path = Path::new(&node.left).join(x);
+            out.push_str(&format!("{}\tleft_only\t\t\n", path.display()));
+        }
+        for x in node.right_only.iter() {
+            let path = Path::new(&node.right).join(x);
+            out.push_str(&format!("{}\tright_only\t\t\n", path.display()));
+        }
+        // Trees are summarized by the rows of their entries
+        if is_tree(node) {
+            continue;
+        }
+        let status = if node.matches { "identical" } else { "different" };
+        // Files compared without counting units have no similarity
+        let similarity = if node.unit.is_some() {
+            format!("{:.6}", node.similarity)
+        }
+        else {
+            String::new()
+        };
+        out.push_str(&format!("{}\t{}\t{}\t{:.4}\n", node.left, status,
+                              similarity, node.seconds));
+    }
+    out
+}
+
+/// List what a text report leaves out: the files that match, one line each,
+/// and with `timings`, how long every file took to compare and the entries
+/// left out of the comparison.
+pub fn details(d: &Diff, timings: bool) -> String {
+    let mut out = String::new();
+    for node in d.flatten() {
+        if is_tree(node) {
+            if timings {
+                for path in node.skipped.iter() {
+                    out.push_str(&format!("skipped: {}\n", path));
+                }
+            }
+            continue;
+        }
+        let status = if node.matches { "match" } else { "differ" };
+        if timings {
+            out.push_str(&format!("{}: {} vs {} in {:.4}s\n", status,
+                                  node.left, node.right, node.seconds));
+        }
+        else if node.matches {
+            out.push_str(&format!("{}: {} vs {}\n", status, node.left,
+                                  node.right));
+        }
+    }
+    out
+}
+
+/// Whether a diff is of two trees, rather than of two files.
+fn is_tree(d: &Diff) -> bool {
+    Path::new(&d.left).is_dir() || !d.common.is_empty()
+        || !d.left_only.is_empty() || !d.right_only.is_empty()
+}
+
+/// Encode a diff's JSON tree as MessagePack, with maps keyed by field name
+/// as in the JSON.
+pub fn msgpack(d: &Diff) -> Vec<u8> {
+    rmp_serde::to_vec_named(&d.to_json()).expect("Can't serialize diff!")
+}
+
+/// Encode a diff's JSON tree as CBOR.
+pub fn cbor(d: &Diff) -> Vec<u8> {
+    let mut out = vec!();
+    ciborium::ser::into_writer(&d.to_json(), &mut out)
+        .expect("Can't serialize diff!");
+    out
+}
+
+/// ReportWriter
+/// Writes a report to a file, compressing it if the file is named `.gz` or
+/// `.zst`. Call `finish` once done, so the compressed stream is completed
+/// and any error in doing so is seen.
+pub struct ReportWriter {
+    sink: Sink,
+}
+
+/// Where a ReportWriter's bytes go.
+enum Sink {
+    Plain(BufWriter<File>),
+    Gzip(GzEncoder<BufWriter<File>>),
+    Zstd(zstd::Encoder<'static, BufWriter<File>>),
+}
+
+impl ReportWriter {
+    /// Create the report file, choosing compression by its extension.
+    pub fn create(path: &str) -> io::Result<ReportWriter> {
+        let file = BufWriter::new(File::create(path)?);
+        let sink = match Path::new(path).extension() {
+            Some(ext) if ext == "gz" => {
+                Sink::Gzip(GzEncoder::new(file, Compression::default()))
+            }
+            Some(ext) if ext == "zst" => {
+                Sink::Zstd(zstd::Encoder::new(file, ZSTD_LEVEL)?)
+            }
+            _ => Sink::Plain(file),
+        };
+        Ok(ReportWriter { sink })
+    }
+
+    /// Complete the compressed stream, if any, and flush the file.
+    pub fn finish(self) -> io::Result<()> {
+        match self.sink {
+            Sink::Plain(mut f) => f.flush(),
+            Sink::Gzip(e) => e.finish()?.flush(),
+            Sink::Zstd(e) => e.finish()?.flush(),
+        }
+    }
+}
+
+impl Write for ReportWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match &mut self.sink {
+            Sink::Plain(f) => f.write(buf),
+            Sink::Gzip(e) => e.write(buf),
+            Sink::Zstd(e) => e.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.sink {
+            Sink::Plain(f) => f.flush(),
+            Sink::Gzip(e) => e.flush(),
+            Sink::Zstd(e) => e.flush(),
+        }
+    }
+}
+
+/// Read a saved report, decompressing it if it is gzip or zstd compressed.
+/// Compression is recognized by its magic bytes rather than the file name,
+/// so renamed reports still read.
+pub fn read(path: &str) -> crate::Result<Vec<u8>> {
+    let bytes = fs::read(path).map_err(|e| RsdiffError::io(path, e))?;
+    let mut out = vec!();
+    let decoded = if bytes.starts_with(&GZIP_MAGIC) {
+        MultiGzDecoder::new(&bytes[..]).read_to_end(&mut out)
+    }
+    else if bytes.starts_with(&ZSTD_MAGIC) {
+        zstd::Decoder::new(&bytes[..]).and_then(|mut d| d.read_to_end(&mut out))
+    }
+    else {
+        return Ok(bytes);
+    };
+    decoded.map_err(|e| RsdiffError::Corrupt(
+        format!("{} can't be decompressed: {}", path, e)
+    ))?;
+    Ok(out)
+}
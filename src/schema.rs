@@ -0,0 +1,144 @@
+//! Schema comparison for rsdiff
+//!
+//! Before investing in a full comparison of large outputs, it is often
+//! enough to know that their shape didn't change: that a JSON report has
+//! the same keys holding the same kinds of values, or that a table has the
+//! same columns holding the same kinds of cells. A schema maps each place
+//! in a file to the type found there; comparing schemas never looks at
+//! the values themselves.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use serde_json::Value;
+
+use crate::{table::{Table, MISSING}, Diff};
+
+/// Most schema differences to name in a report.
+const MAX_REPORTED: usize = 5;
+
+/// Schema
+/// The types found at each place in a file, keyed by where they were found.
+pub type Schema = BTreeMap<String, BTreeSet<&'static str>>;
+
+/// The schema of a JSON value, keyed by JSON pointer. Array elements share
+/// the pointer `*`, so arrays of records with the same fields have the
+/// same schema however long they are, and elements of different types show
+/// up as a union like `number|string`.
+pub fn json_schema(value: &Value) -> Schema {
+    let mut schema = Schema::new();
+    add_json(value, String::new(), &mut schema);
+    schema
+}
+
+fn add_json(value: &Value, pointer: String, schema: &mut Schema) {
+    let kind = match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(items) => {
+            for item in items {
+                add_json(item, format!("{}/*", pointer), schema);
+            }
+            "array"
+        }
+        Value::Object(map) => {
+            for (key, item) in map {
+                let key = key.replace('~', "~0").replace('/', "~1");
+                add_json(item, format!("{}/{}", pointer, key), schema);
+            }
+            "object"
+        }
+    };
+    let location = if pointer.is_empty() { String::from("/") } else { pointer };
+    schema.entry(location).or_default().insert(kind);
+}
+
+/// The schema of a table: each column's name and the type of its cells,
+/// one of `integer`, `number`, `text`, or `empty` if every cell is missing.
+pub fn table_schema(table: &Table) -> Schema {
+    table.columns.iter()
+        .enumerate()
+        .map(|(i, column)| {
+            let cells = table.rows.iter()
+                .map(|row| row[i].trim())
+                .filter(|cell| !cell.is_empty() && *cell != MISSING);
+            (column.clone(), BTreeSet::from([cell_type(cells)]))
+        })
+        .collect()
+}
+
+/// The narrowest type that every cell parses as.
+fn cell_type<'a>(cells: impl Iterator<Item = &'a str>) -> &'static str {
+    let mut kind = "empty";
+    for cell in cells {
+        if cell.parse::<i64>().is_ok() {
+            if kind == "empty" {
+                kind = "integer";
+            }
+        }
+        else if cell.parse::<f64>().is_ok() {
+            if kind != "text" {
+                kind = "number";
+            }
+        }
+        else {
+            return "text";
+        }
+    }
+    kind
+}
+
+/// Compare two schemas, recording the outcome in `d`. Schemas aren't
+/// counted in any unit, so no similarity is set.
+pub fn compare(d: &mut Diff, left: &Schema, right: &Schema) {
+    let mut left_only = vec!();
+    let mut changed = vec!();
+    for (location, kind) in left {
+        match right.get(location) {
+            Some(other) if other == kind => (),
+            Some(other) => changed.push(format!("{} {} vs. {}", location,
+                                                union(kind), union(other))),
+            None => left_only.push(format!("{} ({})", location, union(kind))),
+        }
+    }
+    let right_only: Vec<String> = right.iter()
+        .filter(|(location, _)| !left.contains_key(*location))
+        .map(|(location, kind)| format!("{} ({})", location, union(kind)))
+        .collect();
+
+    let mut problems = vec!();
+    if !left_only.is_empty() {
+        problems.push(format!("only in left: {}", list(&left_only)));
+    }
+    if !right_only.is_empty() {
+        problems.push(format!("only in right: {}", list(&right_only)));
+    }
+    if !changed.is_empty() {
+        problems.push(format!("types differ: {}", list(&changed)));
+    }
+    d.matches = problems.is_empty();
+    if !d.matches {
+        d.additional_info = format!("Schemas diverge; {}",
+                                    problems.join("; "));
+        d.report = format!("{} vs {}: {}", d.left, d.right, d.additional_info);
+    }
+}
+
+/// Write a set of types as a union, like `number|string`.
+fn union(kinds: &BTreeSet<&str>) -> String {
+    kinds.iter().copied().collect::<Vec<_>>().join("|")
+}
+
+/// List a few schema differences for a report.
+fn list(items: &[String]) -> String {
+    let mut listed = items.iter()
+        .take(MAX_REPORTED)
+        .cloned()
+        .collect::<Vec<_>>()
+        .join(", ");
+    if items.len() > MAX_REPORTED {
+        listed.push_str(&format!(" and {} more", items.len() - MAX_REPORTED));
+    }
+    listed
+}
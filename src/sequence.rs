@@ -0,0 +1,302 @@
+//! Sequence alignment for rsdiff
+//!
+//! Text-like formats are compared by aligning them as sequences of tokens
+//! or lines, so that an insertion early in a file doesn't make everything
+//! after it look different. Alignment uses Myers' O(ND) algorithm, which is
+//! fast when the sequences are similar, as versions of the same file
+//! usually are.
+
+/// Length of the longest common subsequence of two sequences, or None if
+/// aligning them would take more than `max_edits` insertions and deletions.
+/// The limit bounds the work on sequences that have little in common.
+pub fn lcs_len<T: PartialEq>(a: &[T], b: &[T], max_edits: usize)
+    -> Option<usize> {
+    // Common prefixes and suffixes are part of any longest alignment
+    let prefix = a.iter().zip(b.iter()).take_while(|(x, y)| x == y).count();
+    let (a, b) = (&a[prefix..], &b[prefix..]);
+    let suffix = a.iter().rev().zip(b.iter().rev())
+        .take_while(|(x, y)| x == y)
+        .count();
+    let (a, b) = (&a[..a.len() - suffix], &b[..b.len() - suffix]);
+    let common = prefix + suffix;
+
+    let (n, m) = (a.len() as isize, b.len() as isize);
+    let max = (n + m) as usize;
+    if max == 0 {
+        return Some(common);
+    }
+    // furthest[k] is how far along a the furthest path on diagonal k got
+    let offset = max as isize + 1;
+    let mut furthest = vec![0isize; 2 * max + 3];
+    for d in 0..=max.min(max_edits) as isize {
+        for k in (-d..=d).step_by(2) {
+            let i = (k + offset) as usize;
+            let mut x = if k == -d
+                || (k != d && furthest[i - 1] < furthest[i + 1]) {
+                furthest[i + 1]
+            }
+            else {
+                furthest[i - 1] + 1
+            };
+            let mut y = x - k;
+            while x < n && y < m && a[x as usize] == b[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            furthest[i] = x;
+            if x >= n && y >= m {
+                return Some(common + (max - d as usize) / 2);
+            }
+        }
+    }
+    None
+}
+
+/// Edit
+/// One step of an edit script turning one sequence into another.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Edit {
+    /// The items at these indices of the first and second sequences are
+    /// the same.
+    Keep(usize, usize),
+    /// The item at this index of the first sequence is removed.
+    Delete(usize),
+    /// The item at this index of the second sequence is inserted.
+    Insert(usize),
+}
+
+/// A shortest edit script turning `a` into `b`, or None if it would take
+/// more than `max_edits` insertions and deletions. Unlike `lcs_len`, this
+/// keeps the furthest paths of every step to trace the script back, so
+/// its memory grows with the square of the edits allowed.
+pub fn edit_script<T: PartialEq>(a: &[T], b: &[T], max_edits: usize)
+    -> Option<Vec<Edit>> {
+    let prefix = a.iter().zip(b.iter()).take_while(|(x, y)| x == y).count();
+    let suffix = a[prefix..].iter().rev().zip(b[prefix..].iter().rev())
+        .take_while(|(x, y)| x == y)
+        .count();
+    let middle_a = &a[prefix..a.len() - suffix];
+    let middle_b = &b[prefix..b.len() - suffix];
+
+    let mut script: Vec<Edit> = (0..prefix).map(|i| Edit::Keep(i, i)).collect();
+    for edit in trace_edits(middle_a, middle_b, max_edits)? {
+        script.push(match edit {
+            Edit::Keep(i, j) => Edit::Keep(i + prefix, j + prefix),
+            Edit::Delete(i) => Edit::Delete(i + prefix),
+            Edit::Insert(j) => Edit::Insert(j + prefix),
+        });
+    }
+    let (tail_a, tail_b) = (a.len() - suffix, b.len() - suffix);
+    script.extend((0..suffix).map(|i| Edit::Keep(tail_a + i, tail_b + i)));
+    Some(script)
+}
+
+/// Myers' algorithm, keeping the furthest path on each diagonal after each
+/// step so the shortest edit script can be traced back from the end.
+fn trace_edits<T: PartialEq>(a: &[T], b: &[T], max_edits: usize)
+    -> Option<Vec<Edit>> {
+    let (n, m) = (a.len() as isize, b.len() as isize);
+    // furthest[d][k + d] is how far along a the furthest path on diagonal
+    // k got with d edits
+    let mut furthest: Vec<Vec<isize>> = vec!();
+    let mut end = None;
+    for d in 0..=((n + m) as usize).min(max_edits) as isize {
+        let previous = furthest.last();
+        let mut current = vec![0isize; 2 * d as usize + 1];
+        for k in (-d..=d).step_by(2) {
+            let mut x = match previous {
+                None => 0,
+                Some(p) => {
+                    let at = |k: isize| p[(k + d - 1) as usize];
+                    if k == -d || (k != d && at(k - 1) < at(k + 1)) {
+                        at(k + 1)
+                    }
+                    else {
+                        at(k - 1) + 1
+                    }
+                }
+            };
+            let mut y = x - k;
+            while x < n && y < m && a[x as usize] == b[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            current[(k + d) as usize] = x;
+            if x >= n && y >= m {
+                end = Some(d);
+                break;
+            }
+        }
+        furthest.push(current);
+        if end.is_some() {
+            break;
+        }
+    }
+    let end = end?;
+
+    // Walk back from the end, one edit and its trailing diagonal at a time
+    let mut script = vec!();
+    let (mut x, mut y) = (n, m);
+    for d in (1..=end).rev() {
+        let k = x - y;
+        let p = &furthest[d as usize - 1];
+        let at = |k: isize| p[(k + d - 1) as usize];
+        let inserted = k == -d || (k != d && at(k - 1) < at(k + 1));
+        let previous_k = if inserted { k + 1 } else { k - 1 };
+        let previous_x = at(previous_k);
+        let previous_y = previous_x - previous_k;
+        let (start_x, start_y) = if inserted {
+            (previous_x, previous_y + 1)
+        }
+        else {
+            (previous_x + 1, previous_y)
+        };
+        while x > start_x && y > start_y {
+            x -= 1;
+            y -= 1;
+            script.push(Edit::Keep(x as usize, y as usize));
+        }
+        script.push(if inserted {
+            Edit::Insert(previous_y as usize)
+        }
+        else {
+            Edit::Delete(previous_x as usize)
+        });
+        x = previous_x;
+        y = previous_y;
+    }
+    while x > 0 && y > 0 {
+        x -= 1;
+        y -= 1;
+        script.push(Edit::Keep(x as usize, y as usize));
+    }
+    script.reverse();
+    Some(script)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The length of the longest common subsequence by dynamic
+    /// programming, to check the alignments against.
+    fn lcs_by_table(a: &[u8], b: &[u8]) -> usize {
+        let mut table = vec![vec![0usize; b.len() + 1]; a.len() + 1];
+        for i in 1..=a.len() {
+            for j in 1..=b.len() {
+                table[i][j] = if a[i - 1] == b[j - 1] {
+                    table[i - 1][j - 1] + 1
+                }
+                else {
+                    table[i - 1][j].max(table[i][j - 1])
+                };
+            }
+        }
+        table[a.len()][b.len()]
+    }
+
+    /// Check that a script turns `a` into `b`, visiting every item of each
+    /// once and in order, and return how many items it keeps.
+    fn check_script(a: &[u8], b: &[u8], script: &[Edit]) -> usize {
+        let (mut i, mut j, mut kept) = (0, 0, 0);
+        for edit in script {
+            match *edit {
+                Edit::Keep(x, y) => {
+                    assert_eq!((x, y), (i, j), "{:?}", script);
+                    assert_eq!(a[x], b[y]);
+                    i += 1;
+                    j += 1;
+                    kept += 1;
+                }
+                Edit::Delete(x) => {
+                    assert_eq!(x, i, "{:?}", script);
+                    i += 1;
+                }
+                Edit::Insert(y) => {
+                    assert_eq!(y, j, "{:?}", script);
+                    j += 1;
+                }
+            }
+        }
+        assert_eq!((i, j), (a.len(), b.len()));
+        kept
+    }
+
+    /// Pseudo-random sequences over a small alphabet, so they share a lot.
+    fn sequences(seed: u64, count: usize) -> Vec<Vec<u8>> {
+        let mut state = seed;
+        let mut next = move || {
+            state = state.wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (state >> 33) as usize
+        };
+        (0..count)
+            .map(|_| {
+                let len = next() % 12;
+                (0..len).map(|_| b"abc"[next() % 3]).collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn lcs_of_the_classic_example() {
+        // Myers' paper aligns these with five edits
+        let (a, b) = (b"ABCABBA", b"CBABAC");
+        assert_eq!(lcs_len(a, b, usize::MAX), Some(4));
+        assert_eq!(lcs_len(a, b, 5), Some(4));
+        assert_eq!(lcs_len(a, b, 4), None);
+        let script = edit_script(a, b, 5).unwrap();
+        assert_eq!(check_script(a, b, &script), 4);
+        assert_eq!(edit_script(a, b, 4), None);
+    }
+
+    #[test]
+    fn empty_and_identical_sequences_need_no_edits() {
+        let empty: &[u8] = b"";
+        assert_eq!(lcs_len(empty, empty, 0), Some(0));
+        assert_eq!(edit_script(empty, empty, 0), Some(vec!()));
+        assert_eq!(lcs_len(b"abc", b"abc", 0), Some(3));
+        assert_eq!(edit_script(b"abc", b"abc", 0),
+                   Some(vec!(Edit::Keep(0, 0), Edit::Keep(1, 1),
+                             Edit::Keep(2, 2))));
+        assert_eq!(lcs_len(b"abc", empty, 3), Some(0));
+        assert_eq!(edit_script(b"abc", empty, 3),
+                   Some(vec!(Edit::Delete(0), Edit::Delete(1),
+                             Edit::Delete(2))));
+        assert_eq!(edit_script(empty, b"ab", 2),
+                   Some(vec!(Edit::Insert(0), Edit::Insert(1))));
+        assert_eq!(edit_script(empty, b"ab", 1), None);
+    }
+
+    #[test]
+    fn the_edit_limit_counts_insertions_and_deletions() {
+        // One changed item is a deletion and an insertion
+        assert_eq!(lcs_len(b"abcd", b"abxd", 1), None);
+        assert_eq!(lcs_len(b"abcd", b"abxd", 2), Some(3));
+        assert_eq!(edit_script(b"abcd", b"abxd", 1), None);
+        let script = edit_script(b"abcd", b"abxd", 2).unwrap();
+        assert_eq!(script, vec!(Edit::Keep(0, 0), Edit::Keep(1, 1),
+                                Edit::Delete(2), Edit::Insert(2),
+                                Edit::Keep(3, 3)));
+    }
+
+    #[test]
+    fn alignments_are_longest_and_shortest() {
+        let pool = sequences(42, 40);
+        for a in pool.iter() {
+            for b in pool.iter() {
+                let expected = lcs_by_table(a, b);
+                let edits = a.len() + b.len() - 2 * expected;
+                assert_eq!(lcs_len(a, b, edits), Some(expected),
+                           "{:?} {:?}", a, b);
+                let script = edit_script(a, b, edits).unwrap();
+                assert_eq!(check_script(a, b, &script), expected,
+                           "{:?} {:?}", a, b);
+                if edits > 0 {
+                    assert_eq!(lcs_len(a, b, edits - 1), None);
+                    assert_eq!(edit_script(a, b, edits - 1), None);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,217 @@
+//! Report signing for rsdiff
+//!
+//! Reports are signed with ed25519 using minisign's key and signature file
+//! formats, so archived results can be checked with stock tooling:
+//! `minisign -Vm report.json -p rsdiff.pub`. Only unencrypted secret keys
+//! (`minisign -G -W`) are supported.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use base64::{Engine, engine::general_purpose::STANDARD};
+use ed25519_dalek::{Signer, SigningKey};
+
+use crate::{Result, RsdiffError};
+
+/// Length of the decoded minisign secret key blob.
+const SECRET_KEY_LEN: usize = 158;
+
+/// MinisignKey
+/// A minisign secret key usable for signing.
+pub struct MinisignKey {
+    key_id: [u8; 8],
+    key: SigningKey,
+}
+
+impl MinisignKey {
+    /// Load an unencrypted minisign secret key file.
+    pub fn from_file(path: &str) -> Result<MinisignKey> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| RsdiffError::io(path, e))?;
+        let invalid = |why: &str| RsdiffError::Corrupt(format!(
+            "{} {}; --sign needs an unencrypted minisign secret key \
+             (minisign -G -W)", path, why
+        ));
+        // The key is the first line that isn't a comment
+        let encoded = text.lines()
+            .find(|l| !l.starts_with("untrusted comment:") && !l.is_empty())
+            .ok_or_else(|| invalid("holds no key"))?;
+        let blob = STANDARD.decode(encoded.trim())
+            .map_err(|_| invalid("is not valid base64"))?;
+        if blob.len() != SECRET_KEY_LEN || &blob[..2] != b"Ed" {
+            return Err(invalid("is not a minisign secret key"));
+        }
+        if blob[2..4] != [0, 0] {
+            return Err(invalid("is encrypted"));
+        }
+        // Layout: alg(2) kdf(2) chk(2) salt(32) ops(8) mem(8) then
+        // key id(8), secret key(64), checksum(32), where the secret key is
+        // the seed followed by the public key
+        let mut key_id = [0u8; 8];
+        key_id.copy_from_slice(&blob[54..62]);
+        let mut seed = [0u8; 32];
+        seed.copy_from_slice(&blob[62..94]);
+        let key = SigningKey::from_bytes(&seed);
+        // A damaged key would sign with a key other than the published one
+        if key.verifying_key().as_bytes()[..] != blob[94..126] {
+            return Err(invalid("holds a public key that doesn't match its \
+                                secret key"));
+        }
+        Ok(MinisignKey { key_id, key })
+    }
+
+    /// Produce the contents of a minisign signature file for `message`.
+    pub fn sign(&self, message: &[u8], trusted_comment: &str) -> String {
+        let signature = self.key.sign(message).to_bytes();
+        let mut sig_blob = Vec::with_capacity(74);
+        sig_blob.extend_from_slice(b"Ed");
+        sig_blob.extend_from_slice(&self.key_id);
+        sig_blob.extend_from_slice(&signature);
+        // The global signature covers the signature and trusted comment
+        let mut global = signature.to_vec();
+        global.extend_from_slice(trusted_comment.as_bytes());
+        let global_signature = self.key.sign(&global).to_bytes();
+        format!(
+            "untrusted comment: signature from rsdiff\n{}\n\
+             trusted comment: {}\n{}\n",
+            STANDARD.encode(&sig_blob),
+            trusted_comment,
+            STANDARD.encode(global_signature)
+        )
+    }
+
+    /// Sign a file, writing the signature next to it with a `.minisig`
+    /// extension. Returns the path of the signature.
+    pub fn sign_file(&self, path: &str) -> Result<PathBuf> {
+        let message = fs::read(path).map_err(|e| RsdiffError::io(path, e))?;
+        let name = Path::new(path).file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let comment = format!("file:{}\tsigner:rsdiff {}",
+                              name, env!("CARGO_PKG_VERSION"));
+        let sig_path = PathBuf::from(format!("{}.minisig", path));
+        fs::write(&sig_path, self.sign(&message, &comment))
+            .map_err(|e| RsdiffError::io(&sig_path.to_string_lossy(), e))?;
+        Ok(sig_path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+
+    use crate::workspace::test_scratch;
+
+    const KEY_ID: [u8; 8] = *b"rsdiffid";
+
+    /// The decoded secret key blob minisign would write for `seed`,
+    /// encrypted or not.
+    fn secret_key_blob(seed: [u8; 32], encrypted: bool) -> Vec<u8> {
+        let key = SigningKey::from_bytes(&seed);
+        let mut blob = b"Ed".to_vec();
+        blob.extend_from_slice(if encrypted { b"Sc" } else { &[0, 0] });
+        blob.extend_from_slice(b"B2");
+        blob.extend_from_slice(&[0; 48]);
+        blob.extend_from_slice(&KEY_ID);
+        blob.extend_from_slice(&key.to_keypair_bytes());
+        blob.extend_from_slice(&[0; 32]);
+        blob
+    }
+
+    /// Write a secret key file holding `blob` and return its path.
+    fn key_file(dir: &Path, name: &str, blob: &[u8]) -> String {
+        let path = dir.join(name);
+        fs::write(&path, format!("untrusted comment: test key\n{}\n",
+                                 STANDARD.encode(blob)))
+            .unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    /// Check a minisign signature file against `message` as `minisign -V`
+    /// does: the signature of the message, then the global signature of
+    /// that signature and the trusted comment.
+    fn verify(public: &VerifyingKey, message: &[u8], minisig: &str)
+        -> bool {
+        let lines: Vec<&str> = minisig.lines().collect();
+        let sig_blob = STANDARD.decode(lines[1]).unwrap();
+        assert_eq!(&sig_blob[..2], b"Ed");
+        assert_eq!(sig_blob[2..10], KEY_ID);
+        let signature = Signature::from_slice(&sig_blob[10..]).unwrap();
+        let comment = lines[2].strip_prefix("trusted comment: ").unwrap();
+        let global = Signature::from_slice(
+            &STANDARD.decode(lines[3]).unwrap()
+        ).unwrap();
+        let mut signed_comment = signature.to_bytes().to_vec();
+        signed_comment.extend_from_slice(comment.as_bytes());
+        public.verify(message, &signature).is_ok()
+            && public.verify(&signed_comment, &global).is_ok()
+    }
+
+    #[test]
+    fn signed_reports_verify_and_tampered_ones_dont() {
+        let scratch = test_scratch("sign-round-trip");
+        let dir = scratch.path();
+        let seed = [7u8; 32];
+        let key = MinisignKey::from_file(
+            &key_file(dir, "rsdiff.key", &secret_key_blob(seed, false))
+        ).unwrap();
+        let public = SigningKey::from_bytes(&seed).verifying_key();
+        let report = dir.join("report.json");
+        fs::write(&report, "{\"matches\": true}\n").unwrap();
+        let sig_path = key.sign_file(&report.to_string_lossy()).unwrap();
+        assert_eq!(sig_path, dir.join("report.json.minisig"));
+        let minisig = fs::read_to_string(&sig_path).unwrap();
+        assert!(minisig.contains("trusted comment: file:report.json\t"));
+        assert!(verify(&public, b"{\"matches\": true}\n", &minisig));
+        assert!(!verify(&public, b"{\"matches\": false}\n", &minisig));
+        // Nor does a signature verify with a trusted comment changed
+        let forged = minisig.replace("file:report.json",
+                                     "file:other.json");
+        assert!(!verify(&public, b"{\"matches\": true}\n", &forged));
+    }
+
+    #[test]
+    fn unusable_keys_are_rejected() {
+        let scratch = test_scratch("sign-keys");
+        let dir = scratch.path();
+        let encrypted = key_file(dir, "encrypted.key",
+                                 &secret_key_blob([1; 32], true));
+        let short = key_file(dir, "short.key", &[0; 64]);
+        let mut damaged = secret_key_blob([1; 32], false);
+        damaged[100] ^= 1;
+        let damaged = key_file(dir, "damaged.key", &damaged);
+        let not_base64 = dir.join("garbled.key");
+        fs::write(&not_base64, "untrusted comment: x\n!!!\n").unwrap();
+        let empty = dir.join("empty.key");
+        fs::write(&empty, "untrusted comment: x\n").unwrap();
+        for path in [encrypted, short, damaged,
+                     not_base64.to_string_lossy().into_owned(),
+                     empty.to_string_lossy().into_owned()] {
+            assert!(matches!(MinisignKey::from_file(&path),
+                             Err(RsdiffError::Corrupt(_))), "{}", path);
+        }
+        assert!(matches!(MinisignKey::from_file(
+            &dir.join("missing.key").to_string_lossy()
+        ), Err(RsdiffError::Io { .. })));
+    }
+
+    #[test]
+    fn signatures_that_cant_be_written_are_io_errors() {
+        let scratch = test_scratch("sign-unwritable");
+        let dir = scratch.path();
+        let key = MinisignKey::from_file(
+            &key_file(dir, "rsdiff.key", &secret_key_blob([3; 32], false))
+        ).unwrap();
+        let report = dir.join("report.json");
+        fs::write(&report, "{}\n").unwrap();
+        // A directory where the signature would go
+        fs::create_dir(dir.join("report.json.minisig")).unwrap();
+        let signed = key.sign_file(&report.to_string_lossy());
+        assert!(matches!(signed, Err(RsdiffError::Io { path, .. })
+                         if path.ends_with("report.json.minisig")));
+    }
+}
@@ -0,0 +1,24 @@
+//! Image similarity metrics for rsdiff
+//!
+//! The fraction of matching voxels is a poor measure of how alike two
+//! images are once they've been through different software: every voxel
+//! of a resampled image may differ slightly, while its correlation with
+//! the original stays near 1. Neuroimaging has metrics of its own for
+//! this, Pearson correlation for continuous images and the Dice and
+//! Jaccard overlaps for masks, any of which can stand in as an image
+//! comparison's similarity.
+
+use crate::Metric;
+
+pub(crate) use rsdiff_core::MetricTally;
+
+/// Describe what a metric measures, for reports.
+pub fn describe(metric: Metric) -> &'static str {
+    match metric {
+        Metric::Matches => "the fraction of matching voxels",
+        Metric::Pearson => "the Pearson correlation of the voxels",
+        Metric::Dice => "the Dice coefficient of the nonzero voxels",
+        Metric::Jaccard => "the Jaccard index of the nonzero voxels",
+        Metric::Auto => "picked by whether the images are masks",
+    }
+}
@@ -0,0 +1,112 @@
+//! Live event streams for rsdiff
+//!
+//! A dashboard following a night of dataset validation wants to know how
+//! far along each run is, and which files failed, as it happens rather
+//! than from the report at the end. A run can stream its progress events
+//! to a TCP or Unix socket as JSON lines: `started` with the objects being
+//! compared, then `directory`, `compared` and `bytes` events as progress is
+//! made, and `finished` with the same summary a webhook gets. A dashboard
+//! that goes away doesn't stop the run; the stream just ends.
+
+use std::{
+    io::{self, BufWriter, Write},
+    net::TcpStream,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+
+use serde::Serialize;
+use serde_json::json;
+
+use crate::{notify::Summary, progress::ProgressEvent};
+
+/// How long to wait for a listener to take an event before giving up on
+/// it.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+type Writer = BufWriter<Box<dyn Write + Send>>;
+
+/// EventStream
+/// A connection to a listener, which clones share.
+#[derive(Clone)]
+pub struct EventStream {
+    url: String,
+    out: Arc<Mutex<Option<Writer>>>,
+}
+
+impl EventStream {
+    /// Connect to a listener at `tcp://host:port` or `unix:///path`.
+    pub fn connect(url: &str) -> io::Result<EventStream> {
+        let out: Box<dyn Write + Send> = if let Some(addr) =
+            url.strip_prefix("tcp://") {
+            let socket = TcpStream::connect(addr)?;
+            socket.set_write_timeout(Some(TIMEOUT))?;
+            Box::new(socket)
+        }
+        else if let Some(path) = url.strip_prefix("unix://") {
+            connect_unix(path)?
+        }
+        else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "expected tcp://host:port or unix:///path"
+            ));
+        };
+        Ok(EventStream {
+            url: String::from(url),
+            out: Arc::new(Mutex::new(Some(BufWriter::new(out)))),
+        })
+    }
+
+    /// Announce the start of a comparison.
+    pub fn start(&self, left: &str, right: &str) {
+        self.send(&json!({ "event": "started", "left": left, "right": right }));
+    }
+
+    /// Pass on a progress event.
+    pub fn progress(&self, event: &ProgressEvent) {
+        self.send(event);
+    }
+
+    /// Announce the end of a run, with its summary.
+    pub fn finish(&self, summary: &Summary) {
+        let mut event = serde_json::to_value(summary)
+            .expect("Can't serialize summary!");
+        event["event"] = json!("finished");
+        self.send(&event);
+    }
+
+    /// Write an event as a line of JSON. The first write that fails is
+    /// warned about and ends the stream.
+    fn send(&self, event: &impl Serialize) {
+        let mut out = self.out.lock().unwrap();
+        let written = match out.as_mut() {
+            Some(out) => serde_json::to_writer(&mut *out, event)
+                .map_err(io::Error::from)
+                .and_then(|_| out.write_all(b"\n"))
+                .and_then(|_| out.flush()),
+            None => return,
+        };
+        if let Err(e) = written {
+            eprintln!("rsdiff: can't stream to {}: {}", self.url, e);
+            *out = None;
+        }
+    }
+}
+
+/// Connect to a Unix socket.
+#[cfg(unix)]
+fn connect_unix(path: &str) -> io::Result<Box<dyn Write + Send>> {
+    let socket = UnixStream::connect(path)?;
+    socket.set_write_timeout(Some(TIMEOUT))?;
+    Ok(Box::new(socket))
+}
+
+/// Unix sockets are only available on Unix.
+#[cfg(not(unix))]
+fn connect_unix(_path: &str) -> io::Result<Box<dyn Write + Send>> {
+    Err(io::Error::new(io::ErrorKind::Unsupported,
+                       "Unix sockets aren't supported on this platform"))
+}
@@ -0,0 +1,115 @@
+//! Symbolic links for rsdiff
+//!
+//! Derivative datasets often link to shared inputs rather than copying
+//! them, and links left behind by moved data dangle. Following every link
+//! compares the shared data over and over, and a dangling link used to
+//! stop the whole comparison. Links are instead handled by a policy: they
+//! are followed, compared by where they point, or left out, and dangling
+//! links are always compared by where they point.
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use crate::{Diff, DiffOptions, Result, RsdiffError, SymlinkPolicy, Unit};
+
+/// Entry
+/// What is at a path, without following a link there.
+enum Entry {
+    /// Not a link.
+    Object,
+    /// A link, with where it points and whether anything is there.
+    Link { target: PathBuf, dangling: bool },
+}
+
+impl Entry {
+    fn inspect(path: &str) -> Result<Entry> {
+        let meta = fs::symlink_metadata(path)
+            .map_err(|e| RsdiffError::io(path, e))?;
+        if !meta.file_type().is_symlink() {
+            return Ok(Entry::Object);
+        }
+        let target = fs::read_link(path).map_err(|e| RsdiffError::io(path, e))?;
+        let dangling = match fs::metadata(path) {
+            Ok(_) => false,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => true,
+            Err(e) => return Err(RsdiffError::io(path, e)),
+        };
+        Ok(Entry::Link { target, dangling })
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            Entry::Object => String::from("not a link"),
+            Entry::Link { target, dangling: false } => {
+                format!("link to {}", target.display())
+            }
+            Entry::Link { target, dangling: true } => {
+                format!("dangling link to {}", target.display())
+            }
+        }
+    }
+}
+
+/// Whether two objects are compared as links: if the policy says to and
+/// either is one, or if either is a dangling link.
+pub(crate) fn compares_as_links(left: &str, right: &str, opts: &DiffOptions)
+    -> Result<bool> {
+    let left_entry = Entry::inspect(left)?;
+    let right_entry = Entry::inspect(right)?;
+    Ok(as_links(&left_entry, &right_entry, opts))
+}
+
+fn as_links(left: &Entry, right: &Entry, opts: &DiffOptions) -> bool {
+    match (left, right) {
+        (Entry::Object, Entry::Object) => false,
+        (Entry::Link { dangling: true, .. }, _)
+            | (_, Entry::Link { dangling: true, .. }) => true,
+        _ => opts.symlinks == SymlinkPolicy::CompareTargets,
+    }
+}
+
+/// Compare two objects as links, if the policy says to or either is a
+/// dangling link. Returns None if they should be compared as the objects
+/// they are or point to.
+pub(crate) fn diff_links(left: &str, right: &str, opts: &DiffOptions)
+    -> Result<Option<Diff>> {
+    let left_entry = Entry::inspect(left)?;
+    let right_entry = Entry::inspect(right)?;
+    if !as_links(&left_entry, &right_entry, opts) {
+        return Ok(None);
+    }
+    let mut d = Diff::new(left, right);
+    d.matches = match (&left_entry, &right_entry) {
+        (Entry::Link { target: l, .. }, Entry::Link { target: r, .. }) => {
+            l == r
+        }
+        _ => false,
+    };
+    d.set_counts(d.matches as usize, 1, Unit::Entries);
+    for (side, entry) in [("left", &left_entry), ("right", &right_entry)] {
+        if let Entry::Link { dangling: true, .. } = entry {
+            d.findings.push(format!("{} is a {}", side, entry.describe()));
+        }
+    }
+    if !d.matches {
+        d.additional_info = format!("{} vs. {}", left_entry.describe(),
+                                    right_entry.describe());
+        d.report = format!("{} vs. {}: {}", left, right, d.additional_info);
+    }
+    Ok(Some(d))
+}
+
+/// Whether a directory entry is left out of comparisons as a link.
+pub(crate) fn is_skipped(path: &Path, opts: &DiffOptions) -> bool {
+    opts.symlinks == SymlinkPolicy::Skip
+        && fs::symlink_metadata(path).is_ok_and(|m| m.is_symlink())
+}
+
+/// Whether a directory entry is a directory that will be descended into,
+/// rather than compared as a link.
+pub(crate) fn is_descended_dir(path: &Path, opts: &DiffOptions) -> bool {
+    let link = fs::symlink_metadata(path).is_ok_and(|m| m.is_symlink());
+    path.is_dir() && !(link && opts.symlinks == SymlinkPolicy::CompareTargets)
+}
@@ -0,0 +1,575 @@
+//! Tabular data for rsdiff
+//!
+//! BIDS datasets keep much of their metadata in tab-separated tables:
+//! participants, sessions, scans, and task events, and analyses write
+//! their results out as CSV. Tables are compared row by row rather than
+//! line by line. Rows are aligned by key columns when there are any, such
+//! as participant_id, so a re-sorted table still matches. Tables without
+//! keys can be compared as multisets of rows for tools that write rows in
+//! no particular order. Volatile columns, such as acquisition dates, can be
+//! left out, and whitespace around cells is ignored. Numeric cells are
+//! compared by value, since tools format floats differently, optionally
+//! with a tolerance per column.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fs,
+};
+
+use globset::{Glob, GlobMatcher};
+
+use crate::{
+    drift, schema,
+    sequence::{edit_script, Edit},
+    Diff, DiffOptions, Result, RsdiffError, Unit,
+};
+
+/// The marker BIDS uses for a missing value.
+pub const MISSING: &str = "n/a";
+/// Most row differences to describe in a report.
+const MAX_REPORTED: usize = 5;
+/// Most row insertions and deletions to consider when aligning rows by
+/// order.
+const MAX_EDITS: usize = 1_000;
+
+/// Table
+/// A table with a header row. Rows are padded or truncated to the width of
+/// the header, so every row has a cell for every column.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Table {
+    /// Column names, in order.
+    pub columns: Vec<String>,
+    /// Rows of cells, in order.
+    pub rows: Vec<Vec<String>>,
+}
+
+impl Table {
+    /// Read a table, as CSV if its name says so and as tab-separated
+    /// otherwise.
+    pub fn read(path: &str) -> Result<Table> {
+        if path.ends_with(".csv") {
+            Table::read_csv(path)
+        }
+        else {
+            Table::read_tsv(path)
+        }
+    }
+
+    /// Read a tab-separated table. Blank lines are skipped.
+    pub fn read_tsv(path: &str) -> Result<Table> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| RsdiffError::io(path, e))?;
+        let records = text.lines()
+            .filter(|l| !l.trim().is_empty())
+            .map(|line| line.split('\t').map(String::from).collect());
+        Table::from_records(records, path)
+    }
+
+    /// Read a comma-separated table, with fields optionally quoted as in
+    /// RFC 4180. Blank lines are skipped.
+    pub fn read_csv(path: &str) -> Result<Table> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| RsdiffError::io(path, e))?;
+        let records = parse_csv(&text).into_iter()
+            .filter(|r| !(r.len() == 1 && r[0].trim().is_empty()));
+        Table::from_records(records, path)
+    }
+
+    /// Build a table from records, the first of which is the header.
+    fn from_records(mut records: impl Iterator<Item = Vec<String>>,
+                    path: &str) -> Result<Table> {
+        let columns = records.next().ok_or_else(|| RsdiffError::Corrupt(
+            format!("{} has no header row", path)
+        ))?;
+        let rows = records
+            .map(|mut row| {
+                row.resize(columns.len(), String::new());
+                row
+            })
+            .collect();
+        Ok(Table { columns, rows })
+    }
+
+    /// The index of a column, if the table has it.
+    pub fn column(&self, name: &str) -> Option<usize> {
+        self.columns.iter().position(|c| c == name)
+    }
+}
+
+/// Split CSV text into records of fields. Quoted fields may hold commas,
+/// line breaks, and doubled quotes.
+fn parse_csv(text: &str) -> Vec<Vec<String>> {
+    let mut records = vec!();
+    let mut record = vec!();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted => {
+                if chars.peek() == Some(&'"') {
+                    field.push('"');
+                    chars.next();
+                }
+                else {
+                    quoted = false;
+                }
+            }
+            '"' if field.is_empty() => quoted = true,
+            ',' if !quoted => record.push(std::mem::take(&mut field)),
+            // The carriage return of a CRLF line break, or of the last line
+            '\r' if !quoted && matches!(chars.peek(), Some('\n') | None) => {}
+            '\n' if !quoted => {
+                record.push(std::mem::take(&mut field));
+                records.push(std::mem::take(&mut record));
+            }
+            _ => field.push(c),
+        }
+    }
+    if !field.is_empty() || !record.is_empty() {
+        record.push(field);
+        records.push(record);
+    }
+    records
+}
+
+/// Whether a file is a table compared row by row.
+pub fn is_table(path: &str) -> bool {
+    path.ends_with(".tsv") || path.ends_with(".csv")
+}
+
+/// Compare two tables row by row.
+pub fn diff_tables(left: &str, right: &str) -> Result<Diff> {
+    diff_tables_with_options(left, right, &DiffOptions::default())
+}
+
+/// Compare two tables row by row with custom options. Rows are aligned by
+/// whichever of the options' key columns both tables have. If they have
+/// none of them, rows are aligned by order, or paired with equal rows
+/// wherever they are if row order is ignored. Columns are matched by name,
+/// so reordering them makes no difference.
+pub fn diff_tables_with_options(left: &str, right: &str, opts: &DiffOptions)
+    -> Result<Diff> {
+    let left_table = Table::read(left)?;
+    let right_table = Table::read(right)?;
+    if opts.schema_only {
+        return Ok(diff_table_schemas(left, right, &left_table, &right_table,
+                                     opts));
+    }
+    let compared = |c: &&String| !opts.ignore_columns.contains(c);
+
+    let mut problems = vec!();
+    let left_only: Vec<&str> = left_table.columns.iter()
+        .filter(compared)
+        .filter(|c| right_table.column(c).is_none())
+        .map(|c| c.as_str())
+        .collect();
+    let right_only: Vec<&str> = right_table.columns.iter()
+        .filter(compared)
+        .filter(|c| left_table.column(c).is_none())
+        .map(|c| c.as_str())
+        .collect();
+    if !left_only.is_empty() {
+        problems.push(format!("columns only in left: {}",
+                              left_only.join(", ")));
+    }
+    if !right_only.is_empty() {
+        problems.push(format!("columns only in right: {}",
+                              right_only.join(", ")));
+    }
+    // Columns both sides have, as (left index, right index)
+    let shared: Vec<(usize, usize)> = left_table.columns.iter()
+        .filter(compared)
+        .filter_map(|c| Some((left_table.column(c)?, right_table.column(c)?)))
+        .collect();
+    let column_tolerances: Vec<(GlobMatcher, f64)> = opts.column_tolerances
+        .iter()
+        .filter_map(|(p, tolerance)| {
+            Some((Glob::new(p).ok()?.compile_matcher(), *tolerance))
+        })
+        .collect();
+    let tolerances: Vec<f64> = shared.iter()
+        .map(|&(lc, _)| {
+            column_tolerances.iter().rev()
+                .find(|(glob, _)| glob.is_match(&left_table.columns[lc]))
+                .map_or(opts.tolerance, |&(_, tolerance)| tolerance)
+        })
+        .collect();
+    let keys: Vec<(usize, usize)> = opts.table_keys.iter()
+        .filter_map(|k| Some((left_table.column(k)?, right_table.column(k)?)))
+        .collect();
+
+    let alignment = if !keys.is_empty() {
+        Some(align_by_key(&left_table, &right_table, &keys))
+    }
+    else if opts.unordered_rows {
+        // Every compared column is part of the key, so only equal rows pair
+        Some(align_by_key(&left_table, &right_table, &shared))
+    }
+    else {
+        align_by_order(&left_table, &right_table, &shared)
+    };
+    let mut d = Diff::new(left, right);
+    let alignment = match alignment {
+        Some(a) => a,
+        None => {
+            d.additional_info = String::from("too different to align");
+            d.report = format!("{} vs {}: {}", left, right, d.additional_info);
+            return Ok(d);
+        }
+    };
+
+    // Describe rows by their keys if they have them, else by number
+    let describe = |table: &Table, row: usize, side: usize| {
+        if keys.is_empty() {
+            return format!("row {}", row + 1);
+        }
+        let key: Vec<String> = keys.iter()
+            .map(|k| {
+                let column = if side == 0 { k.0 } else { k.1 };
+                format!("{}={}", table.columns[column], table.rows[row][column])
+            })
+            .collect();
+        key.join(" ")
+    };
+    let mut matched = 0;
+    let mut differing = vec!();
+    for &(l, r) in alignment.pairs.iter() {
+        if opts.drift {
+            for &(lc, rc) in shared.iter() {
+                let cells = (left_table.rows[l][lc].trim().parse::<f64>(),
+                             right_table.rows[r][rc].trim().parse::<f64>());
+                if let (Ok(a), Ok(b)) = cells {
+                    drift::record(&mut d.max_relative_difference,
+                                  drift::relative_difference(a, b));
+                }
+            }
+        }
+        let cells: Vec<String> = shared.iter()
+            .zip(tolerances.iter())
+            .filter(|&(&(lc, rc), &tolerance)| {
+                !same_cell(&left_table.rows[l][lc], &right_table.rows[r][rc],
+                           tolerance)
+            })
+            .map(|(&(lc, rc), _)| format!("{} {} vs. {}", left_table.columns[lc],
+                                     left_table.rows[l][lc],
+                                     right_table.rows[r][rc]))
+            .collect();
+        if cells.is_empty() {
+            matched += 1;
+        }
+        else {
+            differing.push(format!("{}: {}", describe(&left_table, l, 0),
+                                   cells.join(", ")));
+        }
+    }
+    let unpaired = |rows: &[usize], table: &Table, side: usize| {
+        let mut listed: Vec<String> = rows.iter()
+            .take(MAX_REPORTED)
+            .map(|&row| describe(table, row, side))
+            .collect();
+        if rows.len() > MAX_REPORTED {
+            listed.push(String::from("..."));
+        }
+        listed.join(", ")
+    };
+    if !alignment.left_only.is_empty() {
+        problems.push(format!("{} row(s) only in left: {}",
+                              alignment.left_only.len(),
+                              unpaired(&alignment.left_only, &left_table, 0)));
+    }
+    if !alignment.right_only.is_empty() {
+        problems.push(format!("{} row(s) only in right: {}",
+                              alignment.right_only.len(),
+                              unpaired(&alignment.right_only, &right_table, 1)));
+    }
+    if differing.len() > MAX_REPORTED {
+        let more = differing.len() - MAX_REPORTED;
+        differing.truncate(MAX_REPORTED);
+        differing.push(format!("{} more differing row(s)", more));
+    }
+    problems.extend(differing);
+
+    let total = alignment.pairs.len() + alignment.left_only.len()
+        + alignment.right_only.len();
+    d.set_counts(matched, total, Unit::Rows);
+    d.matches = problems.is_empty();
+    if !d.matches {
+        d.additional_info = format!("{} of {} rows match ({:.1}%); {}",
+                                    matched, total, d.similarity * 100.0,
+                                    problems.join("; "));
+        d.report = format!("{} vs {}: {}", left, right, d.additional_info);
+    }
+    Ok(d)
+}
+
+/// Compare the columns of two tables and the types of their cells, but
+/// not the cells themselves. Ignored columns are left out.
+pub(crate) fn diff_table_schemas(left: &str, right: &str, left_table: &Table,
+                                 right_table: &Table, opts: &DiffOptions)
+    -> Diff {
+    let schema = |table: &Table| {
+        let mut schema = schema::table_schema(table);
+        schema.retain(|column, _| !opts.ignore_columns.contains(column));
+        schema
+    };
+    let mut d = Diff::new(left, right);
+    schema::compare(&mut d, &schema(left_table), &schema(right_table));
+    d
+}
+
+/// Whether two cells hold the same value. Numbers match when they differ
+/// by less than `tolerance`, however they are formatted.
+fn same_cell(a: &str, b: &str, tolerance: f64) -> bool {
+    let (a, b) = (a.trim(), b.trim());
+    if a == b {
+        return true;
+    }
+    match (a.parse::<f64>(), b.parse::<f64>()) {
+        (Ok(x), Ok(y)) => x == y || (x - y).abs() < tolerance,
+        _ => false,
+    }
+}
+
+/// A cell in canonical form for aligning rows: trimmed, with numbers
+/// written the same way however they were formatted.
+fn normalize(cell: &str) -> String {
+    let cell = cell.trim();
+    match cell.parse::<f64>() {
+        Ok(x) => x.to_string(),
+        Err(_) => String::from(cell),
+    }
+}
+
+/// Alignment
+/// Which rows of two tables correspond, and which have no counterpart.
+struct Alignment {
+    pairs: Vec<(usize, usize)>,
+    left_only: Vec<usize>,
+    right_only: Vec<usize>,
+}
+
+/// Pair rows with equal keys. Rows sharing a key are paired in the order
+/// they come.
+fn align_by_key(left: &Table, right: &Table, keys: &[(usize, usize)])
+    -> Alignment {
+    let mut by_key: HashMap<Vec<String>, VecDeque<usize>> = HashMap::new();
+    for (r, row) in right.rows.iter().enumerate() {
+        let key = keys.iter().map(|k| normalize(&row[k.1])).collect();
+        by_key.entry(key).or_default().push_back(r);
+    }
+    let mut alignment = Alignment {
+        pairs: vec!(), left_only: vec!(), right_only: vec!()
+    };
+    for (l, row) in left.rows.iter().enumerate() {
+        let key: Vec<String> = keys.iter().map(|k| normalize(&row[k.0]))
+            .collect();
+        match by_key.get_mut(&key).and_then(|rows| rows.pop_front()) {
+            Some(r) => alignment.pairs.push((l, r)),
+            None => alignment.left_only.push(l),
+        }
+    }
+    alignment.right_only = by_key.into_values().flatten().collect();
+    alignment.right_only.sort_unstable();
+    alignment
+}
+
+/// Pair rows by order, aligning identical rows so that an inserted row
+/// doesn't shift the rest. A removed row directly followed by an added one
+/// is taken to be the same row changed. Returns None if the tables are too
+/// different to align.
+fn align_by_order(left: &Table, right: &Table, shared: &[(usize, usize)])
+    -> Option<Alignment> {
+    let project = |table: &Table, side: usize| -> Vec<Vec<String>> {
+        table.rows.iter()
+            .map(|row| shared.iter()
+                 .map(|c| normalize(&row[if side == 0 { c.0 } else { c.1 }]))
+                 .collect())
+            .collect()
+    };
+    let script = edit_script(&project(left, 0), &project(right, 1),
+                             MAX_EDITS)?;
+    let mut alignment = Alignment {
+        pairs: vec!(), left_only: vec!(), right_only: vec!()
+    };
+    let mut removed = VecDeque::new();
+    for edit in script {
+        match edit {
+            Edit::Keep(l, r) => {
+                alignment.left_only.extend(removed.drain(..));
+                alignment.pairs.push((l, r));
+            }
+            Edit::Delete(l) => removed.push_back(l),
+            Edit::Insert(r) => match removed.pop_front() {
+                Some(l) => alignment.pairs.push((l, r)),
+                None => alignment.right_only.push(r),
+            },
+        }
+    }
+    alignment.left_only.extend(removed);
+    alignment.pairs.sort_unstable();
+    Some(alignment)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::workspace::{test_scratch, Scratch};
+
+    /// Write a table to a test's scratch directory and return its path.
+    fn write(scratch: &Scratch, name: &str, text: &str) -> String {
+        let path = scratch.path().join(name);
+        fs::write(&path, text).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    fn table(rows: &[&[&str]]) -> Table {
+        let strings = |row: &&[&str]| row.iter().map(|&s| String::from(s))
+            .collect();
+        Table {
+            columns: strings(&rows[0]),
+            rows: rows[1..].iter().map(strings).collect(),
+        }
+    }
+
+    fn records(rows: &[&[&str]]) -> Vec<Vec<String>> {
+        rows.iter()
+            .map(|row| row.iter().map(|&s| String::from(s)).collect())
+            .collect()
+    }
+
+    #[test]
+    fn quoted_fields_hold_commas_quotes_and_line_breaks() {
+        let text = "a,b,c\n\"1,5\",\"say \"\"hi\"\"\",\"two\nlines\"\n";
+        assert_eq!(parse_csv(text), records(&[
+            &["a", "b", "c"], &["1,5", "say \"hi\"", "two\nlines"],
+        ]));
+        // An empty quoted field is an empty field
+        assert_eq!(parse_csv("\"\",x\n"), records(&[&["", "x"]]));
+    }
+
+    #[test]
+    fn line_endings_are_crlf_or_lf_with_or_without_a_last_one() {
+        let expected = records(&[&["a", "b"], &["1", "2"]]);
+        assert_eq!(parse_csv("a,b\r\n1,2\r\n"), expected);
+        assert_eq!(parse_csv("a,b\r\n1,2"), expected);
+        assert_eq!(parse_csv("a,b\r\n1,2\r"), expected);
+        assert_eq!(parse_csv("a,b\n1,2"), expected);
+        // Line breaks within quotes are kept as they are
+        assert_eq!(parse_csv("\"x\r\ny\"\r\n"), records(&[&["x\r\ny"]]));
+        assert_eq!(parse_csv("\"x\r\"\n"), records(&[&["x\r"]]));
+    }
+
+    #[test]
+    fn empty_fields_are_kept() {
+        assert_eq!(parse_csv(",,\n"), records(&[&["", "", ""]]));
+        assert_eq!(parse_csv("a,\n"), records(&[&["a", ""]]));
+        assert_eq!(parse_csv(""), records(&[]));
+    }
+
+    #[test]
+    fn rows_are_padded_to_the_header_and_blank_lines_skipped() {
+        let scratch = test_scratch("table");
+        let path = write(&scratch, "padded.csv", "a,b,c\n\n1\n1,2,3,4\n");
+        let t = Table::read(&path).unwrap();
+        assert_eq!(t, table(&[&["a", "b", "c"], &["1", "", ""],
+                              &["1", "2", "3"]]));
+        let path = write(&scratch, "padded.tsv", "a\tb\n\n1\n");
+        assert_eq!(Table::read(&path).unwrap(),
+                   table(&[&["a", "b"], &["1", ""]]));
+    }
+
+    #[test]
+    fn tables_need_a_header() {
+        let scratch = test_scratch("table");
+        let path = write(&scratch, "empty.csv", "");
+        assert!(matches!(Table::read(&path), Err(RsdiffError::Corrupt(_))));
+    }
+
+    #[test]
+    fn tables_of_only_a_header_match() {
+        let scratch = test_scratch("table");
+        let left = write(&scratch, "header-left.tsv", "a\tb\n");
+        let right = write(&scratch, "header-right.tsv", "b\ta\n");
+        let d = diff_tables(&left, &right).unwrap();
+        assert!(d.matches, "{}", d.report);
+        assert_eq!((d.matched, d.total), (0, 0));
+        let right = write(&scratch, "header-rows.tsv", "a\tb\n1\t2\n");
+        let d = diff_tables(&left, &right).unwrap();
+        assert!(!d.matches);
+        assert_eq!((d.matched, d.total), (0, 1));
+    }
+
+    #[test]
+    fn numbers_match_however_they_are_written() {
+        assert!(same_cell("1", " 1.0", 0.0));
+        assert!(same_cell("1e-3", "0.001", 0.0));
+        assert!(same_cell("0.1", "0.1", 0.0));
+        assert!(!same_cell("0.1", "0.2", 0.0));
+        assert!(same_cell("0.1", "0.2", 0.5));
+        assert!(!same_cell("n/a", "0", 1.0));
+        assert_eq!(normalize(" 2.50 "), normalize("2.5"));
+    }
+
+    #[test]
+    fn an_inserted_row_does_not_shift_the_rest() {
+        let left = table(&[&["x"], &["1"], &["2"], &["3"]]);
+        let right = table(&[&["x"], &["1"], &["9"], &["2"], &["3"]]);
+        let a = align_by_order(&left, &right, &[(0, 0)]).unwrap();
+        assert_eq!(a.pairs, vec!((0, 0), (1, 2), (2, 3)));
+        assert_eq!((a.left_only, a.right_only), (vec!(), vec!(1)));
+    }
+
+    #[test]
+    fn a_removed_row_followed_by_an_added_one_is_a_changed_row() {
+        let left = table(&[&["x"], &["1"], &["2"], &["3"]]);
+        let right = table(&[&["x"], &["1"], &["7"], &["3"]]);
+        let a = align_by_order(&left, &right, &[(0, 0)]).unwrap();
+        assert_eq!(a.pairs, vec!((0, 0), (1, 1), (2, 2)));
+        assert!(a.left_only.is_empty() && a.right_only.is_empty());
+        // More removed than added leaves the rest removed
+        let right = table(&[&["x"], &["7"]]);
+        let a = align_by_order(&left, &right, &[(0, 0)]).unwrap();
+        assert_eq!(a.pairs, vec!((0, 0)));
+        assert_eq!(a.left_only, vec!(1, 2));
+    }
+
+    #[test]
+    fn tables_too_different_are_not_aligned_by_order() {
+        let scratch = test_scratch("table");
+        let column = |offset: usize, count: usize| {
+            let mut rows = vec!(vec!(String::from("x")));
+            rows.extend((0..count).map(|i| vec!((offset + i).to_string())));
+            Table { columns: rows.remove(0), rows }
+        };
+        // Every row removed and another added is twice the rows in edits
+        let (half, over) = (MAX_EDITS / 2, MAX_EDITS / 2 + 1);
+        assert!(align_by_order(&column(0, half), &column(half, half),
+                               &[(0, 0)]).is_some());
+        assert!(align_by_order(&column(0, over), &column(over, over),
+                               &[(0, 0)]).is_none());
+        let left = write(&scratch, "far-left.csv", &format!(
+            "x\n{}\n", (0..over).map(|i| i.to_string())
+                .collect::<Vec<_>>().join("\n")
+        ));
+        let right = write(&scratch, "far-right.csv", &format!(
+            "x\n{}\n", (over..2 * over).map(|i| i.to_string())
+                .collect::<Vec<_>>().join("\n")
+        ));
+        let d = diff_tables(&left, &right).unwrap();
+        assert!(!d.matches);
+        assert_eq!(d.additional_info, "too different to align");
+    }
+
+    #[test]
+    fn rows_sharing_a_key_pair_in_order() {
+        let left = table(&[&["id", "v"], &["1", "a"], &["1", "b"],
+                           &["2", "c"]]);
+        let right = table(&[&["id", "v"], &["2", "c"], &["1.0", "a"],
+                            &["3", "d"], &["1", "b"]]);
+        let a = align_by_key(&left, &right, &[(0, 0)]);
+        assert_eq!(a.pairs, vec!((0, 1), (1, 3), (2, 0)));
+        assert_eq!((a.left_only, a.right_only), (vec!(), vec!(2)));
+    }
+}
@@ -0,0 +1,432 @@
+//! Comparing a tar stream against a directory
+//!
+//! A tree on a remote host can be streamed over SSH as a tar archive
+//! (`ssh host tar -cf - dir | rsdiff --left-tar - right/`) and compared
+//! against a local tree without landing it on disk. The archive is read
+//! once, front to back, and each regular file in it is compared byte-wise
+//! against its counterpart under the directory as it streams past.
+//!
+//! Archives made with `tar -C dir -cf - .` hold paths under `./`, which
+//! line up with the directory as they are. Archives made with
+//! `tar -cf - dir` hold paths under `dir/`: if the archive's first entry is
+//! a directory the directory compared against doesn't have, it is taken as
+//! the root of the tree and stripped from the paths of the entries under
+//! it, and every entry must be under it. Archives of several trees, such
+//! as `tar -C dir -cf - anat func`, are compared as they are. How many
+//! leading directories to strip can also be given outright, as with
+//! `tar --strip-components`.
+
+use std::{
+    collections::HashSet,
+    fs::{self, File},
+    io::{self, Read},
+    path::{Component, Path, PathBuf},
+    time::Instant,
+};
+
+use tar::Archive;
+
+use crate::{
+    hash::HashingReader, interrupt, read_chunk, summarize_entries, Diff,
+    DiffOptions, Result, RsdiffError, Unit,
+};
+
+/// Compare a tar stream against a directory. `name` labels the stream in
+/// reports.
+pub fn diff_tar(tar: impl Read, name: &str, right: &str) -> Result<Diff> {
+    diff_tar_with_options(tar, name, right, &DiffOptions::default())
+}
+
+/// Compare a tar stream against a directory with custom options. Regular
+/// files and directories are compared, and symbolic links by where they
+/// point; other entries, such as hard links, are noted and skipped.
+pub fn diff_tar_with_options(tar: impl Read, name: &str, right: &str,
+                             opts: &DiffOptions) -> Result<Diff> {
+    let stream_error = |e| RsdiffError::io(name, e);
+    let mut d = Diff::new(name, right);
+    let mut archive = Archive::new(tar);
+    let mut seen: HashSet<PathBuf> = HashSet::new();
+    let mut root: Option<PathBuf> = None;
+    let mut diffs: Vec<Box<Diff>> = vec!();
+    let mut stopped = false;
+    let mut first = true;
+    for entry in archive.entries().map_err(stream_error)? {
+        if interrupt::requested() {
+            d.interrupted = true;
+            break;
+        }
+        let mut entry = entry.map_err(stream_error)?;
+        let path = normalize(&entry.path().map_err(stream_error)?);
+        let kind = entry.header().entry_type();
+        // Global headers, as `git archive` writes, only describe the archive
+        if kind.is_pax_global_extensions() {
+            continue;
+        }
+        let is_first = std::mem::replace(&mut first, false);
+        let relative = match opts.tar_strip_components {
+            Some(n) => path.components().skip(n).collect(),
+            None if is_first && kind.is_dir() && !path.as_os_str().is_empty()
+                && !Path::new(right).join(&path).is_dir() => {
+                root = Some(path);
+                continue;
+            }
+            None => match &root {
+                Some(r) => path.strip_prefix(r)
+                    .map_err(|_| RsdiffError::Corrupt(format!(
+                        "{} has {} outside {}, which was taken as the root \
+                         of the tree; give how many leading directories to \
+                         strip instead", name, path.display(), r.display()
+                    )))?
+                    .to_path_buf(),
+                None => path,
+            },
+        };
+        if relative.as_os_str().is_empty() || opts.excludes_path(&relative) {
+            continue;
+        }
+        let label = relative.to_string_lossy().into_owned();
+        let target = Path::new(right).join(&relative);
+        let subdiff = if kind.is_dir() {
+            if !target.is_dir() {
+                d.left_only.push(label.clone());
+            }
+            None
+        }
+        else if kind.is_file() {
+            if target.is_file() {
+                let left = format!("{}:{}", name, label);
+                let size = entry.header().size().map_err(stream_error)?;
+                let started = Instant::now();
+                let mut subdiff = diff_entry(&mut entry, size, &left, &target,
+                                             opts)?;
+                subdiff.seconds = started.elapsed().as_secs_f64();
+                Some(subdiff)
+            }
+            else {
+                d.left_only.push(label.clone());
+                None
+            }
+        }
+        else if kind.is_symlink() {
+            let link = entry.link_name().map_err(stream_error)?
+                .unwrap_or_default()
+                .into_owned();
+            if fs::symlink_metadata(&target).is_ok() {
+                let left = format!("{}:{}", name, label);
+                Some(diff_link(&link, &left, &target)?)
+            }
+            else {
+                d.left_only.push(label.clone());
+                None
+            }
+        }
+        else {
+            // Nothing to compare the entry by, so it is neither matched
+            // against nor counted as missing from the directory
+            d.findings.push(format!("{} isn't a regular file, directory or \
+                                     symbolic link, so it wasn't compared",
+                                    label));
+            continue;
+        };
+        seen.insert(relative);
+        if let Some(subdiff) = subdiff {
+            stopped = opts.fail_fast && !subdiff.matches;
+            diffs.push(Box::new(subdiff));
+            d.common.push(label);
+            if stopped {
+                break;
+            }
+        }
+    }
+    d.sub_diffs = diffs;
+    // What the rest of the stream holds is unknown if it wasn't all read
+    if !d.interrupted && !stopped {
+        d.right_only = unseen(Path::new(right), Path::new(""), &seen, opts)
+            .map_err(|e| RsdiffError::io(right, e))?;
+    }
+    summarize_entries(&mut d, opts);
+    Ok(d)
+}
+
+/// Compare one file streaming out of the archive against a file on disk.
+fn diff_entry(entry: &mut impl Read, size: u64, left: &str, right: &Path,
+              opts: &DiffOptions) -> Result<Diff> {
+    let right_name = right.to_string_lossy();
+    let left_error = |e| RsdiffError::io(left, e);
+    let right_error = |e| RsdiffError::io(&right_name, e);
+    let mut d = Diff::new(left, &right_name);
+    let right_len = fs::metadata(right).map_err(right_error)?.len();
+    let mut left_reader = HashingReader::new(entry, opts.hasher());
+    let mut right_reader = HashingReader::new(
+        File::open(right).map_err(right_error)?, opts.hasher()
+    );
+    if size == right_len {
+        let mut left_buffer = vec![0u8; opts.chunk_size];
+        let mut right_buffer = vec![0u8; opts.chunk_size];
+        let mut total_matches = 0;
+        loop {
+            let n = read_chunk(&mut left_reader, &mut left_buffer)
+                .map_err(left_error)?;
+            read_chunk(&mut right_reader, &mut right_buffer[..n])
+                .map_err(right_error)?;
+            if n == 0 {
+                break;
+            }
+            total_matches += left_buffer[..n].iter()
+                .zip(right_buffer[..n].iter())
+                .filter(|(a, b)| a == b)
+                .count();
+        }
+        d.set_counts(total_matches, size as usize, Unit::Bytes);
+        d.matches = total_matches as u64 == size;
+        if !d.matches {
+            d.additional_info = format!(
+                "{} of {} bytes match ({:.1}%)",
+                total_matches, size, d.similarity * 100.0
+            );
+        }
+    }
+    else {
+        d.additional_info = format!("file sizes differ: {} vs. {}", size,
+                                    right_len);
+    }
+    // Finishing drains both sides, so hashes cover whole files even when
+    // their sizes differ
+    d.left_hash = left_reader.finish().map_err(left_error)?;
+    d.right_hash = right_reader.finish().map_err(right_error)?;
+    if !d.matches {
+        d.report = format!("{} vs {}: {}", d.left, d.right, d.additional_info);
+    }
+    Ok(d)
+}
+
+/// Compare a symbolic link in the archive, pointing at `link`, against
+/// whatever is at `right`: a link matches if it points at the same place.
+fn diff_link(link: &Path, left: &str, right: &Path) -> Result<Diff> {
+    let right_name = right.to_string_lossy();
+    let mut d = Diff::new(left, &right_name);
+    let right_link = match fs::read_link(right) {
+        Ok(target) => Some(target),
+        Err(e) if e.kind() == io::ErrorKind::InvalidInput => None,
+        Err(e) => return Err(RsdiffError::io(&right_name, e)),
+    };
+    d.matches = right_link.as_deref() == Some(link);
+    d.set_counts(d.matches as usize, 1, Unit::Entries);
+    if !d.matches {
+        d.additional_info = format!(
+            "link to {} vs. {}", link.display(),
+            right_link.map_or_else(|| String::from("not a link"),
+                                   |t| format!("link to {}", t.display()))
+        );
+        d.report = format!("{} vs {}: {}", d.left, d.right, d.additional_info);
+    }
+    Ok(d)
+}
+
+/// Drop `.` components and anything that could escape the tree.
+pub(crate) fn normalize(path: &Path) -> PathBuf {
+    path.components()
+        .filter_map(|c| match c {
+            Component::Normal(part) => Some(part),
+            _ => None,
+        })
+        .collect()
+}
+
+/// List the entries under `dir` that the archive didn't have, relative to
+/// the directory being compared. Directories missing from the archive are
+/// listed without their contents.
+fn unseen(base: &Path, dir: &Path, seen: &HashSet<PathBuf>,
+          opts: &DiffOptions) -> io::Result<Vec<String>> {
+    let mut missing = vec!();
+    let entries = fs::read_dir(base.join(dir))
+        .and_then(|e| e.collect::<io::Result<Vec<_>>>())?;
+    let mut names: Vec<_> = entries.iter().map(|e| e.file_name()).collect();
+    names.sort();
+    for name in names {
+        let relative = dir.join(&name);
+        if opts.excludes_path(&relative) {
+            continue;
+        }
+        if !seen.contains(&relative) {
+            missing.push(relative.to_string_lossy().into_owned());
+        }
+        else if base.join(&relative).is_dir() {
+            missing.extend(unseen(base, &relative, seen, opts)?);
+        }
+    }
+    Ok(missing)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::os::unix::fs::symlink;
+
+    use tar::{Builder, EntryType, Header};
+
+    use crate::workspace::{test_scratch, Scratch};
+
+    /// An entry of a test archive: its path as stored, its type, and for
+    /// files its contents or for links their target.
+    type Entry = (String, EntryType, &'static str);
+
+    fn entry(path: &str, kind: EntryType, data: &'static str) -> Entry {
+        (String::from(path), kind, data)
+    }
+
+    /// A tar archive of `entries`, with their paths stored exactly as
+    /// given, `./` and all.
+    fn archive(entries: &[Entry]) -> Vec<u8> {
+        let mut builder = Builder::new(vec!());
+        for (path, kind, data) in entries {
+            let kind = *kind;
+            let mut header = Header::new_gnu();
+            header.as_gnu_mut().unwrap().name[..path.len()]
+                .copy_from_slice(path.as_bytes());
+            header.set_entry_type(kind);
+            header.set_mode(0o644);
+            let data = if kind.is_file() {
+                data.as_bytes()
+            }
+            else {
+                if kind.is_symlink() || kind.is_hard_link() {
+                    header.set_link_name(data).unwrap();
+                }
+                b""
+            };
+            header.set_size(data.len() as u64);
+            header.set_cksum();
+            builder.append(&header, data).unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    /// A directory for one test holding `anat/x.nii` and `func/y.nii`.
+    fn tree(test: &str) -> Scratch {
+        let dir = test_scratch(&format!("tar-{}", test));
+        for (path, contents) in [("anat/x.nii", "x"), ("func/y.nii", "y")] {
+            let path = dir.path().join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        }
+        dir
+    }
+
+    fn diff(test: &str, entries: &[Entry], opts: &DiffOptions)
+        -> Result<Diff> {
+        let right = tree(test);
+        diff_tar_with_options(&archive(entries)[..], "stdin",
+                              &right.path().to_string_lossy(), opts)
+    }
+
+    /// Entries for `anat/x.nii` and `func/y.nii`, under `prefix`.
+    fn entries(prefix: &str) -> Vec<Entry> {
+        vec!(
+            entry(&format!("{}anat/", prefix), EntryType::Directory, ""),
+            entry(&format!("{}anat/x.nii", prefix), EntryType::Regular, "x"),
+            entry(&format!("{}func/", prefix), EntryType::Directory, ""),
+            entry(&format!("{}func/y.nii", prefix), EntryType::Regular, "y"),
+        )
+    }
+
+    fn assert_all_match(d: &Diff) {
+        assert!(d.matches, "{}", d.report);
+        assert_eq!(d.common, vec!("anat/x.nii", "func/y.nii"));
+        assert!(d.left_only.is_empty(), "{:?}", d.left_only);
+        assert!(d.right_only.is_empty(), "{:?}", d.right_only);
+    }
+
+    #[test]
+    fn a_dot_rooted_archive_lines_up_as_it_is() {
+        let mut stream = vec!(entry("./", EntryType::Directory, ""));
+        stream.extend(entries("./"));
+        let d = diff("dot", &stream, &DiffOptions::default())
+            .unwrap();
+        assert_all_match(&d);
+    }
+
+    #[test]
+    fn a_named_root_is_stripped() {
+        let mut stream = vec!(entry("sub-01/", EntryType::Directory, ""));
+        stream.extend(entries("sub-01/"));
+        let d = diff("named", &stream, &DiffOptions::default())
+            .unwrap();
+        assert_all_match(&d);
+    }
+
+    #[test]
+    fn several_top_level_directories_are_compared_as_they_are() {
+        let stream = entries("");
+        let d = diff("several", &stream, &DiffOptions::default())
+            .unwrap();
+        assert_all_match(&d);
+    }
+
+    #[test]
+    fn entries_outside_a_guessed_root_are_an_error() {
+        let mut stream = entries("");
+        stream.insert(0, entry("sub-01/", EntryType::Directory, ""));
+        assert!(diff("outside", &stream, &DiffOptions::default())
+                .is_err());
+    }
+
+    #[test]
+    fn leading_directories_can_be_stripped_outright() {
+        let stream = entries("data/sub-01/");
+        let opts = DiffOptions {
+            tar_strip_components: Some(2), ..DiffOptions::default()
+        };
+        let d = diff("strip", &stream, &opts).unwrap();
+        assert_all_match(&d);
+        // Stripping nothing keeps even a root the directory doesn't have
+        let mut stream = entries("sub-01/");
+        stream.insert(0, entry("sub-01/", EntryType::Directory, ""));
+        let opts = DiffOptions {
+            tar_strip_components: Some(0), ..DiffOptions::default()
+        };
+        let d = diff("strip-none", &stream, &opts).unwrap();
+        assert_eq!(d.left_only[0], "sub-01");
+        assert!(d.common.is_empty());
+        assert_eq!(d.right_only, vec!("anat", "func"));
+    }
+
+    #[test]
+    fn links_are_compared_by_where_they_point() {
+        let scratch = tree("links");
+        let right = scratch.path();
+        symlink("x.nii", right.join("anat/same")).unwrap();
+        symlink("y.nii", right.join("anat/elsewhere")).unwrap();
+        let mut stream = entries("");
+        stream.extend([
+            entry("anat/same", EntryType::Symlink, "x.nii"),
+            entry("anat/elsewhere", EntryType::Symlink, "x.nii"),
+            entry("func/y.nii", EntryType::Symlink, "../anat/x.nii"),
+        ]);
+        // The link replaces func/y.nii, as a later entry of the same path
+        stream.remove(3);
+        let d = diff_tar_with_options(&archive(&stream)[..],
+                                      "stdin", &right.to_string_lossy(),
+                                      &DiffOptions::default())
+            .unwrap();
+        assert!(!d.matches);
+        let matched = |name: &str| d.sub_diffs.iter()
+            .find(|sub| sub.left.ends_with(name))
+            .map(|sub| sub.matches);
+        assert_eq!(matched("anat/same"), Some(true));
+        assert_eq!(matched("anat/elsewhere"), Some(false));
+        assert_eq!(matched("func/y.nii"), Some(false));
+    }
+
+    #[test]
+    fn hard_links_are_noted_rather_than_matched() {
+        let mut stream = entries("");
+        stream[3] = entry("func/y.nii", EntryType::Link, "anat/x.nii");
+        let d = diff("hard", &stream, &DiffOptions::default())
+            .unwrap();
+        assert_eq!(d.common, vec!("anat/x.nii"));
+        assert_eq!(d.right_only, vec!("func/y.nii"));
+        assert_eq!(d.findings.len(), 1);
+    }
+}
@@ -0,0 +1,444 @@
+//! Text comparison across encodings for rsdiff
+//!
+//! Logs and tables are text, and comparing them byte by byte says little:
+//! one inserted line shifts everything after it. Text files are decoded and
+//! compared line by line instead, and differences are listed as unified
+//! diff hunks. Decoding matters too: the same log can be written as UTF-16
+//! by a Windows tool and re-exported as UTF-8, or saved as Latin-1 by an
+//! older one, and byte-wise such files share almost nothing. Line endings
+//! are normalized along the way, since they usually change with the
+//! encoding.
+
+use std::{
+    fmt,
+    fs::{self, File},
+    io::Read,
+};
+
+use crate::{
+    hash::hash_bytes_with,
+    sequence::{edit_script, lcs_len, Edit},
+    Diff, DiffOptions, Result, RsdiffError, Unit,
+};
+
+/// Largest file that is decoded for comparison; text is held in memory.
+const MAX_TEXT_SIZE: u64 = 64 * 1024 * 1024;
+/// How much of a file to inspect for the byte patterns of BOM-less UTF-16.
+const SAMPLE_SIZE: usize = 4096;
+/// Most line insertions and deletions to consider when aligning lines.
+const MAX_EDITS: usize = 100_000;
+/// Most line insertions and deletions to list as a diff. Listing them
+/// takes memory quadratic in their number.
+const MAX_SCRIPT_EDITS: usize = 1_000;
+/// Lines of unchanged context shown around each change.
+const CONTEXT_LINES: usize = 3;
+/// Most lines of diff to include in a report.
+const MAX_HUNK_LINES: usize = 40;
+
+/// Encoding
+/// A text encoding rsdiff can detect and decode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    /// UTF-8 without a byte order mark, including plain ASCII.
+    Utf8,
+    /// UTF-8 with a byte order mark.
+    Utf8Bom,
+    /// Little-endian UTF-16, as written by most Windows tools.
+    Utf16Le,
+    /// Big-endian UTF-16.
+    Utf16Be,
+    /// ISO 8859-1, assumed for 8-bit text that isn't valid UTF-8.
+    Latin1,
+}
+
+impl fmt::Display for Encoding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Encoding::Utf8 => "UTF-8",
+            Encoding::Utf8Bom => "UTF-8 with BOM",
+            Encoding::Utf16Le => "UTF-16LE",
+            Encoding::Utf16Be => "UTF-16BE",
+            Encoding::Latin1 => "Latin-1",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Detect the encoding of text from its byte order mark, or failing that
+/// from the pattern of its bytes. Returns None for data that doesn't look
+/// like text.
+pub fn detect(bytes: &[u8]) -> Option<Encoding> {
+    if bytes.starts_with(&[0xEF, 0xBB, 0xBF]) {
+        return Some(Encoding::Utf8Bom);
+    }
+    if bytes.starts_with(&[0xFF, 0xFE]) {
+        return Some(Encoding::Utf16Le);
+    }
+    if bytes.starts_with(&[0xFE, 0xFF]) {
+        return Some(Encoding::Utf16Be);
+    }
+    // Mostly-ASCII UTF-16 has a zero in every other byte, and 8-bit text
+    // has none
+    let sample = &bytes[..bytes.len().min(SAMPLE_SIZE)];
+    let zeros_at = |parity: usize| {
+        sample.iter().skip(parity).step_by(2).filter(|&&b| b == 0).count()
+    };
+    let (even, odd) = (zeros_at(0), zeros_at(1));
+    let pairs = sample.len() / 2;
+    if pairs > 0 && even == 0 && odd * 2 > pairs {
+        return Some(Encoding::Utf16Le);
+    }
+    if pairs > 0 && odd == 0 && even * 2 > pairs {
+        return Some(Encoding::Utf16Be);
+    }
+    if even + odd > 0 {
+        return None;
+    }
+    if std::str::from_utf8(bytes).is_ok() {
+        return Some(Encoding::Utf8);
+    }
+    // Anything can be decoded as Latin-1, so only take text with few
+    // control characters for it
+    let controls = sample.iter()
+        .filter(|&&b| (b < 0x20 && !b"\t\n\r\x0c".contains(&b)) || b == 0x7F)
+        .count();
+    if controls * 100 > sample.len() {
+        return None;
+    }
+    Some(Encoding::Latin1)
+}
+
+/// Decode text in the given encoding, replacing anything malformed.
+pub fn decode(bytes: &[u8], encoding: Encoding) -> String {
+    let utf16 = |to_unit: fn([u8; 2]) -> u16| {
+        let units = bytes.chunks_exact(2)
+            .map(|pair| to_unit([pair[0], pair[1]]))
+            .skip_while(|&unit| unit == 0xFEFF);
+        char::decode_utf16(units)
+            .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+            .collect()
+    };
+    match encoding {
+        Encoding::Utf8 => String::from_utf8_lossy(bytes).into_owned(),
+        Encoding::Utf8Bom => String::from_utf8_lossy(&bytes[3..]).into_owned(),
+        Encoding::Utf16Le => utf16(u16::from_le_bytes),
+        Encoding::Utf16Be => utf16(u16::from_be_bytes),
+        Encoding::Latin1 => bytes.iter().map(|&b| char::from(b)).collect(),
+    }
+}
+
+/// Whether a file looks like text rsdiff can decode and compare line by
+/// line. Files too large to hold in memory are left to byte-wise
+/// comparison.
+pub fn is_text(path: &str) -> bool {
+    let size = match fs::metadata(path) {
+        Ok(meta) if meta.is_file() => meta.len(),
+        _ => return false,
+    };
+    if size > MAX_TEXT_SIZE {
+        return false;
+    }
+    let mut sample = vec!();
+    let read = File::open(path)
+        .and_then(|f| f.take(SAMPLE_SIZE as u64).read_to_end(&mut sample));
+    read.is_ok() && detect(&sample).is_some()
+}
+
+/// Compare two text files line by line after decoding them. Files whose
+/// encoding can't be detected are read as Latin-1, which decodes any bytes.
+pub fn diff_text(left: &str, right: &str) -> Result<Diff> {
+    diff_text_with_options(left, right, &DiffOptions::default())
+}
+
+/// Compare two text files line by line after decoding them, with custom
+/// options. Differences in encoding and line endings alone are reported,
+/// but the files still match.
+pub fn diff_text_with_options(left: &str, right: &str, opts: &DiffOptions)
+    -> Result<Diff> {
+    let left_bytes = fs::read(left).map_err(|e| RsdiffError::io(left, e))?;
+    let right_bytes = fs::read(right).map_err(|e| RsdiffError::io(right, e))?;
+    let left_encoding = detect(&left_bytes).unwrap_or(Encoding::Latin1);
+    let right_encoding = detect(&right_bytes).unwrap_or(Encoding::Latin1);
+    let left_text = decode(&left_bytes, left_encoding);
+    let right_text = decode(&right_bytes, right_encoding);
+    let left_lines: Vec<&str> = left_text.lines().collect();
+    let right_lines: Vec<&str> = right_text.lines().collect();
+
+    let mut d = Diff::new(left, right);
+    d.left_hash = opts.hasher().map(|h| hash_bytes_with(&left_bytes, h));
+    d.right_hash = opts.hasher().map(|h| hash_bytes_with(&right_bytes, h));
+    d.matches = left_lines == right_lines;
+    if d.matches {
+        let total = left_lines.len();
+        d.set_counts(total, total, Unit::Lines);
+        if left_encoding != right_encoding {
+            d.findings.push(format!("same text in {} and {}", left_encoding,
+                                    right_encoding));
+        }
+        else if left_bytes != right_bytes {
+            d.findings.push(String::from("same text with different line \
+                                          endings"));
+        }
+        return Ok(d);
+    }
+
+    let encodings = if left_encoding == right_encoding {
+        String::new()
+    }
+    else {
+        format!("{} vs. {} text; ", left_encoding, right_encoding)
+    };
+    let mut hunks = vec!();
+    match edit_script(&left_lines, &right_lines, MAX_SCRIPT_EDITS) {
+        Some(script) => {
+            let removed = script.iter()
+                .filter(|e| matches!(e, Edit::Delete(_)))
+                .count();
+            let added = script.iter()
+                .filter(|e| matches!(e, Edit::Insert(_)))
+                .count();
+            let kept = script.len() - removed - added;
+            d.set_counts(kept, script.len(), Unit::Lines);
+            d.additional_info = format!(
+                "{}{} line(s) removed, {} added; {} of {} lines match \
+                 ({:.1}%)", encodings, removed, added, kept, script.len(),
+                d.similarity * 100.0
+            );
+            hunks = unified(&left_lines, &right_lines, &script);
+        }
+        // Too many changes to list; count them if they can be aligned
+        None => match lcs_len(&left_lines, &right_lines, MAX_EDITS) {
+            Some(kept) => {
+                let removed = left_lines.len() - kept;
+                let added = right_lines.len() - kept;
+                let total = kept + removed + added;
+                d.set_counts(kept, total, Unit::Lines);
+                d.additional_info = format!(
+                    "{}{} line(s) removed, {} added; {} of {} lines match \
+                     ({:.1}%)", encodings, removed, added, kept, total,
+                    d.similarity * 100.0
+                );
+            }
+            None => {
+                d.additional_info = format!("{}too different to align",
+                                            encodings);
+            }
+        },
+    }
+    d.report = format!("{} vs {}: {}", left, right, d.additional_info);
+    if hunks.len() > MAX_HUNK_LINES {
+        let more = hunks.len() - MAX_HUNK_LINES;
+        hunks.truncate(MAX_HUNK_LINES);
+        hunks.push(format!("... {} more line(s) of diff", more));
+    }
+    for line in hunks {
+        d.report.push('\n');
+        d.report.push_str(&line);
+    }
+    Ok(d)
+}
+
+/// Lay an edit script out as unified diff hunks, with a few lines of
+/// context around each change.
+fn unified(left: &[&str], right: &[&str], script: &[Edit]) -> Vec<String> {
+    // Mark the steps of the script to show: changes and their context
+    let changed: Vec<usize> = script.iter().enumerate()
+        .filter(|(_, e)| !matches!(e, Edit::Keep(..)))
+        .map(|(i, _)| i)
+        .collect();
+    let mut shown = vec![false; script.len()];
+    for &i in changed.iter() {
+        let start = i.saturating_sub(CONTEXT_LINES);
+        let end = (i + CONTEXT_LINES + 1).min(script.len());
+        shown[start..end].iter_mut().for_each(|s| *s = true);
+    }
+
+    let mut lines = vec!();
+    let (mut left_line, mut right_line) = (0, 0);
+    let mut i = 0;
+    while i < script.len() {
+        if !shown[i] {
+            left_line += 1;
+            right_line += 1;
+            i += 1;
+            continue;
+        }
+        let hunk_end = (i..script.len()).find(|&j| !shown[j])
+            .unwrap_or(script.len());
+        let mut body = vec!();
+        let (mut left_count, mut right_count) = (0, 0);
+        for edit in script[i..hunk_end].iter() {
+            match *edit {
+                Edit::Keep(l, _) => {
+                    body.push(format!(" {}", left[l]));
+                    left_count += 1;
+                    right_count += 1;
+                }
+                Edit::Delete(l) => {
+                    body.push(format!("-{}", left[l]));
+                    left_count += 1;
+                }
+                Edit::Insert(r) => {
+                    body.push(format!("+{}", right[r]));
+                    right_count += 1;
+                }
+            }
+        }
+        // Empty ranges are numbered by the line before them
+        let start = |line: usize, count: usize| {
+            if count == 0 { line } else { line + 1 }
+        };
+        lines.push(format!("@@ -{},{} +{},{} @@",
+                           start(left_line, left_count), left_count,
+                           start(right_line, right_count), right_count));
+        lines.extend(body);
+        left_line += left_count;
+        right_line += right_count;
+        i = hunk_end;
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::workspace::{test_scratch, Scratch};
+
+    /// Write a file to a test's scratch directory and return its path.
+    fn write(scratch: &Scratch, name: &str, bytes: &[u8]) -> String {
+        let path = scratch.path().join(name);
+        fs::write(&path, bytes).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    fn utf16(text: &str, to_bytes: fn(u16) -> [u8; 2]) -> Vec<u8> {
+        text.encode_utf16().flat_map(to_bytes).collect()
+    }
+
+    /// The unified diff of two texts, a line per element.
+    fn diff(left: &[&str], right: &[&str]) -> Vec<String> {
+        let script = edit_script(left, right, usize::MAX).unwrap();
+        unified(left, right, &script)
+    }
+
+    #[test]
+    fn byte_order_marks_are_detected_and_stripped() {
+        let mut bom8 = vec!(0xEF, 0xBB, 0xBF);
+        bom8.extend_from_slice("é\n".as_bytes());
+        assert_eq!(detect(&bom8), Some(Encoding::Utf8Bom));
+        assert_eq!(decode(&bom8, Encoding::Utf8Bom), "é\n");
+        let mut le = vec!(0xFF, 0xFE);
+        le.extend(utf16("é\n", u16::to_le_bytes));
+        assert_eq!(detect(&le), Some(Encoding::Utf16Le));
+        assert_eq!(decode(&le, Encoding::Utf16Le), "é\n");
+        let mut be = vec!(0xFE, 0xFF);
+        be.extend(utf16("é\n", u16::to_be_bytes));
+        assert_eq!(detect(&be), Some(Encoding::Utf16Be));
+        assert_eq!(decode(&be, Encoding::Utf16Be), "é\n");
+    }
+
+    #[test]
+    fn utf16_without_a_bom_is_detected_by_its_zeros() {
+        let text = "onset\tduration\n1.0\t0.5\n";
+        let le = utf16(text, u16::to_le_bytes);
+        assert_eq!(detect(&le), Some(Encoding::Utf16Le));
+        assert_eq!(decode(&le, Encoding::Utf16Le), text);
+        let be = utf16(text, u16::to_be_bytes);
+        assert_eq!(detect(&be), Some(Encoding::Utf16Be));
+        assert_eq!(decode(&be, Encoding::Utf16Be), text);
+    }
+
+    #[test]
+    fn eight_bit_text_is_utf8_or_latin1() {
+        assert_eq!(detect(b""), Some(Encoding::Utf8));
+        assert_eq!(detect("naïve\n".as_bytes()), Some(Encoding::Utf8));
+        assert_eq!(detect(b"caf\xe9\n"), Some(Encoding::Latin1));
+        assert_eq!(decode(b"caf\xe9\n", Encoding::Latin1), "café\n");
+    }
+
+    #[test]
+    fn binary_data_is_not_text() {
+        // Zeros in both halves of the byte pairs
+        assert_eq!(detect(b"\x00\x01\x00\x00\x02\x00"), None);
+        // Too few bytes to be UTF-16
+        assert_eq!(detect(b"\x00"), None);
+        // Mostly control characters
+        assert_eq!(detect(b"\x01\x02\x03\xff\x04\x05"), None);
+    }
+
+    #[test]
+    fn malformed_utf16_is_replaced() {
+        let lone_surrogate = [0x00, 0xD8, 0x41, 0x00];
+        assert_eq!(decode(&lone_surrogate, Encoding::Utf16Le), "\u{FFFD}A");
+    }
+
+    #[test]
+    fn hunks_are_numbered_by_their_first_line() {
+        let left: Vec<String> = (1..=10).map(|i| i.to_string()).collect();
+        let left: Vec<&str> = left.iter().map(String::as_str).collect();
+        let mut right = left.clone();
+        right.insert(5, "x");
+        assert_eq!(diff(&left, &right),
+                   vec!("@@ -3,6 +3,7 @@", " 3", " 4", " 5", "+x", " 6",
+                        " 7", " 8"));
+        let mut right = left.clone();
+        right[0] = "one";
+        assert_eq!(diff(&left, &right),
+                   vec!("@@ -1,4 +1,4 @@", "-1", "+one", " 2", " 3", " 4"));
+    }
+
+    #[test]
+    fn empty_ranges_are_numbered_by_the_line_before_them() {
+        assert_eq!(diff(&[], &["a", "b"]),
+                   vec!("@@ -0,0 +1,2 @@", "+a", "+b"));
+        assert_eq!(diff(&["a", "b"], &[]),
+                   vec!("@@ -1,2 +0,0 @@", "-a", "-b"));
+    }
+
+    #[test]
+    fn distant_changes_get_hunks_of_their_own() {
+        let left: Vec<String> = (1..=20).map(|i| i.to_string()).collect();
+        let left: Vec<&str> = left.iter().map(String::as_str).collect();
+        let mut right = left.clone();
+        right[1] = "b";
+        right[18] = "s";
+        let hunks: Vec<String> = diff(&left, &right).into_iter()
+            .filter(|line| line.starts_with("@@"))
+            .collect();
+        assert_eq!(hunks, vec!("@@ -1,5 +1,5 @@", "@@ -16,5 +16,5 @@"));
+        // Changes whose context touches share a hunk
+        let mut right = left.clone();
+        right[1] = "b";
+        right[8] = "i";
+        let hunks: Vec<String> = diff(&left, &right).into_iter()
+            .filter(|line| line.starts_with("@@"))
+            .collect();
+        assert_eq!(hunks, vec!("@@ -1,12 +1,12 @@"));
+    }
+
+    #[test]
+    fn encodings_and_line_endings_alone_do_not_differ() {
+        let scratch = test_scratch("text");
+        let left = write(&scratch, "utf8.txt", b"a\nb\n");
+        let right = write(&scratch, "utf16.txt",
+                          &utf16("a\r\nb\r\n", u16::to_le_bytes));
+        let d = diff_text(&left, &right).unwrap();
+        assert!(d.matches, "{}", d.report);
+        assert_eq!(d.findings, vec!("same text in UTF-8 and UTF-16LE"));
+        let right = write(&scratch, "crlf.txt", b"a\r\nb\r\n");
+        let d = diff_text(&left, &right).unwrap();
+        assert!(d.matches, "{}", d.report);
+        assert_eq!(d.findings, vec!("same text with different line endings"));
+    }
+
+    #[test]
+    fn a_nul_byte_is_not_an_empty_file() {
+        let scratch = test_scratch("text");
+        let left = write(&scratch, "empty.txt", b"");
+        let right = write(&scratch, "nul.txt", b"\x00");
+        assert!(!is_text(&right));
+        let d = diff_text(&left, &right).unwrap();
+        assert!(!d.matches);
+    }
+}
@@ -0,0 +1,336 @@
+//! Integrity triage for rsdiff
+//!
+//! Triage answers "is this file damaged?" for a single file, without a
+//! second copy to compare against. It runs the integrity checks that fit
+//! the detected format, reusing the parsing code of the comparators.
+
+use std::{
+    fs::File,
+    io::{self, BufReader, Read},
+};
+
+use nifti::NiftiHeader;
+
+use crate::{gz, read_nii_header};
+
+/// Triage
+/// The outcome of checking a single file's integrity.
+#[derive(Debug)]
+pub struct Triage {
+    /// The file that was checked.
+    pub path: String,
+    /// The format the file was checked as.
+    pub format: String,
+    /// The checks that were run.
+    pub checks: Vec<String>,
+    /// Problems found; empty if the file looks intact.
+    pub problems: Vec<String>,
+}
+
+impl Triage {
+    /// Whether the file passed every check.
+    pub fn ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// Check a file's integrity according to its detected format.
+pub fn triage(path: &str) -> Triage {
+    let mut t = Triage {
+        path: String::from(path),
+        format: String::from("unknown"),
+        checks: vec!(),
+        problems: vec!(),
+    };
+    let magic = match read_magic(path) {
+        Ok(m) => m,
+        Err(e) => {
+            t.problems.push(format!("can't be read: {}", e));
+            return t;
+        }
+    };
+    let gzipped = magic.starts_with(&[0x1f, 0x8b]);
+
+    if path.ends_with(".nii") || path.ends_with(".nii.gz") {
+        t.format = String::from(if gzipped { "gzipped NIfTI" } else { "NIfTI" });
+        check_nii(path, gzipped, &mut t);
+    }
+    else if gzipped {
+        t.format = String::from("gzip");
+        if gz::is_bgzf(path).unwrap_or(false) {
+            t.format = String::from("BGZF");
+            check_bgzf(path, &mut t);
+        }
+        check_gzip(path, &mut t);
+    }
+    else if magic.starts_with(b"PK\x03\x04") || magic.starts_with(b"PK\x05\x06") {
+        t.format = String::from("zip");
+        check_zip(path, &mut t);
+    }
+    t
+}
+
+/// Read up to the first four bytes of a file.
+fn read_magic(path: &str) -> io::Result<Vec<u8>> {
+    let mut magic = vec!();
+    File::open(path)?.take(4).read_to_end(&mut magic)?;
+    Ok(magic)
+}
+
+/// Decompress a whole gzip file; the decoder verifies every member's CRC32
+/// and length trailers along the way.
+fn check_gzip(path: &str, t: &mut Triage) {
+    t.checks.push(String::from("gzip CRC32 and length trailers"));
+    let result = File::open(path)
+        .and_then(|f| io::copy(&mut gz::decoder(BufReader::new(f)),
+                               &mut io::sink()));
+    if let Err(e) = result {
+        t.problems.push(gz::describe_corruption("the", &e));
+    }
+}
+
+/// Walk a BGZF file's blocks and check that it ends with the empty block
+/// bgzip writes as an end-of-file marker.
+fn check_bgzf(path: &str, t: &mut Triage) {
+    t.checks.push(String::from("BGZF block structure"));
+    match gz::bgzf_blocks(path) {
+        Ok(blocks) => {
+            if blocks.last().map(|b| b.size) != Some(0) {
+                t.problems.push(String::from(
+                    "BGZF end-of-file marker is missing; the file may be \
+                     truncated"
+                ));
+            }
+        }
+        Err(e) => t.problems.push(format!("BGZF blocks are damaged: {}", e)),
+    }
+}
+
+/// Check that a NIfTI header is sane and that the file holds as much data
+/// as the header promises.
+fn check_nii(path: &str, gzipped: bool, t: &mut Triage) {
+    t.checks.push(String::from("NIfTI header sanity"));
+    let hdr = match read_nii_header(path) {
+        Ok(h) => h,
+        Err(e) => {
+            t.problems.push(format!("header can't be parsed: {}", e));
+            return;
+        }
+    };
+    t.problems.extend(header_problems(&hdr));
+
+    // Stream the data, which also verifies the gzip trailers
+    t.checks.push(String::from("NIfTI data length"));
+    if gzipped {
+        t.checks.push(String::from("gzip CRC32 and length trailers"));
+    }
+    let length = File::open(path).and_then(|f| {
+        let f = BufReader::new(f);
+        if gzipped {
+            io::copy(&mut gz::decoder(f), &mut io::sink())
+        }
+        else {
+            io::copy(&mut f.take(u64::MAX), &mut io::sink())
+        }
+    });
+    let length = match length {
+        Ok(n) => n,
+        Err(e) if gzipped => {
+            t.problems.push(gz::describe_corruption("the", &e));
+            return;
+        }
+        Err(e) => {
+            t.problems.push(format!("data can't be read: {}", e));
+            return;
+        }
+    };
+    if let Ok(dims) = hdr.dim() {
+        // A damaged header can promise more bytes than can be counted
+        let expected = dims.iter()
+            .try_fold(1u64, |voxels, &n| voxels.checked_mul(n as u64))
+            .and_then(|voxels| {
+                voxels.checked_mul(hdr.bitpix.max(0) as u64 / 8)
+            })
+            .and_then(|bytes| bytes.checked_add(hdr.vox_offset as u64));
+        match expected {
+            Some(expected) if length < expected => t.problems.push(format!(
+                "data is truncated: header needs {} bytes, file has {}",
+                expected, length
+            )),
+            Some(_) => {}
+            None => t.problems.push(format!(
+                "implausible image size: header dimensions {:?} give more \
+                 bytes than can be counted", dims
+            )),
+        }
+    }
+}
+
+/// List anything implausible about a NIfTI-1 header.
+fn header_problems(hdr: &NiftiHeader) -> Vec<String> {
+    let mut problems = vec!();
+    if hdr.sizeof_hdr != 348 {
+        problems.push(format!("sizeof_hdr is {}, not 348", hdr.sizeof_hdr));
+    }
+    if &hdr.magic != b"n+1\0" && &hdr.magic != b"ni1\0" {
+        problems.push(format!("magic is {:?}, not n+1 or ni1", hdr.magic));
+    }
+    if hdr.dim().is_err() {
+        problems.push(format!("dim is invalid: {:?}", hdr.dim));
+    }
+    let expected_bitpix = match hdr.datatype {
+        2 | 256 => Some(8),
+        4 | 512 => Some(16),
+        8 | 16 | 768 => Some(32),
+        32 | 64 | 1024 | 1280 => Some(64),
+        128 => Some(24),
+        1536 | 1792 => Some(128),
+        2048 => Some(256),
+        _ => None,
+    };
+    match expected_bitpix {
+        Some(bitpix) if bitpix != hdr.bitpix => problems.push(format!(
+            "bitpix is {} but datatype {} needs {}",
+            hdr.bitpix, hdr.datatype, bitpix
+        )),
+        None => problems.push(format!("datatype {} is unknown", hdr.datatype)),
+        _ => {}
+    }
+    if &hdr.magic == b"n+1\0" && hdr.vox_offset < 352.0 {
+        problems.push(format!(
+            "vox_offset is {}, inside the header", hdr.vox_offset
+        ));
+    }
+    problems
+}
+
+/// Read every entry of a zip archive, which verifies each entry's CRC32.
+fn check_zip(path: &str, t: &mut Triage) {
+    t.checks.push(String::from("zip entry CRC32s"));
+    let archive = File::open(path)
+        .map_err(|e| e.to_string())
+        .and_then(|f| zip::ZipArchive::new(f).map_err(|e| e.to_string()));
+    let mut archive = match archive {
+        Ok(a) => a,
+        Err(e) => {
+            t.problems.push(format!("archive can't be opened: {}", e));
+            return;
+        }
+    };
+    for i in 0..archive.len() {
+        let result = archive.by_index(i)
+            .map_err(|e| e.to_string())
+            .and_then(|mut entry| {
+                let name = String::from(entry.name());
+                io::copy(&mut entry, &mut io::sink())
+                    .map_err(|e| format!("{}: {}", name, e))
+            });
+        if let Err(e) = result {
+            t.problems.push(format!("entry is damaged: {}", e));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{fs, io::Write};
+
+    use flate2::{write::GzEncoder, Compression};
+    use zip::{write::SimpleFileOptions, CompressionMethod, ZipWriter};
+
+    use crate::workspace::{test_scratch, Scratch};
+
+    /// A single-file NIfTI-1 header of uint8 voxels with the dimensions
+    /// `dim`, followed by `voxels`.
+    fn nifti(dim: [i16; 8], voxels: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![0u8; 352];
+        bytes[0..4].copy_from_slice(&348i32.to_le_bytes());
+        for (i, n) in dim.iter().enumerate() {
+            bytes[40 + 2 * i..42 + 2 * i].copy_from_slice(&n.to_le_bytes());
+        }
+        bytes[70..72].copy_from_slice(&2i16.to_le_bytes());
+        bytes[72..74].copy_from_slice(&8i16.to_le_bytes());
+        for i in 0..8 {
+            bytes[76 + 4 * i..80 + 4 * i]
+                .copy_from_slice(&1f32.to_le_bytes());
+        }
+        bytes[108..112].copy_from_slice(&352f32.to_le_bytes());
+        bytes[112..116].copy_from_slice(&1f32.to_le_bytes());
+        bytes[344..348].copy_from_slice(b"n+1\0");
+        bytes.extend_from_slice(voxels);
+        bytes
+    }
+
+    /// Write `contents` to `name` in a test's scratch directory and triage
+    /// it.
+    fn triaged(scratch: &Scratch, name: &str, contents: &[u8]) -> Triage {
+        let path = scratch.path().join(name);
+        fs::write(&path, contents).unwrap();
+        triage(&path.to_string_lossy())
+    }
+
+    fn has_problem(t: &Triage, start: &str) -> bool {
+        t.problems.iter().any(|p| p.starts_with(start))
+    }
+
+    #[test]
+    fn a_bad_gzip_crc_is_a_problem() {
+        let scratch = test_scratch("triage-gzip");
+        let mut gz = GzEncoder::new(vec!(), Compression::default());
+        gz.write_all(b"some text\n").unwrap();
+        let mut gz = gz.finish().unwrap();
+        assert!(triaged(&scratch, "good.txt.gz", &gz).ok());
+        // The CRC32 is the first half of the eight byte trailer
+        let crc = gz.len() - 8;
+        gz[crc] ^= 0xff;
+        let t = triaged(&scratch, "bad.txt.gz", &gz);
+        assert_eq!(t.format, "gzip");
+        assert!(!t.ok());
+    }
+
+    #[test]
+    fn a_truncated_nifti_is_a_problem() {
+        let scratch = test_scratch("triage-truncated");
+        let dim = [3, 2, 2, 2, 1, 1, 1, 1];
+        let t = triaged(&scratch, "whole.nii", &nifti(dim, &[0; 8]));
+        assert!(t.ok(), "{:?}", t.problems);
+        let t = triaged(&scratch, "truncated.nii", &nifti(dim, &[0; 4]));
+        assert_eq!(t.problems, vec!(
+            "data is truncated: header needs 360 bytes, file has 356"
+        ));
+    }
+
+    #[test]
+    fn an_insane_nifti_header_is_a_problem_not_a_crash() {
+        let scratch = test_scratch("triage-insane");
+        let huge = [7, 32767, 32767, 32767, 32767, 32767, 32767, 32767];
+        let t = triaged(&scratch, "huge.nii", &nifti(huge, &[0; 8]));
+        assert!(has_problem(&t, "implausible image size"), "{:?}",
+                t.problems);
+        let mut bytes = nifti([3, 2, 2, 2, 1, 1, 1, 1], &[0; 8]);
+        bytes[70..72].copy_from_slice(&4i16.to_le_bytes());
+        let t = triaged(&scratch, "bitpix.nii", &bytes);
+        assert!(has_problem(&t, "bitpix is 8 but datatype 4 needs 16"),
+                "{:?}", t.problems);
+    }
+
+    #[test]
+    fn a_damaged_zip_entry_is_a_problem() {
+        let scratch = test_scratch("triage-zip");
+        let mut zip = ZipWriter::new(io::Cursor::new(vec!()));
+        let options = SimpleFileOptions::default()
+            .compression_method(CompressionMethod::Stored);
+        zip.start_file("data.txt", options).unwrap();
+        zip.write_all(b"stored as is\n").unwrap();
+        let mut bytes = zip.finish().unwrap().into_inner();
+        assert!(triaged(&scratch, "good.zip", &bytes).ok());
+        let at = bytes.windows(6).position(|w| w == b"stored").unwrap();
+        bytes[at] = b'S';
+        let t = triaged(&scratch, "bad.zip", &bytes);
+        assert_eq!(t.format, "zip");
+        assert!(has_problem(&t, "entry is damaged: data.txt"), "{:?}",
+                t.problems);
+    }
+}
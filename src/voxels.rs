@@ -0,0 +1,107 @@
+//! Voxel-by-voxel access to NIfTI comparisons for rsdiff
+//!
+//! A comparison reports how many voxels diverge, and summaries of how far
+//! apart they are, but analyses differ in what they need to know about
+//! each one: where the worst voxels are, whether divergence follows a
+//! tissue boundary, or how it is distributed across a mask. Rather than
+//! report every such thing, rsdiff can hand each diverging voxel to a
+//! caller's closure, as the comparison streams past it.
+
+use std::cell::{Cell, RefCell};
+
+use nifti::Endianness;
+
+use crate::{
+    diff_voxels_streamed, drift, gz, read_nii_header, scale_voxel,
+    swap_byte_order, voxel_scaling, DiffOptions, Result, RsdiffError,
+};
+
+/// Call `visit` with the index, left value, and right value of each voxel
+/// that diverges between two NIfTI images, in the order voxels are stored.
+/// Voxels diverge as they would in a comparison with the same options, and
+/// their values are given as compared, so scaled if voxels are compared
+/// scaled. A voxel missing on one side, by being stored as a sentinel, has
+/// a value of NaN there.
+///
+/// With a `limit`, only that many voxels are visited, though all are still
+/// compared. Returns how many voxels diverge, or None if the images'
+/// shapes or data types diverge, since then voxels can't be paired up.
+pub fn diverging_voxels(left: &str, right: &str, opts: &DiffOptions,
+                        limit: Option<usize>,
+                        visit: impl FnMut(usize, f64, f64))
+    -> Result<Option<usize>> {
+    let nifti_error = |path: &str| {
+        let path = String::from(path);
+        move |source| RsdiffError::Nifti { path, source }
+    };
+    let left_hdr = read_nii_header(left).map_err(nifti_error(left))?;
+    let right_hdr = read_nii_header(right).map_err(nifti_error(right))?;
+    if left_hdr.dim != right_hdr.dim
+        || left_hdr.datatype != right_hdr.datatype {
+        return Ok(None);
+    }
+    let dtype = left_hdr.datatype;
+    let (read, width) = drift::voxel_reader(dtype)
+        .ok_or(RsdiffError::UnsupportedDatatype(dtype))?;
+    let (left_scaling, right_scaling, sentinels) = if opts.scaled_voxels {
+        (voxel_scaling(&left_hdr), voxel_scaling(&right_hdr),
+         &opts.sentinels[..])
+    }
+    else {
+        ((1.0, 0.0), (1.0, 0.0), &[][..])
+    };
+    // Judge voxels as their datatype's transmuter would
+    let tolerance = opts.tolerance;
+    let comparison = opts.float_comparison;
+    let same = |a: &[u8], b: &[u8], x: f64, y: f64| match dtype {
+        _ if opts.scaled_voxels => comparison.same_f64(x, y, tolerance),
+        16 => comparison.same_f32(x as f32, y as f32, tolerance as f32),
+        64 => comparison.same_f64(x, y, tolerance),
+        // Integers match when their bytes do, even above 2^53
+        _ => a == b,
+    };
+    let swap_left = left_hdr.endianness == Endianness::Big;
+    let swap_right = right_hdr.endianness == Endianness::Big;
+
+    let index = Cell::new(0);
+    let visited = Cell::new(0);
+    let visit = RefCell::new(visit);
+    let buffer_differ = |a: &mut [u8], b: &mut [u8]| {
+        if swap_left {
+            swap_byte_order(a, width);
+        }
+        if swap_right {
+            swap_byte_order(b, width);
+        }
+        let mut matches = 0;
+        for (a, b) in a.chunks_exact(width).zip(b.chunks_exact(width)) {
+            let i = index.get();
+            index.set(i + 1);
+            let x = scale_voxel(read(a), left_scaling, sentinels);
+            let y = scale_voxel(read(b), right_scaling, sentinels);
+            let diverges = match (x, y) {
+                (Some(x), Some(y)) => !same(a, b, x, y),
+                (None, None) => false,
+                _ => true,
+            };
+            if !diverges {
+                matches += 1;
+                continue;
+            }
+            if limit.is_none_or(|l| visited.get() < l) {
+                visited.set(visited.get() + 1);
+                (visit.borrow_mut())(i, x.unwrap_or(f64::NAN),
+                                     y.unwrap_or(f64::NAN));
+            }
+        }
+        matches
+    };
+    let gzipped = |path: &str| {
+        gz::is_gzip(path).map_err(|e| RsdiffError::io(path, e))
+    };
+    let (matches, _, _) = diff_voxels_streamed(
+        left, gzipped(left)?, right, gzipped(right)?,
+        left_hdr.vox_offset as usize, &buffer_differ, opts
+    )?;
+    Ok(Some(index.get() - matches))
+}
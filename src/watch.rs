@@ -0,0 +1,201 @@
+//! Watching comparisons for rsdiff
+//!
+//! While a pipeline is being debugged, its outputs are regenerated again
+//! and again, and each time they need comparing with the reference. In
+//! watch mode, rsdiff waits for files on either side to change, compares
+//! them again, and reports only how the results moved since the last run:
+//! entries that came to differ or to match, appeared or went away, or got
+//! closer or further apart.
+
+use std::{
+    collections::BTreeMap,
+    fmt,
+    path::Path,
+    sync::mpsc::{self, Receiver, RecvTimeoutError},
+    time::Duration,
+};
+
+use notify::{
+    event::{AccessKind, AccessMode, MetadataKind, ModifyKind},
+    Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher,
+};
+
+use crate::{interrupt, Diff, Result, RsdiffError};
+
+/// How often a wait checks for interruption.
+const POLL: Duration = Duration::from_millis(200);
+
+/// Watch
+/// Notifications of changes to files under some paths.
+pub struct Watch {
+    _watcher: RecommendedWatcher,
+    events: Receiver<notify::Result<Event>>,
+}
+
+impl Watch {
+    /// Start watching the trees, or files, at `paths`.
+    pub fn new(paths: &[&str]) -> Result<Watch> {
+        let (sender, events) = mpsc::channel();
+        let mut watcher = notify::recommended_watcher(sender)
+            .map_err(|e| RsdiffError::Corrupt(
+                format!("can't watch for changes: {}", e)
+            ))?;
+        for path in paths {
+            watcher.watch(Path::new(path), RecursiveMode::Recursive)
+                .map_err(|e| RsdiffError::Corrupt(
+                    format!("can't watch {} for changes: {}", path, e)
+                ))?;
+        }
+        Ok(Watch { _watcher: watcher, events })
+    }
+
+    /// Wait for something under the watched paths to change, then for
+    /// `settle` to pass without further changes, so that files being
+    /// written are compared once they are whole. Returns false if the wait
+    /// was interrupted or the watch stopped instead.
+    pub fn wait(&self, settle: Duration) -> bool {
+        let mut changed = false;
+        loop {
+            if interrupt::requested() {
+                return false;
+            }
+            let timeout = if changed { settle } else { POLL };
+            match self.events.recv_timeout(timeout) {
+                Ok(Ok(event)) => changed |= is_change(&event.kind),
+                // Events lost to an overflowing queue were changes too
+                Ok(Err(_)) => changed = true,
+                Err(RecvTimeoutError::Timeout) if changed => return true,
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => return false,
+            }
+        }
+    }
+}
+
+/// Whether an event changes what a comparison would find. Comparisons
+/// open and read files themselves, which mustn't set them off again.
+fn is_change(kind: &EventKind) -> bool {
+    match kind {
+        EventKind::Access(AccessKind::Close(AccessMode::Write)) => true,
+        EventKind::Access(_) => false,
+        EventKind::Modify(ModifyKind::Metadata(MetadataKind::AccessTime))
+            => false,
+        _ => true,
+    }
+}
+
+/// Outcome
+/// What a comparison found of one entry.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Outcome {
+    /// The entry is the same on both sides.
+    Matches,
+    /// The entry differs, with its similarity, or -1 if there is none.
+    Differs(f32),
+    /// The entry is only on the left.
+    LeftOnly,
+    /// The entry is only on the right.
+    RightOnly,
+}
+
+/// Change
+/// How what a comparison found of an entry changed since an earlier one,
+/// where `None` is an entry the comparison didn't have.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Change {
+    pub name: String,
+    pub before: Option<Outcome>,
+    pub after: Option<Outcome>,
+}
+
+/// How the entries of comparison `after` fare differently than in the
+/// earlier comparison `before` of the same objects, by name.
+pub fn changes(before: &Diff, after: &Diff) -> Vec<Change> {
+    let mut before = outcomes(before);
+    let mut changed = vec!();
+    for (name, outcome) in outcomes(after) {
+        let earlier = before.remove(&name);
+        if earlier != Some(outcome) {
+            changed.push(Change {
+                name, before: earlier, after: Some(outcome)
+            });
+        }
+    }
+    changed.extend(before.into_iter().map(|(name, outcome)| {
+        Change { name, before: Some(outcome), after: None }
+    }));
+    changed.sort_by(|a, b| a.name.cmp(&b.name));
+    changed
+}
+
+/// The outcome of every entry of a comparison that isn't a directory, by
+/// its path under the compared objects. Two files compared on their own
+/// are a single entry named for the right one.
+fn outcomes(d: &Diff) -> BTreeMap<String, Outcome> {
+    let mut found = BTreeMap::new();
+    let mut pending = vec!((String::new(), d));
+    while let Some((dir, node)) = pending.pop() {
+        let under = |name: &str| if dir.is_empty() {
+            String::from(name)
+        }
+        else {
+            format!("{}/{}", dir, name)
+        };
+        let listed = !(node.common.is_empty() && node.left_only.is_empty()
+                       && node.right_only.is_empty());
+        if !listed {
+            let name = if dir.is_empty() { node.right.clone() } else { dir };
+            let outcome = if node.matches {
+                Outcome::Matches
+            }
+            else {
+                Outcome::Differs(node.similarity)
+            };
+            found.insert(name, outcome);
+            continue;
+        }
+        for name in node.left_only.iter() {
+            found.insert(under(name), Outcome::LeftOnly);
+        }
+        for name in node.right_only.iter() {
+            found.insert(under(name), Outcome::RightOnly);
+        }
+        for subdiff in node.sub_diffs.iter() {
+            let name = Path::new(&subdiff.right).strip_prefix(&node.right)
+                .map(|p| p.to_string_lossy().into_owned())
+                .unwrap_or_else(|_| subdiff.right.clone());
+            pending.push((under(&name), subdiff));
+        }
+    }
+    found
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let similar = |s: f32| if s < 0.0 {
+            String::new()
+        }
+        else {
+            format!(" ({:.1}% similar)", s * 100.0)
+        };
+        match (self.before, self.after) {
+            (Some(Outcome::Differs(was)), Some(Outcome::Differs(now))) => {
+                write!(f, "± {} still differs, {:.1}% → {:.1}% similar",
+                       self.name, was.max(0.0) * 100.0, now.max(0.0) * 100.0)
+            }
+            (_, Some(Outcome::Differs(now))) => {
+                write!(f, "± {} now differs{}", self.name, similar(now))
+            }
+            (_, Some(Outcome::Matches)) => {
+                write!(f, "✓ {} now matches", self.name)
+            }
+            (_, Some(Outcome::LeftOnly)) => {
+                write!(f, "- {} is now only on the left", self.name)
+            }
+            (_, Some(Outcome::RightOnly)) => {
+                write!(f, "+ {} is now only on the right", self.name)
+            }
+            (_, None) => write!(f, "  {} is gone from both sides", self.name),
+        }
+    }
+}
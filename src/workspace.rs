@@ -0,0 +1,140 @@
+//! Scratch space for rsdiff
+//!
+//! Some comparisons have to write intermediate files before they can
+//! compare anything, as when container images are unpacked into the
+//! filesystems they describe. Those files go to a workspace, by default in
+//! the system's temporary directory and never beside the data being
+//! compared, where each comparison gets a directory of its own that is
+//! removed once it is done. A workspace may be capped in size, counting
+//! every directory the run has open at once, so that a run over many large
+//! images fails cleanly rather than filling a shared disk.
+//!
+//! Directories left behind by runs that were killed are removed by the
+//! next run to use the same workspace.
+
+use std::{
+    env,
+    fs,
+    io,
+    path::{Path, PathBuf},
+    process,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Once,
+    },
+};
+
+use serde::Serialize;
+
+/// Prefix of the directories rsdiff creates in a workspace.
+const PREFIX: &str = "rsdiff-";
+
+/// Bytes written to all of this process's scratch directories.
+static USED: AtomicU64 = AtomicU64::new(0);
+/// Scratch directories created so far, to name the next one.
+static CREATED: AtomicUsize = AtomicUsize::new(0);
+/// Whether stale directories have been swept from the workspace yet.
+static SWEPT: Once = Once::new();
+
+/// Workspace
+/// Where intermediate files are written, and how much room they may take.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Workspace {
+    /// The directory scratch directories are created in.
+    pub dir: PathBuf,
+    /// Most bytes all scratch directories may hold at once, if capped.
+    pub max_size: Option<u64>,
+}
+
+impl Default for Workspace {
+    fn default() -> Workspace {
+        Workspace {
+            dir: env::temp_dir(),
+            max_size: None,
+        }
+    }
+}
+
+impl Workspace {
+    /// Create a scratch directory for one comparison's intermediate files,
+    /// its name starting with `purpose`.
+    pub fn scratch(&self, purpose: &str) -> io::Result<Scratch> {
+        fs::create_dir_all(&self.dir)?;
+        SWEPT.call_once(|| sweep(&self.dir));
+        let dir = self.dir.join(format!(
+            "{}{}-{}-{}", PREFIX, purpose, process::id(),
+            CREATED.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::create_dir(&dir)?;
+        Ok(Scratch { dir, max_size: self.max_size, charged: AtomicU64::new(0) })
+    }
+}
+
+/// Scratch
+/// A directory of intermediate files, removed when dropped.
+#[derive(Debug)]
+pub struct Scratch {
+    dir: PathBuf,
+    max_size: Option<u64>,
+    charged: AtomicU64,
+}
+
+impl Scratch {
+    /// Where the directory is.
+    pub fn path(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Account for `bytes` about to be written, failing if that would take
+    /// the workspace past its cap.
+    pub fn charge(&self, bytes: u64) -> io::Result<()> {
+        let used = USED.fetch_add(bytes, Ordering::SeqCst) + bytes;
+        if let Some(max_size) = self.max_size.filter(|m| used > *m) {
+            USED.fetch_sub(bytes, Ordering::SeqCst);
+            return Err(io::Error::other(format!(
+                "the workspace in {} would outgrow its {} bytes",
+                self.dir.parent().unwrap_or(&self.dir).display(), max_size
+            )));
+        }
+        self.charged.fetch_add(bytes, Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+        USED.fetch_sub(self.charged.load(Ordering::SeqCst), Ordering::SeqCst);
+    }
+}
+
+/// Remove the scratch directories of rsdiff runs that are no longer
+/// running. Directories are named for the process that created them.
+fn sweep(dir: &Path) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let pid = name.strip_prefix(PREFIX)
+            .and_then(|rest| rest.rsplit('-').nth(1))
+            .and_then(|pid| pid.parse::<i32>().ok());
+        if pid.is_some_and(|pid| !is_running(pid)) {
+            let _ = fs::remove_dir_all(entry.path());
+        }
+    }
+}
+
+/// Whether a process is running, as far as signals can tell.
+fn is_running(pid: i32) -> bool {
+    // Signal 0 checks that the process exists without disturbing it
+    let sent = unsafe { libc::kill(pid, 0) };
+    sent == 0 || io::Error::last_os_error().raw_os_error() != Some(libc::ESRCH)
+}
+
+/// A scratch directory for one test's files, removed when the test is done.
+#[cfg(test)]
+pub(crate) fn test_scratch(test: &str) -> Scratch {
+    Workspace::default().scratch(&format!("test-{}", test)).unwrap()
+}
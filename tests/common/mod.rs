@@ -0,0 +1,79 @@
+//! Fixtures shared by the integration tests: NIfTI-1 images built byte by
+//! byte, and scratch directories to write them to.
+
+// Each test crate uses only some of the fixtures
+#![allow(dead_code)]
+
+use std::{
+    fs::{self, File},
+    io::Write,
+    path::Path,
+};
+
+use flate2::{write::GzEncoder, Compression};
+use rsdiff::workspace::{Scratch, Workspace};
+
+/// Where voxels start: a NIfTI-1 header and an empty extension block.
+pub const VOX_OFFSET: usize = 352;
+
+/// The dimensions of a 2x2x2 image.
+pub const CUBE: [i16; 8] = [3, 2, 2, 2, 1, 1, 1, 1];
+
+/// A single-file NIfTI-1 image of voxels of the given datatype and bitpix
+/// with the dimensions `dim`, holding `voxels` whatever the dimensions say.
+pub fn nifti(datatype: i16, bitpix: i16, dim: [i16; 8], voxels: &[u8])
+    -> Vec<u8> {
+    let mut bytes = vec![0u8; VOX_OFFSET];
+    bytes[0..4].copy_from_slice(&348i32.to_le_bytes());
+    for (i, n) in dim.iter().enumerate() {
+        bytes[40 + 2 * i..42 + 2 * i].copy_from_slice(&n.to_le_bytes());
+    }
+    bytes[70..72].copy_from_slice(&datatype.to_le_bytes());
+    bytes[72..74].copy_from_slice(&bitpix.to_le_bytes());
+    for i in 0..8 {
+        bytes[76 + 4 * i..80 + 4 * i].copy_from_slice(&1f32.to_le_bytes());
+    }
+    bytes[108..112].copy_from_slice(&(VOX_OFFSET as f32).to_le_bytes());
+    bytes[112..116].copy_from_slice(&1f32.to_le_bytes());
+    bytes[344..348].copy_from_slice(b"n+1\0");
+    bytes.extend_from_slice(voxels);
+    bytes
+}
+
+/// The little-endian bytes of float32 voxels.
+pub fn f32_voxels(voxels: &[f32]) -> Vec<u8> {
+    voxels.iter().flat_map(|v| v.to_le_bytes()).collect()
+}
+
+/// The little-endian bytes of float64 voxels.
+pub fn f64_voxels(voxels: &[f64]) -> Vec<u8> {
+    voxels.iter().flat_map(|v| v.to_le_bytes()).collect()
+}
+
+/// A scratch directory for one test, removed when the test is done.
+pub fn scratch(test: &str) -> Scratch {
+    Workspace::default().scratch(&format!("test-{}", test)).unwrap()
+}
+
+/// Write `bytes` to `name` under `dir`, gzipped if asked, and return its
+/// path.
+pub fn write(dir: &Path, name: &str, bytes: &[u8], gzipped: bool)
+    -> String {
+    let path = dir.join(name);
+    if gzipped {
+        let mut encoder = GzEncoder::new(File::create(&path).unwrap(),
+                                         Compression::default());
+        encoder.write_all(bytes).unwrap();
+        encoder.finish().unwrap();
+    }
+    else {
+        fs::write(&path, bytes).unwrap();
+    }
+    path.to_string_lossy().into_owned()
+}
+
+/// Write uncompressed left and right images under `dir` and return their
+/// paths.
+pub fn write_pair(dir: &Path, left: &[u8], right: &[u8]) -> (String, String) {
+    (write(dir, "left.nii", left, false), write(dir, "right.nii", right, false))
+}
@@ -0,0 +1,45 @@
+//! `--emit-hashes` lists every file compared, whichever differ compared it.
+
+mod common;
+
+use std::{collections::BTreeSet, fs, process::Command};
+
+use common::scratch;
+
+#[test]
+fn the_manifest_covers_every_compared_file() {
+    let scratch = scratch("emit-hashes");
+    let dir = scratch.path();
+    let (left, right) = (dir.join("left"), dir.join("right"));
+    let files: [(&str, &[u8]); 4] = [
+        ("s.json", b"{\"RepetitionTime\": 2.0}\n"),
+        ("t.tsv", b"onset\tduration\n1.0\t0.5\n"),
+        ("r.bin", b"\x00\x01\x02\x03"),
+        ("x.txt", b"some text\n"),
+    ];
+    for side in [&left, &right] {
+        fs::create_dir_all(side).unwrap();
+        for (name, contents) in files {
+            fs::write(side.join(name), contents).unwrap();
+        }
+    }
+    let manifest = dir.join("hashes.sha256");
+    let status = Command::new(env!("CARGO_BIN_EXE_rsdiff"))
+        .arg(&left)
+        .arg(&right)
+        .arg("--emit-hashes")
+        .arg(&manifest)
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    let listed: BTreeSet<String> = fs::read_to_string(&manifest).unwrap()
+        .lines()
+        .map(|line| line.split_once("  ").unwrap().1.to_string())
+        .collect();
+    let compared: BTreeSet<String> = [&left, &right].iter()
+        .flat_map(|side| files.iter().map(move |(name, _)| side.join(name)))
+        .map(|path| path.to_string_lossy().into_owned())
+        .collect();
+    assert_eq!(listed, compared);
+}
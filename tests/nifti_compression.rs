@@ -0,0 +1,110 @@
+//! NIfTI comparisons pair a reader with each side as it is stored, so
+//! gzipped and uncompressed files compare by their voxels in any
+//! combination.
+
+mod common;
+
+use std::path::Path;
+
+use rsdiff::{diff_nii_with_options, DiffOptions, MixedCompression, Unit};
+
+use common::{f32_voxels, nifti, scratch, CUBE};
+
+/// Write a 2x2x2 float32 image under `dir`, gzipped if asked, and return
+/// its path.
+fn write(dir: &Path, name: &str, voxels: &[f32; 8], gzipped: bool)
+    -> String {
+    common::write(dir, name, &nifti(16, 32, CUBE, &f32_voxels(voxels)),
+                  gzipped)
+}
+
+/// The file name an image would usually have.
+fn name(side: &str, gzipped: bool) -> String {
+    if gzipped {
+        format!("{}.nii.gz", side)
+    }
+    else {
+        format!("{}.nii", side)
+    }
+}
+
+const VOXELS: [f32; 8] = [0.0, 1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0];
+const CHANGED: [f32; 8] = [0.0, 1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 70.0];
+
+/// Every pairing of gzipped and uncompressed sides, with a name for the
+/// test's scratch directory.
+const COMBINATIONS: [(&str, bool, bool); 4] = [
+    ("plain-plain", false, false),
+    ("plain-gz", false, true),
+    ("gz-plain", true, false),
+    ("gz-gz", true, true),
+];
+
+#[test]
+fn identical_voxels_match_in_every_combination() {
+    for (test, left_gz, right_gz) in COMBINATIONS.iter() {
+        let scratch = scratch(&format!("identical-{}", test));
+        let dir = scratch.path();
+        let left = write(dir, &name("left", *left_gz), &VOXELS, *left_gz);
+        let right = write(dir, &name("right", *right_gz), &VOXELS,
+                          *right_gz);
+        let d = diff_nii_with_options(&left, &right, &DiffOptions::default())
+            .unwrap();
+        assert!(d.matches, "{}: {}", test, d.report);
+        assert_eq!((d.matched, d.total, d.unit), (8, 8, Some(Unit::Elements)));
+    }
+}
+
+#[test]
+fn changed_voxels_are_counted_in_every_combination() {
+    for (test, left_gz, right_gz) in COMBINATIONS.iter() {
+        let scratch = scratch(&format!("changed-{}", test));
+        let dir = scratch.path();
+        let left = write(dir, &name("left", *left_gz), &VOXELS, *left_gz);
+        let right = write(dir, &name("right", *right_gz), &CHANGED,
+                          *right_gz);
+        let d = diff_nii_with_options(&left, &right, &DiffOptions::default())
+            .unwrap();
+        assert!(!d.matches, "{}", test);
+        assert_eq!((d.matched, d.total), (7, 8), "{}", test);
+    }
+}
+
+#[test]
+fn compression_is_detected_whatever_the_name() {
+    let scratch = scratch("misnamed");
+    let left = write(scratch.path(), "left.nii", &VOXELS, true);
+    let right = write(scratch.path(), "right.nii.gz", &VOXELS, false);
+    let d = diff_nii_with_options(&left, &right, &DiffOptions::default())
+        .unwrap();
+    assert!(d.matches, "{}", d.report);
+}
+
+#[test]
+fn mixed_compression_can_be_noted_or_count_as_a_difference() {
+    let scratch = scratch("mixed");
+    let left = write(scratch.path(), "left.nii.gz", &VOXELS, true);
+    let right = write(scratch.path(), "right.nii", &VOXELS, false);
+    let finding = String::from("only the left file is gzipped");
+
+    let compare = diff_nii_with_options(&left, &right, &DiffOptions::default())
+        .unwrap();
+    assert!(compare.matches);
+    assert!(compare.findings.is_empty());
+
+    let opts = DiffOptions::builder()
+        .mixed_compression(MixedCompression::Note)
+        .build()
+        .unwrap();
+    let note = diff_nii_with_options(&left, &right, &opts).unwrap();
+    assert!(note.matches);
+    assert_eq!(note.findings, vec!(finding.clone()));
+
+    let opts = DiffOptions::builder()
+        .mixed_compression(MixedCompression::Differ)
+        .build()
+        .unwrap();
+    let differ = diff_nii_with_options(&left, &right, &opts).unwrap();
+    assert!(!differ.matches);
+    assert_eq!(differ.findings, vec!(finding));
+}
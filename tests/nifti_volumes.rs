@@ -0,0 +1,44 @@
+//! 4D NIfTI comparisons count each volume separately, and headers with
+//! more voxels than can be counted are findings rather than crashes.
+
+mod common;
+
+use rsdiff::{diff_nii_with_options, DiffOptions};
+
+use common::{nifti, scratch, write_pair};
+
+/// A uint8 image with the dimensions `dim`.
+fn uint8(dim: [i16; 8], voxels: &[u8]) -> Vec<u8> {
+    nifti(2, 8, dim, voxels)
+}
+
+#[test]
+fn each_volume_is_counted_separately() {
+    let dim = [4, 2, 2, 1, 3, 1, 1, 1];
+    let left = [0u8; 12];
+    let mut right = left;
+    right[5] = 1;
+    let scratch = scratch("volumes");
+    let (left, right) = write_pair(scratch.path(), &uint8(dim, &left),
+                                   &uint8(dim, &right));
+    let d = diff_nii_with_options(&left, &right, &DiffOptions::default())
+        .unwrap();
+    let volumes: Vec<bool> = d.sub_diffs.iter().map(|s| s.matches).collect();
+    assert_eq!(volumes, vec!(true, false, true));
+}
+
+#[test]
+fn dimensions_too_large_to_address_are_a_finding() {
+    // Every dimension is 65535 as NIfTI-1 stores them unsigned
+    let dim = [7, -1, -1, -1, -1, -1, -1, -1];
+    let voxels = [7u8; 12];
+    let scratch = scratch("huge-volumes");
+    let (left, right) = write_pair(scratch.path(), &uint8(dim, &voxels),
+                                   &uint8(dim, &voxels));
+    let d = diff_nii_with_options(&left, &right, &DiffOptions::default())
+        .unwrap();
+    assert!(!d.matches);
+    assert!(d.sub_diffs.is_empty());
+    assert!(d.findings.iter().any(|f| f.contains("can be addressed")),
+            "{:?}", d.findings);
+}
@@ -0,0 +1,58 @@
+//! Presets that set the tolerance to zero still let equal floats match:
+//! floats are compared exactly then, rather than not at all.
+
+mod common;
+
+use rsdiff::{diff_nii_with_options, preset::{self, Preset}, DiffOptions};
+
+use common::{f32_voxels, f64_voxels, nifti, scratch, write_pair, CUBE};
+
+/// Options as the built-in preset `name` leaves them.
+fn options(name: &str) -> DiffOptions {
+    let mut opts = DiffOptions::default();
+    Preset::built_in(name).unwrap().apply(&mut opts);
+    opts
+}
+
+const VOXELS: [f64; 8] = [0.0, -1.5, 2.25, 1e-30, 4.0, -0.0, 6.0, 7.0e10];
+
+#[test]
+fn identical_float32_images_match_under_every_preset() {
+    let image = nifti(16, 32, CUBE, &f32_voxels(&VOXELS.map(|v| v as f32)));
+    let scratch = scratch("preset-f32");
+    let (left, right) = write_pair(scratch.path(), &image, &image);
+    for name in preset::BUILT_IN {
+        let d = diff_nii_with_options(&left, &right, &options(name))
+            .unwrap();
+        assert!(d.matches, "{}: {}", name, d.report);
+        assert_eq!((d.matched, d.total), (8, 8), "{}", name);
+    }
+}
+
+#[test]
+fn identical_float64_images_match_under_every_preset() {
+    let image = nifti(64, 64, CUBE, &f64_voxels(&VOXELS));
+    let scratch = scratch("preset-f64");
+    let (left, right) = write_pair(scratch.path(), &image, &image);
+    for name in preset::BUILT_IN {
+        let d = diff_nii_with_options(&left, &right, &options(name))
+            .unwrap();
+        assert!(d.matches, "{}: {}", name, d.report);
+        assert_eq!((d.matched, d.total), (8, 8), "{}", name);
+    }
+}
+
+#[test]
+fn changed_voxels_still_differ_at_zero_tolerance() {
+    let voxels = VOXELS.map(|v| v as f32);
+    let mut changed = voxels;
+    changed[1] = -1.5 + 1e-6;
+    let scratch = scratch("preset-changed");
+    let (left, right) = write_pair(scratch.path(),
+                                   &nifti(16, 32, CUBE, &f32_voxels(&voxels)),
+                                   &nifti(16, 32, CUBE, &f32_voxels(&changed)));
+    let d = diff_nii_with_options(&left, &right, &options("strict"))
+        .unwrap();
+    assert!(!d.matches);
+    assert_eq!((d.matched, d.total), (7, 8));
+}
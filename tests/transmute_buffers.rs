@@ -0,0 +1,41 @@
+//! The public buffer comparisons count matching voxels, and buffers of
+//! different lengths are errors rather than panics.
+
+use rsdiff::*;
+
+#[test]
+fn matching_values_of_every_type_are_counted() {
+    let ints: Vec<u8> = (0..16).collect();
+    let mut other = ints.clone();
+    other[15] = 0xff;
+    assert_eq!(diff_transmute_buffers_u16(&ints, &other).unwrap(), 7);
+    assert_eq!(diff_transmute_buffers_i16(&ints, &other).unwrap(), 7);
+    assert_eq!(diff_transmute_buffers_u32(&ints, &other).unwrap(), 3);
+    assert_eq!(diff_transmute_buffers_i32(&ints, &other).unwrap(), 3);
+    assert_eq!(diff_transmute_buffers_u64(&ints, &other).unwrap(), 1);
+    assert_eq!(diff_transmute_buffers_i64(&ints, &other).unwrap(), 1);
+    let floats = |values: &[f32]| -> Vec<u8> {
+        values.iter().flat_map(|v| v.to_le_bytes()).collect()
+    };
+    let (left, right) = (floats(&[1.0, 2.0]), floats(&[1.0, 2.05]));
+    assert_eq!(diff_transmute_buffers_f32(&left, &right, 0.0).unwrap(), 1);
+    assert_eq!(diff_transmute_buffers_f32(&left, &right, 0.1).unwrap(), 2);
+    let doubles = |values: &[f64]| -> Vec<u8> {
+        values.iter().flat_map(|v| v.to_le_bytes()).collect()
+    };
+    let (left, right) = (doubles(&[1.0, 2.0]), doubles(&[1.0, 2.05]));
+    assert_eq!(diff_transmute_buffers_f64(&left, &right, 0.0).unwrap(), 1);
+    assert_eq!(diff_transmute_buffers_f64_by(&left, &right, |_, _| true)
+                   .unwrap(), 2);
+}
+
+#[test]
+fn buffers_of_different_lengths_are_errors() {
+    let (short, long) = ([0u8; 8], [0u8; 16]);
+    assert!(matches!(diff_transmute_buffers_u16(&short, &long),
+                     Err(RsdiffError::Corrupt(_))));
+    assert!(diff_transmute_buffers_i64(&short, &long).is_err());
+    assert!(diff_transmute_buffers_f32(&short, &long, 0.0).is_err());
+    assert!(diff_transmute_buffers_f64_by(&short, &long, |a, b| a == b)
+                .is_err());
+}
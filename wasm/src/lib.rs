@@ -0,0 +1,118 @@
+//! rsdiff for the browser
+//!
+//! A web page can compare NIfTI images a user uploads without sending them
+//! anywhere, by comparing their voxels with rsdiff's engine compiled to
+//! WebAssembly. Built with `wasm-pack build wasm --target web`, this
+//! offers
+//!
+//! ```js
+//! import init, { compare } from "./pkg/rsdiff_wasm.js";
+//! await init();
+//! const c = compare(leftVoxels, rightVoxels, "float32", 1e-6);
+//! console.log(c.matched, c.total, c.similarity, c.rmse);
+//! ```
+//!
+//! where the voxels are `Uint8Array`s of the images' little-endian data,
+//! as found past their headers' `vox_offset`.
+
+use rsdiff_core::{compare_voxels, FloatComparison, Metric, VoxelComparison};
+use wasm_bindgen::prelude::*;
+
+/// Tolerance for floats when none is given, as rsdiff's default.
+const DEFAULT_TOLERANCE: f64 = 1e-16;
+
+/// Comparison
+/// How two arrays of voxels compare.
+#[wasm_bindgen]
+pub struct Comparison {
+    voxels: VoxelComparison,
+}
+
+#[wasm_bindgen]
+impl Comparison {
+    /// How many voxels match.
+    #[wasm_bindgen(getter)]
+    pub fn matched(&self) -> usize {
+        self.voxels.matched
+    }
+
+    /// How many voxels were compared.
+    #[wasm_bindgen(getter)]
+    pub fn total(&self) -> usize {
+        self.voxels.total
+    }
+
+    /// Whether every voxel matches.
+    #[wasm_bindgen(getter)]
+    pub fn matches(&self) -> bool {
+        self.voxels.matched == self.voxels.total
+    }
+
+    /// The similarity by the requested metric, if the arrays have one.
+    #[wasm_bindgen(getter)]
+    pub fn similarity(&self) -> Option<f64> {
+        self.voxels.similarity
+    }
+
+    /// The root mean square of the voxels' differences.
+    #[wasm_bindgen(getter)]
+    pub fn rmse(&self) -> Option<f64> {
+        self.voxels.numeric_differences.map(|n| n.rmse)
+    }
+
+    /// The largest absolute difference between voxels.
+    #[wasm_bindgen(getter, js_name = maxAbs)]
+    pub fn max_abs(&self) -> Option<f64> {
+        self.voxels.numeric_differences.map(|n| n.max_abs)
+    }
+
+    /// The mean absolute difference between voxels.
+    #[wasm_bindgen(getter, js_name = meanAbs)]
+    pub fn mean_abs(&self) -> Option<f64> {
+        self.voxels.numeric_differences.map(|n| n.mean_abs)
+    }
+}
+
+/// Compare two arrays of voxels of the type `dtype`, e.g. "int16" or
+/// "float32". Floats match when closer than `tolerance`, and similarity
+/// is measured by `metric`, "matches", "pearson", "dice", "jaccard", or
+/// "auto", as on rsdiff's command line.
+#[wasm_bindgen]
+pub fn compare(left: &[u8], right: &[u8], dtype: &str,
+               tolerance: Option<f64>, metric: Option<String>)
+    -> Result<Comparison, JsError> {
+    compare_named(left, right, dtype, tolerance, metric.as_deref())
+        .map_err(|e| JsError::new(&e))
+}
+
+/// Compare two arrays of voxels, with their type and metric given by name.
+pub fn compare_named(left: &[u8], right: &[u8], dtype: &str,
+                     tolerance: Option<f64>, metric: Option<&str>)
+    -> Result<Comparison, String> {
+    let metric = match metric {
+        Some(name) => name.parse()?,
+        None => Metric::default(),
+    };
+    let voxels = compare_voxels(
+        left, right, datatype(dtype)?, FloatComparison::Absolute,
+        tolerance.unwrap_or(DEFAULT_TOLERANCE), metric
+    )?;
+    Ok(Comparison { voxels })
+}
+
+/// The NIfTI datatype code of a type named as in NumPy.
+fn datatype(dtype: &str) -> Result<i16, String> {
+    match dtype {
+        "uint8" => Ok(2),
+        "int8" => Ok(256),
+        "int16" => Ok(4),
+        "uint16" => Ok(512),
+        "int32" => Ok(8),
+        "uint32" => Ok(768),
+        "int64" => Ok(1024),
+        "uint64" => Ok(1280),
+        "float32" => Ok(16),
+        "float64" => Ok(64),
+        _ => Err(format!("Unknown dtype {}", dtype)),
+    }
+}